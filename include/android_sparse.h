@@ -0,0 +1,70 @@
+/*
+ * C API of android-sparse, built with the `ffi` feature:
+ *
+ *     cargo rustc --profile ffi --lib --crate-type cdylib --features ffi
+ *
+ * Functions return 0 on success and -1 on failure, in which case
+ * simg_last_error() describes what went wrong.
+ */
+
+#ifndef ANDROID_SPARSE_H
+#define ANDROID_SPARSE_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The log level of errors. */
+#define SIMG_LOG_ERROR 0
+/* The log level of warnings. */
+#define SIMG_LOG_WARNING 1
+/* The log level of details about what is being done. */
+#define SIMG_LOG_INFO 2
+
+/*
+ * Called with the number of bytes of the input read so far and the size of
+ * the input. Returning false cancels the conversion.
+ */
+typedef bool (*simg_progress_callback)(void *user_data, uint64_t done, uint64_t total);
+
+/*
+ * Called with the level and text of a log message. The text is only valid
+ * during the call.
+ */
+typedef void (*simg_log_callback)(void *user_data, int level, const char *message);
+
+/*
+ * Registers callback to receive log messages from all threads, along with
+ * user_data. A null callback unregisters it.
+ */
+void simg_set_log_callback(simg_log_callback callback, void *user_data);
+
+/*
+ * Returns a description of the error of the last function that failed on
+ * this thread, or null if none did. It is valid until the next call on this
+ * thread.
+ */
+const char *simg_last_error(void);
+
+/*
+ * Encodes the raw image at input to the sparse image output, adding a
+ * checksum if crc is set. progress may be null.
+ */
+int simg_encode(const char *input, const char *output, bool crc,
+                simg_progress_callback progress, void *user_data);
+
+/*
+ * Decodes the sparse image at input to the raw image output, verifying its
+ * checksums. progress may be null.
+ */
+int simg_decode(const char *input, const char *output,
+                simg_progress_callback progress, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ANDROID_SPARSE_H */
@@ -0,0 +1,286 @@
+//! A Node.js binding built with napi-rs.
+//!
+//! The built shared library is a native addon once renamed to
+//! `android_sparse.node`. Web tools then encode and decode images
+//! in-process, instead of shelling out to `img2simg` and `simg2img`.
+//!
+//! A `SparseStream` converts the bytes written to it on a worker thread.
+//! Its output is fetched with `read`, which resolves to `null` once the
+//! input has ended and everything was converted. Only a few chunks are
+//! queued in either direction, so `write` resolves once its chunk is
+//! queued, and output has to be read while writing, like in a
+//! `stream.Transform`:
+//!
+//! ```text
+//! const stream = SparseStream.decoder();
+//! const reading = (async () => {
+//!     for (let out; (out = await stream.read()) !== null; ) sink.write(out);
+//! })();
+//! await stream.write(chunk);
+//! stream.end();
+//! await reading;
+//! ```
+
+use android_sparse::{
+    io::ZeroSeek,
+    pipeline,
+    result::{Error, Result},
+    Decoder, Encoder, Reader, Writer,
+};
+use napi::{
+    bindgen_prelude::{AsyncTask, Buffer},
+    Env, Task,
+};
+use napi_derive::napi;
+use std::{
+    io::{self, prelude::*, BufWriter},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+/// The size of the output chunks, which are passed to Node one at a time.
+const OUTPUT_CHUNK_SIZE: usize = 1 << 20;
+/// The number of chunks queued in either direction, which bounds the
+/// memory a stream takes when one side is slower than the other.
+const QUEUED_CHUNKS: usize = 4;
+
+/// A conversion running on a worker thread, fed and drained by channels.
+struct Pipe {
+    input: Mutex<Option<mpsc::SyncSender<Vec<u8>>>>,
+    output: Mutex<mpsc::Receiver<Vec<u8>>>,
+    worker: Mutex<Option<JoinHandle<Result<()>>>>,
+}
+
+impl Pipe {
+    /// Starts converting the written input to the output with `convert`.
+    fn spawn<F>(convert: F) -> Self
+    where
+        F: FnOnce(&mut Input, &mut BufWriter<Output>) -> Result<()> + Send + 'static,
+    {
+        let (input, input_rx) = mpsc::sync_channel(QUEUED_CHUNKS);
+        let (output_tx, output) = mpsc::sync_channel(QUEUED_CHUNKS);
+        let worker = thread::spawn(move || {
+            let mut input = Input {
+                chunks: input_rx,
+                chunk: Vec::new(),
+                pos: 0,
+            };
+            let mut output = BufWriter::with_capacity(OUTPUT_CHUNK_SIZE, Output(output_tx));
+            convert(&mut input, &mut output)?;
+            Ok(output.flush()?)
+        });
+        Self {
+            input: Mutex::new(Some(input)),
+            output: Mutex::new(output),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Queues `chunk` as input, waiting while the queue is full. Once the
+    /// conversion failed, the input is discarded and `read` reports the
+    /// error.
+    fn write(&self, chunk: Vec<u8>) -> Result<()> {
+        let Some(input) = self.input.lock().unwrap().clone() else {
+            return Err(Error::msg("Write after end"));
+        };
+        let _ = input.send(chunk);
+        Ok(())
+    }
+
+    /// Marks the end of the input.
+    fn end(&self) {
+        *self.input.lock().unwrap() = None;
+    }
+
+    /// Waits for the next chunk of output, returning `None` once the
+    /// conversion is done, or its error if it failed.
+    fn read(&self) -> Result<Option<Vec<u8>>> {
+        if let Ok(chunk) = self.output.lock().unwrap().recv() {
+            return Ok(Some(chunk));
+        }
+        match self.worker.lock().unwrap().take() {
+            Some(worker) => match worker.join() {
+                Ok(result) => result.map(|()| None),
+                Err(_) => Err(Error::msg("Internal error")),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reads the chunks written to a `Pipe` until its input ended.
+struct Input {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Passes the output of a `Pipe` to its reader.
+struct Output(mpsc::SyncSender<Vec<u8>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::other("Stream dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn decode(input: &mut Input, output: &mut BufWriter<Output>) -> Result<()> {
+    let mut reader = Reader::new(input, true)?;
+    let mut decoder = Decoder::new(ZeroSeek::new(output))?;
+    pipeline::copy(&mut reader, &mut decoder)?;
+    decoder.close()?;
+    Ok(())
+}
+
+fn encode(input: &mut Input, output: &mut BufWriter<Output>, crc: bool) -> Result<()> {
+    // Sparse images are only written once all of their chunks are known.
+    let mut spool = tempfile::tempfile()?;
+    let mut writer = Writer::new(spool.try_clone()?, crc)?;
+    pipeline::copy(&mut Encoder::new(input)?, &mut writer)?;
+    writer.close()?;
+
+    spool.rewind()?;
+    io::copy(&mut spool, output)?;
+    Ok(())
+}
+
+/// A stream encoding raw images to sparse images, or decoding them.
+#[napi]
+pub struct SparseStream {
+    pipe: Arc<Pipe>,
+}
+
+#[napi]
+impl SparseStream {
+    /// Creates a stream decoding a sparse image, verifying its checksums.
+    #[napi(factory)]
+    pub fn decoder() -> Self {
+        Self {
+            pipe: Arc::new(Pipe::spawn(decode)),
+        }
+    }
+
+    /// Creates a stream encoding a raw image, adding a checksum if `crc`
+    /// is set. The sparse image is only output once the input has ended.
+    #[napi(factory)]
+    pub fn encoder(crc: Option<bool>) -> Self {
+        let crc = crc.unwrap_or(false);
+        Self {
+            pipe: Arc::new(Pipe::spawn(move |input, output| encode(input, output, crc))),
+        }
+    }
+
+    /// Resolves once `chunk` of the input is queued. Rejects after `end`.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn write(&self, chunk: Buffer) -> AsyncTask<WriteTask> {
+        AsyncTask::new(WriteTask(self.pipe.clone(), Some(chunk.to_vec())))
+    }
+
+    /// Marks the end of the input.
+    #[napi]
+    pub fn end(&self) {
+        self.pipe.end();
+    }
+
+    /// Resolves to the next chunk of output, or `null` once the conversion
+    /// is done. Rejects with the error of a failed conversion.
+    #[napi(ts_return_type = "Promise<Buffer | null>")]
+    pub fn read(&self) -> AsyncTask<ReadTask> {
+        AsyncTask::new(ReadTask(self.pipe.clone()))
+    }
+}
+
+/// Waits for room in the input queue of a `SparseStream` on the libuv
+/// thread pool.
+pub struct WriteTask(Arc<Pipe>, Option<Vec<u8>>);
+
+impl Task for WriteTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let chunk = self.1.take().unwrap_or_default();
+        self.0.write(chunk).map_err(to_napi)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Waits for the output of a `SparseStream` on the libuv thread pool.
+pub struct ReadTask(Arc<Pipe>);
+
+impl Task for ReadTask {
+    type Output = Option<Vec<u8>>;
+    type JsValue = Option<Buffer>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        self.0.read().map_err(to_napi)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.map(Buffer::from))
+    }
+}
+
+fn to_napi(err: Error) -> napi::Error {
+    napi::Error::from_reason(format!("{err:#}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(pipe: Pipe, input: &[u8]) -> Result<Vec<u8>> {
+        thread::scope(|s| {
+            s.spawn(|| {
+                for chunk in input.chunks(1000) {
+                    pipe.write(chunk.to_vec()).unwrap();
+                }
+                pipe.end();
+            });
+            let mut output = Vec::new();
+            while let Some(chunk) = pipe.read()? {
+                output.extend(chunk);
+            }
+            Ok(output)
+        })
+    }
+
+    #[test]
+    fn streams() {
+        // Enough raw data for the queues to fill up.
+        let raw: Vec<u8> = (0..4096 * 4096).map(|i| (i / 4096) as u8).collect();
+        let sparse = convert(Pipe::spawn(|i, o| encode(i, o, true)), &raw).unwrap();
+        assert_eq!(convert(Pipe::spawn(decode), &sparse).unwrap(), raw);
+
+        let err = convert(Pipe::spawn(decode), &raw).unwrap_err();
+        assert!(format!("{err:#}").contains("magic"), "{err:#}");
+        let pipe = Pipe::spawn(decode);
+        pipe.end();
+        assert!(pipe.write(vec![0]).is_err());
+    }
+}
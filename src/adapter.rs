@@ -0,0 +1,304 @@
+//! Adapters for plugging other kinds of data sources and sinks into block
+//! pipelines.
+
+use crate::{
+    block::Block,
+    pipeline::{BlockSink, BlockSource},
+    read::Encoder,
+};
+use crate::result::{ensure, Result};
+use std::{
+    cmp, io,
+    ops::{Bound, RangeBounds},
+};
+
+/// Encodes raw image data yielded by an iterator into sparse blocks.
+///
+/// The iterator may yield byte slices of arbitrary sizes, e.g. packets
+/// received from a network stream or output buffers of a decompressor.
+/// They are rebuffered internally into properly aligned blocks. A trailing
+/// partial block is padded with zeros.
+pub struct IterSource<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    encoder: Encoder<IterRead<I>>,
+}
+
+impl<I> IterSource<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    /// Creates a new source that encodes the data yielded by `iter`.
+    pub fn new<II>(iter: II) -> Self
+    where
+        II: IntoIterator<IntoIter = I>,
+    {
+        let src = IterRead {
+            iter: iter.into_iter(),
+            current: None,
+            pos: 0,
+        };
+        Self {
+            encoder: Encoder::new(src).unwrap(),
+        }
+    }
+}
+
+impl<I> BlockSource for IterSource<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        BlockSource::read_block(&mut self.encoder)
+    }
+}
+
+/// Implements `Read` for an iterator over byte slices.
+struct IterRead<I: Iterator> {
+    iter: I,
+    current: Option<I::Item>,
+    pos: usize,
+}
+
+impl<I> io::Read for IterRead<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = &self.current {
+                let data = &current.as_ref()[self.pos..];
+                if !data.is_empty() {
+                    let n = cmp::min(data.len(), buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+
+            match self.iter.next() {
+                Some(item) => {
+                    self.current = Some(item);
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A cipher that transforms the data of raw blocks.
+///
+/// Ciphers are keyed by block index, i.e. the position of the block in
+/// the raw image, which makes them suitable for stream ciphers like
+/// AES-CTR. Closures of type `FnMut(u64, &mut [u8])` implement this trait.
+pub trait BlockCipher {
+    /// Transforms the data of the raw block at `index` in place.
+    fn apply(&mut self, index: u64, data: &mut [u8]);
+}
+
+impl<F: FnMut(u64, &mut [u8])> BlockCipher for F {
+    fn apply(&mut self, index: u64, data: &mut [u8]) {
+        self(index, data)
+    }
+}
+
+/// Passes the raw blocks read from a source through a cipher, e.g. to
+/// decrypt them.
+///
+/// Only raw blocks are transformed, so the chunk structure of the image
+/// stays intact. Note that this means fill values are not encrypted.
+pub struct CipherSource<S, C> {
+    src: S,
+    cipher: C,
+    index: u64,
+}
+
+impl<S: BlockSource, C: BlockCipher> CipherSource<S, C> {
+    /// Creates a new source that transforms the blocks of `src` with `cipher`.
+    pub fn new(src: S, cipher: C) -> Self {
+        Self {
+            src,
+            cipher,
+            index: 0,
+        }
+    }
+
+    /// Consumes this source, returning the wrapped source.
+    pub fn into_inner(self) -> S {
+        self.src
+    }
+}
+
+impl<S: BlockSource, C: BlockCipher> BlockSource for CipherSource<S, C> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut block = match self.src.read_block()? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+
+        if let Block::Raw(buf) = &mut block {
+            self.cipher.apply(self.index, &mut buf[..]);
+        }
+        self.index += block_count(&block);
+
+        Ok(Some(block))
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        self.src.raw_size()
+    }
+}
+
+/// Passes raw blocks through a cipher before writing them to a sink, e.g.
+/// to encrypt them.
+///
+/// Only raw blocks are transformed, so the chunk structure of the image
+/// stays intact. Note that this means fill values are not encrypted. If
+/// the sink computes checksums, they cover the transformed data.
+pub struct CipherSink<K, C> {
+    dst: K,
+    cipher: C,
+    index: u64,
+}
+
+impl<K: BlockSink, C: BlockCipher> CipherSink<K, C> {
+    /// Creates a new sink that transforms blocks with `cipher` before
+    /// writing them to `dst`.
+    pub fn new(dst: K, cipher: C) -> Self {
+        Self {
+            dst,
+            cipher,
+            index: 0,
+        }
+    }
+
+    /// Consumes this sink without closing it, returning the wrapped sink.
+    pub fn into_inner(self) -> K {
+        self.dst
+    }
+}
+
+impl<K: BlockSink, C: BlockCipher> BlockSink for CipherSink<K, C> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        let index = self.index;
+        self.index += block_count(block);
+
+        match block {
+            Block::Raw(buf) => {
+                let mut buf = buf.clone();
+                self.cipher.apply(index, &mut buf[..]);
+                self.dst.write_block(&Block::Raw(buf))
+            }
+            _ => self.dst.write_block(block),
+        }
+    }
+
+    fn end_chunk(&mut self) -> Result<()> {
+        self.dst.end_chunk()
+    }
+
+    fn close(self) -> Result<()> {
+        self.dst.close()
+    }
+}
+
+/// Changes the granularity of the raw chunks written to a sink.
+///
+/// Some flashing tools buffer a whole chunk at a time and cannot handle
+/// large raw chunks, others choke on many tiny ones. This sink ends raw
+/// chunks once they reach the maximum number of blocks and extends raw
+/// chunks shorter than the minimum by storing the blocks that follow as
+/// raw data, decoding fill and don't-care blocks to it. Only the last raw
+/// chunk of an image may be shorter than the minimum.
+///
+/// Chunks are ended with `BlockSink::end_chunk`, so sinks that don't write
+/// chunks only see the conversions to raw data.
+pub struct Rechunk<K> {
+    dst: K,
+    min: u32,
+    max: u32,
+    /// The number of blocks in the current raw chunk.
+    raw_run: u32,
+}
+
+impl<K: BlockSink> Rechunk<K> {
+    /// Creates a new sink that writes to `dst`, with raw chunks of a size
+    /// in `blocks`, e.g. `..=1024` or `16..=1024`.
+    pub fn new<B: RangeBounds<u32>>(dst: K, blocks: B) -> Result<Self> {
+        let min = match blocks.start_bound() {
+            Bound::Included(&min) => min,
+            Bound::Excluded(&min) => min.saturating_add(1),
+            Bound::Unbounded => 1,
+        };
+        let max = match blocks.end_bound() {
+            Bound::Included(&max) => max,
+            Bound::Excluded(&max) => max.saturating_sub(1),
+            Bound::Unbounded => u32::MAX,
+        };
+        ensure!(
+            max > 0 && min <= max,
+            "Invalid raw chunk size range {min} to {max} blocks"
+        );
+        Ok(Self {
+            dst,
+            min,
+            max,
+            raw_run: 0,
+        })
+    }
+
+    /// Consumes this sink without closing it, returning the wrapped sink.
+    pub fn into_inner(self) -> K {
+        self.dst
+    }
+
+    fn write_raw(&mut self, block: &Block) -> Result<()> {
+        if self.raw_run == self.max {
+            self.dst.end_chunk()?;
+            self.raw_run = 0;
+        }
+        self.dst.write_block(block)?;
+        self.raw_run += 1;
+        Ok(())
+    }
+}
+
+impl<K: BlockSink> BlockSink for Rechunk<K> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        match block {
+            Block::Raw(_) => self.write_raw(block),
+            Block::Fill(_) | Block::Skip if self.raw_run > 0 && self.raw_run < self.min => {
+                let mut buf = [0; Block::SIZE as usize];
+                block.decode_into(&mut buf);
+                self.write_raw(&Block::Raw(buf.into()))
+            }
+            _ => {
+                self.raw_run = 0;
+                self.dst.write_block(block)
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) -> Result<()> {
+        self.raw_run = 0;
+        self.dst.end_chunk()
+    }
+
+    fn close(self) -> Result<()> {
+        self.dst.close()
+    }
+}
+
+/// Returns the number of raw image blocks `block` covers.
+fn block_count(block: &Block) -> u64 {
+    match block {
+        Block::Crc32(_) => 0,
+        _ => 1,
+    }
+}
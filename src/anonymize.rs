@@ -0,0 +1,400 @@
+//! Wiping sensitive data from sparse images before sharing them.
+//!
+//! Images attached to bug reports often contain data that must not leave
+//! the device: serial numbers, MAC addresses, private keys. A `Profile`
+//! names the data to wipe, as ranges of the raw image or as regular
+//! expressions matched against its raw blocks, and `apply` overwrites all
+//! of it with zeros in one pass.
+//!
+//! A `Registry` holds the built-in profiles plus those loaded from a
+//! profile file, which uses the subset of TOML described in `settings`,
+//! with a table per profile:
+//!
+//! ```toml
+//! [my-board]
+//! blocks = "0..2"                # raw image blocks, end exclusive
+//! bytes = "0x8000..0x8040"       # raw image bytes, end exclusive
+//! pattern = 'wifi_psk=(\S+)'     # regular expression
+//! ```
+//!
+//! Keys may be repeated. Patterns are best written in single quotes, which
+//! keep backslashes as they are. If a pattern has capture groups, only the
+//! bytes they match are wiped, so `key=value` settings keep their keys.
+
+use crate::{
+    block::{Block, BlockBuf},
+    pipeline::{next_data_block, BlockSink, BlockSource},
+    result::{bail, ensure, Context, Result},
+    settings::{self, Value},
+};
+use regex::bytes::Regex;
+use std::{fmt, fs, ops::Range, path::Path};
+
+/// A part of the raw image to wipe.
+#[derive(Clone, Debug)]
+pub enum Rule {
+    /// A range of raw image blocks.
+    Blocks(Range<u64>),
+    /// A range of raw image bytes.
+    Bytes(Range<u64>),
+    /// A regular expression matched against the raw blocks of the image.
+    Pattern(Regex),
+}
+
+impl Rule {
+    /// Returns the range of raw image bytes the rule wipes, unless it is a
+    /// pattern.
+    pub fn byte_range(&self) -> Option<Range<u64>> {
+        let block_size = u64::from(Block::SIZE);
+        match self {
+            Rule::Blocks(blocks) => {
+                let start = blocks.start.saturating_mul(block_size);
+                Some(start..blocks.end.saturating_mul(block_size))
+            }
+            Rule::Bytes(bytes) => Some(bytes.clone()),
+            Rule::Pattern(_) => None,
+        }
+    }
+}
+
+/// A named set of rules describing the data to wipe from images.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// The name of the profile.
+    pub name: String,
+    /// The parts of the raw image to wipe.
+    pub rules: Vec<Rule>,
+}
+
+impl Profile {
+    /// Creates a profile named `name` without any rules.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let patterns = self
+            .rules
+            .iter()
+            .filter(|r| matches!(r, Rule::Pattern(_)))
+            .count();
+        let ranges = self.rules.len() - patterns;
+        let plural = |n| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{}: {ranges} range{}, {patterns} pattern{}",
+            self.name,
+            plural(ranges),
+            plural(patterns)
+        )
+    }
+}
+
+/// A set of anonymization profiles, looked up by name.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    profiles: Vec<Profile>,
+}
+
+impl Registry {
+    /// Creates a registry of the built-in profiles.
+    ///
+    /// `identifiers` wipes the values of `serialno` settings, MAC addresses
+    /// and PEM-encoded private keys.
+    pub fn builtin() -> Self {
+        let patterns = [
+            r"(?-u)serialno=([[:graph:]]+)",
+            r"(?i-u)\b[0-9a-f]{2}(?::[0-9a-f]{2}){5}\b",
+            r"(?s-u)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?-----END [A-Z ]*PRIVATE KEY-----",
+        ];
+        let identifiers = Profile {
+            rules: patterns
+                .iter()
+                .map(|p| Rule::Pattern(Regex::new(p).unwrap()))
+                .collect(),
+            ..Profile::new("identifiers")
+        };
+        Self {
+            profiles: vec![identifiers],
+        }
+    }
+
+    /// Adds `profile`, replacing any profile of the same name.
+    pub fn insert(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    /// Adds the profiles in the profile file at `path`.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read profile file {}", path.display()))?;
+        self.parse(&content)
+            .with_context(|| format!("Invalid profile file {}", path.display()))
+    }
+
+    /// Adds the profiles in the profile file `content`.
+    pub fn parse(&mut self, content: &str) -> Result<()> {
+        let profiles = settings::parse_tables(content, Profile::new, |profile, key, value| {
+            profile.rules.push(parse_rule(key, value)?);
+            Ok(())
+        })?;
+        for profile in profiles {
+            self.insert(profile);
+        }
+        Ok(())
+    }
+
+    /// Returns the profile `name`.
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Iterates over all profiles, built-in ones first.
+    pub fn iter(&self) -> impl Iterator<Item = &Profile> {
+        self.profiles.iter()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Parses the rule `key = value` of a profile table.
+fn parse_rule(key: &str, value: Value) -> Result<Rule> {
+    let value = value.as_str()?;
+    Ok(match key {
+        "blocks" => Rule::Blocks(parse_range(value)?),
+        "bytes" => Rule::Bytes(parse_range(value)?),
+        "pattern" => Rule::Pattern(Regex::new(value)?),
+        key => bail!("Unknown key `{key}`"),
+    })
+}
+
+/// Parses a range `start..end` of decimal or hexadecimal integers.
+fn parse_range(value: &str) -> Result<Range<u64>> {
+    let Some((start, end)) = value.split_once("..") else {
+        bail!("Expected `start..end`, found `{value}`");
+    };
+    let start = settings::parse_int(start.trim())?;
+    let end = settings::parse_int(end.trim())?;
+    ensure!(start <= end, "Range {value} ends before it starts");
+    Ok(start..end)
+}
+
+/// Wipes the data described by `profile` from the raw image of `src`,
+/// writing the result to `dst` and returning the number of bytes that
+/// were wiped, i.e. changed to zero.
+///
+/// Only blocks that change are rewritten, as raw blocks; all others are
+/// written as read. Ranges beyond the end of the image are ignored, so
+/// profiles can be shared between images of different sizes. Patterns are
+/// matched against raw blocks only, including matches that span two
+/// consecutive raw blocks, so matches of up to a block are found wherever
+/// they are. `dst` is not closed.
+pub fn apply<S, K>(mut src: S, profile: &Profile, dst: &mut K) -> Result<u64>
+where
+    S: BlockSource,
+    K: BlockSink + ?Sized,
+{
+    let block_size = u64::from(Block::SIZE);
+    let ranges: Vec<_> = profile.rules.iter().filter_map(Rule::byte_range).collect();
+    let patterns: Vec<_> = profile
+        .rules
+        .iter()
+        .filter_map(|r| match r {
+            Rule::Pattern(regex) => Some(regex),
+            _ => None,
+        })
+        .collect();
+
+    let mut wiped = 0;
+    let mut start = 0;
+    // The last raw block, held back until the next block is known, so
+    // matches continuing in the next block are found.
+    let mut held = None;
+    let mut window = Vec::with_capacity(2 * Block::SIZE as usize);
+    while let Some(mut block) = next_data_block(&mut src)? {
+        let block_range = start..start + block_size;
+        start = block_range.end;
+
+        let overlaps: Vec<_> = ranges
+            .iter()
+            .filter_map(|r| overlap(r, &block_range))
+            .collect();
+        if !overlaps.is_empty() {
+            let mut buf = [0; Block::SIZE as usize];
+            block.decode_into(&mut buf);
+            let mut changed = 0;
+            for range in overlaps {
+                let start = (range.start - block_range.start) as usize;
+                let end = (range.end - block_range.start) as usize;
+                changed += wipe(&mut buf[start..end]);
+            }
+            if changed > 0 {
+                wiped += changed;
+                block = Block::Raw(buf.into());
+            }
+        }
+
+        let Block::Raw(buf) = block else {
+            if let Some(mut prev) = held.take() {
+                wiped += wipe_patterns(&patterns, &mut prev, None, &mut window);
+                dst.write_block(&Block::Raw(prev))?;
+            }
+            dst.write_block(&block)?;
+            continue;
+        };
+        if let Some(mut prev) = held.replace(buf) {
+            let next = held.as_mut();
+            wiped += wipe_patterns(&patterns, &mut prev, next, &mut window);
+            dst.write_block(&Block::Raw(prev))?;
+        }
+    }
+    if let Some(mut prev) = held {
+        wiped += wipe_patterns(&patterns, &mut prev, None, &mut window);
+        dst.write_block(&Block::Raw(prev))?;
+    }
+    Ok(wiped)
+}
+
+/// Wipes the matches of `patterns` that start in `buf`, continuing into
+/// `next` if it is the following raw block, returning the number of bytes
+/// changed. `window` is scratch space.
+fn wipe_patterns(
+    patterns: &[&Regex],
+    buf: &mut BlockBuf,
+    next: Option<&mut BlockBuf>,
+    window: &mut Vec<u8>,
+) -> u64 {
+    if patterns.is_empty() {
+        return 0;
+    }
+    window.clear();
+    window.extend_from_slice(buf.as_array());
+    if let Some(next) = &next {
+        window.extend_from_slice(next.as_array());
+    }
+    let wiped = patterns
+        .iter()
+        .map(|regex| wipe_matches(regex, window, Block::SIZE as usize))
+        .sum();
+    let (first, rest) = window.split_at(Block::SIZE as usize);
+    buf.as_mut_array().copy_from_slice(first);
+    if let Some(next) = next {
+        next.as_mut_array().copy_from_slice(rest);
+    }
+    wiped
+}
+
+/// Wipes the matches of `regex` in `data` that start before `end`, or only
+/// their capture groups if it has any, returning the number of bytes
+/// changed.
+fn wipe_matches(regex: &Regex, data: &mut [u8], end: usize) -> u64 {
+    let mut found = Vec::new();
+    for captures in regex.captures_iter(data) {
+        if captures.get(0).is_some_and(|m| m.start() >= end) {
+            break;
+        }
+        if captures.len() == 1 {
+            found.extend(captures.get(0).map(|m| m.range()));
+        } else {
+            found.extend(captures.iter().skip(1).flatten().map(|m| m.range()));
+        }
+    }
+    found.into_iter().map(|range| wipe(&mut data[range])).sum()
+}
+
+/// Sets `data` to zeros, returning the number of bytes changed.
+fn wipe(data: &mut [u8]) -> u64 {
+    let changed = data.iter().filter(|&&b| b != 0).count();
+    data.fill(0);
+    changed as u64
+}
+
+/// Returns the intersection of `a` and `b`, if they overlap.
+fn overlap(a: &Range<u64>, b: &Range<u64>) -> Option<Range<u64>> {
+    let start = a.start.max(b.start);
+    let end = a.end.min(b.end);
+    (start < end).then_some(start..end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read::Reader, testutil::write_sparse, write::Writer};
+    use std::io::Cursor;
+
+    fn raw_block(strings: &[(usize, &str)]) -> Block {
+        let mut data = [0; Block::SIZE as usize];
+        for (offset, s) in strings {
+            data[*offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        }
+        Block::Raw(data.into())
+    }
+
+    #[test]
+    fn wipe_profile() {
+        let mut registry = Registry::builtin();
+        registry
+            .parse(
+                "[board] # shared with vendors\n\
+                 blocks = \"3..4\"\n\
+                 bytes = \"0x2002..0x2004\"\n\
+                 pattern = 'psk=\\w+'\n",
+            )
+            .unwrap();
+        let mut profile = registry.get("board").unwrap().clone();
+        assert_eq!(profile.to_string(), "board: 2 ranges, 1 pattern");
+        profile
+            .rules
+            .extend(registry.get("identifiers").unwrap().rules.iter().cloned());
+
+        let blocks = [
+            raw_block(&[(10, "mac 00:1A:2b:3c:4d:5e!"), (4085, "serialno=AB")]),
+            raw_block(&[(0, "CD12 psk=secret")]),
+            Block::fill_u32(0x01010101),
+            Block::fill_u32(0x02020202),
+            Block::Skip,
+        ];
+        let image = write_sparse(&blocks, true).unwrap();
+
+        let reader = Reader::new(&image[..], true).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        let wiped = apply(reader, &profile, &mut writer).unwrap();
+        let image = writer.close().unwrap().into_inner();
+
+        let expected = [
+            raw_block(&[(10, "mac "), (31, "!"), (4085, "serialno=")]),
+            raw_block(&[(4, " ")]),
+            raw_block(&[]),
+            Block::Skip,
+        ];
+        let mut expected_fill = [1; Block::SIZE as usize];
+        expected_fill[2..4].fill(0);
+        let blocks: Vec<_> = Reader::new(&image[..], false)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(blocks[0], expected[0]);
+        assert_eq!(blocks[1], expected[1]);
+        assert_eq!(blocks[2], Block::Raw(expected_fill.into()));
+        assert_eq!(blocks[3], expected[2]);
+        assert_eq!(blocks[4], expected[3]);
+        assert_eq!(wiped, 17 + 6 + 10 + 2 + 4096);
+
+        assert!(registry.parse("[a]\nblocks = 1..2").is_err());
+        assert!(registry.parse("[a]\nbytes = \"2..1\"").is_err());
+        assert!(registry.parse("[a]\npattern = '('").is_err());
+    }
+}
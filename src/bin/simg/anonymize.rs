@@ -0,0 +1,89 @@
+use crate::common;
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use sparse::{
+    anonymize::{self, Profile, Registry},
+    human::HumanSize,
+};
+
+/// Wipe serial numbers, MAC addresses, keys and other data described by
+/// anonymization profiles from a sparse image, e.g. to attach it to a bug
+/// report
+#[derive(FromArgs)]
+#[argh(subcommand, name = "anonymize")]
+pub struct Args {
+    /// anonymization profile to apply, may be repeated to apply several in
+    /// one pass (default: identifiers), see --list-profiles
+    #[argh(option, short = 'p')]
+    profile: Vec<String>,
+
+    /// file with further anonymization profiles
+    #[argh(option)]
+    profiles: Option<String>,
+
+    /// list the available profiles and exit
+    #[argh(switch)]
+    list_profiles: bool,
+
+    /// output sparse image
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// add checksum to output image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output image, even if the `crc` config
+    /// setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// sparse image
+    #[argh(positional)]
+    sparse_image: Option<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let mut registry = Registry::builtin();
+    if let Some(path) = &args.profiles {
+        registry.load(path)?;
+    }
+    if args.list_profiles {
+        for profile in registry.iter() {
+            println!("{profile}");
+        }
+        return Ok(());
+    }
+
+    let names = match args.profile.as_slice() {
+        [] => vec!["identifiers".to_string()],
+        names => names.to_vec(),
+    };
+    let mut profile = Profile::new(names.join("+"));
+    for name in &names {
+        let rules = &registry
+            .get(name)
+            .with_context(|| format!("Unknown profile: {name} (see --list-profiles)"))?
+            .rules;
+        profile.rules.extend(rules.iter().cloned());
+    }
+
+    let (Some(sparse_image), Some(output)) = (&args.sparse_image, &args.output) else {
+        bail!("A sparse image and --output are required");
+    };
+    let config = common::Config::load()?;
+    let reader = config.reader(common::open_input(sparse_image)?, false)?;
+    let mut fo = common::create_output(output, args.force)?;
+    let mut wiped = 0;
+    config.write_sparse(&mut fo, config.crc_enabled(args.crc, args.no_crc)?, |writer| {
+        wiped = anonymize::apply(reader, &profile, writer)?;
+        Ok(())
+    })?;
+    fo.commit()?;
+    common::info(format!("Wiped {} with {}", HumanSize(wiped), profile.name));
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use crate::common;
+use anyhow::Result;
+use argh::FromArgs;
+use std::io::{self, prelude::*, BufReader};
+
+/// Write the block map of a sparse image's decoded content for bmaptool
+#[derive(FromArgs)]
+#[argh(subcommand, name = "bmap")]
+pub struct Args {
+    /// overwrite output block map
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// sparse image
+    #[argh(positional)]
+    image: String,
+
+    /// output block map (default: stdout)
+    #[argh(positional)]
+    output: Option<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let reader = sparse::Reader::new(BufReader::new(common::open_input(&args.image)?), false)?;
+    let xml = sparse::bmap::bmap(reader)?.to_xml();
+
+    match &args.output {
+        Some(path) => {
+            let mut output = common::create_output(path, args.force)?;
+            output.write_all(xml.as_bytes())?;
+            Ok(output.commit()?)
+        }
+        None => Ok(io::stdout().write_all(xml.as_bytes())?),
+    }
+}
@@ -0,0 +1,100 @@
+use crate::common;
+use anyhow::{ensure, Context, Result};
+use argh::FromArgs;
+use sparse::care_map::{CareMap, CareRanges, PartitionInfo};
+use std::{fs, io::prelude::*, io::BufReader};
+
+/// Write the care map update_verifier reads after an A/B update, computed
+/// from sparse partition images without decoding them, and print the
+/// checksum of every partition's care ranges
+#[derive(FromArgs)]
+#[argh(subcommand, name = "care-map")]
+pub struct Args {
+    /// size of a partition's file system as NAME=SIZE, e.g. system=3G, to
+    /// leave out the verity metadata after it like the AOSP build
+    #[argh(option)]
+    image_size: Vec<String>,
+
+    /// build.prop file of a partition as NAME=PATH, to record its
+    /// fingerprint
+    #[argh(option)]
+    build_prop: Vec<String>,
+
+    /// output care map (default: only print checksums)
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// write the text form of the care map instead of care_map.pb
+    #[argh(switch)]
+    text: bool,
+
+    /// overwrite output care map
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// sparse image of a partition as NAME=PATH, e.g. system=system.simg
+    #[argh(positional)]
+    partitions: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    ensure!(!args.partitions.is_empty(), "No partitions given");
+    let image_sizes = args
+        .image_size
+        .iter()
+        .map(|arg| {
+            let (name, size) = split_arg(arg)?;
+            Ok((name, common::parse_size(size).map_err(anyhow::Error::msg)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let build_props = args
+        .build_prop
+        .iter()
+        .map(|arg| split_arg(arg))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut care_map = CareMap::default();
+    for arg in &args.partitions {
+        let (name, path) = split_arg(arg)?;
+        let image_size = image_sizes
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, s)| *s);
+        let reader = sparse::Reader::new(BufReader::new(common::open_input(path)?), false)?;
+        let care = CareRanges::from_source(reader, image_size)
+            .with_context(|| format!("Cannot compute the care map of {path}"))?;
+        println!(
+            "{name}: {} blocks, sha1 {}",
+            care.blocks_count(),
+            care.sha1_hex()
+        );
+
+        let mut partition = PartitionInfo::new(name, &care);
+        if let Some((_, prop_path)) = build_props.iter().find(|(n, _)| *n == name) {
+            let content = fs::read_to_string(prop_path)
+                .with_context(|| format!("Cannot read {prop_path}"))?;
+            if !partition.set_fingerprint(&content) {
+                common::warn(format!("No fingerprint for {name} in {prop_path}"));
+            }
+        }
+        care_map.partitions.push(partition);
+    }
+
+    if let Some(path) = &args.output {
+        let data = match args.text {
+            true => care_map.to_text().into_bytes(),
+            false => care_map.to_protobuf(),
+        };
+        let mut output = common::create_output(path, args.force)?;
+        output.write_all(&data)?;
+        output.commit()?;
+    }
+    Ok(())
+}
+
+/// Splits a `NAME=VALUE` argument.
+fn split_arg(arg: &str) -> Result<(&str, &str)> {
+    arg.split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .with_context(|| format!("Expected NAME=VALUE, found `{arg}`"))
+}
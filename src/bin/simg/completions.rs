@@ -0,0 +1,130 @@
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use std::fmt::Write;
+
+/// Print a shell completion script for bash, zsh or fish
+#[derive(FromArgs)]
+#[argh(subcommand, name = "completions")]
+pub struct Args {
+    /// shell to generate completions for
+    #[argh(positional)]
+    shell: String,
+}
+
+/// A subcommand and the flags it accepts.
+struct Command {
+    name: String,
+    flags: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let commands = commands();
+    let script = match args.shell.as_str() {
+        "bash" => bash(&commands),
+        "zsh" => format!(
+            "autoload -U bashcompinit && bashcompinit\n{}",
+            bash(&commands)
+        ),
+        "fish" => fish(&commands),
+        shell => bail!("Unsupported shell: {shell}"),
+    };
+    print!("{script}");
+    Ok(())
+}
+
+/// Collects the subcommands and their flags from the generated help texts,
+/// so completions never get out of sync with the actual arguments.
+fn commands() -> Vec<Command> {
+    section(&help(&["--help"]), "Commands:")
+        .into_iter()
+        .map(|name| {
+            let flags = section(&help(&[&name, "--help"]), "Options:")
+                .into_iter()
+                .filter(|f| f.starts_with('-'))
+                .collect();
+            Command { name, flags }
+        })
+        .collect()
+}
+
+fn help(args: &[&str]) -> String {
+    match crate::Args::from_args(&["simg"], args) {
+        Ok(_) => String::new(),
+        Err(exit) => exit.output,
+    }
+}
+
+/// Returns the names listed in a section of a help text.
+fn section(help: &str, title: &str) -> Vec<String> {
+    help.lines()
+        .skip_while(|line| *line != title)
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter(|line| line.starts_with("  ") && !line.starts_with("   "))
+        .flat_map(|line| {
+            let names = line.trim_start().split("  ").next().unwrap_or("");
+            names.split(", ").map(str::to_string).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn bash(commands: &[Command]) -> String {
+    let names: Vec<_> = commands.iter().map(|c| c.name.as_str()).collect();
+
+    let mut script = String::new();
+    script.push_str("_simg() {\n");
+    script.push_str("    local cur=${COMP_WORDS[COMP_CWORD]} opts\n");
+    script.push_str("    if [ \"$COMP_CWORD\" -eq 1 ]; then\n");
+    writeln!(
+        script,
+        "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+        names.join(" ")
+    )
+    .unwrap();
+    script.push_str("        return\n");
+    script.push_str("    fi\n");
+    script.push_str("    case ${COMP_WORDS[1]} in\n");
+    for command in commands {
+        writeln!(
+            script,
+            "        {}) opts=\"{}\" ;;",
+            command.name,
+            command.flags.join(" ")
+        )
+        .unwrap();
+    }
+    script.push_str("    esac\n");
+    script.push_str("    if [[ $cur == -* ]]; then\n");
+    script.push_str("        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n");
+    script.push_str("    else\n");
+    script.push_str("        COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    script.push_str("    fi\n");
+    script.push_str("}\n");
+    script.push_str("complete -F _simg simg\n");
+    script
+}
+
+fn fish(commands: &[Command]) -> String {
+    let mut script = String::new();
+    for command in commands {
+        writeln!(
+            script,
+            "complete -c simg -n __fish_use_subcommand -f -a {}",
+            command.name
+        )
+        .unwrap();
+        for flag in &command.flags {
+            let option = match flag.strip_prefix("--") {
+                Some(long) => format!("-l {long}"),
+                None => format!("-s {}", flag.trim_start_matches('-')),
+            };
+            writeln!(
+                script,
+                "complete -c simg -n '__fish_seen_subcommand_from {}' {option}",
+                command.name
+            )
+            .unwrap();
+        }
+    }
+    script
+}
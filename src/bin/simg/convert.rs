@@ -0,0 +1,40 @@
+use crate::common;
+use anyhow::Result;
+use argh::FromArgs;
+use std::io::{self, prelude::*};
+
+/// Convert between sparse and raw images, detecting the input format
+#[derive(FromArgs)]
+#[argh(subcommand, name = "convert")]
+pub struct Args {
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// input and output image, - for stdin/stdout (default)
+    #[argh(positional)]
+    images: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    anyhow::ensure!(args.images.len() <= 2, "Too many images given");
+    let mut images = args.images.iter().map(String::as_str);
+    let (input, output) = (images.next().unwrap_or("-"), images.next().unwrap_or("-"));
+
+    let input: Box<dyn Read> = match input {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(common::open_input(path)?),
+    };
+
+    match output {
+        "-" => {
+            sparse::auto_convert(input, io::stdout().lock())?;
+        }
+        path => {
+            let mut fo = common::create_output(path, args.force)?;
+            sparse::auto_convert(input, &mut fo)?;
+            fo.commit()?;
+        }
+    }
+    Ok(())
+}
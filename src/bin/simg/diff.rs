@@ -0,0 +1,96 @@
+use crate::common;
+use anyhow::{ensure, Result};
+use argh::FromArgs;
+use sparse::block::Block;
+use std::process;
+
+/// Compare the decoded content of two sparse images
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff")]
+pub struct Args {
+    /// write a patch turning the old image into the new one to this file,
+    /// requires --reverse
+    #[argh(option)]
+    forward: Option<String>,
+
+    /// write a patch turning the new image back into the old one to this
+    /// file, requires --forward
+    #[argh(option)]
+    reverse: Option<String>,
+
+    /// add checksums to the patches (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksums to the patches, even if the `crc` config
+    /// setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite the patches
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// old sparse image
+    #[argh(positional)]
+    old_image: String,
+
+    /// new sparse image
+    #[argh(positional)]
+    new_image: String,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let old = sparse::Reader::new(common::open_input(&args.old_image)?, false)?;
+    let new = sparse::Reader::new(common::open_input(&args.new_image)?, false)?;
+
+    match (&args.forward, &args.reverse) {
+        (Some(forward), Some(reverse)) => return write_patches(old, new, forward, reverse, &args),
+        (None, None) => (),
+        _ => ensure!(false, "--forward and --reverse must be given together"),
+    }
+
+    let ranges = sparse::diff::diff_ranges(old, new)?;
+    if ranges.is_empty() {
+        return Ok(());
+    }
+
+    let block_size = u64::from(Block::SIZE);
+    for range in ranges {
+        println!(
+            "blocks {}..{} (bytes {:#x}..{:#x})",
+            range.start,
+            range.end,
+            range.start * block_size,
+            range.end * block_size
+        );
+    }
+
+    // Like diff(1), signal differing inputs with exit status 1.
+    process::exit(1);
+}
+
+/// Writes the forward and reverse patches between `old` and `new`.
+fn write_patches<A, B>(old: A, new: B, forward: &str, reverse: &str, args: &Args) -> Result<()>
+where
+    A: sparse::BlockSource,
+    B: sparse::BlockSource,
+{
+    let config = common::Config::load()?;
+    let crc = config.crc_enabled(args.crc, args.no_crc)?;
+    let mut forward_out = common::create_output(forward, args.force)?;
+    let mut reverse_out = common::create_output(reverse, args.force)?;
+
+    let mut changed = 0;
+    config.write_sparse(&mut forward_out, crc, |forward| {
+        config.write_sparse(&mut reverse_out, crc, |reverse| {
+            changed = sparse::diff::deltas(old, new, forward, reverse)?;
+            Ok(())
+        })
+    })?;
+    forward_out.commit()?;
+    reverse_out.commit()?;
+
+    println!("{changed} blocks differ");
+    Ok(())
+}
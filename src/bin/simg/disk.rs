@@ -0,0 +1,293 @@
+//! Conversion between sparse images and virtual disk containers.
+
+use crate::common::{self, Config};
+use anyhow::Result;
+use argh::FromArgs;
+use sparse::{pipeline, BlockSource, Reader};
+use std::{fs::File, io::BufReader, path::Path};
+
+/// Convert between sparse and qcow2 images, detecting the input format
+#[cfg(feature = "qcow2")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "qcow2")]
+pub struct Qcow2Args {
+    /// add checksum to output sparse image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output sparse image, even if the `crc`
+    /// config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// input sparse or qcow2 image
+    #[argh(positional)]
+    input: String,
+
+    /// output qcow2 or sparse image
+    #[argh(positional)]
+    output: String,
+}
+
+/// Convert between sparse and VHD images, detecting the input format
+#[cfg(feature = "vhd")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "vhd")]
+pub struct VhdArgs {
+    /// add checksum to output sparse image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output sparse image, even if the `crc`
+    /// config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// derive the disk's ID from its size instead of generating a random
+    /// one, so identical inputs yield identical VHDs
+    #[argh(switch)]
+    deterministic: bool,
+
+    /// input sparse or VHD image
+    #[argh(positional)]
+    input: String,
+
+    /// output VHD or sparse image
+    #[argh(positional)]
+    output: String,
+}
+
+/// Convert between sparse and VHDX images, detecting the input format
+#[cfg(feature = "vhdx")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "vhdx")]
+pub struct VhdxArgs {
+    /// add checksum to output sparse image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output sparse image, even if the `crc`
+    /// config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// derive the disk's ID from its size instead of generating a random
+    /// one, so identical inputs yield identical VHDXs
+    #[argh(switch)]
+    deterministic: bool,
+
+    /// input sparse or VHDX image
+    #[argh(positional)]
+    input: String,
+
+    /// output VHDX or sparse image
+    #[argh(positional)]
+    output: String,
+}
+
+/// Convert between sparse and VMDK images, detecting the input format
+#[cfg(feature = "vmdk")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "vmdk")]
+pub struct VmdkArgs {
+    /// add checksum to output sparse image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output sparse image, even if the `crc`
+    /// config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// derive the disk's ID from its size instead of generating a random
+    /// one, so identical inputs yield identical VMDKs
+    #[argh(switch)]
+    deterministic: bool,
+
+    /// input sparse or VMDK image
+    #[argh(positional)]
+    input: String,
+
+    /// output VMDK or sparse image
+    #[argh(positional)]
+    output: String,
+}
+
+#[cfg(feature = "qcow2")]
+pub fn run_qcow2(args: Qcow2Args) -> Result<()> {
+    run(
+        Container::Qcow2,
+        common::crc_flag(args.crc, args.no_crc)?,
+        args.force,
+        false,
+        &args.input,
+        &args.output,
+    )
+}
+
+#[cfg(feature = "vhd")]
+pub fn run_vhd(args: VhdArgs) -> Result<()> {
+    run(
+        Container::Vhd,
+        common::crc_flag(args.crc, args.no_crc)?,
+        args.force,
+        args.deterministic,
+        &args.input,
+        &args.output,
+    )
+}
+
+#[cfg(feature = "vhdx")]
+pub fn run_vhdx(args: VhdxArgs) -> Result<()> {
+    run(
+        Container::Vhdx,
+        common::crc_flag(args.crc, args.no_crc)?,
+        args.force,
+        args.deterministic,
+        &args.input,
+        &args.output,
+    )
+}
+
+#[cfg(feature = "vmdk")]
+pub fn run_vmdk(args: VmdkArgs) -> Result<()> {
+    run(
+        Container::Vmdk,
+        common::crc_flag(args.crc, args.no_crc)?,
+        args.force,
+        args.deterministic,
+        &args.input,
+        &args.output,
+    )
+}
+
+fn run(
+    container: Container,
+    crc: Option<bool>,
+    force: bool,
+    deterministic: bool,
+    input: &str,
+    output: &str,
+) -> Result<()> {
+    let config = Config::load()?;
+    let crc = crc.unwrap_or(config.crc);
+    let mut fi = BufReader::new(common::open_input(input)?);
+    let mut fo = common::create_output(output, force)?;
+
+    if container.detect(&mut fi)? {
+        let mut reader = container.reader(fi)?;
+        config.write_sparse(&mut fo, crc, |writer| {
+            pipeline::copy(&mut reader, writer)?;
+            Ok(())
+        })?;
+    } else {
+        // The container names its own file in some formats.
+        let name = Path::new(output).file_name().unwrap_or_default();
+        let mut reader = Reader::new(fi, crc)?;
+        let name = name.to_string_lossy();
+        container.write(&mut reader, fo.as_file(), &name, deterministic)?;
+    }
+
+    Ok(fo.commit()?)
+}
+
+/// A virtual disk container format.
+#[derive(Clone, Copy)]
+enum Container {
+    #[cfg(feature = "qcow2")]
+    Qcow2,
+    #[cfg(feature = "vhd")]
+    Vhd,
+    #[cfg(feature = "vhdx")]
+    Vhdx,
+    #[cfg(feature = "vmdk")]
+    Vmdk,
+}
+
+type Input = BufReader<File>;
+
+impl Container {
+    /// Checks whether `fi` is an image in this format.
+    fn detect(self, fi: &mut Input) -> Result<bool> {
+        match self {
+            #[cfg(feature = "qcow2")]
+            Container::Qcow2 => Ok(sparse::qcow2::is_qcow2(fi)?),
+            #[cfg(feature = "vhd")]
+            Container::Vhd => Ok(sparse::vhd::is_vhd(fi)?),
+            #[cfg(feature = "vhdx")]
+            Container::Vhdx => Ok(sparse::vhdx::is_vhdx(fi)?),
+            #[cfg(feature = "vmdk")]
+            Container::Vmdk => Ok(sparse::vmdk::is_vmdk(fi)?),
+        }
+    }
+
+    /// Opens `fi` as an image in this format.
+    fn reader(self, fi: Input) -> Result<Box<dyn BlockSource>> {
+        match self {
+            #[cfg(feature = "qcow2")]
+            Container::Qcow2 => Ok(Box::new(sparse::qcow2::Qcow2Reader::new(fi)?)),
+            #[cfg(feature = "vhd")]
+            Container::Vhd => Ok(Box::new(sparse::vhd::VhdReader::new(fi)?)),
+            #[cfg(feature = "vhdx")]
+            Container::Vhdx => Ok(Box::new(sparse::vhdx::VhdxReader::new(fi)?)),
+            #[cfg(feature = "vmdk")]
+            Container::Vmdk => Ok(Box::new(sparse::vmdk::VmdkReader::new(fi)?)),
+        }
+    }
+
+    /// Writes the blocks of `reader` as an image in this format to `fo`.
+    #[cfg_attr(not(feature = "vmdk"), allow(unused_variables))]
+    fn write(
+        self,
+        reader: &mut Reader<Input>,
+        fo: &File,
+        name: &str,
+        deterministic: bool,
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "qcow2")]
+            Container::Qcow2 => {
+                let mut writer = sparse::qcow2::Qcow2Writer::new(fo)?;
+                pipeline::copy(reader, &mut writer)?;
+                Ok(writer.close()?)
+            }
+            #[cfg(feature = "vhd")]
+            Container::Vhd => {
+                let mut writer =
+                    sparse::vhd::VhdWriter::new(fo, reader.size)?.deterministic(deterministic);
+                pipeline::copy(reader, &mut writer)?;
+                Ok(writer.close()?)
+            }
+            #[cfg(feature = "vhdx")]
+            Container::Vhdx => {
+                let mut writer =
+                    sparse::vhdx::VhdxWriter::new(fo, reader.size)?.deterministic(deterministic);
+                pipeline::copy(reader, &mut writer)?;
+                Ok(writer.close()?)
+            }
+            #[cfg(feature = "vmdk")]
+            Container::Vmdk => {
+                let mut writer = sparse::vmdk::VmdkWriter::new(fo, reader.size, name)?
+                    .deterministic(deterministic);
+                pipeline::copy(reader, &mut writer)?;
+                Ok(writer.close()?)
+            }
+        }
+    }
+}
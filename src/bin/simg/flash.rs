@@ -0,0 +1,93 @@
+use crate::common;
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use sparse::block::Block;
+use std::fs::OpenOptions;
+
+/// Write sparse images to a block device or existing raw image in place
+#[derive(FromArgs)]
+#[argh(subcommand, name = "flash")]
+pub struct Args {
+    /// verify checksum (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't verify checksum, even if the `crc` config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// write images even if the destination is one of them
+    #[argh(switch)]
+    allow_same_file: bool,
+
+    /// lock the destination while writing, failing if another process
+    /// holds a lock on it
+    #[argh(switch)]
+    lock: bool,
+
+    /// sync each image to the destination before writing the next one, so
+    /// it is complete once the command exits
+    #[argh(switch)]
+    sync: bool,
+
+    /// destination device or raw image
+    #[argh(positional)]
+    device: String,
+
+    /// input sparse images, written in order
+    #[argh(positional)]
+    sparse_images: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    anyhow::ensure!(!args.sparse_images.is_empty(), "No input images given");
+    let config = common::Config::load()?;
+
+    // The lock is held until this handle is closed, across all images.
+    let _lock = match args.lock {
+        true => {
+            let device = OpenOptions::new().write(true).open(&args.device)?;
+            sparse::io::try_lock(&device)
+                .with_context(|| format!("Cannot lock {}", args.device))?;
+            Some(device)
+        }
+        false => None,
+    };
+
+    // Skipped blocks are left untouched, so parts of a split image can be
+    // written one after the other.
+    for image in &args.sparse_images {
+        common::info(format_args!("Writing {image} to {}", args.device));
+        let input = common::open_input(image)?;
+        if !args.allow_same_file {
+            common::check_distinct(&input, &args.device)?;
+        }
+        let is_file = input.metadata()?.is_file();
+        let mut reader = config.reader(input, config.crc_enabled(args.crc, args.no_crc)?)?;
+        // Catch corrupt images before writing anything to the device.
+        if is_file {
+            reader.prescan()?;
+        }
+        let device = OpenOptions::new().write(true).open(&args.device)?;
+        let mut decoder = config
+            .decoder(device)?
+            .preserve_skipped(true)
+            .sync_on_close(args.sync);
+
+        let bar = common::progress_bar(reader.size);
+        let chunk_bar = bar.clone();
+        let reader = reader.on_progress(move |progress| {
+            if progress.is_chunk_start() {
+                chunk_bar.set_message(progress.to_string());
+            }
+        });
+        for block in reader {
+            decoder.write_block(&block?)?;
+            bar.inc(Block::SIZE.into());
+        }
+
+        bar.finish();
+        decoder.close()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+extern crate android_sparse as sparse;
+
+#[cfg(feature = "anonymize")]
+mod anonymize;
+#[cfg(feature = "bmap")]
+mod bmap;
+#[cfg(feature = "care_map")]
+mod care_map;
+mod completions;
+mod convert;
+mod diff;
+#[cfg(any(feature = "qcow2", feature = "vhd", feature = "vhdx", feature = "vmdk"))]
+mod disk;
+mod flash;
+mod merge;
+mod probe;
+mod splice;
+mod split;
+mod verify;
+
+use argh::FromArgs;
+use sparse::cli::{common, decode, dump, encode, strings};
+
+/// Work with Android sparse images
+#[derive(FromArgs)]
+struct Args {
+    /// only report errors on stderr, no warnings or progress bars
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// also report details of what is being done on stderr
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    #[cfg(feature = "anonymize")]
+    Anonymize(anonymize::Args),
+    #[cfg(feature = "bmap")]
+    Bmap(bmap::Args),
+    #[cfg(feature = "care_map")]
+    CareMap(care_map::Args),
+    Completions(completions::Args),
+    Convert(convert::Args),
+    Decode(decode::Args),
+    Diff(diff::Args),
+    Dump(dump::Args),
+    Encode(encode::Args),
+    Flash(flash::Args),
+    #[cfg(feature = "grep")]
+    Grep(strings::GrepArgs),
+    Merge(merge::Args),
+    Probe(probe::Args),
+    #[cfg(feature = "qcow2")]
+    Qcow2(disk::Qcow2Args),
+    Splice(splice::Args),
+    Split(split::Args),
+    Strings(strings::StringsArgs),
+    Verify(verify::Args),
+    #[cfg(feature = "vhd")]
+    Vhd(disk::VhdArgs),
+    #[cfg(feature = "vhdx")]
+    Vhdx(disk::VhdxArgs),
+    #[cfg(feature = "vmdk")]
+    Vmdk(disk::VmdkArgs),
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Args = argh::from_env();
+    common::init_logging(args.quiet, args.verbose);
+    common::cleanup_on_interrupt()?;
+
+    match args.command {
+        #[cfg(feature = "anonymize")]
+        Command::Anonymize(args) => anonymize::run(args),
+        #[cfg(feature = "bmap")]
+        Command::Bmap(args) => bmap::run(args),
+        #[cfg(feature = "care_map")]
+        Command::CareMap(args) => care_map::run(args),
+        Command::Completions(args) => completions::run(args),
+        Command::Convert(args) => convert::run(args),
+        Command::Decode(args) => decode::run(args),
+        Command::Diff(args) => diff::run(args),
+        Command::Dump(args) => dump::run(args),
+        Command::Encode(args) => encode::run(args),
+        Command::Flash(args) => flash::run(args),
+        #[cfg(feature = "grep")]
+        Command::Grep(args) => strings::run_grep(args),
+        Command::Merge(args) => merge::run(args),
+        Command::Probe(args) => probe::run(args),
+        #[cfg(feature = "qcow2")]
+        Command::Qcow2(args) => disk::run_qcow2(args),
+        Command::Splice(args) => splice::run(args),
+        Command::Split(args) => split::run(args),
+        Command::Strings(args) => strings::run_strings(args),
+        Command::Verify(args) => verify::run(args),
+        #[cfg(feature = "vhd")]
+        Command::Vhd(args) => disk::run_vhd(args),
+        #[cfg(feature = "vhdx")]
+        Command::Vhdx(args) => disk::run_vhdx(args),
+        #[cfg(feature = "vmdk")]
+        Command::Vmdk(args) => disk::run_vmdk(args),
+    }
+}
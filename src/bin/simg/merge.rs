@@ -0,0 +1,48 @@
+use crate::common;
+use anyhow::{ensure, Result};
+use argh::FromArgs;
+
+/// Merge sparse images into one, later images overlaying earlier ones
+#[derive(FromArgs)]
+#[argh(subcommand, name = "merge")]
+pub struct Args {
+    /// add checksum to output image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output image, even if the `crc` config
+    /// setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// output sparse image
+    #[argh(option, short = 'o')]
+    output: String,
+
+    /// input sparse images
+    #[argh(positional)]
+    sparse_images: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    ensure!(!args.sparse_images.is_empty(), "No input images given");
+    let config = common::Config::load()?;
+
+    let mut readers = args
+        .sparse_images
+        .iter()
+        .map(|path| Ok(sparse::Reader::new(common::open_input(path)?, false)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut fo = common::create_output(&args.output, args.force)?;
+    config.write_sparse(&mut fo, config.crc_enabled(args.crc, args.no_crc)?, |writer| {
+        sparse::merge::merge(&mut readers, writer)?;
+        Ok(())
+    })?;
+    fo.commit()?;
+    Ok(())
+}
@@ -0,0 +1,26 @@
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use sparse::platform::Capabilities;
+
+/// Report which fast conversion features a filesystem supports
+#[derive(FromArgs)]
+#[argh(subcommand, name = "probe")]
+pub struct Args {
+    /// directories on the filesystems to probe (default: .)
+    #[argh(positional)]
+    dirs: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let dirs = match args.dirs.is_empty() {
+        true => vec![String::from(".")],
+        false => args.dirs,
+    };
+
+    for dir in &dirs {
+        let capabilities =
+            Capabilities::probe(dir).with_context(|| format!("Cannot probe {dir}"))?;
+        println!("{dir}: {capabilities}");
+    }
+    Ok(())
+}
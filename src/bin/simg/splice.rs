@@ -0,0 +1,91 @@
+use crate::common;
+use anyhow::{bail, ensure, Result};
+use argh::FromArgs;
+use sparse::splice::{self, Edit};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+};
+
+/// Replace bytes at a raw offset of a sparse image, e.g. to stamp a serial
+/// number
+#[derive(FromArgs)]
+#[argh(subcommand, name = "splice")]
+pub struct Args {
+    /// offset of the bytes to replace in the raw image, decimal or
+    /// hexadecimal with a 0x prefix
+    #[argh(option, from_str_fn(parse_offset))]
+    offset: Option<u64>,
+
+    /// file holding the new bytes
+    #[argh(option)]
+    data: Option<String>,
+
+    /// apply the edits listed in this patch file instead of --offset and
+    /// --data, a line per edit holding the offset and the new bytes in hex
+    /// or as a quoted string
+    #[argh(option)]
+    patch: Option<String>,
+
+    /// write the result to this sparse image instead of changing the image
+    /// in place, which is required if the bytes aren't stored as raw data
+    /// or the image has a checksum
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// add checksum to output image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output image, even if the `crc` config
+    /// setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// sparse image
+    #[argh(positional)]
+    sparse_image: String,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let edits = match (args.offset, &args.data, &args.patch) {
+        (Some(offset), Some(data), None) => vec![Edit {
+            offset,
+            data: fs::read(data)?,
+        }],
+        (None, None, Some(patch)) => splice::read_edits(BufReader::new(File::open(patch)?))?,
+        _ => bail!("Either --offset and --data or --patch are required"),
+    };
+
+    let Some(output) = &args.output else {
+        ensure!(!args.crc, "--crc requires --output");
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&args.sparse_image)?;
+        splice::apply_in_place(image, &edits)?;
+        return Ok(());
+    };
+
+    let config = common::Config::load()?;
+    let reader = config.reader(common::open_input(&args.sparse_image)?, false)?;
+    let mut fo = common::create_output(output, args.force)?;
+    config.write_sparse(&mut fo, config.crc_enabled(args.crc, args.no_crc)?, |writer| {
+        splice::apply(reader, &edits, writer)?;
+        Ok(())
+    })?;
+    fo.commit()?;
+    Ok(())
+}
+
+fn parse_offset(value: &str) -> std::result::Result<u64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid offset: {value}"))
+}
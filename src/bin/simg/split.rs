@@ -0,0 +1,66 @@
+use crate::common;
+use anyhow::{ensure, Context, Result};
+use argh::FromArgs;
+use std::path::Path;
+
+/// Split a sparse image into parts of limited size
+#[derive(FromArgs)]
+#[argh(subcommand, name = "split")]
+pub struct Args {
+    /// maximum size of a part, with an optional K, M or G suffix
+    /// (default: `split_size` config setting)
+    #[argh(option, short = 's', from_str_fn(common::parse_size))]
+    size: Option<u64>,
+
+    /// split into parts that satisfy this device profile, see
+    /// `simg_lint --list-profiles`
+    #[argh(option, short = 'p')]
+    profile: Option<String>,
+
+    /// file with further profiles (default: $SIMG_PROFILES)
+    #[argh(option)]
+    profiles: Option<String>,
+
+    /// split at this raw image offset instead of by size, e.g. at a
+    /// partition boundary; may be repeated
+    #[argh(option, from_str_fn(common::parse_size))]
+    at: Vec<u64>,
+
+    /// input sparse image
+    #[argh(positional)]
+    sparse_image: String,
+
+    /// prefix of the output images (default: the input image)
+    #[argh(positional)]
+    prefix: Option<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let config = common::Config::load()?;
+    let reader = sparse::Reader::new(common::open_input(&args.sparse_image)?, false)?;
+    let prefix = Path::new(args.prefix.as_ref().unwrap_or(&args.sparse_image));
+
+    let parts = if !args.at.is_empty() {
+        ensure!(
+            args.size.is_none() && args.profile.is_none(),
+            "--at cannot be combined with --size or --profile"
+        );
+        let mut boundaries = args.at;
+        boundaries.sort_unstable();
+        sparse::split::split_at(reader, &boundaries, prefix)?
+    } else if let Some(name) = &args.profile {
+        let mut profile = common::load_profile(name, args.profiles.as_deref())?;
+        profile.max_size = args.size.or(profile.max_size);
+        sparse::split::split_for(reader, &profile, prefix)?
+    } else {
+        let size = args.size.or(config.split_size).context(
+            "No part size given (use --size, --profile, --at or the `split_size` config setting)",
+        )?;
+        sparse::split::split(reader, size, prefix)?
+    };
+
+    for part in parts {
+        println!("{}", part.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use crate::common::{self, Config};
+use anyhow::{bail, ensure, Result};
+use argh::FromArgs;
+use sparse::{
+    checksum,
+    sidecar::{self, Selection},
+    Block,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Check that a sparse image is well-formed and its checksum matches
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify")]
+pub struct Args {
+    /// number of threads hashing image files (default: number of CPUs)
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+
+    /// only check chunks against the chunk checksums in this file, as
+    /// written by `simg encode --chunk-crcs`, instead of the whole image
+    #[argh(option)]
+    chunk_crcs: Option<String>,
+
+    /// with --chunk-crcs, check this many chunks picked at random
+    #[argh(option)]
+    sample: Option<usize>,
+
+    /// with --chunk-crcs, check the chunk at this index of the checksum
+    /// file, may be repeated
+    #[argh(option)]
+    chunk: Vec<usize>,
+
+    /// sparse image
+    #[argh(positional)]
+    image: String,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let config = Config::load()?;
+    let input = common::open_input(&args.image)?;
+
+    if let Some(path) = &args.chunk_crcs {
+        return verify_chunks(input, Path::new(path), &args);
+    }
+    ensure!(
+        args.sample.is_none() && args.chunk.is_empty(),
+        "--sample and --chunk require --chunk-crcs"
+    );
+
+    // Files are hashed in segments on several threads, pipes block by
+    // block.
+    let (blocks, checksum) = if input.metadata()?.is_file() {
+        let jobs = args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+        let verified = checksum::verify(input, jobs, config.crc_policy)?;
+        (verified.blocks, verified.checksum)
+    } else {
+        let mut blocks = 0;
+        let mut checksum = None;
+        for block in config.reader(input, true)? {
+            match block? {
+                Block::Crc32(crc) => checksum = Some(crc),
+                _ => blocks += 1,
+            }
+        }
+        (blocks, checksum)
+    };
+
+    match checksum {
+        Some(crc) => println!("{}: OK ({blocks} blocks, checksum {crc:#010x})", args.image),
+        None => println!("{}: OK ({blocks} blocks, no checksum)", args.image),
+    }
+    Ok(())
+}
+
+/// Spot-checks the chunks of `image` selected by `args` against the chunk
+/// checksums in `path`.
+fn verify_chunks(image: File, path: &Path, args: &Args) -> Result<()> {
+    let crcs = sidecar::read(BufReader::new(File::open(path)?))?;
+    let selection = match (args.sample, args.chunk.is_empty()) {
+        (Some(_), false) => bail!("--sample and --chunk cannot be combined"),
+        (Some(count), true) => {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_nanos() as u64);
+            Selection::Sample { count, seed }
+        }
+        (None, false) => Selection::Indexes(args.chunk.clone()),
+        (None, true) => Selection::All,
+    };
+
+    let checked = sidecar::verify(image, &crcs, &selection)?;
+    println!(
+        "{}: OK ({checked} of {} chunks checked)",
+        args.image,
+        crcs.len()
+    );
+    Ok(())
+}
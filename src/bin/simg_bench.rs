@@ -0,0 +1,166 @@
+extern crate android_sparse as sparse;
+
+use anyhow::{ensure, Result};
+use sparse::{
+    block::Block,
+    gen::{ImageSpec, Layout},
+    human::{HumanSize, Percent},
+    tools, Decoder, Encoder, Reader, Writer,
+};
+use std::{
+    hint::black_box,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+/// Time encoding, writing, reading and decoding synthetic images in memory
+#[derive(argh::FromArgs)]
+struct Args {
+    /// size of the raw image, e.g. 512M (default: 256M)
+    #[argh(option, from_str_fn(tools::parse_size), default = "256 << 20")]
+    size: u64,
+
+    /// fraction of blocks that are filled with a 32-bit value (default:
+    /// 0.2)
+    #[argh(option, from_str_fn(parse_ratio), default = "0.2")]
+    fill_ratio: f64,
+
+    /// fraction of blocks that are holes (default: 0.2)
+    #[argh(option, from_str_fn(parse_ratio), default = "0.2")]
+    hole_ratio: f64,
+
+    /// fraction of the bytes of raw blocks that are random, the rest are
+    /// zeros (default: 1)
+    #[argh(option, from_str_fn(parse_ratio), default = "1.0")]
+    entropy: f64,
+
+    /// seed of the generated image (default: 0)
+    #[argh(option, default = "0")]
+    seed: u64,
+
+    /// number of runs of each path, of which the fastest counts (default:
+    /// 3)
+    #[argh(option, short = 'n', default = "3")]
+    iterations: u32,
+
+    /// write and verify checksums
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// buffer size of writers and decoders, e.g. 1M (default: 64K)
+    #[argh(option, from_str_fn(tools::parse_size), default = "64 << 10")]
+    buffer_size: u64,
+
+    /// number of raw blocks decoders write at once (default: 16)
+    #[argh(option, default = "16")]
+    raw_batch: usize,
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    ensure!(args.iterations > 0, "At least one iteration is required");
+
+    let spec = ImageSpec {
+        size: args.size,
+        layout: Layout {
+            fill_ratio: args.fill_ratio,
+            hole_ratio: args.hole_ratio,
+            entropy: args.entropy,
+            ..Layout::default()
+        },
+        seed: args.seed,
+    };
+    let blocks: Vec<_> = spec.blocks()?.collect();
+    let raw = decode(&args, &blocks)?;
+    let sparse = write(&args, &blocks)?;
+
+    let raw_size = raw.len() as u64;
+    println!(
+        "raw image:      {} ({} fill, {} holes, entropy {:.2})",
+        HumanSize(raw_size),
+        ratio(args.fill_ratio),
+        ratio(args.hole_ratio),
+        args.entropy
+    );
+    println!(
+        "sparse image:   {} ({} of raw)",
+        HumanSize(sparse.len() as u64),
+        Percent {
+            part: sparse.len() as u64,
+            whole: raw_size,
+        }
+    );
+    println!();
+    println!("{:<8}  {:>10}  {:>12}", "path", "best", "throughput");
+
+    let paths: [(&str, &dyn Fn() -> Result<()>); 4] = [
+        ("encode", &|| encode(&raw)),
+        ("write", &|| write(&args, &blocks).map(drop)),
+        ("read", &|| read(&args, &sparse)),
+        ("decode", &|| decode(&args, &blocks).map(drop)),
+    ];
+    for (name, path) in paths {
+        let mut best = Duration::MAX;
+        for _ in 0..args.iterations {
+            let start = Instant::now();
+            path()?;
+            best = best.min(start.elapsed());
+        }
+
+        // Throughput is measured in raw bytes for all paths, so the paths
+        // are comparable.
+        let throughput = (raw_size as f64 / best.as_secs_f64()) as u64;
+        println!(
+            "{name:<8}  {:>8.3} s  {:>10}/s",
+            best.as_secs_f64(),
+            HumanSize(throughput)
+        );
+    }
+    Ok(())
+}
+
+fn encode(raw: &[u8]) -> Result<()> {
+    for block in Encoder::new(raw)? {
+        black_box(block?);
+    }
+    Ok(())
+}
+
+fn write(args: &Args, blocks: &[Block]) -> Result<Vec<u8>> {
+    let mut sparse = Cursor::new(Vec::new());
+    let mut writer = Writer::with_capacity(args.buffer_size as usize, &mut sparse, args.crc)?;
+    for block in blocks {
+        writer.write_block(block)?;
+    }
+    writer.close()?;
+    Ok(sparse.into_inner())
+}
+
+fn read(args: &Args, sparse: &[u8]) -> Result<()> {
+    for block in Reader::new(sparse, args.crc)? {
+        black_box(block?);
+    }
+    Ok(())
+}
+
+fn decode(args: &Args, blocks: &[Block]) -> Result<Vec<u8>> {
+    let mut raw = Cursor::new(Vec::new());
+    let mut decoder =
+        Decoder::with_capacity(args.buffer_size as usize, &mut raw)?.raw_batch_size(args.raw_batch);
+    for block in blocks {
+        decoder.write_block(block)?;
+    }
+    decoder.close()?;
+    Ok(raw.into_inner())
+}
+
+fn ratio(value: f64) -> String {
+    format!("{:.1}%", value * 100.0)
+}
+
+fn parse_ratio(value: &str) -> std::result::Result<f64, String> {
+    match value.parse() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("invalid ratio, expected 0 to 1: {value}")),
+    }
+}
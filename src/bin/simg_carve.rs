@@ -0,0 +1,59 @@
+extern crate android_sparse as sparse;
+
+use anyhow::{ensure, Result};
+use sparse::{carve, human::HumanSize, io::AtomicFile};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// Find and extract sparse images embedded in other files
+#[derive(argh::FromArgs)]
+struct Args {
+    /// only list the images found, don't extract them
+    #[argh(switch, short = 'l')]
+    list: bool,
+
+    /// directory to extract images to (default: current directory)
+    #[argh(option, short = 'o', default = "PathBuf::from(\".\")")]
+    output_dir: PathBuf,
+
+    /// file to scan, e.g. an OTA package or flash dump
+    #[argh(positional)]
+    input: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+
+    let mut input = BufReader::new(File::open(&args.input)?);
+    let images = carve::scan(&mut input)?;
+    ensure!(!images.is_empty(), "No sparse images found");
+
+    let stem = args.input.file_stem().unwrap_or_default().to_string_lossy();
+    for image in &images {
+        println!(
+            "{:#010x} {:>10}: {}",
+            image.offset,
+            HumanSize(image.size),
+            image.header
+        );
+
+        if !args.list {
+            let path = args
+                .output_dir
+                .join(format!("{stem}-{:x}.simg", image.offset));
+            extract(&mut input, image, &path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn extract(input: &mut BufReader<File>, image: &carve::EmbeddedImage, path: &Path) -> Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    carve::extract(input, image, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
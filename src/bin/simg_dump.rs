@@ -0,0 +1,9 @@
+//! Alias for `simg dump`.
+
+extern crate android_sparse as sparse;
+
+use sparse::cli::dump;
+
+fn main() -> anyhow::Result<()> {
+    dump::run(argh::from_env())
+}
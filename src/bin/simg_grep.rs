@@ -0,0 +1,9 @@
+//! Alias for `simg grep`.
+
+extern crate android_sparse as sparse;
+
+use sparse::cli::strings;
+
+fn main() -> anyhow::Result<()> {
+    strings::run_grep(argh::from_env())
+}
@@ -0,0 +1,87 @@
+extern crate android_sparse as sparse;
+
+use anyhow::Result;
+use sparse::{lint, tools};
+use std::{fs::File, io::BufReader, process};
+
+/// Check sparse images strictly against the limits of a device
+#[derive(argh::FromArgs)]
+struct Args {
+    /// device profile to check against (default: generic), see
+    /// --list-profiles
+    #[argh(option, short = 'p', default = "String::from(\"generic\")")]
+    profile: String,
+
+    /// override the maximum image size of the profile, e.g. 512M
+    #[argh(option, from_str_fn(tools::parse_size))]
+    max_size: Option<u64>,
+
+    /// override the maximum number of chunks of the profile
+    #[argh(option)]
+    max_chunks: Option<u32>,
+
+    /// require images to contain a checksum
+    #[argh(switch)]
+    require_crc: bool,
+
+    /// file with further profiles (default: $SIMG_PROFILES)
+    #[argh(option)]
+    profiles: Option<String>,
+
+    /// list the available profiles and exit
+    #[argh(switch)]
+    list_profiles: bool,
+
+    /// sparse images to check
+    #[argh(positional)]
+    images: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+
+    if args.list_profiles {
+        for profile in tools::load_profiles(args.profiles.as_deref())?.iter() {
+            println!("{profile}");
+        }
+        return Ok(());
+    }
+
+    let mut profile = tools::load_profile(&args.profile, args.profiles.as_deref())?;
+    profile.max_size = args.max_size.or(profile.max_size);
+    profile.max_chunks = args.max_chunks.or(profile.max_chunks);
+    profile.require_crc |= args.require_crc;
+
+    let mut failed = 0;
+    for image in &args.images {
+        let report = File::open(image)
+            .map_err(Into::into)
+            .and_then(|file| lint::lint(BufReader::new(file), &profile));
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                println!("{image}: FAIL");
+                println!("  error: {err:#}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        match report.passed() {
+            true => println!("{image}: PASS"),
+            false => {
+                println!("{image}: FAIL");
+                failed += 1;
+            }
+        }
+        for finding in &report.findings {
+            println!("  {finding}");
+        }
+    }
+
+    if failed > 0 {
+        // Like diff(1), signal problems with exit status 1.
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,337 @@
+extern crate android_sparse as sparse;
+
+use anyhow::{bail, Context, Result};
+use sparse::{
+    io::{copy_with_progress, ZeroSeek},
+    tools,
+};
+use std::{
+    fs::File,
+    io::{self, prelude::*, BufReader, BufWriter, SeekFrom},
+    net::{TcpListener, TcpStream},
+    ops::ControlFlow,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+const USAGE: &str = "\
+POST /encode   convert the raw image in the request body to a sparse image
+POST /decode   convert the sparse image in the request body to a raw image
+
+Append ?crc to add a checksum when encoding or verify it when decoding.
+";
+
+/// How long a client may keep a worker waiting for data.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serve sparse image conversion over HTTP
+#[derive(argh::FromArgs)]
+struct Args {
+    /// address to listen on (default: 127.0.0.1:8080)
+    #[argh(option, short = 'l', default = "String::from(\"127.0.0.1:8080\")")]
+    listen: String,
+
+    /// accept connections on the inherited listening socket with this
+    /// descriptor instead, e.g. for socket activation
+    #[argh(option)]
+    fd: Option<i32>,
+
+    /// number of requests handled at once, further connections wait until
+    /// a request is done (default: 4)
+    #[argh(option, short = 'j', default = "4")]
+    jobs: usize,
+
+    /// only report errors on stderr, no warnings or progress bars
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// also report details of what is being done on stderr
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    tools::init_logging(args.quiet, args.verbose);
+
+    let listener = match args.fd {
+        Some(fd) => inherited_listener(fd)?,
+        None => TcpListener::bind(&args.listen)?,
+    };
+    println!("Listening on {}", listener.local_addr()?);
+
+    // Connections are handed to the first idle worker, and wait in the
+    // listen backlog while all of them are busy.
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..args.jobs.max(1) {
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+            let stream = receiver.lock().unwrap().recv();
+            let Ok(stream) = stream else {
+                break;
+            };
+            if let Err(err) = handle(stream) {
+                tools::error(format_args!("{err:#}"));
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(err) => {
+                tools::error(err);
+                continue;
+            }
+        };
+
+        if let Ok(peer) = stream.peer_addr() {
+            tools::info(format_args!("Connection from {peer}"));
+        }
+        sender.send(stream)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn inherited_listener(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::BorrowedFd;
+
+    // SAFETY: Descriptors handed to us stay open for our whole lifetime.
+    // If the descriptor isn't open, duplicating it fails with EBADF.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(TcpListener::from(fd.try_clone_to_owned()?))
+}
+
+#[cfg(not(unix))]
+fn inherited_listener(_fd: i32) -> Result<TcpListener> {
+    bail!("Inherited sockets are not supported on this platform")
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    content_length: Option<u64>,
+    chunked: bool,
+    expect_continue: bool,
+}
+
+fn handle(stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::with_capacity(1 << 16, stream);
+
+    let request = read_request(&mut input)?;
+    let crc = request.query.split('&').any(|p| p == "crc" || p == "crc=1");
+
+    if request.expect_continue {
+        output.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        output.flush()?;
+    }
+
+    let body: Box<dyn Read> = if request.chunked {
+        Box::new(ChunkedReader::new(input))
+    } else {
+        Box::new(input.take(request.content_length.unwrap_or(0)))
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/encode") => encode(body, &mut output, crc),
+        ("POST", "/decode") => decode(body, &mut output, crc),
+        ("GET", "/") => respond(&mut output, "200 OK", USAGE),
+        _ => respond(&mut output, "404 Not Found", "Not found\n"),
+    }
+}
+
+fn read_request<R: BufRead>(r: &mut R) -> Result<Request> {
+    let line = read_line(r)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(m), Some(t)) => (m.to_string(), t),
+        _ => bail!("Invalid request line: {line}"),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        content_length: None,
+        chunked: false,
+        expect_continue: false,
+    };
+
+    loop {
+        let line = read_line(r)?;
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_once(':').context("Invalid header line")?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => request.content_length = Some(value.parse()?),
+            "transfer-encoding" => request.chunked = value.eq_ignore_ascii_case("chunked"),
+            "expect" => request.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => (),
+        }
+    }
+
+    Ok(request)
+}
+
+fn read_line<R: BufRead>(r: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        bail!("Unexpected end of request");
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn respond<W: Write>(w: &mut W, status: &str, body: &str) -> Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    w.flush()?;
+    Ok(())
+}
+
+fn encode<R: Read, W: Write>(body: R, w: &mut W, crc: bool) -> Result<()> {
+    // Sparse images can only be written once all chunks are known, so
+    // spool the output to a temporary file to keep memory usage bounded.
+    let mut spool = match spool_sparse(body, crc) {
+        Ok(f) => f,
+        Err(err) => return respond(w, "400 Bad Request", &format!("{err}\n")),
+    };
+
+    let len = spool.seek(SeekFrom::End(0))?;
+    spool.rewind()?;
+
+    write!(
+        w,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {len}\r\nConnection: close\r\n\r\n"
+    )?;
+    copy_with_progress(&mut spool, w, |_| ControlFlow::Continue(()))?;
+    w.flush()?;
+    Ok(())
+}
+
+fn spool_sparse<R: Read>(body: R, crc: bool) -> Result<File> {
+    let spool = tempfile::tempfile()?;
+    let encoder = sparse::Encoder::new(body)?;
+    let mut writer = sparse::Writer::new(spool.try_clone()?, crc)?;
+    for block in encoder {
+        writer.write_block(&block?)?;
+    }
+    writer.close()?;
+    Ok(spool)
+}
+
+fn decode<R: Read, W: Write>(mut body: R, w: &mut W, crc: bool) -> Result<()> {
+    let reader = match sparse::Reader::new(&mut body, crc) {
+        Ok(r) => r,
+        Err(err) => {
+            // Consume the rest of the request, otherwise closing the
+            // connection would reset it before the client got the response.
+            copy_with_progress(&mut body, &mut io::sink(), |_| ControlFlow::Continue(()))?;
+            return respond(w, "400 Bad Request", &format!("{err}\n"));
+        }
+    };
+
+    write!(
+        w,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+    )?;
+
+    // Errors from here on abort the connection without a final chunk, which
+    // tells the client that the response is incomplete.
+    let mut decoder = sparse::Decoder::new(ZeroSeek::new(ChunkedWriter(&mut *w)))?;
+    for block in reader {
+        decoder.write_block(&block?)?;
+    }
+    decoder.close()?;
+
+    w.write_all(b"0\r\n\r\n")?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Reads a body sent with chunked transfer encoding.
+struct ChunkedReader<R> {
+    inner: R,
+    remaining: u64,
+    finished: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            finished: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = read_line(&mut self.inner).map_err(io::Error::other)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        self.remaining = u64::from_str_radix(size, 16).map_err(io::Error::other)?;
+
+        if self.remaining == 0 {
+            // Skip trailers.
+            while !read_line(&mut self.inner).map_err(io::Error::other)?.is_empty() {}
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.finished {
+            self.next_chunk()?;
+        }
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            read_line(&mut self.inner).map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Writes a body with chunked transfer encoding.
+///
+/// The terminating chunk has to be written separately.
+struct ChunkedWriter<W>(W);
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,177 @@
+extern crate android_sparse as sparse;
+
+use anyhow::{bail, Result};
+use sparse::{dump::Chunks, headers::ChunkType, tools, Block, Reader};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, prelude::*, BufReader, BufWriter},
+};
+
+/// Aggregate chunk statistics of sparse images into CSV
+#[derive(argh::FromArgs)]
+struct Args {
+    /// file to write the CSV to (default: stdout)
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// only report errors on stderr, no warnings or progress bars
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// also report details of what is being done on stderr
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    /// sparse images to analyze
+    #[argh(positional)]
+    images: Vec<String>,
+}
+
+const COLUMNS: &str = "image,raw_size,sparse_size,ratio,\
+raw_chunks,fill_chunks,dontcare_chunks,crc32_chunks,\
+raw_blocks,fill_blocks,dontcare_blocks,\
+fill_values,zero_fill_blocks,top_fill_value";
+
+/// Statistics of one or more sparse images.
+#[derive(Default)]
+struct Stats {
+    raw_size: u64,
+    sparse_size: u64,
+    /// Chunk counts, indexed by `chunk_index`.
+    chunks: [u64; 4],
+    raw_blocks: u64,
+    fill_blocks: u64,
+    dontcare_blocks: u64,
+    /// Number of fill blocks per fill value.
+    fill_values: HashMap<[u8; 4], u64>,
+}
+
+impl Stats {
+    fn collect(path: &str) -> Result<Self> {
+        let mut stats = Self {
+            sparse_size: fs::metadata(path)?.len(),
+            ..Self::default()
+        };
+
+        for chunk in Chunks::new(BufReader::new(File::open(path)?))? {
+            if let Some(index) = chunk_index(chunk?.header.chunk_type) {
+                stats.chunks[index] += 1;
+            }
+        }
+
+        let reader = Reader::new(BufReader::new(File::open(path)?), false)?;
+        stats.raw_size = reader.size;
+        for block in reader {
+            match block? {
+                Block::Raw(_) => stats.raw_blocks += 1,
+                Block::Fill(value) => {
+                    stats.fill_blocks += 1;
+                    *stats.fill_values.entry(value).or_default() += 1;
+                }
+                Block::Skip => stats.dontcare_blocks += 1,
+                Block::Crc32(_) => (),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.raw_size += other.raw_size;
+        self.sparse_size += other.sparse_size;
+        for (total, count) in self.chunks.iter_mut().zip(other.chunks) {
+            *total += count;
+        }
+        self.raw_blocks += other.raw_blocks;
+        self.fill_blocks += other.fill_blocks;
+        self.dontcare_blocks += other.dontcare_blocks;
+        for (value, count) in &other.fill_values {
+            *self.fill_values.entry(*value).or_default() += count;
+        }
+    }
+
+    fn write_row<W: Write>(&self, w: &mut W, name: &str) -> io::Result<()> {
+        let ratio = match self.raw_size {
+            0 => String::new(),
+            raw => format!("{:.4}", self.sparse_size as f64 / raw as f64),
+        };
+
+        // Ties are broken by the smaller value to keep the output stable.
+        let top_fill = self
+            .fill_values
+            .iter()
+            .max_by_key(|(value, count)| (**count, std::cmp::Reverse(**value)))
+            .map(|(value, _)| format!("{:#010x}", u32::from_le_bytes(*value)))
+            .unwrap_or_default();
+
+        let [raw, fill, dontcare, crc] = self.chunks;
+        writeln!(
+            w,
+            "{},{},{},{ratio},{raw},{fill},{dontcare},{crc},{},{},{},{},{},{top_fill}",
+            csv_field(name),
+            self.raw_size,
+            self.sparse_size,
+            self.raw_blocks,
+            self.fill_blocks,
+            self.dontcare_blocks,
+            self.fill_values.len(),
+            self.fill_values.get(&[0; 4]).copied().unwrap_or(0),
+        )
+    }
+}
+
+/// Returns the index of the count of `chunk_type`, or `None` for metadata
+/// chunks, which aren't counted.
+fn chunk_index(chunk_type: ChunkType) -> Option<usize> {
+    match chunk_type {
+        ChunkType::Raw => Some(0),
+        ChunkType::Fill => Some(1),
+        ChunkType::DontCare => Some(2),
+        ChunkType::Crc32 => Some(3),
+        ChunkType::Metadata => None,
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    tools::init_logging(args.quiet, args.verbose);
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut output = BufWriter::new(output);
+    writeln!(output, "{COLUMNS}")?;
+
+    let mut total = Stats::default();
+    let mut failed = 0;
+    for image in &args.images {
+        match Stats::collect(image) {
+            Ok(stats) => {
+                stats.write_row(&mut output, image)?;
+                total.add(&stats);
+            }
+            Err(err) => {
+                tools::error(format_args!("{image}: {err:#}"));
+                failed += 1;
+            }
+        }
+    }
+
+    total.write_row(&mut output, "total")?;
+    output.flush()?;
+
+    if failed > 0 {
+        bail!("{failed} of {} images failed", args.images.len());
+    }
+    Ok(())
+}
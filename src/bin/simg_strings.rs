@@ -0,0 +1,9 @@
+//! Alias for `simg strings`.
+
+extern crate android_sparse as sparse;
+
+use sparse::cli::strings;
+
+fn main() -> anyhow::Result<()> {
+    strings::run_strings(argh::from_env())
+}
@@ -0,0 +1,214 @@
+//! Kotlin and Swift bindings generated with UniFFI.
+//!
+//! Only available with the `uniffi` feature. Apps embedding the library,
+//! like flashing tools for Android or macOS, get the high-level `convert`
+//! and `verify` functions without hand-written JNI. The bindings are
+//! generated from the built library with the `uniffi-bindgen` binary:
+//!
+//! ```text
+//! $ cargo build --release --lib --features uniffi
+//! $ cargo run --features uniffi --bin uniffi-bindgen -- generate \
+//!     --library target/release/libandroid_sparse.so --language kotlin --out-dir out
+//! ```
+
+use crate::{
+    block::CrcPolicy,
+    checksum,
+    convert::{self, Format},
+    io::AtomicFile,
+    result::{Context, Error, Result},
+};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, prelude::*, BufReader, BufWriter},
+};
+
+/// The format of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum ImageFormat {
+    /// A sparse image.
+    Sparse,
+    /// A raw image.
+    Raw,
+}
+
+impl From<Format> for ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Sparse => ImageFormat::Sparse,
+            Format::Raw => ImageFormat::Raw,
+        }
+    }
+}
+
+/// The result of verifying the checksum of a sparse image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct Verification {
+    /// The number of blocks of the image.
+    pub blocks: u64,
+    /// The checksum that matched, or `None` if the image has no checksum.
+    pub checksum: Option<u32>,
+}
+
+/// An error raised by the bindings, thrown as an exception.
+#[derive(Debug, uniffi::Error)]
+pub enum SparseError {
+    /// The operation failed.
+    Failed {
+        /// What went wrong, including its context.
+        message: String,
+    },
+    /// The progress listener cancelled the conversion.
+    Cancelled,
+}
+
+impl fmt::Display for SparseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SparseError::Failed { message } => f.write_str(message),
+            SparseError::Cancelled => f.write_str("Conversion cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for SparseError {}
+
+impl From<Error> for SparseError {
+    fn from(err: Error) -> Self {
+        SparseError::Failed {
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+/// Implemented by apps to follow the progress of a conversion.
+#[uniffi::export(callback_interface)]
+pub trait ProgressListener: Send + Sync {
+    /// Called with the number of bytes of the input read so far and the
+    /// size of the input. Returning `false` cancels the conversion.
+    fn on_progress(&self, done: u64, total: u64) -> bool;
+}
+
+/// Converts the image at `input` to the respective other format, writing
+/// it to `output`, and returns the detected format of the input.
+///
+/// Sparse images are decoded, with their checksums being verified. Raw
+/// images are encoded. `output` is only replaced once the conversion
+/// succeeded.
+#[uniffi::export]
+pub fn convert(
+    input: String,
+    output: String,
+    listener: Option<Box<dyn ProgressListener>>,
+) -> std::result::Result<ImageFormat, SparseError> {
+    let mut cancelled = false;
+    let result = convert_file(&input, &output, listener.as_deref(), &mut cancelled);
+    match result {
+        Ok(format) => Ok(format.into()),
+        Err(_) if cancelled => Err(SparseError::Cancelled),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Verifies the checksum of the sparse image at `path` on up to `jobs`
+/// threads.
+///
+/// Images without a checksum pass once their structure is valid.
+#[uniffi::export]
+pub fn verify(path: String, jobs: u32) -> std::result::Result<Verification, SparseError> {
+    let file = File::open(&path).with_context(|| format!("Opening {path}"))?;
+    let verified = checksum::verify(file, jobs.max(1) as usize, CrcPolicy::default())?;
+    Ok(Verification {
+        blocks: verified.blocks,
+        checksum: verified.checksum,
+    })
+}
+
+fn convert_file(
+    input: &str,
+    output: &str,
+    listener: Option<&dyn ProgressListener>,
+    cancelled: &mut bool,
+) -> Result<Format> {
+    let file = File::open(input).with_context(|| format!("Opening {input}"))?;
+    let total = file.metadata()?.len();
+    let input = Listened {
+        inner: file,
+        done: 0,
+        total,
+        listener,
+        cancelled,
+    };
+
+    let mut out = AtomicFile::create(output).with_context(|| format!("Creating {output}"))?;
+    // Large reads keep the listener from being called too often.
+    let input = BufReader::with_capacity(1 << 20, input);
+    let format = convert::auto_convert(input, BufWriter::new(&mut out))?;
+    out.commit()?;
+    Ok(format)
+}
+
+/// Reports the bytes read from `inner` to a progress listener.
+struct Listened<'a, R> {
+    inner: R,
+    done: u64,
+    total: u64,
+    listener: Option<&'a dyn ProgressListener>,
+    cancelled: &'a mut bool,
+}
+
+impl<R: Read> Read for Listened<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.done += len as u64;
+        if let Some(listener) = self.listener {
+            if !listener.on_progress(self.done, self.total) {
+                *self.cancelled = true;
+                return Err(io::Error::other("Conversion cancelled"));
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    /// Cancels once this many bytes are read.
+    struct Limit(u64);
+
+    impl ProgressListener for Limit {
+        fn on_progress(&self, done: u64, _total: u64) -> bool {
+            done < self.0
+        }
+    }
+
+    #[test]
+    fn convert_and_verify() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = |name: &str| tmpdir.path().join(name).to_str().unwrap().to_string();
+        let raw: Vec<u8> = (0..3 * 4096).map(|i| (i / 4096) as u8).collect();
+        fs::write(path("raw.img"), &raw).unwrap();
+
+        let format = convert(path("raw.img"), path("sparse.simg"), None).unwrap();
+        assert_eq!(format, ImageFormat::Raw);
+        let verification = verify(path("sparse.simg"), 2).unwrap();
+        assert_eq!(verification.blocks, 3);
+
+        let listener = Some(Box::new(Limit(0)) as _);
+        let err = convert(path("sparse.simg"), path("out.img"), listener);
+        assert!(matches!(err, Err(SparseError::Cancelled)));
+        assert!(!tmpdir.path().join("out.img").exists());
+
+        let listener = Some(Box::new(Limit(u64::MAX)) as _);
+        let format = convert(path("sparse.simg"), path("out.img"), listener);
+        assert_eq!(format.unwrap(), ImageFormat::Sparse);
+        assert_eq!(fs::read(path("out.img")).unwrap(), raw);
+
+        let err = verify(path("raw.img"), 1).unwrap_err();
+        assert!(matches!(err, SparseError::Failed { .. }));
+    }
+}
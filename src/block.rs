@@ -1,13 +1,27 @@
 //! A data structure for representing sparse blocks.
 
-use std::fmt;
+use crate::ext::WriteBlock;
+use crate::result::{ensure, Error, Result};
+use crc32fast::Hasher;
+use std::{
+    fmt,
+    io::{self, Write},
+    ops::{Deref, DerefMut},
+};
+
+const BLOCK_SIZE: usize = Block::SIZE as usize;
 
 /// A sparse block and its associated data.
 #[derive(Clone)]
 pub enum Block {
     /// A raw block holding a byte buffer of length `Block::SIZE`.
-    Raw(Box<[u8; Block::SIZE as usize]>),
+    Raw(BlockBuf),
     /// A fill block holding a 4-byte fill value.
+    ///
+    /// The bytes are repeated in this order across the block and stored
+    /// as-is in the chunk payload. Use `Block::fill_u32` and
+    /// `Block::fill_value_u32` to work with the value as a little-endian
+    /// `u32`, which is how libsparse interprets it.
     Fill([u8; 4]),
     /// A block that signifies a part of the image that can be skipped.
     Skip,
@@ -18,6 +32,85 @@ pub enum Block {
 impl Block {
     /// The size of a sparse file block.
     pub const SIZE: u32 = 4096;
+
+    /// Creates a fill block repeating `value` in little-endian byte order.
+    pub fn fill_u32(value: u32) -> Self {
+        Block::Fill(value.to_le_bytes())
+    }
+
+    /// Returns the fill value of a fill block as a little-endian `u32`, or
+    /// `None` for other blocks.
+    pub fn fill_value_u32(&self) -> Option<u32> {
+        match self {
+            Block::Fill(value) => Some(u32::from_le_bytes(*value)),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of raw image bytes this block decodes to, which is
+    /// 0 for checksum blocks.
+    pub fn decoded_len(&self) -> u32 {
+        match self {
+            Block::Crc32(_) => 0,
+            _ => Block::SIZE,
+        }
+    }
+
+    /// Checks whether this block holds data, i.e. is a raw or fill block.
+    pub fn is_data(&self) -> bool {
+        matches!(self, Block::Raw(_) | Block::Fill(_))
+    }
+
+    /// Checks whether this block is a hole, i.e. a skip block.
+    pub fn is_hole(&self) -> bool {
+        matches!(self, Block::Skip)
+    }
+
+    /// Returns the data of a raw block, or `None` for other blocks.
+    pub fn as_raw_slice(&self) -> Option<&[u8]> {
+        match self {
+            Block::Raw(buf) => Some(buf),
+            _ => None,
+        }
+    }
+
+    /// Computes the CRC32 checksum of the raw data this block decodes to.
+    ///
+    /// This is the block's contribution to the image checksum, which is
+    /// the checksum of all decoded blocks in order.
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.write_block(self);
+        hasher.finalize()
+    }
+
+    /// Writes the raw data this block decodes to into `w`.
+    ///
+    /// Skip blocks are written as zeros, checksum blocks not at all.
+    pub fn write_decoded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        match self {
+            Block::Raw(buf) => w.write_all(buf),
+            Block::Crc32(_) => Ok(()),
+            Block::Fill(_) | Block::Skip => {
+                let mut buf = [0; BLOCK_SIZE];
+                self.decode_into(&mut buf);
+                w.write_all(&buf)
+            }
+        }
+    }
+
+    /// Writes the raw data this block decodes to into `buf`.
+    pub(crate) fn decode_into(&self, buf: &mut [u8; BLOCK_SIZE]) {
+        match self {
+            Block::Raw(r) => buf.copy_from_slice(&r[..]),
+            Block::Fill(value) => {
+                for chunk in buf.chunks_exact_mut(4) {
+                    chunk.copy_from_slice(value);
+                }
+            }
+            Block::Skip | Block::Crc32(_) => buf.fill(0),
+        }
+    }
 }
 
 impl fmt::Debug for Block {
@@ -25,7 +118,7 @@ impl fmt::Debug for Block {
         use self::Block::*;
 
         match self {
-            Raw(r) => write!(f, "Raw({:?})", &r[..]),
+            Raw(r) => write!(f, "Raw({r:?})"),
             Fill(_) | Skip | Crc32(_) => self.fmt(f),
         }
     }
@@ -36,7 +129,7 @@ impl PartialEq for Block {
         use self::Block::*;
 
         match (self, other) {
-            (Raw(r1), Raw(r2)) => r1[..] == r2[..],
+            (Raw(r1), Raw(r2)) => r1 == r2,
             (Fill(v1), Fill(v2)) => v1 == v2,
             (Skip, Skip) => true,
             (Crc32(c1), Crc32(c2)) => c1 == c2,
@@ -44,3 +137,182 @@ impl PartialEq for Block {
         }
     }
 }
+
+/// Which blocks contribute to the checksum of a sparse image.
+///
+/// libsparse checksums the raw image, so skipped blocks contribute zeros.
+/// Some vendor tools leave them out instead, and their images only verify
+/// with `ExcludeSkipped`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrcPolicy {
+    /// Skipped blocks contribute zeros, like libsparse does.
+    #[default]
+    IncludeSkipped,
+    /// Skipped blocks don't contribute.
+    ExcludeSkipped,
+}
+
+impl CrcPolicy {
+    /// Checks whether `block` contributes to the checksum.
+    pub fn covers(&self, block: &Block) -> bool {
+        match block {
+            Block::Skip => *self == CrcPolicy::IncludeSkipped,
+            Block::Crc32(_) => false,
+            Block::Raw(_) | Block::Fill(_) => true,
+        }
+    }
+}
+
+/// Where a `Writer` stores the checksum of a sparse image.
+///
+/// The format defines a checksum field in the file header, but libsparse
+/// always leaves it 0 and appends a checksum chunk instead. Some tools
+/// expect the checksum in the header, though.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrcPlacement {
+    /// In a checksum chunk at the end of the image, like libsparse does.
+    #[default]
+    Chunk,
+    /// In the file header.
+    Header,
+    /// In both the file header and a checksum chunk.
+    Both,
+}
+
+impl CrcPlacement {
+    /// Checks whether the checksum is stored in the file header.
+    pub fn in_header(&self) -> bool {
+        *self != CrcPlacement::Chunk
+    }
+
+    /// Checks whether the checksum is stored in a checksum chunk.
+    pub fn in_chunk(&self) -> bool {
+        *self != CrcPlacement::Header
+    }
+}
+
+/// The data of a raw block, `Block::SIZE` bytes long.
+///
+/// How the data is stored is an implementation detail that may change, so
+/// it is accessed as a byte slice through `Deref`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BlockBuf(Box<[u8; BLOCK_SIZE]>);
+
+impl BlockBuf {
+    /// Creates a buffer filled with zeros.
+    pub fn zeroed() -> Self {
+        Self(Box::new([0; BLOCK_SIZE]))
+    }
+
+    /// Returns the data as an array.
+    pub fn as_array(&self) -> &[u8; BLOCK_SIZE] {
+        &self.0
+    }
+
+    /// Returns the data as a mutable array.
+    pub fn as_mut_array(&mut self) -> &mut [u8; BLOCK_SIZE] {
+        &mut self.0
+    }
+}
+
+impl Default for BlockBuf {
+    fn default() -> Self {
+        Self::zeroed()
+    }
+}
+
+impl From<[u8; BLOCK_SIZE]> for BlockBuf {
+    fn from(data: [u8; BLOCK_SIZE]) -> Self {
+        Self(Box::new(data))
+    }
+}
+
+impl From<Box<[u8; BLOCK_SIZE]>> for BlockBuf {
+    fn from(data: Box<[u8; BLOCK_SIZE]>) -> Self {
+        Self(data)
+    }
+}
+
+impl TryFrom<&[u8]> for BlockBuf {
+    type Error = Error;
+
+    /// Copies `data`, which must be exactly `Block::SIZE` bytes long.
+    fn try_from(data: &[u8]) -> Result<Self> {
+        ensure!(
+            data.len() == BLOCK_SIZE,
+            "Block data must be {BLOCK_SIZE} bytes long, not {}",
+            data.len()
+        );
+        let mut buf = Self::zeroed();
+        buf.copy_from_slice(data);
+        Ok(buf)
+    }
+}
+
+impl Deref for BlockBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl DerefMut for BlockBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+
+impl AsRef<[u8]> for BlockBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for BlockBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl fmt::Debug for BlockBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", &self[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_buf_from_slice() {
+        let buf = BlockBuf::try_from(&[7; BLOCK_SIZE][..]).unwrap();
+        assert_eq!(buf, BlockBuf::from([7; BLOCK_SIZE]));
+        assert!(BlockBuf::try_from(&[7; 10][..]).is_err());
+    }
+
+    #[test]
+    fn fill_u32() {
+        let block = Block::fill_u32(0x1122_3344);
+        assert_eq!(block, Block::Fill([0x44, 0x33, 0x22, 0x11]));
+        assert_eq!(block.fill_value_u32(), Some(0x1122_3344));
+        assert_eq!(Block::Skip.fill_value_u32(), None);
+    }
+
+    #[test]
+    fn helpers() {
+        let fill = Block::Fill([b'A'; 4]);
+        let raw = Block::Raw([b'A'; BLOCK_SIZE].into());
+        assert_eq!(fill.checksum(), raw.checksum());
+        assert_eq!(raw.as_raw_slice(), Some(&[b'A'; BLOCK_SIZE][..]));
+        assert!(fill.is_data() && !fill.is_hole());
+        assert!(Block::Skip.is_hole());
+
+        let mut decoded = Vec::new();
+        fill.write_decoded(&mut decoded).unwrap();
+        Block::Crc32(0).write_decoded(&mut decoded).unwrap();
+        assert_eq!(decoded, [b'A'; BLOCK_SIZE]);
+        assert_eq!(Block::Crc32(0).decoded_len(), 0);
+    }
+}
@@ -0,0 +1,384 @@
+//! Block maps as used by `bmaptool`.
+//!
+//! A block map (bmap) lists the block ranges of a raw image that hold data,
+//! so flashers like `bmaptool copy` only write those and leave the rest of
+//! the target device untouched. The block map of a sparse image is known
+//! without decoding it: skip blocks are unmapped, all other blocks are
+//! mapped.
+//!
+//! Conversely, a raw image and its block map can be encoded without
+//! scanning the image: mapped blocks become raw data, everything else
+//! becomes don't-care.
+//!
+//! Block maps are read and written in version 2 of the XML format, with
+//! SHA-256 checksums of every mapped range and of the block map itself.
+
+use crate::{
+    block::Block,
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{bail, ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{prelude::*, SeekFrom},
+    ops::Range,
+};
+
+const DIGEST_SIZE: usize = 32;
+
+/// A range of mapped blocks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappedRange {
+    /// The indices of the blocks in this range.
+    pub blocks: Range<u64>,
+    /// The SHA-256 digest of the raw data of the blocks.
+    pub checksum: [u8; DIGEST_SIZE],
+}
+
+/// The block map of a raw image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bmap {
+    /// The size of the raw image in bytes.
+    pub image_size: u64,
+    /// The mapped block ranges, in ascending order.
+    pub ranges: Vec<MappedRange>,
+}
+
+impl Bmap {
+    /// Returns the number of blocks in the raw image.
+    pub fn blocks_count(&self) -> u64 {
+        self.image_size.div_ceil(u64::from(Block::SIZE))
+    }
+
+    /// Returns the number of mapped blocks.
+    pub fn mapped_blocks_count(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|r| r.blocks.end - r.blocks.start)
+            .sum()
+    }
+
+    /// Parses a block map from its XML representation.
+    ///
+    /// The checksums of the block map itself are verified, those of the
+    /// ranges are verified when reading the image with a `BmapSource`.
+    pub fn parse(xml: &str) -> Result<Self> {
+        let xml = strip_comments(xml);
+
+        let version = attribute(&xml, "bmap", "version").context("Missing bmap version")?;
+        ensure!(
+            version.split('.').next() == Some("2"),
+            "Unsupported bmap version {version}"
+        );
+        let checksum_type = element(&xml, "ChecksumType")?;
+        ensure!(
+            checksum_type == "sha256",
+            "Unsupported bmap checksum type {checksum_type}"
+        );
+        let block_size: u32 = element(&xml, "BlockSize")?.parse()?;
+        ensure!(
+            block_size == Block::SIZE,
+            "Unsupported bmap block size {block_size}"
+        );
+
+        let file_checksum = element(&xml, "BmapFileChecksum")?;
+        let zeroed = xml.replacen(file_checksum, &"0".repeat(file_checksum.len()), 1);
+        ensure!(
+            hex(&Sha256::digest(zeroed.as_bytes())) == file_checksum,
+            "Bmap checksum mismatch"
+        );
+
+        let image_size: u64 = element(&xml, "ImageSize")?.parse()?;
+        let blocks_count = image_size.div_ceil(u64::from(Block::SIZE));
+
+        let mut ranges: Vec<MappedRange> = Vec::new();
+        let mut rest = element(&xml, "BlockMap")?;
+        while let Some(start) = rest.find("<Range") {
+            let tag_end = start + rest[start..].find('>').context("Unterminated Range")?;
+            let end = rest.find("</Range>").context("Unterminated Range")?;
+            let (tag, text) = (&rest[start..=tag_end], rest[tag_end + 1..end].trim());
+            rest = &rest[end + "</Range>".len()..];
+
+            let blocks = parse_range(text).with_context(|| format!("Invalid range {text}"))?;
+            ensure!(
+                blocks.end <= blocks_count
+                    && ranges.last().is_none_or(|r| r.blocks.end <= blocks.start),
+                "Range {text} is out of order or exceeds the image"
+            );
+            let checksum = attribute(tag, "Range", "chksum")
+                .and_then(parse_digest)
+                .with_context(|| format!("Missing or invalid checksum of range {text}"))?;
+            ranges.push(MappedRange { blocks, checksum });
+        }
+
+        Ok(Self { image_size, ranges })
+    }
+
+    /// Renders the block map as XML, as understood by `bmaptool`.
+    pub fn to_xml(&self) -> String {
+        // The file checksum is computed with the checksum itself replaced
+        // by zeros.
+        let placeholder = "0".repeat(2 * DIGEST_SIZE);
+        let xml = self.render(&placeholder);
+        let checksum = hex(&Sha256::digest(xml.as_bytes()));
+        xml.replacen(&placeholder, &checksum, 1)
+    }
+
+    /// Writes the block map as XML to `w`.
+    pub fn write_xml<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(self.to_xml().as_bytes())?;
+        Ok(())
+    }
+
+    fn render(&self, file_checksum: &str) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" ?>\n\
+             <bmap version=\"2.0\">\n\
+             \x20   <ImageSize> {} </ImageSize>\n\
+             \x20   <BlockSize> {} </BlockSize>\n\
+             \x20   <BlocksCount> {} </BlocksCount>\n\
+             \x20   <MappedBlocksCount> {} </MappedBlocksCount>\n\
+             \x20   <ChecksumType> sha256 </ChecksumType>\n\
+             \x20   <BmapFileChecksum> {file_checksum} </BmapFileChecksum>\n\
+             \x20   <BlockMap>\n",
+            self.image_size,
+            Block::SIZE,
+            self.blocks_count(),
+            self.mapped_blocks_count(),
+        );
+
+        for range in &self.ranges {
+            let (first, last) = (range.blocks.start, range.blocks.end - 1);
+            let blocks = match first == last {
+                true => first.to_string(),
+                false => format!("{first}-{last}"),
+            };
+            xml.push_str(&format!(
+                "        <Range chksum=\"{}\"> {blocks} </Range>\n",
+                hex(&range.checksum)
+            ));
+        }
+
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+        xml
+    }
+}
+
+/// Builds the block map of the raw image sparse blocks decode to.
+pub struct BmapBuilder {
+    ranges: Vec<MappedRange>,
+    /// The first block and running digest of the range being built.
+    current: Option<(u64, Sha256)>,
+    num_blocks: u64,
+}
+
+impl BmapBuilder {
+    /// Creates a new builder.
+    pub fn new() -> Self {
+        Self {
+            ranges: Vec::new(),
+            current: None,
+            num_blocks: 0,
+        }
+    }
+
+    /// Computes the block map of the blocks written so far.
+    pub fn finish(mut self) -> Bmap {
+        self.end_range();
+        Bmap {
+            image_size: self.num_blocks * u64::from(Block::SIZE),
+            ranges: self.ranges,
+        }
+    }
+
+    fn end_range(&mut self) {
+        if let Some((start, hasher)) = self.current.take() {
+            self.ranges.push(MappedRange {
+                blocks: start..self.num_blocks,
+                checksum: hasher.finalize().into(),
+            });
+        }
+    }
+}
+
+impl Default for BmapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockSink for BmapBuilder {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        match block {
+            Block::Crc32(_) => return Ok(()),
+            Block::Skip => self.end_range(),
+            Block::Raw(_) | Block::Fill(_) => {
+                let start = self.num_blocks;
+                let (_, hasher) = self.current.get_or_insert_with(|| (start, Sha256::new()));
+                let mut buf = [0; Block::SIZE as usize];
+                block.decode_into(&mut buf);
+                hasher.update(buf);
+            }
+        }
+
+        self.num_blocks += 1;
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the block map of the blocks in `src`.
+pub fn bmap<S: BlockSource>(mut src: S) -> Result<Bmap> {
+    let mut builder = BmapBuilder::new();
+    crate::pipeline::copy(&mut src, &mut builder)?;
+    Ok(builder.finish())
+}
+
+/// Reads the blocks of a raw image as described by its block map.
+///
+/// Mapped blocks are read as raw blocks, verifying the checksum of each
+/// range once it has been read. All other blocks are skip blocks and never
+/// read.
+pub struct BmapSource<R> {
+    src: R,
+    bmap: Bmap,
+    /// The index of the next block.
+    block: u64,
+    /// The index of the range containing or following the next block.
+    range: usize,
+    hasher: Sha256,
+}
+
+impl<R: Read + Seek> BmapSource<R> {
+    /// Creates a source reading the raw image `src` described by `bmap`.
+    pub fn new(src: R, bmap: Bmap) -> Self {
+        Self {
+            src,
+            bmap,
+            block: 0,
+            range: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn read_mapped(&mut self) -> Result<Block> {
+        let range = &self.bmap.ranges[self.range];
+        let pos = self.block * u64::from(Block::SIZE);
+        if self.block == range.blocks.start {
+            self.src.seek(SeekFrom::Start(pos))?;
+        }
+
+        // The last block of an image whose size isn't a multiple of the
+        // block size is padded with zeros, but only its actual content is
+        // covered by the checksum.
+        let len = (self.bmap.image_size - pos).min(u64::from(Block::SIZE)) as usize;
+        let mut buf = [0; Block::SIZE as usize];
+        self.src.read_exact(&mut buf[..len])?;
+        self.hasher.update(&buf[..len]);
+
+        if self.block + 1 == range.blocks.end {
+            let digest: [u8; DIGEST_SIZE] = self.hasher.finalize_reset().into();
+            if digest != range.checksum {
+                bail!(
+                    "Checksum mismatch in blocks {}..{} of the raw image",
+                    range.blocks.start,
+                    range.blocks.end
+                );
+            }
+            self.range += 1;
+        }
+
+        Ok(Block::Raw(buf.into()))
+    }
+}
+
+impl<R: Read + Seek> BlockSource for BmapSource<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        if self.block >= self.bmap.blocks_count() {
+            return Ok(None);
+        }
+
+        let mapped = self
+            .bmap
+            .ranges
+            .get(self.range)
+            .is_some_and(|r| r.blocks.contains(&self.block));
+        let block = match mapped {
+            true => self.read_mapped()?,
+            false => Block::Skip,
+        };
+
+        self.block += 1;
+        Ok(Some(block))
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        Some(self.bmap.blocks_count() * u64::from(Block::SIZE))
+    }
+}
+
+fn strip_comments(xml: &str) -> String {
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<!--") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start..].find("-->") {
+            Some(end) => &rest[start + end + 3..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns the trimmed text of the first `name` element in `xml`.
+fn element<'a>(xml: &'a str, name: &str) -> Result<&'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    let start = xml.find(&open).with_context(|| format!("Missing {name}"))? + open.len();
+    let end = start
+        + xml[start..]
+            .find(&close)
+            .with_context(|| format!("Unterminated {name}"))?;
+    Ok(xml[start..end].trim())
+}
+
+/// Returns the value of attribute `attr` of the first `name` tag in `xml`.
+fn attribute<'a>(xml: &'a str, name: &str, attr: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}"))?;
+    let tag = &xml[start..start + xml[start..].find('>')?];
+    let pattern = format!("{attr}=\"");
+    let value = &tag[tag.find(&pattern)? + pattern.len()..];
+    Some(&value[..value.find('"')?])
+}
+
+/// Parses a range of block indices, given as `first-last` or a single
+/// index.
+fn parse_range(text: &str) -> Option<Range<u64>> {
+    let (first, last) = match text.split_once('-') {
+        Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+        None => {
+            let index = text.parse().ok()?;
+            (index, index)
+        }
+    };
+    (first <= last).then(|| first..last + 1)
+}
+
+fn parse_digest(text: &str) -> Option<[u8; DIGEST_SIZE]> {
+    if text.len() != 2 * DIGEST_SIZE {
+        return None;
+    }
+    let mut digest = [0; DIGEST_SIZE];
+    for (byte, pair) in digest.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
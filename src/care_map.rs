@@ -0,0 +1,418 @@
+//! Care maps as used by AOSP's `update_verifier`.
+//!
+//! After an A/B update, `update_verifier` reads the blocks of the updated
+//! partitions listed in the care map, so dm-verity checks all of them
+//! before the update is marked successful. The AOSP build computes the care
+//! map from the sparse partition images: it covers the blocks of raw and
+//! fill chunks, up to the size of the partition's file system, but no
+//! don't-care blocks. All of this is known without decoding the images.
+//!
+//! `CareRanges` computes the care ranges of an image, along with the SHA-1
+//! digest of their data, like the `range_sha1` of the AOSP OTA tools.
+//! `CareMap` writes them as `care_map.pb`, the protocol buffer read by
+//! `update_verifier`, or as the text form `care_map_generator` converts:
+//!
+//! ```text
+//! system
+//! 4,0,1024,2048,3000
+//! ro.system.build.fingerprint
+//! google/device/device:14/...
+//! ```
+//!
+//! Ranges are written in the raw format of AOSP's `RangeSet`: the number of
+//! bounds, then the start and exclusive end of every range.
+
+use crate::{
+    block::Block,
+    pipeline::{BlockSink, BlockSource},
+    result::{bail, ensure, Context, Error, Result},
+};
+use sha1::{Digest, Sha1};
+use std::{fmt::Write as _, ops::Range};
+
+const DIGEST_SIZE: usize = 20;
+
+/// The property id and fingerprint used when a partition has none.
+pub const UNKNOWN: &str = "unknown";
+
+/// The blocks of a partition image that `update_verifier` reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CareRanges {
+    /// The block ranges, in ascending order.
+    pub blocks: Vec<Range<u64>>,
+    /// The SHA-1 digest of the data of the blocks, in order.
+    pub sha1: [u8; DIGEST_SIZE],
+}
+
+impl CareRanges {
+    /// Computes the care ranges of the blocks in `src`.
+    ///
+    /// If `image_size` is given, e.g. the `system_image_size` of the build,
+    /// only blocks within the first `image_size` bytes are included, which
+    /// leaves out the verity metadata at the end of the partition.
+    pub fn from_source<S: BlockSource>(mut src: S, image_size: Option<u64>) -> Result<Self> {
+        let mut builder = CareRangesBuilder::new();
+        if let Some(size) = image_size {
+            builder = builder.limit(size / u64::from(Block::SIZE));
+        }
+        crate::pipeline::copy(&mut src, &mut builder)?;
+        Ok(builder.finish())
+    }
+
+    /// Returns the number of blocks in the ranges.
+    pub fn blocks_count(&self) -> u64 {
+        self.blocks.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Returns the ranges in the raw format of AOSP's `RangeSet`.
+    pub fn to_string_raw(&self) -> String {
+        format_ranges(&self.blocks)
+    }
+
+    /// Returns the SHA-1 digest in hexadecimal.
+    pub fn sha1_hex(&self) -> String {
+        let mut hex = String::with_capacity(2 * DIGEST_SIZE);
+        for byte in self.sha1 {
+            write!(hex, "{byte:02x}").unwrap();
+        }
+        hex
+    }
+}
+
+/// Builds the care ranges of the raw image sparse blocks decode to.
+pub struct CareRangesBuilder {
+    blocks: Vec<Range<u64>>,
+    hasher: Sha1,
+    num_blocks: u64,
+    limit: u64,
+}
+
+impl CareRangesBuilder {
+    /// Creates a new builder.
+    pub fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            hasher: Sha1::new(),
+            num_blocks: 0,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Leaves out all blocks from block `blocks` on.
+    pub fn limit(mut self, blocks: u64) -> Self {
+        self.limit = blocks;
+        self
+    }
+
+    /// Computes the care ranges of the blocks written so far.
+    pub fn finish(self) -> CareRanges {
+        CareRanges {
+            blocks: self.blocks,
+            sha1: self.hasher.finalize().into(),
+        }
+    }
+}
+
+impl Default for CareRangesBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockSink for CareRangesBuilder {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        if let Block::Crc32(_) = block {
+            return Ok(());
+        }
+
+        let index = self.num_blocks;
+        self.num_blocks += 1;
+        if block.is_hole() || index >= self.limit {
+            return Ok(());
+        }
+        match self.blocks.last_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => self.blocks.push(index..index + 1),
+        }
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The care map entry of a partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The name of the partition, e.g. `system`.
+    pub name: String,
+    /// The blocks to read, in ascending order.
+    pub ranges: Vec<Range<u64>>,
+    /// The name of the build property holding the fingerprint, e.g.
+    /// `ro.system.build.fingerprint`.
+    pub id: String,
+    /// The build fingerprint of the partition, which `update_verifier`
+    /// compares to the property to make sure the care map matches.
+    pub fingerprint: String,
+}
+
+impl PartitionInfo {
+    /// Creates the entry of partition `name` with the care ranges `care`
+    /// and an unknown fingerprint.
+    pub fn new<S: Into<String>>(name: S, care: &CareRanges) -> Self {
+        Self {
+            name: name.into(),
+            ranges: care.blocks.clone(),
+            id: UNKNOWN.into(),
+            fingerprint: UNKNOWN.into(),
+        }
+    }
+
+    /// Takes the fingerprint from the `build.prop` file `content` of the
+    /// partition, preferring `ro.<name>.build.fingerprint` over
+    /// `ro.<name>.build.thumbprint` like the AOSP build. Returns whether
+    /// either was found.
+    pub fn set_fingerprint(&mut self, content: &str) -> bool {
+        let keys = [
+            format!("ro.{}.build.fingerprint", self.name),
+            format!("ro.{}.build.thumbprint", self.name),
+        ];
+        let props: Vec<_> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        for key in keys {
+            if let Some((_, value)) = props.iter().find(|(k, _)| k.trim() == key) {
+                self.fingerprint = value.trim().into();
+                self.id = key;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The care map of the partitions of an update.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CareMap {
+    /// The partitions, in order.
+    pub partitions: Vec<PartitionInfo>,
+}
+
+impl CareMap {
+    /// Encodes the care map as `care_map.pb`.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut pb = Vec::new();
+        for partition in &self.partitions {
+            let mut message = Vec::new();
+            let fields = [
+                partition.name.clone(),
+                format_ranges(&partition.ranges),
+                partition.id.clone(),
+                partition.fingerprint.clone(),
+            ];
+            for (number, value) in (1..).zip(&fields) {
+                // Like protobuf, leave out fields with default values.
+                if !value.is_empty() {
+                    put_field(&mut message, number, value.as_bytes());
+                }
+            }
+            put_field(&mut pb, 1, &message);
+        }
+        pb
+    }
+
+    /// Decodes a `care_map.pb`.
+    pub fn from_protobuf(mut pb: &[u8]) -> Result<Self> {
+        let mut care_map = Self::default();
+        while !pb.is_empty() {
+            let (number, value) = get_field(&mut pb)?;
+            let Some(mut message) = value.filter(|_| number == 1) else {
+                continue;
+            };
+            let mut fields: [String; 4] = Default::default();
+            while !message.is_empty() {
+                let (number, value) = get_field(&mut message)?;
+                let field = (number as usize)
+                    .checked_sub(1)
+                    .and_then(|i| fields.get_mut(i));
+                if let (Some(field), Some(value)) = (field, value) {
+                    *field = String::from_utf8(value.to_vec())
+                        .map_err(|_| Error::msg("Invalid UTF-8 in care map"))?;
+                }
+            }
+            let [name, ranges, id, fingerprint] = fields;
+            care_map.partitions.push(PartitionInfo {
+                ranges: parse_ranges(&ranges).with_context(|| format!("Partition {name}"))?,
+                name,
+                id,
+                fingerprint,
+            });
+        }
+        Ok(care_map)
+    }
+
+    /// Returns the care map in the text form `care_map_generator` reads,
+    /// with four lines per partition: the name, the ranges, the property id
+    /// and the fingerprint.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for p in &self.partitions {
+            let ranges = format_ranges(&p.ranges);
+            writeln!(text, "{}\n{ranges}\n{}\n{}", p.name, p.id, p.fingerprint).unwrap();
+        }
+        text
+    }
+}
+
+/// Formats `ranges` in the raw format of AOSP's `RangeSet`.
+pub fn format_ranges(ranges: &[Range<u64>]) -> String {
+    let mut raw = (2 * ranges.len()).to_string();
+    for range in ranges {
+        write!(raw, ",{},{}", range.start, range.end).unwrap();
+    }
+    raw
+}
+
+/// Parses ranges in the raw format of AOSP's `RangeSet`.
+pub fn parse_ranges(raw: &str) -> Result<Vec<Range<u64>>> {
+    let numbers = raw
+        .split(',')
+        .map(|n| Ok(n.trim().parse()?))
+        .collect::<Result<Vec<u64>>>()
+        .with_context(|| format!("Invalid ranges `{raw}`"))?;
+    let Some((&count, bounds)) = numbers.split_first() else {
+        bail!("Empty ranges");
+    };
+    ensure!(
+        count == bounds.len() as u64 && count % 2 == 0,
+        "Ranges `{raw}` don't have {count} bounds"
+    );
+    let ranges: Vec<_> = bounds.chunks(2).map(|pair| pair[0]..pair[1]).collect();
+    for pair in ranges.windows(2) {
+        ensure!(
+            pair[0].end <= pair[1].start,
+            "Ranges `{raw}` are not in order"
+        );
+    }
+    ensure!(
+        ranges.iter().all(|r| r.start < r.end),
+        "Ranges `{raw}` hold empty ranges"
+    );
+    Ok(ranges)
+}
+
+/// Appends field `number` with the length-delimited `value` to `pb`.
+fn put_field(pb: &mut Vec<u8>, number: u64, value: &[u8]) {
+    put_varint(pb, number << 3 | 2);
+    put_varint(pb, value.len() as u64);
+    pb.extend_from_slice(value);
+}
+
+fn put_varint(pb: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        pb.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    pb.push(value as u8);
+}
+
+/// Reads the next field of `pb`, returning its number and its value if it
+/// is length-delimited.
+fn get_field<'a>(pb: &mut &'a [u8]) -> Result<(u64, Option<&'a [u8]>)> {
+    let key = get_varint(pb)?;
+    let len = match key & 7 {
+        0 => {
+            get_varint(pb)?;
+            0
+        }
+        1 => 8,
+        2 => get_varint(pb)?,
+        5 => 4,
+        wire_type => bail!("Unsupported protobuf wire type {wire_type}"),
+    };
+    ensure!(len <= pb.len() as u64, "Truncated protobuf field");
+    let (value, rest) = pb.split_at(len as usize);
+    *pb = rest;
+    Ok((key >> 3, (key & 7 == 2).then_some(value)))
+}
+
+fn get_varint(pb: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = pb.split_first().context("Truncated protobuf varint")?;
+        *pb = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid protobuf varint")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn care_map() {
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::fill_u32(0),
+            Block::Skip,
+            Block::Crc32(0),
+            Block::Raw([2; Block::SIZE as usize].into()),
+            Block::fill_u32(3),
+        ];
+        // The last block is beyond the file system.
+        let mut builder = CareRangesBuilder::new().limit(4);
+        for block in &blocks {
+            builder.write_block(block).unwrap();
+        }
+        let care = builder.finish();
+        assert_eq!(care.blocks, [0..2, 3..4]);
+        assert_eq!(care.blocks_count(), 3);
+        assert_eq!(care.to_string_raw(), "4,0,2,3,4");
+
+        let mut hasher = Sha1::new();
+        hasher.update([1; Block::SIZE as usize]);
+        hasher.update([0; Block::SIZE as usize]);
+        hasher.update([2; Block::SIZE as usize]);
+        assert_eq!(care.sha1, <[u8; DIGEST_SIZE]>::from(hasher.finalize()));
+
+        let mut system = PartitionInfo::new("system", &care);
+        assert!(system.set_fingerprint(
+            "# begin build properties\n\
+             ro.system.build.thumbprint=thumb\n\
+             ro.system.build.fingerprint=google/device:14/release-keys\n"
+        ));
+        assert_eq!(system.id, "ro.system.build.fingerprint");
+        let vendor = PartitionInfo {
+            ranges: vec![0..100, 200..300],
+            ..PartitionInfo::new("vendor", &care)
+        };
+        let care_map = CareMap {
+            partitions: vec![system, vendor],
+        };
+        assert_eq!(
+            care_map.to_text(),
+            "system\n4,0,2,3,4\nro.system.build.fingerprint\ngoogle/device:14/release-keys\n\
+             vendor\n4,0,100,200,300\nunknown\nunknown\n"
+        );
+
+        let pb = care_map.to_protobuf();
+        assert_eq!(&pb[2..10], b"\x0a\x06system");
+        assert_eq!(CareMap::from_protobuf(&pb).unwrap(), care_map);
+        assert!(CareMap::from_protobuf(&pb[..pb.len() - 1]).is_err());
+
+        assert!(parse_ranges("3,0,1,2").is_err());
+        assert!(parse_ranges("4,5,6,0,1").is_err());
+        assert!(parse_ranges("2,1,1").is_err());
+    }
+}
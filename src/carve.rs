@@ -0,0 +1,152 @@
+//! Discovery of sparse images embedded in arbitrary data.
+//!
+//! OTA packages, flash dumps and similar blobs often contain sparse images
+//! at unknown offsets. Candidates are located by their file magic and only
+//! reported if their whole chunk structure is intact.
+
+use crate::{
+    dump::Chunks,
+    headers::{FileHeader, FILE_MAGIC},
+};
+use crate::result::Result;
+use std::io::{prelude::*, ErrorKind, SeekFrom};
+
+/// The amount of data searched for the file magic at once.
+const SCAN_BUF_SIZE: usize = 64 * 1024;
+
+/// A sparse image found inside other data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddedImage {
+    /// The offset of the image's file header in the scanned data.
+    pub offset: u64,
+    /// The size of the sparse image in bytes.
+    pub size: u64,
+    /// The file header of the image.
+    pub header: FileHeader,
+}
+
+/// Scans `r` for embedded sparse images.
+///
+/// Data covered by a valid image is not searched again, so images nested
+/// in raw chunks of other images are not reported.
+pub fn scan<R: Read + Seek>(mut r: R) -> Result<Vec<EmbeddedImage>> {
+    let mut images = Vec::new();
+    let mut pos = 0;
+
+    while let Some(offset) = find_magic(&mut r, pos)? {
+        match validate(&mut r, offset)? {
+            Some(image) => {
+                pos = offset + image.size;
+                images.push(image);
+            }
+            None => pos = offset + 1,
+        }
+    }
+
+    Ok(images)
+}
+
+/// Copies the embedded image `image` from `r` to `w`.
+pub fn extract<R, W>(mut r: R, image: &EmbeddedImage, mut w: W) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    r.seek(SeekFrom::Start(image.offset))?;
+    let copied = std::io::copy(&mut r.take(image.size), &mut w)?;
+    if copied < image.size {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Returns the offset of the next file magic at or after `from`.
+fn find_magic<R: Read + Seek>(r: &mut R, from: u64) -> Result<Option<u64>> {
+    let magic = FILE_MAGIC.to_le_bytes();
+    let mut buf = vec![0; SCAN_BUF_SIZE];
+    let mut base = from;
+    let mut len = 0;
+
+    r.seek(SeekFrom::Start(from))?;
+    loop {
+        let n = match r.read(&mut buf[len..]) {
+            Ok(0) => return Ok(None),
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        len += n;
+
+        if let Some(i) = buf[..len].windows(magic.len()).position(|w| w == magic) {
+            return Ok(Some(base + i as u64));
+        }
+
+        // Keep the tail, it may hold the start of a magic.
+        let keep = len.min(magic.len() - 1);
+        buf.copy_within(len - keep..len, 0);
+        base += (len - keep) as u64;
+        len = keep;
+    }
+}
+
+/// Checks whether a complete sparse image starts at `offset`.
+fn validate<R: Read + Seek>(r: &mut R, offset: u64) -> Result<Option<EmbeddedImage>> {
+    r.seek(SeekFrom::Start(offset))?;
+    let mut chunks = match Chunks::new(&mut *r) {
+        Ok(chunks) => chunks,
+        Err(_) => return Ok(None),
+    };
+    let header = chunks.header().clone();
+
+    let mut size = u64::from(FileHeader::SIZE);
+    let mut blocks = 0;
+    for chunk in &mut chunks {
+        let chunk = match chunk {
+            Ok(c) if c.header.is_plausible() => c,
+            _ => return Ok(None),
+        };
+        size = chunk.offset + u64::from(chunk.header.total_size);
+        blocks += u64::from(chunk.header.chunk_size);
+    }
+
+    if blocks != u64::from(header.total_blocks) {
+        return Ok(None);
+    }
+
+    Ok(Some(EmbeddedImage {
+        offset,
+        size,
+        header,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{write::Writer, Block};
+    use std::io::Cursor;
+
+    #[test]
+    fn scan_embedded() {
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut image, false).unwrap();
+        writer.write_block(&Block::Fill([1; 4])).unwrap();
+        writer.write_block(&Block::Skip).unwrap();
+        writer.close().unwrap();
+        let image = image.into_inner();
+
+        let mut blob = b"junk".to_vec();
+        blob.extend(FILE_MAGIC.to_le_bytes());
+        blob.extend(&image);
+        blob.extend(&image[..image.len() - 1]);
+
+        let images = scan(Cursor::new(&blob)).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].offset, 8);
+        assert_eq!(images[0].size, image.len() as u64);
+
+        let mut extracted = Vec::new();
+        extract(Cursor::new(&blob), &images[0], &mut extracted).unwrap();
+        assert_eq!(extracted, image);
+    }
+}
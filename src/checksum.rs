@@ -0,0 +1,267 @@
+//! Parallel checksum verification of sparse image files.
+//!
+//! Verifying the checksum of a large image with a `Reader` hashes every
+//! block on a single thread. `verify` instead uses the chunk index from
+//! `Reader::prescan` to split the image into segments of equal size,
+//! hashes them on several threads with positioned reads and combines the
+//! partial checksums, which is as fast as the storage allows.
+
+use crate::{
+    block::{Block, CrcPolicy},
+    dump::ChunkEntry,
+    headers::{ChunkType, FileHeader},
+    io::PositionedFile,
+    par::parallel,
+    read::Reader,
+    result::{ensure, Context, Result},
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crc32fast::Hasher;
+use std::{
+    fs::File,
+    io::{prelude::*, SeekFrom},
+    sync::Arc,
+};
+
+/// The smallest number of blocks worth hashing on a separate thread.
+const MIN_SEGMENT_BLOCKS: u64 = 1024;
+
+/// The number of blocks read at once when hashing raw data.
+const READ_BATCH_BLOCKS: u64 = 256;
+
+/// The result of verifying the checksum of a sparse image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Verified {
+    /// The number of blocks of the image.
+    pub blocks: u64,
+    /// The checksum that matched, or `None` if the image has no checksum.
+    /// Of images with checksums in both chunks and the file header, this
+    /// is the one in the last chunk.
+    pub checksum: Option<u32>,
+}
+
+/// A run of blocks within a chunk.
+struct Segment<'a> {
+    chunk: &'a ChunkEntry,
+    /// The index of the first block within the chunk.
+    first: u64,
+    count: u64,
+}
+
+/// Verifies the checksum of the sparse image `file` on up to `jobs`
+/// threads.
+///
+/// The structure of the image is validated first, like `Reader::prescan`
+/// does. Every checksum chunk covers the blocks preceding it and is
+/// verified, like `Reader` does. A checksum in the file header covers all
+/// blocks and is verified unless it is 0. Images without a checksum pass
+/// once their structure is valid.
+pub fn verify<F: Into<Arc<File>>>(file: F, jobs: usize, policy: CrcPolicy) -> Result<Verified> {
+    let file = file.into();
+    let mut reader = Reader::new(PositionedFile::new(Arc::clone(&file)), false)?;
+    let scan = reader.prescan()?;
+    let blocks = scan.raw_blocks + scan.fill_blocks + scan.skip_blocks;
+
+    let image_checksum =
+        FileHeader::read_from(PositionedFile::new(Arc::clone(&file)))?.image_checksum;
+    let has_chunk = scan
+        .chunks
+        .iter()
+        .any(|c| c.header.chunk_type == ChunkType::Crc32);
+    if !has_chunk && image_checksum == 0 {
+        return Ok(Verified {
+            blocks,
+            checksum: None,
+        });
+    }
+
+    let mut hasher = Hasher::new();
+    let mut covered = 0;
+    let mut checksum = None;
+    for (position, crc_chunk) in scan.chunks.iter().enumerate() {
+        if crc_chunk.header.chunk_type != ChunkType::Crc32 {
+            continue;
+        }
+        let chunks = &scan.chunks[covered..position];
+        hasher.combine(&hash_chunks(&file, chunks, jobs, policy)?);
+        covered = position + 1;
+        let value = read_at(&file, crc_chunk)
+            .read_u32::<LittleEndian>()
+            .with_context_at(crc_chunk.payload_offset(), || "Reading checksum chunk")?;
+        ensure!(
+            hasher.clone().finalize() == value,
+            "Checksum does not match"
+        );
+        checksum = Some(value);
+    }
+
+    // The checksum in the file header covers all blocks. libsparse leaves
+    // it 0.
+    if image_checksum != 0 {
+        hasher.combine(&hash_chunks(&file, &scan.chunks[covered..], jobs, policy)?);
+        ensure!(
+            hasher.finalize() == image_checksum,
+            "Checksum in file header does not match"
+        );
+    }
+
+    Ok(Verified {
+        blocks,
+        checksum: checksum.or((image_checksum != 0).then_some(image_checksum)),
+    })
+}
+
+/// Hashes the blocks of `chunks` on up to `jobs` threads.
+fn hash_chunks(
+    file: &Arc<File>,
+    chunks: &[ChunkEntry],
+    jobs: usize,
+    policy: CrcPolicy,
+) -> Result<Hasher> {
+    let segments = split(chunks, jobs);
+    let hashers = parallel(&segments, jobs, |segment| hash(file, segment, policy));
+    let mut hasher = Hasher::new();
+    for partial in hashers {
+        hasher.combine(&partial?);
+    }
+    Ok(hasher)
+}
+
+/// Splits the blocks of `chunks` into segments, a few per thread so
+/// threads that finish early can pick up more work.
+fn split(chunks: &[ChunkEntry], jobs: usize) -> Vec<Segment<'_>> {
+    let blocks: u64 = chunks.iter().map(|c| u64::from(c.header.chunk_size)).sum();
+    let per_segment = (blocks / (jobs.max(1) as u64 * 4)).max(MIN_SEGMENT_BLOCKS);
+
+    let mut segments = Vec::new();
+    for chunk in chunks {
+        let size = u64::from(chunk.header.chunk_size);
+        let mut first = 0;
+        while first < size {
+            let count = per_segment.min(size - first);
+            segments.push(Segment {
+                chunk,
+                first,
+                count,
+            });
+            first += count;
+        }
+    }
+    segments
+}
+
+/// Returns a reader positioned at the payload of `chunk`.
+fn read_at(file: &Arc<File>, chunk: &ChunkEntry) -> PositionedFile {
+    let mut reader = PositionedFile::new(Arc::clone(file));
+    // Seeking a positioned file never fails for offsets from the start.
+    reader
+        .seek(SeekFrom::Start(chunk.payload_offset()))
+        .unwrap();
+    reader
+}
+
+/// Hashes the blocks of `segment`.
+fn hash(file: &Arc<File>, segment: &Segment, policy: CrcPolicy) -> Result<Hasher> {
+    let mut hasher = Hasher::new();
+    let chunk = segment.chunk;
+    let mut src = read_at(file, chunk);
+
+    let block = match chunk.header.chunk_type {
+        ChunkType::Raw => {
+            let size = u64::from(Block::SIZE);
+            let offset = chunk.payload_offset() + segment.first * size;
+            src.seek(SeekFrom::Start(offset))?;
+
+            let mut buf = vec![0; (segment.count.min(READ_BATCH_BLOCKS) * size) as usize];
+            let mut remaining = segment.count * size;
+            while remaining > 0 {
+                let len = remaining.min(buf.len() as u64) as usize;
+                src.read_exact(&mut buf[..len])
+                    .with_context_at(offset, || "Reading raw chunk payload")?;
+                hasher.update(&buf[..len]);
+                remaining -= len as u64;
+            }
+            return Ok(hasher);
+        }
+        ChunkType::Fill => {
+            let mut value = [0; 4];
+            src.read_exact(&mut value)
+                .with_context_at(chunk.payload_offset(), || "Reading fill value")?;
+            Block::Fill(value)
+        }
+        ChunkType::DontCare => Block::Skip,
+        ChunkType::Crc32 | ChunkType::Metadata => return Ok(hasher),
+    };
+
+    if policy.covers(&block) {
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        for _ in 0..segment.count {
+            hasher.update(&buf);
+        }
+    }
+    Ok(hasher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{gen::ImageSpec, write::Writer};
+
+    fn image(policy: CrcPolicy) -> File {
+        let spec = ImageSpec {
+            size: 8 << 20,
+            seed: 3,
+            ..ImageSpec::default()
+        };
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = Writer::new(&mut file, true).unwrap().crc_policy(policy);
+        spec.write_sparse(&mut writer).unwrap();
+        writer.close().unwrap();
+        file
+    }
+
+    #[test]
+    fn parallel_crc() {
+        for policy in [CrcPolicy::IncludeSkipped, CrcPolicy::ExcludeSkipped] {
+            let file = Arc::new(image(policy));
+            let expected = Reader::from_file_positioned(Arc::clone(&file), true)
+                .unwrap()
+                .crc_policy(policy)
+                .filter_map(|b| match b.unwrap() {
+                    Block::Crc32(crc) => Some(crc),
+                    _ => None,
+                })
+                .next();
+            for jobs in [1, 4] {
+                let verified = verify(Arc::clone(&file), jobs, policy).unwrap();
+                assert_eq!(verified.blocks, 2048);
+                assert_eq!(verified.checksum, expected);
+            }
+        }
+
+        let file = image(CrcPolicy::IncludeSkipped);
+        assert!(verify(file, 4, CrcPolicy::ExcludeSkipped).is_err());
+    }
+
+    #[test]
+    fn header_crc() {
+        use crate::block::CrcPlacement;
+
+        let spec = ImageSpec {
+            size: 1 << 20,
+            ..ImageSpec::default()
+        };
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = Writer::new(&mut file, true)
+            .unwrap()
+            .crc_placement(CrcPlacement::Header);
+        spec.write_sparse(&mut writer).unwrap();
+        writer.close().unwrap();
+
+        let file = Arc::new(file);
+        let verified = verify(Arc::clone(&file), 4, CrcPolicy::default()).unwrap();
+        assert!(verified.checksum.is_some());
+        assert!(verify(file, 4, CrcPolicy::ExcludeSkipped).is_err());
+    }
+}
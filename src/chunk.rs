@@ -0,0 +1,159 @@
+//! Low-level reading and writing of individual chunks.
+//!
+//! `Reader` and `Writer` handle complete sparse images. Tools that embed
+//! sparse streams in containers of their own, e.g. with extra framing
+//! between chunks, can use these primitives instead of reimplementing the
+//! header serialization. Keeping the block and chunk counts in the file
+//! header (see `FileHeader::write_to`) consistent with the chunks written
+//! is up to them.
+
+use crate::{
+    block::Block,
+    headers::{ChunkHeader, ChunkType},
+    metadata::Metadata,
+};
+use crate::result::{ensure, Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::prelude::*;
+
+/// Reads a chunk header from `r`, checking that its sizes are consistent
+/// with its chunk type.
+///
+/// The chunk payload, `total_size - ChunkHeader::SIZE` bytes, follows the
+/// header and is left for the caller to read.
+pub fn read_chunk_header<R: Read>(r: R) -> Result<ChunkHeader> {
+    let header = ChunkHeader::read_from(r)?;
+    ensure!(header.is_plausible(), "Invalid chunk sizes: {header:?}");
+    Ok(header)
+}
+
+/// Writes a raw chunk holding `data`, which must be a non-empty multiple of
+/// the block size, to `w`.
+///
+/// Returns the header of the written chunk.
+pub fn write_raw_chunk<W: Write>(mut w: W, data: &[u8]) -> Result<ChunkHeader> {
+    let block_size = Block::SIZE as usize;
+    ensure!(
+        !data.is_empty() && data.len().is_multiple_of(block_size),
+        "Raw chunk data must be a non-empty multiple of the block size"
+    );
+
+    let total_size = u32::try_from(data.len())
+        .ok()
+        .and_then(|size| size.checked_add(u32::from(ChunkHeader::SIZE)))
+        .context("Raw chunk data too large")?;
+    let header = ChunkHeader {
+        chunk_type: ChunkType::Raw,
+        chunk_size: (data.len() / block_size) as u32,
+        total_size,
+    };
+
+    header.write_to(&mut w)?;
+    w.write_all(data)?;
+    Ok(header)
+}
+
+/// Writes a fill chunk of `blocks` blocks filled with `value` to `w`.
+///
+/// Returns the header of the written chunk.
+pub fn write_fill_chunk<W: Write>(mut w: W, value: [u8; 4], blocks: u32) -> Result<ChunkHeader> {
+    ensure!(blocks > 0, "Fill chunks must cover at least one block");
+
+    let header = ChunkHeader {
+        chunk_type: ChunkType::Fill,
+        chunk_size: blocks,
+        total_size: u32::from(ChunkHeader::SIZE) + 4,
+    };
+
+    header.write_to(&mut w)?;
+    w.write_all(&value)?;
+    Ok(header)
+}
+
+/// Writes a don't care chunk of `blocks` blocks to `w`.
+///
+/// Returns the header of the written chunk.
+pub fn write_dont_care_chunk<W: Write>(w: W, blocks: u32) -> Result<ChunkHeader> {
+    ensure!(
+        blocks > 0,
+        "Don't care chunks must cover at least one block"
+    );
+
+    let header = ChunkHeader {
+        chunk_type: ChunkType::DontCare,
+        chunk_size: blocks,
+        total_size: u32::from(ChunkHeader::SIZE),
+    };
+
+    header.write_to(w)?;
+    Ok(header)
+}
+
+/// Writes a CRC32 chunk holding `checksum` to `w`.
+///
+/// Returns the header of the written chunk.
+pub fn write_crc32_chunk<W: Write>(mut w: W, checksum: u32) -> Result<ChunkHeader> {
+    let header = ChunkHeader {
+        chunk_type: ChunkType::Crc32,
+        chunk_size: 0,
+        total_size: u32::from(ChunkHeader::SIZE) + 4,
+    };
+
+    header.write_to(&mut w)?;
+    w.write_u32::<LittleEndian>(checksum)?;
+    Ok(header)
+}
+
+/// Writes a metadata chunk holding `metadata` to `w`.
+///
+/// Returns the header of the written chunk.
+pub fn write_metadata_chunk<W: Write>(mut w: W, metadata: &Metadata) -> Result<ChunkHeader> {
+    let payload = metadata.to_bytes()?;
+    let header = ChunkHeader {
+        chunk_type: ChunkType::Metadata,
+        chunk_size: 0,
+        total_size: u32::from(ChunkHeader::SIZE) + payload.len() as u32,
+    };
+
+    header.write_to(&mut w)?;
+    w.write_all(&payload)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{headers::FileHeader, read::Reader};
+
+    #[test]
+    fn roundtrip() {
+        let mut bytes = Vec::new();
+        let header = FileHeader {
+            total_blocks: 4,
+            total_chunks: 3,
+            image_checksum: 0,
+        };
+        header.write_to(&mut bytes).unwrap();
+        write_raw_chunk(&mut bytes, &[0xaa; Block::SIZE as usize]).unwrap();
+        write_fill_chunk(&mut bytes, [1, 2, 3, 4], 2).unwrap();
+        let dont_care = write_dont_care_chunk(&mut bytes, 1).unwrap();
+
+        let blocks: Vec<_> = Reader::new(&bytes[..], false)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            [
+                Block::Raw([0xaa; Block::SIZE as usize].into()),
+                Block::Fill([1, 2, 3, 4]),
+                Block::Fill([1, 2, 3, 4]),
+                Block::Skip,
+            ]
+        );
+
+        let end = bytes.len() - usize::from(ChunkHeader::SIZE);
+        assert_eq!(read_chunk_header(&bytes[end..]).unwrap(), dont_care);
+        assert!(write_raw_chunk(&mut bytes, &[0; 100]).is_err());
+    }
+}
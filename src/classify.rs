@@ -0,0 +1,74 @@
+//! Customization of how encoders represent raw image blocks.
+
+use crate::block::Block;
+
+/// The kind of sparse block a raw block is encoded as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    /// Keep the block's data as-is.
+    Raw,
+    /// Encode the block as a fill block with the given value.
+    Fill([u8; 4]),
+    /// Encode the block as a skip block.
+    Skip,
+}
+
+/// Decides which kind of sparse block each raw block is encoded as.
+///
+/// Classifiers can, for example, treat blocks of `0xff` bytes (the erased
+/// state of NAND flash) as skip blocks, or force certain ranges of an image
+/// to be stored raw. Custom classifiers can delegate to `DefaultClassifier`
+/// for blocks they don't care about.
+pub trait BlockClassifier {
+    /// Returns the kind of sparse block to encode `data`, the content of
+    /// block `index` of the raw image, as.
+    fn classify(&mut self, index: u64, data: &[u8; Block::SIZE as usize]) -> BlockKind;
+}
+
+impl<F> BlockClassifier for F
+where
+    F: FnMut(u64, &[u8; Block::SIZE as usize]) -> BlockKind,
+{
+    fn classify(&mut self, index: u64, data: &[u8; Block::SIZE as usize]) -> BlockKind {
+        self(index, data)
+    }
+}
+
+/// The classification encoders use by default.
+///
+/// Blocks consisting of a single repeated 4-byte value become fill blocks,
+/// or skip blocks if that value is zero. All other blocks stay raw.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultClassifier;
+
+impl BlockClassifier for DefaultClassifier {
+    fn classify(&mut self, _index: u64, data: &[u8; Block::SIZE as usize]) -> BlockKind {
+        let value = [data[0], data[1], data[2], data[3]];
+        if !data.chunks_exact(4).all(|c| c == value) {
+            BlockKind::Raw
+        } else if value == [0; 4] {
+            BlockKind::Skip
+        } else {
+            BlockKind::Fill(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_classifier() {
+        let mut data = [0; Block::SIZE as usize];
+        assert_eq!(DefaultClassifier.classify(0, &data), BlockKind::Skip);
+
+        for chunk in data.chunks_exact_mut(4) {
+            chunk.copy_from_slice(&[1, 2, 3, 4]);
+        }
+        assert_eq!(DefaultClassifier.classify(0, &data), BlockKind::Fill([1, 2, 3, 4]));
+
+        data[100] = 0;
+        assert_eq!(DefaultClassifier.classify(0, &data), BlockKind::Raw);
+    }
+}
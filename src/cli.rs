@@ -0,0 +1,22 @@
+//! Internals of the `simg` command line tool.
+//!
+//! Some subcommands are also installed as binaries of their own, e.g.
+//! `simg encode` as `img2simg`, so their code lives here rather than in
+//! the `simg` binary, where all of them can reach it. This is not a stable API;
+//! programs should use `tools` instead. Only available with the `cli`
+//! feature.
+
+pub mod common;
+pub mod decode;
+pub mod dump;
+pub mod encode;
+pub mod strings;
+
+// The aliases parse their arguments like `simg` parses those of the
+// subcommand.
+impl argh::TopLevelCommand for decode::Args {}
+impl argh::TopLevelCommand for dump::Args {}
+impl argh::TopLevelCommand for encode::Args {}
+#[cfg(feature = "grep")]
+impl argh::TopLevelCommand for strings::GrepArgs {}
+impl argh::TopLevelCommand for strings::StringsArgs {}
@@ -0,0 +1,292 @@
+//! Flags, defaults and progress UI shared by the subcommands.
+
+use crate::{
+    human::{HumanSize, Percent},
+    io::{copy_with_progress, RetryPolicy},
+    settings, CrcPlacement, CrcPolicy, Decoder, Reader, Writer,
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, prelude::*},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+};
+
+pub use crate::tools::{
+    check_distinct, cleanup_on_interrupt, create_output, error, expand_glob, info, init_logging,
+    load_profile, open_input, parallel, parse_size, progress_bar, warn, Output,
+};
+
+/// Written images whose chunks cover fewer blocks than this on average are
+/// warned about.
+const FRAGMENTED_BLOCKS_PER_CHUNK: u32 = 8;
+
+/// Prints a table of the input and output sizes of a batch conversion.
+///
+/// Fails if converting any of the images failed.
+pub fn print_summary<'a, I>(rows: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a Path, &'a Result<(u64, u64)>)>,
+{
+    println!(
+        "{:<48} {:>12} {:>12} {:>7}",
+        "image", "input size", "output size", "ratio"
+    );
+
+    let (mut total, mut failed) = (0, 0);
+    for (image, result) in rows {
+        total += 1;
+        match result {
+            Ok((input, output)) => println!(
+                "{:<48} {:>12} {:>12} {:>7}",
+                image.display(),
+                HumanSize(*input),
+                HumanSize(*output),
+                Percent::new(*output, *input)
+            ),
+            Err(err) => {
+                failed += 1;
+                println!("{:<48} error: {err:#}", image.display());
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {total} images failed");
+    }
+    Ok(())
+}
+
+/// Assigns the positional `images` of a conversion to its input and output
+/// image, in that order, leaving out those given as inherited descriptors
+/// with `--input-fd` and `--output-fd`.
+///
+/// Descriptors are passed on as `/dev/fd/N`. The input is `None` if only
+/// the output image is given.
+pub fn conversion_images(
+    images: &[String],
+    input_fd: Option<i32>,
+    output_fd: Option<i32>,
+) -> Result<(Option<String>, String)> {
+    let expected = usize::from(input_fd.is_none()) + usize::from(output_fd.is_none());
+    ensure!(images.len() <= expected, "Too many images given");
+
+    // The output comes last, so it is given whenever the input is.
+    let mut images = images.iter().rev().cloned();
+    let output = match output_fd {
+        Some(fd) => format!("/dev/fd/{fd}"),
+        None => images.next().context("No output image given")?,
+    };
+    let input = match input_fd {
+        Some(fd) => Some(format!("/dev/fd/{fd}")),
+        None => images.next(),
+    };
+    Ok((input, output))
+}
+
+/// Combines the `--crc` and `--no-crc` switches, returning `None` if
+/// neither is given.
+pub fn crc_flag(crc: bool, no_crc: bool) -> Result<Option<bool>> {
+    match (crc, no_crc) {
+        (true, true) => bail!("--crc and --no-crc cannot be combined"),
+        (true, false) => Ok(Some(true)),
+        (false, true) => Ok(Some(false)),
+        (false, false) => Ok(None),
+    }
+}
+
+/// Defaults read from the config file.
+///
+/// The config file is looked up at `$SIMG_CONFIG`, falling back to
+/// `$XDG_CONFIG_HOME/simg/config` and `~/.config/simg/config`. It consists
+/// of `key = value` lines in the subset of TOML described in
+/// `settings`.
+#[derive(Default)]
+pub struct Config {
+    /// Whether to write and verify checksums by default.
+    pub crc: bool,
+    /// Which blocks contribute to checksums.
+    pub crc_policy: CrcPolicy,
+    /// Where written sparse images store their checksum.
+    pub crc_placement: CrcPlacement,
+    /// The size of output buffers.
+    pub buffer_size: Option<usize>,
+    /// The maximum number of bytes readers buffer.
+    pub memory_limit: Option<usize>,
+    /// The default maximum size of split images.
+    pub split_size: Option<u64>,
+    /// The maximum number of chunks in written sparse images.
+    pub max_chunks: Option<u32>,
+    /// The average number of blocks per chunk written sparse images are
+    /// kept at.
+    pub min_blocks_per_chunk: Option<u32>,
+    /// The number of times failed reads of inputs are retried.
+    pub retries: Option<u32>,
+}
+
+impl Config {
+    /// Loads the config file, if there is one.
+    pub fn load() -> Result<Self> {
+        let path = match config_path() {
+            Some(p) if p.exists() => p,
+            _ => return Ok(Self::default()),
+        };
+
+        info(format_args!("Using config file {}", path.display()));
+        let content = fs::read_to_string(&path)?;
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut config = Self::default();
+
+        settings::parse(content, |key, value| {
+            match key {
+                "crc" => config.crc = value.as_bool()?,
+                "crc_skipped" => {
+                    config.crc_policy = match value.as_bool()? {
+                        true => CrcPolicy::IncludeSkipped,
+                        false => CrcPolicy::ExcludeSkipped,
+                    }
+                }
+                "crc_placement" => {
+                    config.crc_placement = match value.text() {
+                        "chunk" => CrcPlacement::Chunk,
+                        "header" => CrcPlacement::Header,
+                        "both" => CrcPlacement::Both,
+                        _ => return Err(crate::Error::msg("Expected chunk, header or both")),
+                    }
+                }
+                "buffer_size" => config.buffer_size = Some(value.as_size()?.try_into()?),
+                "memory_limit" => config.memory_limit = Some(value.as_size()?.try_into()?),
+                "split_size" => config.split_size = Some(value.as_size()?),
+                "max_chunks" => config.max_chunks = Some(value.as_int()?.try_into()?),
+                "min_blocks_per_chunk" => {
+                    config.min_blocks_per_chunk = Some(value.as_int()?.try_into()?)
+                }
+                "retries" => config.retries = Some(value.as_int()?.try_into()?),
+                key => return Err(crate::Error::msg(format!("Unknown key `{key}`"))),
+            }
+            Ok(())
+        })?;
+
+        Ok(config)
+    }
+
+    /// Returns whether to write or verify checksums, with the `--crc` and
+    /// `--no-crc` switches taking precedence over the `crc` setting.
+    pub fn crc_enabled(&self, crc: bool, no_crc: bool) -> Result<bool> {
+        Ok(crc_flag(crc, no_crc)?.unwrap_or(self.crc))
+    }
+
+    /// Creates a reader from `r`, honoring the configured memory limit and
+    /// checksum policy.
+    pub fn reader<R: Read>(&self, r: R, crc: bool) -> crate::Result<Reader<R>> {
+        let reader = match self.memory_limit {
+            Some(limit) => Reader::with_memory_limit(limit, r, crc)?,
+            None => Reader::new(r, crc)?,
+        };
+        Ok(reader.crc_policy(self.crc_policy))
+    }
+
+    /// Returns the policy for retrying failed reads of inputs, honoring the
+    /// configured number of retries.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let policy = RetryPolicy::default();
+        RetryPolicy {
+            retries: self.retries.unwrap_or(policy.retries),
+            ..policy
+        }
+    }
+
+    /// Creates a writer to `w`, honoring the configured buffer size, chunk
+    /// limits and checksum policy and placement.
+    ///
+    /// The writer warns if the image gets fragmented.
+    pub fn writer<W: Write + Seek>(&self, w: W, crc: bool) -> Result<Writer<W>> {
+        let writer = match self.buffer_size {
+            Some(size) => Writer::with_capacity(size, w, crc)?,
+            None => Writer::new(w, crc)?,
+        };
+        let writer = writer
+            .crc_policy(self.crc_policy)
+            .crc_placement(self.crc_placement)
+            .on_fragmentation(FRAGMENTED_BLOCKS_PER_CHUNK, warn_fragmented);
+        let writer = match self.max_chunks {
+            Some(max) => writer.max_chunks(max),
+            None => writer,
+        };
+        Ok(match self.min_blocks_per_chunk {
+            Some(blocks) => writer.min_blocks_per_chunk(blocks),
+            None => writer,
+        })
+    }
+
+    /// Writes a sparse image with the blocks `f` passes to the writer to
+    /// `output`.
+    ///
+    /// Sparse images can only be written once all of their chunks are
+    /// known, so outputs that can't seek are spooled to a temporary file.
+    pub fn write_sparse<F>(&self, output: &mut Output, crc: bool, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Writer<File>) -> Result<()>,
+    {
+        let mut spool = match output.is_seekable() {
+            true => None,
+            false => Some(tempfile::tempfile()?),
+        };
+        let file = match &spool {
+            Some(spool) => spool.try_clone()?,
+            None => output.as_file().try_clone()?,
+        };
+
+        let mut writer = self.writer(file, crc)?;
+        f(&mut writer)?;
+        writer.close()?;
+
+        if let Some(spool) = spool.as_mut() {
+            spool.rewind()?;
+            copy_with_progress(spool, output, |_| ControlFlow::Continue(()))?;
+        }
+        Ok(())
+    }
+
+    /// Creates a decoder to `w`, honoring the configured buffer size.
+    pub fn decoder<W: Write + Seek>(&self, w: W) -> Result<Decoder<W>> {
+        let decoder = match self.buffer_size {
+            Some(size) => Decoder::with_capacity(size, w)?,
+            None => Decoder::new(w)?,
+        };
+        Ok(decoder)
+    }
+}
+
+/// Warns that a failed read is retried, see `io::Retry::on_retry`.
+pub fn warn_retry(err: &io::Error, attempt: u32) {
+    warn(format_args!(
+        "Reading failed ({err}), retrying (attempt {attempt})"
+    ));
+}
+
+/// Warns that a written image is fragmented, see
+/// `Writer::on_fragmentation`.
+fn warn_fragmented(blocks: u64, chunks: u32) {
+    warn(format_args!(
+        "Image is fragmented ({chunks} chunks for {blocks} blocks) and may flash slowly, see \
+         the `min_blocks_per_chunk` config setting"
+    ));
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("SIMG_CONFIG") {
+        return Some(path.into());
+    }
+
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("simg").join("config"))
+}
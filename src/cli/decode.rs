@@ -0,0 +1,426 @@
+use super::common::{self, Config, Output};
+use crate::{
+    block::Block,
+    io::{self as sparse_io, Retry, ZeroSeek},
+    platform::Capabilities,
+    BlockCountPolicy, Reader,
+};
+use anyhow::{ensure, Result};
+use argh::FromArgs;
+use indicatif::ProgressBar;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, prelude::*},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+};
+
+/// The number of 256 KiB buffers downloads are read ahead into.
+#[cfg(feature = "http")]
+const DOWNLOAD_BUFFERS: usize = 64;
+
+/// Decode a sparse image to a raw image
+#[derive(FromArgs)]
+#[argh(subcommand, name = "decode")]
+pub struct Args {
+    /// verify checksum (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't verify checksum, even if the `crc` config setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// only report errors on stderr, no warnings or progress bars
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// also report details of what is being done on stderr
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    /// write the output even if it is the input image, e.g. a hard link
+    /// to it
+    #[argh(switch)]
+    allow_same_file: bool,
+
+    /// sync the output to storage before exiting, e.g. before copying it
+    /// to a device or unplugging removable media
+    #[argh(switch)]
+    sync: bool,
+
+    /// keep decoding if further sparse images follow the input image in
+    /// the same file or stream, e.g. several images joined with `cat`
+    #[argh(switch)]
+    concatenated: bool,
+
+    /// copy input image to output without decoding
+    #[argh(switch, short = 'p')]
+    passthru: bool,
+
+    /// how to decode images whose chunks don't cover as many blocks as
+    /// their header announces: `error`, `truncate` excess blocks or `pad`
+    /// missing blocks with zeros and truncate excess ones (default: fail
+    /// on files, decode the blocks as stored from streams)
+    #[argh(option, from_str_fn(parse_block_count_policy))]
+    block_count: Option<BlockCountPolicy>,
+
+    /// verify the input image's signature with an Ed25519 key before
+    /// decoding
+    #[argh(option)]
+    verify: Option<String>,
+
+    /// signature file to verify (default: <sparse_image>.sig)
+    #[argh(option)]
+    signature: Option<String>,
+
+    /// decode all given images (wildcards are expanded) into this
+    /// directory, naming the outputs <name>.img
+    #[argh(option, short = 'o')]
+    output_dir: Option<String>,
+
+    /// number of images to decode in parallel with --output-dir
+    /// (default: 1)
+    #[argh(option, short = 'j', default = "1")]
+    jobs: usize,
+
+    /// read the input sparse image from the inherited descriptor N, e.g.
+    /// one handed in by a build sandbox
+    #[argh(option, arg_name = "N")]
+    input_fd: Option<i32>,
+
+    /// write the output raw image to the inherited descriptor N
+    #[argh(option, arg_name = "N")]
+    output_fd: Option<i32>,
+
+    /// input sparse image, or an HTTP(S) URL to decode while downloading,
+    /// and output raw image, leaving out those given as descriptors and
+    /// reading the input from stdin if only the output is given, or any
+    /// number of input images with --output-dir
+    #[argh(positional, arg_name = "image")]
+    images: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    common::init_logging(args.quiet, args.verbose);
+    let config = Config::load()?;
+
+    if let Some(dir) = &args.output_dir {
+        ensure!(
+            args.input_fd.is_none() && args.output_fd.is_none(),
+            "--output-dir cannot be combined with descriptors"
+        );
+        return decode_batch(Path::new(dir), &args, &config);
+    }
+
+    ensure!(
+        args.images.len() <= 2,
+        "Too many images given (use --output-dir to decode several)"
+    );
+    let (sparse_image, raw_image) =
+        common::conversion_images(&args.images, args.input_fd, args.output_fd)?;
+    let raw_image = &raw_image;
+    let sparse_image = match sparse_image {
+        Some(sparse_image) => sparse_image,
+        None => return decode_stream(io::stdin(), raw_image, &args, &config),
+    };
+
+    #[cfg(feature = "http")]
+    if crate::http::is_url(&sparse_image) {
+        return decode_download(&sparse_image, raw_image, &args, &config);
+    }
+
+    let signature = match &args.signature {
+        Some(signature) => PathBuf::from(signature),
+        None => signature_path(Path::new(&sparse_image)),
+    };
+
+    let dir = output_dir(Path::new(raw_image));
+    if Capabilities::probe(dir).is_ok_and(|c| !c.holes) {
+        common::warn(format_args!(
+            "{} doesn't support holes, skipped blocks will take up space",
+            dir.display()
+        ));
+    }
+
+    let bar = common::progress_bar(0);
+    decode(
+        Path::new(&sparse_image),
+        Path::new(raw_image),
+        &signature,
+        &args,
+        &config,
+        &bar,
+    )?;
+    bar.finish();
+    Ok(())
+}
+
+/// Decodes the sparse image at `url` to `dst` as it is downloaded.
+#[cfg(feature = "http")]
+fn decode_download(url: &str, dst: &str, args: &Args, config: &Config) -> Result<()> {
+    common::info(format_args!("Downloading {url}"));
+    let download = crate::http::HttpReader::open(url)?;
+    let download = Retry::resumable(download, config.retry_policy()).on_retry(common::warn_retry);
+    let input = crate::io::ReadAhead::new(download, DOWNLOAD_BUFFERS);
+    decode_stream(input, dst, args, config)
+}
+
+/// Decodes the sparse image streamed from `fi` to `dst`, e.g. from stdin
+/// or a download.
+fn decode_stream<R: Read>(mut fi: R, dst: &str, args: &Args, config: &Config) -> Result<()> {
+    ensure!(
+        args.verify.is_none(),
+        "Signatures can only be verified for file inputs"
+    );
+
+    let mut fo = common::create_output(dst, args.force)?;
+
+    let reader = match config.reader(&mut fi, config.crc_enabled(args.crc, args.no_crc)?) {
+        Ok(reader) => reader,
+        Err(err) => {
+            ensure!(args.passthru, "{err} (use --passthru to write image as-is)");
+
+            // We don't know the size of the stream, so only count the bytes.
+            let bar = common::progress_bar(0);
+            sparse_io::copy_with_progress(&mut fi, &mut fo, |copied| {
+                bar.set_position(copied);
+                ControlFlow::Continue(())
+            })?;
+            bar.finish();
+            sync_output(&fo, args.sync)?;
+            fo.commit()?;
+
+            return Ok(());
+        }
+    };
+
+    let bar = common::progress_bar(0);
+    let reader = with_block_count_policy(reader, args).concatenated(args.concatenated);
+    write_raw(reader, &fo, None, args.sync, config, &bar)?;
+    bar.finish();
+    Ok(fo.commit()?)
+}
+
+fn decode_batch(dst_dir: &Path, args: &Args, config: &Config) -> Result<()> {
+    ensure!(
+        args.signature.is_none(),
+        "--signature cannot be used with --output-dir"
+    );
+
+    let mut images = Vec::new();
+    ensure!(!args.images.is_empty(), "No input images given");
+    for pattern in &args.images {
+        images.extend(common::expand_glob(pattern)?);
+    }
+
+    let mut jobs = Vec::new();
+    let mut outputs = HashSet::new();
+    for image in &images {
+        let mut name = image.file_stem().unwrap_or_default().to_owned();
+        name.push(".img");
+        let dst = dst_dir.join(name);
+        ensure!(
+            outputs.insert(dst.clone()),
+            "Several images decode to {}",
+            dst.display()
+        );
+        jobs.push((image, dst));
+    }
+
+    fs::create_dir_all(dst_dir)?;
+
+    let bar = common::progress_bar(0);
+    let results = common::parallel(&jobs, args.jobs, |(src, dst)| {
+        decode(src, dst, &signature_path(src), args, config, &bar)
+    });
+    bar.finish();
+
+    common::print_summary(images.iter().map(PathBuf::as_path).zip(&results))
+}
+
+/// Decodes `src` to `dst`, returning the sizes of both images.
+///
+/// The raw size of the image is added to the length of `bar`.
+fn decode(
+    src: &Path,
+    dst: &Path,
+    signature: &Path,
+    args: &Args,
+    config: &Config,
+    bar: &ProgressBar,
+) -> Result<(u64, u64)> {
+    if let Some(key) = &args.verify {
+        verify(src, key, signature)?;
+    }
+
+    common::info(format_args!(
+        "Decoding {} to {}",
+        src.display(),
+        dst.display()
+    ));
+    let mut fi = common::open_input(src)?;
+    if !args.allow_same_file {
+        common::check_distinct(&fi, dst)?;
+    }
+    let metadata = fi.metadata()?;
+    let mut fo = common::create_output(dst, args.force)?;
+
+    // Raw blocks can only be cloned from files, not from pipes, and only if
+    // the filesystem supports it. If probing fails, cloning is attempted
+    // anyway.
+    let reflink = Capabilities::probe(output_dir(dst)).map_or(true, |c| c.reflink);
+    if !reflink {
+        common::info("Reflinks not supported by the output filesystem, copying raw data");
+    }
+    let reflink_src = match metadata.is_file() && reflink {
+        true => Some(fi.try_clone()?),
+        false => None,
+    };
+
+    // Files on network filesystems may fail to read now and then.
+    let input = Retry::new(&mut fi, config.retry_policy()).on_retry(common::warn_retry);
+    let reader = match config.reader(input, config.crc_enabled(args.crc, args.no_crc)?) {
+        Ok(reader) => reader,
+        Err(err) => {
+            ensure!(args.passthru, "{err} (use --passthru to write image as-is)");
+            ensure!(metadata.is_file(), "Only files can be passed through");
+
+            fi.rewind()?;
+            bar.inc_length(metadata.len());
+            let start = bar.position();
+            sparse_io::copy_with_progress(&mut fi, &mut fo, |copied| {
+                bar.set_position(start + copied);
+                ControlFlow::Continue(())
+            })?;
+            sync_output(&fo, args.sync)?;
+            fo.commit()?;
+
+            return Ok((metadata.len(), metadata.len()));
+        }
+    };
+
+    let mut reader = with_block_count_policy(reader, args);
+    // Catch corrupt images before writing anything.
+    if metadata.is_file() {
+        let scan = reader.prescan()?;
+        common::info(format_args!(
+            "{} chunks: {} raw, {} fill and {} don't-care blocks",
+            scan.chunks.len(),
+            scan.raw_blocks,
+            scan.fill_blocks,
+            scan.skip_blocks
+        ));
+    }
+    let reader = reader.concatenated(args.concatenated);
+    write_raw(reader, &fo, reflink_src.as_ref(), args.sync, config, bar)?;
+    let size = fo.as_file().metadata()?.len();
+    fo.commit()?;
+    Ok((metadata.len(), size))
+}
+
+/// Decodes the image in `reader` to `fo`, cloning raw blocks from
+/// `reflink_src` where possible, and syncs it to storage if `sync` is set.
+///
+/// The raw size of the image is added to the length of `bar`.
+fn write_raw<R: Read>(
+    mut reader: crate::Reader<R>,
+    fo: &Output,
+    reflink_src: Option<&File>,
+    sync: bool,
+    config: &Config,
+    bar: &ProgressBar,
+) -> Result<()> {
+    bar.inc_length(reader.size);
+    let mut size = reader.size;
+    // The size grows whenever a concatenated image starts.
+    let mut inc = |reader: &crate::Reader<R>| {
+        bar.inc_length(reader.size - size);
+        size = reader.size;
+        bar.inc(Block::SIZE.into());
+    };
+    let file = fo.as_file().try_clone()?;
+
+    // Pipes and sockets can't skip over holes, so zeros are written instead.
+    if !fo.is_seekable() {
+        let mut decoder = config.decoder(ZeroSeek::new(file))?;
+        while let Some(block) = reader.next() {
+            decoder.write_block(&block?)?;
+            inc(&reader);
+        }
+        decoder.close()?;
+        return Ok(());
+    }
+
+    let mut decoder = config.decoder(file)?.sync_on_close(sync);
+    if let Some(src) = reflink_src {
+        decoder.reflink_from(src)?;
+    }
+
+    while let Some(block) = reader.next() {
+        let block = block?;
+        let offset = reader.offset().saturating_sub(Block::SIZE.into());
+        decoder.write_block_from(&block, offset)?;
+        inc(&reader);
+    }
+    decoder.close()?;
+    Ok(())
+}
+
+/// Syncs the data written to `fo` to storage if `sync` is set. Pipes and
+/// sockets have nothing to sync.
+fn sync_output(fo: &Output, sync: bool) -> Result<()> {
+    if sync && fo.is_seekable() {
+        fo.as_file().sync_data()?;
+    }
+    Ok(())
+}
+
+/// Returns the directory the raw image `dst` is written to.
+fn output_dir(dst: &Path) -> &Path {
+    match dst.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn signature_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".sig");
+    path.into()
+}
+
+#[cfg(feature = "sign")]
+fn verify(image: &Path, key: &str, signature: &Path) -> Result<()> {
+    let key = crate::sign::read_verifying_key(key)?;
+    let signature = crate::sign::read_signature(signature)?;
+    let reader = crate::Reader::new(File::open(image)?, false)?;
+    Ok(crate::sign::verify(reader, &key, &signature)?)
+}
+
+#[cfg(not(feature = "sign"))]
+fn verify(_image: &Path, _key: &str, _signature: &Path) -> Result<()> {
+    anyhow::bail!("Signatures are not supported by this build (enable the `sign` feature)")
+}
+
+/// Applies the `--block-count` policy, if any, to `reader`.
+fn with_block_count_policy<R: Read>(reader: Reader<R>, args: &Args) -> Reader<R> {
+    match args.block_count {
+        Some(policy) => reader.block_count_policy(policy),
+        None => reader,
+    }
+}
+
+fn parse_block_count_policy(value: &str) -> std::result::Result<BlockCountPolicy, String> {
+    match value {
+        "error" => Ok(BlockCountPolicy::Error),
+        "truncate" => Ok(BlockCountPolicy::Truncate),
+        "pad" => Ok(BlockCountPolicy::PadWithZeros),
+        _ => Err(format!("invalid block count policy: {value}")),
+    }
+}
@@ -0,0 +1,318 @@
+use super::common;
+use crate::{
+    block::Block,
+    dump::{self, Chunks, Hexdump},
+    entropy::EntropyReport,
+    human::HumanSize,
+    read::Reader,
+    space::SpaceReport,
+};
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use std::{
+    io::{BufReader, Read},
+    process,
+};
+
+/// Print the header and chunk layout of a sparse image
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dump")]
+pub struct Args {
+    /// check the chunk headers for inconsistent sizes instead of printing
+    /// them, exiting with status 1 if there are any problems
+    #[argh(switch)]
+    lint: bool,
+
+    /// report the space the decoded image takes up with and without holes
+    /// and the partition size it needs, instead of printing the chunks
+    #[argh(switch)]
+    space: bool,
+
+    /// report the entropy of every data chunk and the estimated size of
+    /// the data after compression, flagging chunks that look compressed
+    /// already, instead of printing the chunks
+    #[argh(switch)]
+    entropy: bool,
+
+    /// print the structural differences to this other sparse image, i.e.
+    /// its header fields and where the chunk layouts diverge, exiting with
+    /// status 1 if the images differ
+    #[argh(option)]
+    compare: Option<String>,
+
+    /// print the chunks as a table (default), or as tab- or
+    /// comma-separated values for spreadsheets: table, tsv or csv
+    #[argh(option, default = "Format::Table", from_str_fn(parse_format))]
+    format: Format,
+
+    /// print a hexdump of the decoded contents of the block with this
+    /// index, instead of printing the chunks
+    #[argh(option)]
+    dump_block: Option<u64>,
+
+    /// print a hexdump of the decoded contents of the chunk with this
+    /// index, instead of printing the chunks
+    #[argh(option)]
+    dump_chunk: Option<usize>,
+
+    /// write the file and chunk headers of the image to this file, as a
+    /// layout sample for `tests/corpus`, instead of printing them
+    #[argh(option)]
+    capture: Option<String>,
+
+    /// capture at most this many chunk headers with --capture
+    #[argh(option)]
+    max_chunks: Option<u32>,
+
+    /// write a shell script decoding the image with dd to this file, for
+    /// devices without a sparse image decoder, instead of printing the
+    /// chunks
+    #[argh(option)]
+    dd_script: Option<String>,
+
+    /// overwrite the output of --capture or --dd-script
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// sparse image
+    #[argh(positional)]
+    image: String,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    if let Some(sample) = &args.capture {
+        let mut output = common::create_output(sample, args.force)?;
+        let input = BufReader::new(common::open_input(&args.image)?);
+        crate::corpus::capture(input, &mut output, args.max_chunks)?;
+        return Ok(output.commit()?);
+    }
+
+    if let Some(script) = &args.dd_script {
+        let mut output = common::create_output(script, args.force)?;
+        let input = BufReader::new(common::open_input(&args.image)?);
+        crate::script::dd_script(input, &mut output)?;
+        return Ok(output.commit()?);
+    }
+
+    if args.dump_block.is_some() || args.dump_chunk.is_some() {
+        return hexdump(&args);
+    }
+
+    if args.space {
+        let input = BufReader::new(common::open_input(&args.image)?);
+        let report = SpaceReport::from_image(input)?;
+        println!("{report}");
+        println!(
+            "partition size: {} (aligned to 1 MiB)",
+            HumanSize(report.partition_size(1 << 20))
+        );
+        return Ok(());
+    }
+
+    if args.entropy {
+        let input = BufReader::new(common::open_input(&args.image)?);
+        return print_entropy(EntropyReport::from_image(input)?);
+    }
+
+    if let Some(other) = &args.compare {
+        let old = BufReader::new(common::open_input(&args.image)?);
+        let new = BufReader::new(common::open_input(other)?);
+        let comparison = dump::compare(old, new)?;
+        println!("{comparison}");
+        if !comparison.is_identical() {
+            // Like diff(1), signal differences with exit status 1.
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let chunks = Chunks::new(BufReader::new(common::open_input(&args.image)?))?;
+    if args.lint {
+        return lint(chunks, &args.image);
+    }
+
+    if let Some(separator) = args.format.separator() {
+        return print_values(chunks, separator);
+    }
+
+    let header = chunks.header();
+    let raw_size = u64::from(header.total_blocks) * u64::from(Block::SIZE);
+    println!("total blocks:   {} ({})", header.total_blocks, HumanSize(raw_size));
+    println!("total chunks:   {}", header.total_chunks);
+    println!("image checksum: {:#010x}", header.image_checksum);
+    println!();
+    println!("{:>7}  {:>12}  {:<8}  {:>8}  {:>12}", "chunk", "offset", "type", "blocks", "raw offset");
+
+    for (index, chunk) in chunks.enumerate() {
+        let chunk = chunk?;
+        println!(
+            "{:>7}  {:>#12x}  {:<8}  {:>8}  {:>#12x}",
+            index,
+            chunk.offset,
+            chunk.header.chunk_type,
+            chunk.header.chunk_size,
+            chunk.raw_offset(),
+        );
+    }
+
+    Ok(())
+}
+
+/// How the chunks are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Table,
+    Tsv,
+    Csv,
+}
+
+impl Format {
+    /// Returns the separator of the values of a row, if the chunks are
+    /// printed as separated values.
+    fn separator(self) -> Option<char> {
+        match self {
+            Format::Table => None,
+            Format::Tsv => Some('\t'),
+            Format::Csv => Some(','),
+        }
+    }
+}
+
+fn parse_format(value: &str) -> std::result::Result<Format, String> {
+    match value {
+        "table" => Ok(Format::Table),
+        "tsv" => Ok(Format::Tsv),
+        "csv" => Ok(Format::Csv),
+        _ => Err(format!("invalid format: {value}")),
+    }
+}
+
+/// Prints a row per chunk with its values separated by `separator`, after
+/// a row of column names. Numbers are decimal, for spreadsheets to parse.
+fn print_values<R: Read>(chunks: Chunks<R>, separator: char) -> Result<()> {
+    let columns = ["chunk", "offset", "type", "blocks", "raw_offset", "size"];
+    println!("{}", columns.join(&separator.to_string()));
+    for (index, chunk) in chunks.enumerate() {
+        let chunk = chunk?;
+        let values = [
+            index.to_string(),
+            chunk.offset.to_string(),
+            chunk.header.chunk_type.to_string(),
+            chunk.header.chunk_size.to_string(),
+            chunk.raw_offset().to_string(),
+            chunk.header.total_size.to_string(),
+        ];
+        println!("{}", values.join(&separator.to_string()));
+    }
+    Ok(())
+}
+
+/// Prints a hexdump of the block or chunk selected by `args`, reading only
+/// its data.
+fn print_entropy(report: EntropyReport) -> Result<()> {
+    println!(
+        "{:>7}  {:>12}  {:<8}  {:>8}  {:>7}  {:>10}",
+        "chunk", "raw offset", "type", "blocks", "entropy", "estimated"
+    );
+    for c in &report.chunks {
+        let flag = if c.is_compressed() {
+            "  compressed"
+        } else {
+            ""
+        };
+        println!(
+            "{:>7}  {:>#12x}  {:<8}  {:>8}  {:>7.2}  {:>10}{}",
+            c.index,
+            c.chunk.raw_offset(),
+            c.chunk.header.chunk_type,
+            c.chunk.header.chunk_size,
+            c.entropy,
+            HumanSize(c.estimated_size()),
+            flag
+        );
+    }
+    println!();
+    println!("{report}");
+    Ok(())
+}
+
+fn hexdump(args: &Args) -> Result<()> {
+    let mut input = BufReader::new(common::open_input(&args.image)?);
+    let chunks = Reader::new(&mut input, false)?.prescan()?.chunks;
+
+    let (chunk, blocks) = match (args.dump_block, args.dump_chunk) {
+        (Some(block), None) => {
+            let index = chunks.partition_point(|c| c.blocks().end <= block);
+            let Some(chunk) = chunks.get(index).filter(|c| c.blocks().contains(&block)) else {
+                bail!("Block {block} is beyond the end of the image");
+            };
+            (chunk, block..block + 1)
+        }
+        (None, Some(index)) => {
+            let Some(chunk) = chunks.get(index) else {
+                bail!(
+                    "Chunk {index} is beyond the end of the image, which has {} chunks",
+                    chunks.len()
+                );
+            };
+            (chunk, chunk.blocks())
+        }
+        _ => bail!("--dump-block and --dump-chunk cannot be combined"),
+    };
+
+    let data = chunk.decode(&mut input, blocks.clone())?;
+    let offset = blocks.start * u64::from(Block::SIZE);
+    let dump = Hexdump {
+        data: &data,
+        offset,
+    };
+    println!("{dump}");
+    Ok(())
+}
+
+/// Prints every inconsistency in the chunk headers with its file offset.
+fn lint<R: Read>(mut chunks: Chunks<R>, image: &str) -> Result<()> {
+    let total_blocks = u64::from(chunks.header().total_blocks);
+    let mut blocks = 0;
+    let mut problems = 0;
+
+    let mut index = 0;
+    while let Some(chunk) = chunks.next() {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                println!("chunk {index} at {:#x}: {err:#}", chunks.offset());
+                problems += 1;
+                break;
+            }
+        };
+
+        for issue in chunk.issues() {
+            println!("chunk {index} at {:#x}: {issue}", chunk.offset);
+            problems += 1;
+        }
+
+        let end = chunk.start_block + u64::from(chunk.header.chunk_size);
+        if blocks < total_blocks && end > total_blocks {
+            println!(
+                "chunk {index} at {:#x}: covers blocks {}..{end}, beyond the {total_blocks} blocks of the image",
+                chunk.offset, chunk.start_block
+            );
+            problems += 1;
+        }
+        blocks = end;
+        index += 1;
+    }
+
+    if blocks != total_blocks {
+        println!("file header at 0x0: announces {total_blocks} blocks, but chunks cover {blocks}");
+        problems += 1;
+    }
+
+    if problems > 0 {
+        // Like diff(1), signal problems with exit status 1.
+        process::exit(1);
+    }
+    println!("{image}: no problems found");
+    Ok(())
+}
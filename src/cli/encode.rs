@@ -0,0 +1,402 @@
+use super::common::{self, Config, Output};
+use crate::{
+    block::Block,
+    compress::Compression,
+    extents::{ExtentMap, ExtentSource},
+    io::AtomicFile,
+    sidecar, BlockSource, EncoderOptions,
+};
+use anyhow::{ensure, Context, Result};
+use argh::FromArgs;
+use indicatif::ProgressBar;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Encode a raw image to a sparse image
+#[derive(FromArgs)]
+#[argh(subcommand, name = "encode")]
+pub struct Args {
+    /// add checksum to output image (default: `crc` config setting)
+    #[argh(switch, short = 'c')]
+    crc: bool,
+
+    /// don't add checksum to output image, even if the `crc` config
+    /// setting is on
+    #[argh(switch)]
+    no_crc: bool,
+
+    /// overwrite output image
+    #[argh(switch, short = 'f')]
+    force: bool,
+
+    /// only report errors on stderr, no warnings or progress bars
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// also report details of what is being done on stderr
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    /// write the output even if it is the input image, e.g. a hard link
+    /// to it
+    #[argh(switch)]
+    allow_same_file: bool,
+
+    /// sign the decoded image with an Ed25519 key, writing the signature
+    /// to <sparse_image>.sig
+    #[argh(option, short = 's')]
+    sign: Option<String>,
+
+    /// write the checksum of every chunk to <sparse_image>.crcs, for
+    /// spot-checking the image with `simg verify --chunk-crcs`
+    #[argh(switch)]
+    chunk_crcs: bool,
+
+    /// encode blocks filled with this 32-bit value (in hex, e.g.
+    /// 0xffffffff for erased flash) as don't-care, may be repeated
+    #[argh(option, from_str_fn(parse_fill_value))]
+    dontcare_fill: Vec<[u8; 4]>,
+
+    /// store gaps of fewer than this many blocks between raw data as raw
+    /// data too, reducing the number of chunks
+    #[argh(option, default = "0")]
+    min_chunk_blocks: u32,
+
+    /// limit the output image to this many chunks, storing the rest of
+    /// the image as raw data once it is reached (default: `max_chunks`
+    /// config setting)
+    #[argh(option)]
+    max_chunks: Option<u32>,
+
+    /// encode only the blocks mapped in this block map (as written by
+    /// `bmaptool create`) as raw data and the rest as don't-care, instead
+    /// of scanning the input image
+    #[argh(option)]
+    bmap: Option<String>,
+
+    /// encode the regions the filesystem allocated for the input image as
+    /// raw data and its holes as don't-care, instead of scanning the input
+    /// image (Linux only)
+    #[argh(switch)]
+    fiemap: bool,
+
+    /// number of images to encode in parallel in directory mode
+    /// (default: 1)
+    #[argh(option, short = 'j', default = "1")]
+    jobs: usize,
+
+    /// also encode images in subdirectories in directory mode
+    #[argh(switch, short = 'r')]
+    recursive: bool,
+
+    /// keep running in directory mode, re-encoding images whenever they
+    /// change
+    #[argh(switch, short = 'w')]
+    watch: bool,
+
+    /// seconds between checks for changed images with --watch
+    /// (default: 1)
+    #[argh(option, default = "1")]
+    interval: u64,
+
+    /// read the input raw image from the inherited descriptor N, e.g. one
+    /// handed in by a build sandbox
+    #[argh(option, arg_name = "N")]
+    input_fd: Option<i32>,
+
+    /// write the output sparse image to the inherited descriptor N
+    #[argh(option, arg_name = "N")]
+    output_fd: Option<i32>,
+
+    /// input raw image, which may be compressed (*.gz, *.xz or *.zst), or
+    /// a directory whose *.img files to encode, and output sparse image,
+    /// or the directory to write *.simg files to, leaving out those given
+    /// as descriptors
+    #[argh(positional, arg_name = "image")]
+    images: Vec<String>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    common::init_logging(args.quiet, args.verbose);
+    let mut config = Config::load()?;
+    if args.max_chunks.is_some() {
+        config.max_chunks = args.max_chunks;
+    }
+
+    let (raw_image, sparse_image) =
+        common::conversion_images(&args.images, args.input_fd, args.output_fd)?;
+    let raw_image = raw_image.context("No input image given")?;
+    let (src, dst) = (Path::new(&raw_image), Path::new(&sparse_image));
+    let is_dir = src.is_dir();
+    ensure!(!args.watch || is_dir, "--watch requires an input directory");
+    ensure!(
+        args.bmap.is_none() || !is_dir,
+        "--bmap requires a single input image"
+    );
+    ensure!(
+        args.bmap.is_none() || !args.fiemap,
+        "--bmap and --fiemap cannot be combined"
+    );
+
+    if args.watch {
+        return watch_dir(src, dst, &args, &config);
+    } else if is_dir {
+        return encode_dir(src, dst, &args, &config);
+    }
+
+    let bar = common::progress_bar(0);
+    encode(src, dst, &args, &config, &bar)?;
+    bar.finish();
+    Ok(())
+}
+
+fn encode_dir(src_dir: &Path, dst_dir: &Path, args: &Args, config: &Config) -> Result<()> {
+    let mut images = Vec::new();
+    find_images(src_dir, Path::new(""), args.recursive, &mut images)?;
+    images.sort();
+
+    let bar = common::progress_bar(0);
+    let results = common::parallel(&images, args.jobs, |image| {
+        let dst = dst_dir.join(image).with_extension("simg");
+        fs::create_dir_all(dst.parent().unwrap_or(dst_dir))?;
+        encode(src_dir.join(image), dst, args, config, &bar)
+    });
+    bar.finish();
+
+    common::print_summary(images.iter().map(PathBuf::as_path).zip(&results))
+}
+
+fn watch_dir(src_dir: &Path, dst_dir: &Path, args: &Args, config: &Config) -> Result<()> {
+    fs::create_dir_all(dst_dir)?;
+
+    // The modification time and size of every image when it was last
+    // seen, and when it was last encoded.
+    let mut seen: HashMap<PathBuf, (SystemTime, u64)> = HashMap::new();
+    let mut encoded = HashMap::new();
+
+    println!("Watching {} for changes", src_dir.display());
+    loop {
+        let mut images = Vec::new();
+        find_images(src_dir, Path::new(""), args.recursive, &mut images)?;
+        images.sort();
+
+        for image in images {
+            let src = src_dir.join(&image);
+            let stamp = match fs::metadata(&src).and_then(|m| Ok((m.modified()?, m.len()))) {
+                Ok(stamp) => stamp,
+                Err(_) => continue,
+            };
+
+            // Only pick up images that didn't change since the last check,
+            // so we don't encode them while they are still being written.
+            let stable = seen.insert(image.clone(), stamp) == Some(stamp);
+            if !stable || encoded.get(&image) == Some(&stamp) {
+                continue;
+            }
+
+            let dst = dst_dir.join(&image).with_extension("simg");
+            match encode_atomic(&src, &dst, args, config) {
+                Ok(()) => println!("Encoded {}", image.display()),
+                Err(err) => common::error(format_args!("{}: {err:#}", image.display())),
+            }
+            encoded.insert(image, stamp);
+        }
+
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
+
+/// Encodes `src` to `dst` via a temporary file, so `dst` is replaced only
+/// once the new image is complete.
+fn encode_atomic(src: &Path, dst: &Path, args: &Args, config: &Config) -> Result<()> {
+    fs::create_dir_all(dst.parent().unwrap_or(Path::new(".")))?;
+
+    let mut fo = Output::Atomic(AtomicFile::create(dst)?);
+    encode_into(src, &mut fo, args, config, &ProgressBar::hidden())?;
+    fo.commit()?;
+
+    if let Some(key) = &args.sign {
+        sign(dst, key)?;
+    }
+    Ok(())
+}
+
+/// Collects the paths of all raw images in `dir`, relative to the
+/// directory the search started at.
+fn find_images(
+    dir: &Path,
+    prefix: &Path,
+    recursive: bool,
+    images: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() && recursive {
+            find_images(&entry.path(), &path, recursive, images)?;
+        } else if file_type.is_file() && path.extension().is_some_and(|e| e == "img") {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Encodes `src` to `dst`, returning the sizes of both images.
+fn encode<P, Q>(
+    src: P,
+    dst: Q,
+    args: &Args,
+    config: &Config,
+    bar: &ProgressBar,
+) -> Result<(u64, u64)>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if !args.allow_same_file {
+        common::check_distinct(&common::open_input(&src)?, &dst)?;
+    }
+    let mut fo = common::create_output(&dst, args.force)?;
+    ensure!(
+        args.sign.is_none() || matches!(fo, Output::Atomic(_)),
+        "Signing requires the output image to be a file"
+    );
+    ensure!(
+        !args.chunk_crcs || matches!(fo, Output::Atomic(_)),
+        "--chunk-crcs requires the output image to be a file"
+    );
+
+    let size = encode_into(src.as_ref(), &mut fo, args, config, bar)?;
+    let sparse_size = fo.as_file().metadata()?.len();
+    fo.commit()?;
+
+    if args.chunk_crcs {
+        write_chunk_crcs(dst.as_ref())?;
+    }
+
+    if let Some(key) = &args.sign {
+        sign(dst.as_ref(), key)?;
+    }
+
+    Ok((size, sparse_size))
+}
+
+/// Encodes `src` to `dst`, returning the size of the raw image.
+///
+/// Compressed inputs are decompressed on the fly. Their raw size is not
+/// known in advance, so the progress bar only counts the encoded bytes.
+fn encode_into(
+    src: &Path,
+    dst: &mut Output,
+    args: &Args,
+    config: &Config,
+    bar: &ProgressBar,
+) -> Result<u64> {
+    common::info(format_args!("Encoding {}", src.display()));
+    let fi = common::open_input(src)?;
+    let compression = Compression::from_path(src);
+    if let Some(compression) = compression {
+        common::info(format_args!("Decompressing {compression} input"));
+    }
+    let size = match compression {
+        Some(_) => None,
+        None => Some(fi.metadata()?.len()),
+    };
+    bar.inc_length(size.unwrap_or(0));
+
+    let mut blocks: Box<dyn BlockSource> = match (&args.bmap, compression) {
+        (Some(_), Some(compression)) => {
+            anyhow::bail!("--bmap requires an uncompressed input image, not {compression}")
+        }
+        (None, Some(compression)) if args.fiemap => {
+            anyhow::bail!("--fiemap requires an uncompressed input image, not {compression}")
+        }
+        (Some(bmap), None) => bmap_source(fi, Path::new(bmap))?,
+        (None, None) if args.fiemap => {
+            let map = ExtentMap::from_file(&fi)?;
+            Box::new(ExtentSource::new(fi, map))
+        }
+        (None, _) => {
+            let input: Box<dyn Read> = match compression {
+                Some(compression) => compression.decoder(BufReader::new(fi))?,
+                None => Box::new(fi),
+            };
+            let options = EncoderOptions::new()
+                .dontcare_fill_values(&args.dontcare_fill)
+                .min_chunk_blocks(args.min_chunk_blocks);
+            Box::new(crate::Encoder::with_options(input, options)?)
+        }
+    };
+    let mut encoded = 0;
+    config.write_sparse(dst, config.crc_enabled(args.crc, args.no_crc)?, |writer| {
+        while let Some(block) = blocks.read_block()? {
+            writer.write_block(&block)?;
+            bar.inc(Block::SIZE.into());
+            encoded += u64::from(Block::SIZE);
+        }
+        Ok(())
+    })?;
+
+    Ok(size.unwrap_or(encoded))
+}
+
+/// Writes the chunk checksums of the sparse image `image` next to it.
+fn write_chunk_crcs(image: &Path) -> Result<()> {
+    let crcs = sidecar::from_image(File::open(image)?)?;
+    let mut path = image.as_os_str().to_owned();
+    path.push(".crcs");
+    let mut file = AtomicFile::create(PathBuf::from(path))?;
+    sidecar::write(&mut file, &crcs)?;
+    file.commit()?;
+    Ok(())
+}
+
+fn parse_fill_value(value: &str) -> std::result::Result<[u8; 4], String> {
+    let digits = value.trim_start_matches("0x");
+    u32::from_str_radix(digits, 16)
+        .map(u32::to_le_bytes)
+        .map_err(|_| format!("invalid fill value: {value}"))
+}
+
+#[cfg(feature = "sign")]
+fn sign(image: &Path, key: &str) -> Result<()> {
+    let key = crate::sign::read_signing_key(key)?;
+    let reader = crate::Reader::new(fs::File::open(image)?, false)?;
+    let signature = crate::sign::sign(reader, &key)?;
+
+    let mut path = image.as_os_str().to_owned();
+    path.push(".sig");
+    Ok(crate::sign::write_signature(path, &signature)?)
+}
+
+#[cfg(not(feature = "sign"))]
+fn sign(_image: &Path, _key: &str) -> Result<()> {
+    anyhow::bail!("Signing is not supported by this build (enable the `sign` feature)")
+}
+
+#[cfg(feature = "bmap")]
+fn bmap_source(image: File, bmap: &Path) -> Result<Box<dyn BlockSource>> {
+    use anyhow::Context;
+
+    let xml = fs::read_to_string(bmap)?;
+    let bmap = crate::bmap::Bmap::parse(&xml)
+        .with_context(|| format!("Invalid block map {}", bmap.display()))?;
+    ensure!(
+        image.metadata()?.len() == bmap.image_size,
+        "The size of the input image doesn't match its block map"
+    );
+    Ok(Box::new(crate::bmap::BmapSource::new(image, bmap)))
+}
+
+#[cfg(not(feature = "bmap"))]
+fn bmap_source(_image: File, _bmap: &Path) -> Result<Box<dyn BlockSource>> {
+    anyhow::bail!("Block maps are not supported by this build (enable the `bmap` feature)")
+}
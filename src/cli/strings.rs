@@ -0,0 +1,85 @@
+//! Searching the raw data of sparse images for strings.
+
+use super::common;
+use crate::strings;
+use anyhow::Result;
+use argh::FromArgs;
+#[cfg(feature = "grep")]
+use regex::RegexBuilder;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+
+/// Print the printable strings in the raw chunks of a sparse image with
+/// their raw offsets, skipping holes and fills
+#[derive(FromArgs)]
+#[argh(subcommand, name = "strings")]
+pub struct StringsArgs {
+    /// minimum length of the printed strings (default: 4)
+    #[argh(option, short = 'n', default = "strings::DEFAULT_MIN_LEN")]
+    min_len: usize,
+
+    /// sparse image
+    #[argh(positional)]
+    image: String,
+}
+
+/// Print the strings in the raw chunks of a sparse image that match a
+/// regular expression, like `strings | grep`, with their raw offsets
+#[cfg(feature = "grep")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "grep")]
+pub struct GrepArgs {
+    /// ignore case when matching
+    #[argh(switch, short = 'i')]
+    ignore_case: bool,
+
+    /// print only the matches with their offsets, not the whole strings
+    #[argh(switch, short = 'o')]
+    only_matching: bool,
+
+    /// regular expression to search for
+    #[argh(positional)]
+    pattern: String,
+
+    /// sparse image
+    #[argh(positional)]
+    image: String,
+}
+
+pub fn run_strings(args: StringsArgs) -> Result<()> {
+    let input = BufReader::new(common::open_input(&args.image)?);
+    let mut out = BufWriter::new(io::stdout().lock());
+    strings::scan(input, args.min_len, |offset, text| {
+        Ok(writeln!(out, "{offset:#x} {text}")?)
+    })?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "grep")]
+pub fn run_grep(args: GrepArgs) -> Result<()> {
+    let regex = RegexBuilder::new(&args.pattern)
+        .case_insensitive(args.ignore_case)
+        .build()?;
+    let input = BufReader::new(common::open_input(&args.image)?);
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut found = false;
+    strings::scan(input, 1, |offset, text| {
+        if args.only_matching {
+            for m in regex.find_iter(text) {
+                writeln!(out, "{:#x} {}", offset + m.start() as u64, m.as_str())?;
+                found = true;
+            }
+        } else if regex.is_match(text) {
+            writeln!(out, "{offset:#x} {text}")?;
+            found = true;
+        }
+        Ok(())
+    })?;
+    out.flush()?;
+
+    // Like grep(1), signal that nothing matched with exit status 1.
+    if !found {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Decompression of compressed raw images.
+//!
+//! Factory images often ship raw images compressed with gzip, xz or zstd.
+//! Rather than decompressing them to a huge intermediate file first, wrap
+//! the compressed input in `Compression::decoder` and stream it right into
+//! an `Encoder`.
+//!
+//! Each decompressor is behind a feature of the same name (`gzip`, `xz` and
+//! `zstd`). Without it, creating a decoder for that format fails.
+
+#[cfg(not(all(feature = "gzip", feature = "xz", feature = "zstd")))]
+use crate::result::bail;
+use crate::result::Result;
+use std::{fmt, io::prelude::*, path::Path};
+
+/// A compression format of raw images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, `.gz` files.
+    Gzip,
+    /// xz, `.xz` files.
+    Xz,
+    /// Zstandard, `.zst` files.
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression format of the file at `path`, judging by
+    /// its extension, or `None` if it is not compressed.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?;
+        match extension.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "xz" => Some(Compression::Xz),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Creates a reader that decompresses `r`.
+    ///
+    /// Concatenated gzip members and xz streams are decompressed as one
+    /// image, like `zcat` and `xzcat` do. Of zstd inputs, only the first
+    /// frame is decompressed.
+    pub fn decoder<'a, R: BufRead + 'a>(self, r: R) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::Gzip => gzip_decoder(r),
+            Compression::Xz => xz_decoder(r),
+            Compression::Zstd => zstd_decoder(r),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_decoder<'a, R: BufRead + 'a>(r: R) -> Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(flate2::bufread::MultiGzDecoder::new(r)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_decoder<'a, R: BufRead + 'a>(_r: R) -> Result<Box<dyn Read + 'a>> {
+    bail!("gzip input is not supported by this build (enable the `gzip` feature)")
+}
+
+#[cfg(feature = "xz")]
+fn xz_decoder<'a, R: BufRead + 'a>(r: R) -> Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(lzma_rust2::XzReader::new(r, true)))
+}
+
+#[cfg(not(feature = "xz"))]
+fn xz_decoder<'a, R: BufRead + 'a>(_r: R) -> Result<Box<dyn Read + 'a>> {
+    bail!("xz input is not supported by this build (enable the `xz` feature)")
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decoder<'a, R: BufRead + 'a>(r: R) -> Result<Box<dyn Read + 'a>> {
+    use crate::result::{Context, Error};
+
+    let decoder = ruzstd::decoding::StreamingDecoder::new(r)
+        .map_err(Error::new)
+        .context("Invalid zstd input")?;
+    Ok(Box::new(decoder))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decoder<'a, R: BufRead + 'a>(_r: R) -> Result<Box<dyn Read + 'a>> {
+    bail!("zstd input is not supported by this build (enable the `zstd` feature)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_path() {
+        assert_eq!(
+            Compression::from_path("system.img.gz"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(Compression::from_path("vendor.xz"), Some(Compression::Xz));
+        assert_eq!(
+            Compression::from_path("boot.img.zst"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_path("system.img"), None);
+        assert_eq!(Compression::from_path("gz"), None);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip() {
+        use flate2::{write::GzEncoder, Compression as Level};
+
+        let data = vec![0xa5; 10000];
+        let mut compressed = Vec::new();
+        for chunk in data.chunks(6000) {
+            let mut encoder = GzEncoder::new(&mut compressed, Level::default());
+            encoder.write_all(chunk).unwrap();
+            encoder.finish().unwrap();
+        }
+
+        let mut decompressed = Vec::new();
+        Compression::Gzip
+            .decoder(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
@@ -0,0 +1,259 @@
+//! Building blocks shared by the virtual disk container formats.
+//!
+//! Containers like qcow2, VHD, VHDX and VMDK allocate their data in fixed-size
+//! clusters. Writers group sparse blocks into clusters and store each one
+//! as unallocated, zero or data. Readers map clusters back to those three
+//! kinds and yield skip, zero fill or raw blocks respectively.
+
+use crate::{block::Block, pipeline::BlockSource};
+use crate::result::Result;
+use std::io::{self, prelude::*, SeekFrom};
+
+/// How a cluster of a container is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mapping {
+    /// The cluster isn't allocated and reads as zeros.
+    Unallocated,
+    /// The cluster is marked as reading as zeros.
+    Zero,
+    /// The cluster's data is stored at this offset.
+    Data(u64),
+}
+
+/// Groups sparse blocks into clusters.
+pub(crate) struct Clusters {
+    blocks: Vec<Block>,
+    blocks_per_cluster: usize,
+}
+
+impl Clusters {
+    /// Creates a grouping into clusters of `cluster_size` bytes, which must
+    /// be a multiple of the block size.
+    pub(crate) fn new(cluster_size: u64) -> Self {
+        let blocks_per_cluster = (cluster_size / u64::from(Block::SIZE)) as usize;
+        Self {
+            blocks: Vec::with_capacity(blocks_per_cluster),
+            blocks_per_cluster,
+        }
+    }
+
+    /// Adds a block, returning the blocks of the cluster it completes.
+    ///
+    /// Checksum blocks don't cover any data and are dropped.
+    pub(crate) fn push(&mut self, block: &Block) -> Option<Vec<Block>> {
+        if let Block::Crc32(_) = block {
+            return None;
+        }
+
+        self.blocks.push(block.clone());
+        if self.blocks.len() == self.blocks_per_cluster {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the blocks of the incomplete last cluster, if any.
+    pub(crate) fn take(&mut self) -> Option<Vec<Block>> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let capacity = self.blocks_per_cluster;
+        Some(std::mem::replace(
+            &mut self.blocks,
+            Vec::with_capacity(capacity),
+        ))
+    }
+}
+
+/// Determines how a cluster consisting of `blocks` can be stored.
+///
+/// The offset of data clusters is left at 0 for the caller to allocate.
+pub(crate) fn classify(blocks: &[Block]) -> Mapping {
+    if blocks.iter().all(|b| *b == Block::Skip) {
+        Mapping::Unallocated
+    } else if blocks
+        .iter()
+        .all(|b| matches!(b, Block::Skip | Block::Fill([0, 0, 0, 0])))
+    {
+        Mapping::Zero
+    } else {
+        Mapping::Data(0)
+    }
+}
+
+/// Writes the data of a cluster consisting of `blocks`, padded with zeros
+/// to `cluster_size` bytes.
+pub(crate) fn write_cluster<W: Write>(mut w: W, blocks: &[Block], cluster_size: u64) -> Result<()> {
+    for block in blocks {
+        block.write_decoded(&mut w)?;
+    }
+    write_zeros(
+        w,
+        cluster_size - blocks.len() as u64 * u64::from(Block::SIZE),
+    )
+}
+
+/// Writes `len` zero bytes.
+pub(crate) fn write_zeros<W: Write>(mut w: W, len: u64) -> Result<()> {
+    io::copy(&mut io::repeat(0).take(len), &mut w)?;
+    Ok(())
+}
+
+/// Looks up where the clusters of a container are stored.
+pub(crate) trait ClusterMap {
+    /// Returns the size of a cluster in bytes, a multiple of the block
+    /// size.
+    fn cluster_size(&self) -> u64;
+
+    /// Returns how cluster `index` is stored, reading metadata from `src`
+    /// as needed.
+    fn lookup<R: Read + Seek>(&mut self, src: &mut R, index: u64) -> Result<Mapping>;
+}
+
+/// Reads the blocks of a container through its cluster map.
+pub(crate) struct MappedReader<R, M> {
+    pub(crate) src: R,
+    pub(crate) map: M,
+    /// The offset of the container in `src`, which all stored offsets are
+    /// relative to.
+    pub(crate) start: u64,
+    /// The virtual size of the disk in bytes.
+    pub(crate) size: u64,
+    block: u64,
+}
+
+impl<R: Read + Seek, M: ClusterMap> MappedReader<R, M> {
+    pub(crate) fn new(src: R, map: M, start: u64, size: u64) -> Self {
+        Self {
+            src,
+            map,
+            start,
+            size,
+            block: 0,
+        }
+    }
+
+    fn next_block(&mut self) -> Result<Block> {
+        let pos = self.block * u64::from(Block::SIZE);
+        let cluster_size = self.map.cluster_size();
+
+        let block = match self.map.lookup(&mut self.src, pos / cluster_size)? {
+            Mapping::Unallocated => Block::Skip,
+            Mapping::Zero => Block::Fill([0; 4]),
+            Mapping::Data(offset) => {
+                // A virtual size that isn't a multiple of the block size is
+                // padded with zeros.
+                let len = (self.size - pos).min(u64::from(Block::SIZE)) as usize;
+                let mut buf = [0; Block::SIZE as usize];
+                let offset = self.start + offset + pos % cluster_size;
+                self.src.seek(SeekFrom::Start(offset))?;
+                self.src.read_exact(&mut buf[..len])?;
+                Block::Raw(buf.into())
+            }
+        };
+
+        self.block += 1;
+        Ok(block)
+    }
+}
+
+impl<R: Read + Seek, M: ClusterMap> BlockSource for MappedReader<R, M> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        if self.block * u64::from(Block::SIZE) >= self.size {
+            return Ok(None);
+        }
+        self.next_block().map(Some)
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        Some(self.size.next_multiple_of(u64::from(Block::SIZE)))
+    }
+}
+
+/// Reads a table of `len` big-endian integers of `width` bytes at `offset`.
+pub(crate) fn read_table<R: Read + Seek, B: byteorder::ByteOrder>(
+    src: &mut R,
+    offset: u64,
+    len: usize,
+    width: usize,
+) -> Result<Vec<u64>> {
+    let mut buf = vec![0; len * width];
+    src.seek(SeekFrom::Start(offset))?;
+    src.read_exact(&mut buf)?;
+    Ok(buf.chunks(width).map(|c| B::read_uint(c, width)).collect())
+}
+
+/// Writes `table` as integers of `width` bytes, padded with zeros to a
+/// multiple of `align` bytes.
+pub(crate) fn write_table<W: Write, B: byteorder::ByteOrder>(
+    mut w: W,
+    table: &[u64],
+    width: usize,
+    align: u64,
+) -> Result<()> {
+    let mut buf = vec![0; table.len() * width];
+    for (chunk, value) in buf.chunks_mut(width).zip(table) {
+        B::write_uint(chunk, *value, width);
+    }
+    w.write_all(&buf)?;
+
+    let len = buf.len() as u64;
+    write_zeros(w, len.next_multiple_of(align) - len)
+}
+
+/// Checks whether `r` starts with `magic`, rewinding it afterward.
+pub(crate) fn has_magic<R: Read + Seek>(mut r: R, magic: &[u8]) -> Result<bool> {
+    let start = r.stream_position()?;
+    let mut buf = vec![0; magic.len()];
+    let result = match r.read_exact(&mut buf) {
+        Ok(()) => buf == magic,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    r.seek(SeekFrom::Start(start))?;
+    Ok(result)
+}
+
+/// Generates a random ID, so hypervisors can tell converted disks apart.
+///
+/// If `deterministic` is set, the ID is derived from `size` instead.
+#[cfg(any(feature = "vhd", feature = "vhdx"))]
+pub(crate) fn unique_id(size: u64, deterministic: bool) -> [u8; 16] {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    let mut id = [0; 16];
+    for half in id.chunks_mut(8) {
+        let value = match deterministic {
+            true => size,
+            false => RandomState::new().build_hasher().finish(),
+        };
+        half.copy_from_slice(&value.to_le_bytes());
+    }
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clusters() {
+        let mut clusters = Clusters::new(2 * u64::from(Block::SIZE));
+        assert_eq!(clusters.push(&Block::Skip), None);
+        assert_eq!(clusters.push(&Block::Crc32(0)), None);
+        let full = clusters.push(&Block::Fill([0; 4])).unwrap();
+        assert_eq!(full, [Block::Skip, Block::Fill([0; 4])]);
+        assert_eq!(classify(&full), Mapping::Zero);
+
+        assert_eq!(clusters.push(&Block::Skip), None);
+        let partial = clusters.take().unwrap();
+        assert_eq!(classify(&partial), Mapping::Unallocated);
+        assert_eq!(clusters.take(), None);
+
+        assert_eq!(classify(&[Block::Fill([1; 4])]), Mapping::Data(0));
+    }
+}
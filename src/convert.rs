@@ -0,0 +1,82 @@
+//! Conversion between sparse and raw images with format auto-detection.
+
+use crate::{
+    headers::FILE_MAGIC,
+    io::ZeroSeek,
+    pipeline,
+    read::{Encoder, Reader},
+    write::{Decoder, Writer},
+};
+use crate::result::Result;
+use std::io::{self, prelude::*, Cursor, ErrorKind};
+
+/// The format of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A sparse image.
+    Sparse,
+    /// A raw image.
+    Raw,
+}
+
+/// Detects the format of the image in `r` by sniffing its magic.
+///
+/// Returns the detected format and a reader that yields the whole image,
+/// including the sniffed bytes.
+pub fn detect<R: Read>(mut r: R) -> Result<(Format, impl Read)> {
+    let mut magic = [0; 4];
+    let mut len = 0;
+    while len < magic.len() {
+        match r.read(&mut magic[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let format = if len == magic.len() && u32::from_le_bytes(magic) == FILE_MAGIC {
+        Format::Sparse
+    } else {
+        Format::Raw
+    };
+
+    let head = Cursor::new(magic).take(len as u64);
+    Ok((format, head.chain(r)))
+}
+
+/// Converts the image in `input` to the respective other format, writing
+/// the result to `output`.
+///
+/// Sparse images are decoded, with any checksums they contain being
+/// verified. Raw images are encoded. Returns the detected format of the
+/// input image.
+///
+/// Neither `input` nor `output` need to be seekable, so both can be pipes.
+/// When encoding, the sparse image is spooled to a temporary file, since
+/// sparse images can only be written once all of their chunks are known.
+pub fn auto_convert<R: Read, W: Write>(input: R, mut output: W) -> Result<Format> {
+    let (format, input) = detect(input)?;
+
+    match format {
+        Format::Sparse => {
+            let mut reader = Reader::new(input, true)?;
+            let mut decoder = Decoder::new(ZeroSeek::new(&mut output))?;
+            pipeline::copy(&mut reader, &mut decoder)?;
+            decoder.close()?;
+        }
+        Format::Raw => {
+            let mut spool = tempfile::tempfile()?;
+            let mut encoder = Encoder::new(input)?;
+            let mut writer = Writer::new(spool.try_clone()?, false)?;
+            pipeline::copy(&mut encoder, &mut writer)?;
+            writer.close()?;
+
+            spool.rewind()?;
+            io::copy(&mut spool, &mut output)?;
+        }
+    }
+
+    output.flush()?;
+    Ok(format)
+}
@@ -0,0 +1,169 @@
+//! Samples of sparse image layouts for testing the parser against.
+//!
+//! Sparse images produced by different tools vary in their chunk layouts,
+//! and real images are far too large to check into a repository. A sample
+//! captures only the structure of an image: its file header followed by
+//! its chunk headers, without any chunk payloads. Samples may be truncated
+//! after any chunk header, so capturing the head of a huge image is
+//! enough. The samples in `tests/corpus` are layout fixtures, captured from
+//! the test images or synthesized, rather than captures of vendor images.
+//!
+//! `capture` creates a sample from a sparse image, `load_dir` loads all
+//! samples (`*.head` files) in a directory, and `Sample::check` verifies
+//! that the parser accepts a sample's layout.
+
+use crate::{
+    block::Block,
+    dump::Chunks,
+    headers::{ChunkHeader, ChunkType, FileHeader},
+};
+use crate::result::{ensure, Context, Result};
+use std::{
+    fs::{self, File},
+    io::{self, prelude::*, BufReader},
+    path::Path,
+};
+
+/// The file extension of corpus samples.
+pub const EXTENSION: &str = "head";
+
+/// The captured structure of a sparse image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The name of the sample, i.e. its file stem.
+    pub name: String,
+    /// The file header of the image.
+    pub header: FileHeader,
+    /// The captured chunk headers, possibly fewer than the image has.
+    pub chunks: Vec<ChunkHeader>,
+}
+
+impl Sample {
+    /// Reads a sample named `name` from `r`.
+    pub fn read_from<R: Read>(name: &str, mut r: R) -> Result<Self> {
+        let header = FileHeader::read_from(&mut r)?;
+
+        let mut chunks = Vec::new();
+        let mut buf = [0; ChunkHeader::SIZE as usize];
+        while chunks.len() < header.total_chunks as usize {
+            if !read_exact_or_eof(&mut r, &mut buf)? {
+                break;
+            }
+            let chunk = ChunkHeader::read_from(&buf[..])
+                .with_context(|| format!("Chunk {}", chunks.len()))?;
+            chunks.push(chunk);
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            header,
+            chunks,
+        })
+    }
+
+    /// Loads the sample stored at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        Self::read_from(&name, BufReader::new(File::open(path)?))
+            .with_context(|| format!("Invalid sample {}", path.display()))
+    }
+
+    /// Checks whether all chunks of the image were captured.
+    pub fn is_complete(&self) -> bool {
+        self.chunks.len() == self.header.total_chunks as usize
+    }
+
+    /// Returns the size of the raw image in bytes.
+    pub fn raw_size(&self) -> u64 {
+        u64::from(self.header.total_blocks) * u64::from(Block::SIZE)
+    }
+
+    /// Returns the number of raw image blocks the captured chunks cover.
+    pub fn blocks(&self) -> u64 {
+        self.chunks.iter().map(|c| u64::from(c.chunk_size)).sum()
+    }
+
+    /// Returns the size of the sparse image up to the end of the last
+    /// captured chunk.
+    pub fn sparse_size(&self) -> u64 {
+        let chunks: u64 = self.chunks.iter().map(|c| u64::from(c.total_size)).sum();
+        u64::from(FileHeader::SIZE) + chunks
+    }
+
+    /// Checks that the parser accepts the layout of this sample.
+    ///
+    /// Every chunk header must be consistent with its chunk type, the
+    /// chunks must not cover more blocks than the file header announces
+    /// (exactly as many if the sample is complete), and a checksum chunk
+    /// may only be the last chunk.
+    pub fn check(&self) -> Result<()> {
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            ensure!(chunk.is_plausible(), "Chunk {index} is invalid: {chunk}");
+            ensure!(
+                chunk.chunk_type != ChunkType::Crc32
+                    || index + 1 == self.header.total_chunks as usize,
+                "Chunk {index} is a checksum chunk before the end of the image"
+            );
+        }
+
+        let total_blocks = u64::from(self.header.total_blocks);
+        let blocks = self.blocks();
+        ensure!(
+            blocks <= total_blocks,
+            "Chunks cover {blocks} blocks, more than the {total_blocks} in the header"
+        );
+        ensure!(
+            !self.is_complete() || blocks == total_blocks,
+            "Chunks cover {blocks} blocks, but the header announces {total_blocks}"
+        );
+        Ok(())
+    }
+}
+
+/// Captures the structure of the sparse image in `r` as a sample written
+/// to `w`.
+///
+/// If `max_chunks` is given, only the first `max_chunks` chunk headers are
+/// captured.
+pub fn capture<R: Read, W: Write>(r: R, mut w: W, max_chunks: Option<u32>) -> Result<()> {
+    let chunks = Chunks::new(r)?;
+    chunks.header().write_to(&mut w)?;
+
+    let max_chunks = max_chunks.unwrap_or(u32::MAX) as usize;
+    for chunk in chunks.take(max_chunks) {
+        chunk?.header.write_to(&mut w)?;
+    }
+    Ok(())
+}
+
+/// Loads all samples in `dir`, ordered by name.
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<Sample>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths.iter().map(Sample::load).collect()
+}
+
+/// Fills `buf` from `r`, returning `false` if `r` is at its end.
+///
+/// Fails if `r` ends after only part of `buf` was read.
+fn read_exact_or_eof<R: Read>(mut r: R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,211 @@
+//! Comparison of the decoded content of sparse images.
+
+use crate::{
+    block::Block,
+    pipeline::{next_data_block, BlockSink, BlockSource},
+};
+use crate::result::Result;
+use std::ops::Range;
+
+/// A block that differs between two images.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    /// The index of the block in the raw image.
+    pub index: u64,
+    /// The block in the old image, or `None` if the old image is shorter.
+    pub old: Option<Block>,
+    /// The block in the new image, or `None` if the new image is shorter.
+    pub new: Option<Block>,
+}
+
+/// Iterates over the blocks whose decoded content differs between two
+/// images.
+///
+/// Blocks are compared by the raw data they decode to, so e.g. a skip
+/// block and a raw block full of zeros are considered equal. Checksum
+/// blocks are ignored.
+pub struct Diff<A, B> {
+    old: A,
+    new: B,
+    index: u64,
+    finished: bool,
+}
+
+impl<A: BlockSource, B: BlockSource> Diff<A, B> {
+    /// Creates a new iterator over the differences between `old` and `new`.
+    pub fn new(old: A, new: B) -> Self {
+        Self {
+            old,
+            new,
+            index: 0,
+            finished: false,
+        }
+    }
+
+    fn next_difference(&mut self) -> Result<Option<Difference>> {
+        loop {
+            let old = next_data_block(&mut self.old)?;
+            let new = next_data_block(&mut self.new)?;
+
+            let index = self.index;
+            self.index += 1;
+
+            match (&old, &new) {
+                (None, None) => return Ok(None),
+                (Some(o), Some(n)) if same_content(o, n) => (),
+                _ => return Ok(Some(Difference { index, old, new })),
+            }
+        }
+    }
+}
+
+impl<A: BlockSource, B: BlockSource> Iterator for Diff<A, B> {
+    type Item = Result<Difference>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let result = self.next_difference().transpose();
+        self.finished = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// Returns the ranges of blocks whose decoded content differs between
+/// `old` and `new`.
+pub fn diff_ranges<A: BlockSource, B: BlockSource>(old: A, new: B) -> Result<Vec<Range<u64>>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for difference in Diff::new(old, new) {
+        let index = difference?.index;
+        match ranges.last_mut() {
+            Some(r) if r.end == index => r.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Writes a forward patch from `old` to `new` to `forward` and a reverse
+/// patch from `new` back to `old` to `reverse`, returning the number of
+/// blocks that differ.
+///
+/// Patches hold the blocks that differ and don't-care blocks elsewhere, so
+/// `compose` turns `old` into `new` with the forward patch, and `new` back
+/// into `old` with the reverse one, e.g. to roll back an update. Blocks
+/// that change to zeros are stored as fill blocks, as don't-care blocks
+/// would leave the old data in place.
+///
+/// Patches cannot shrink an image: if the images are of different sizes,
+/// composing leaves the blocks of the larger one past the end of the
+/// smaller one in place. `forward` and `reverse` are not closed.
+pub fn deltas<A, B, F, R>(mut old: A, mut new: B, forward: &mut F, reverse: &mut R) -> Result<u64>
+where
+    A: BlockSource,
+    B: BlockSource,
+    F: BlockSink + ?Sized,
+    R: BlockSink + ?Sized,
+{
+    let mut changed = 0;
+    loop {
+        let old_block = next_data_block(&mut old)?;
+        let new_block = next_data_block(&mut new)?;
+
+        let (to_new, to_old) = match (&old_block, &new_block) {
+            (None, None) => return Ok(changed),
+            (Some(o), Some(n)) if same_content(o, n) => (Block::Skip, Block::Skip),
+            (o, n) => {
+                changed += 1;
+                (patch_block(n.as_ref()), patch_block(o.as_ref()))
+            }
+        };
+        forward.write_block(&to_new)?;
+        reverse.write_block(&to_old)?;
+    }
+}
+
+/// Returns the block a patch stores to replace a block with `block`.
+fn patch_block(block: Option<&Block>) -> Block {
+    match block {
+        Some(Block::Skip) => Block::Fill([0; 4]),
+        Some(block) => block.clone(),
+        None => Block::Skip,
+    }
+}
+
+/// Checks whether two blocks decode to the same raw data.
+pub fn same_content(a: &Block, b: &Block) -> bool {
+    match (fill_value(a), fill_value(b)) {
+        (Some(va), Some(vb)) => return va == vb,
+        (None, None) => {
+            if let (Block::Raw(ra), Block::Raw(rb)) = (a, b) {
+                return ra[..] == rb[..];
+            }
+        }
+        _ => (),
+    }
+
+    let mut buf_a = [0; Block::SIZE as usize];
+    let mut buf_b = [0; Block::SIZE as usize];
+    a.decode_into(&mut buf_a);
+    b.decode_into(&mut buf_b);
+    buf_a == buf_b
+}
+
+fn fill_value(block: &Block) -> Option<[u8; 4]> {
+    match block {
+        Block::Fill(value) => Some(*value),
+        Block::Skip | Block::Crc32(_) => Some([0; 4]),
+        Block::Raw(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deltas() {
+        use crate::{merge::compose, read::Reader, testutil::write_sparse, write::Writer};
+        use std::io::Cursor;
+
+        let sparse = |blocks: &[Block]| write_sparse(blocks, false).unwrap();
+        let source = |image: &[u8]| Reader::new(Cursor::new(image.to_vec()), false).unwrap();
+        let raw = |byte| Block::Raw([byte; Block::SIZE as usize].into());
+        let old = sparse(&[raw(1), Block::Skip, raw(2), Block::fill_u32(3)]);
+        let new = sparse(&[raw(1), raw(4), Block::Skip, Block::fill_u32(3)]);
+
+        let mut forward = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        let mut reverse = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        let changed = super::deltas(source(&old), source(&new), &mut forward, &mut reverse);
+        assert_eq!(changed.unwrap(), 2);
+        let forward = forward.close().unwrap().into_inner();
+        let reverse = reverse.close().unwrap().into_inner();
+
+        // Applying a patch leaves no differences.
+        let apply = |base: &[u8], patch: &[u8]| {
+            let writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+            let writer = compose(source(base), source(patch), writer).unwrap();
+            writer.close().unwrap().into_inner()
+        };
+        let updated = apply(&old, &forward);
+        let updated = diff_ranges(source(&updated), source(&new)).unwrap();
+        assert!(updated.is_empty());
+        let rolled_back = apply(&new, &reverse);
+        let rolled_back = diff_ranges(source(&rolled_back), source(&old)).unwrap();
+        assert!(rolled_back.is_empty());
+    }
+
+    #[test]
+    fn same_content() {
+        let zeros = Block::Raw([0; Block::SIZE as usize].into());
+        let ones = Block::Raw([1; Block::SIZE as usize].into());
+
+        assert!(super::same_content(&Block::Skip, &zeros));
+        assert!(super::same_content(&Block::Fill([0; 4]), &Block::Skip));
+        assert!(super::same_content(&Block::Fill([1; 4]), &ones));
+        assert!(!super::same_content(&Block::Fill([1; 4]), &zeros));
+        assert!(!super::same_content(&ones, &zeros));
+    }
+}
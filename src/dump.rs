@@ -0,0 +1,582 @@
+//! Inspection of the chunk structure of sparse images.
+
+use crate::{
+    block::Block,
+    headers::{ChunkHeader, ChunkType, FileHeader},
+    human::HumanSize,
+    metadata::Metadata,
+};
+use crate::result::{ensure, Context, Error, Result};
+use crc32fast::Hasher;
+use std::{
+    fmt,
+    io::{self, prelude::*, SeekFrom},
+    ops::Range,
+};
+
+/// A chunk of a sparse image and its location.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkEntry {
+    /// The header of the chunk.
+    pub header: ChunkHeader,
+    /// The offset of the chunk header in the sparse image.
+    pub offset: u64,
+    /// The index of the first raw image block the chunk covers.
+    pub start_block: u64,
+}
+
+impl ChunkEntry {
+    /// Returns the offset of the chunk payload in the sparse image.
+    pub fn payload_offset(&self) -> u64 {
+        self.offset + u64::from(ChunkHeader::SIZE)
+    }
+
+    /// Returns the size of the chunk payload in bytes.
+    pub fn payload_size(&self) -> u64 {
+        u64::from(self.header.total_size).saturating_sub(u64::from(ChunkHeader::SIZE))
+    }
+
+    /// Returns the offset in the raw image the chunk decodes to.
+    pub fn raw_offset(&self) -> u64 {
+        self.start_block * u64::from(Block::SIZE)
+    }
+
+    /// Returns the range of raw image blocks the chunk covers.
+    pub fn blocks(&self) -> Range<u64> {
+        self.start_block..self.start_block + u64::from(self.header.chunk_size)
+    }
+
+    /// Reads the fill value of a fill chunk from the sparse image `image`.
+    pub fn fill_value<R: Read + Seek>(&self, mut image: R) -> Result<[u8; 4]> {
+        let mut value = [0; 4];
+        image.seek(SeekFrom::Start(self.payload_offset()))?;
+        image
+            .read_exact(&mut value)
+            .with_context_at(self.payload_offset(), || "Reading fill value")?;
+        Ok(value)
+    }
+
+    /// Decodes the raw image blocks `blocks` of the chunk, reading only the
+    /// bytes they need from the sparse image `image`.
+    ///
+    /// Don't-care blocks decode to zeros.
+    pub fn decode<R: Read + Seek>(&self, mut image: R, blocks: Range<u64>) -> Result<Vec<u8>> {
+        let covered = self.blocks();
+        ensure!(
+            covered.start <= blocks.start
+                && blocks.start <= blocks.end
+                && blocks.end <= covered.end,
+            "Blocks {}..{} are not part of the chunk covering blocks {}..{}",
+            blocks.start,
+            blocks.end,
+            covered.start,
+            covered.end
+        );
+
+        let block_size = u64::from(Block::SIZE);
+        let mut data = vec![0; ((blocks.end - blocks.start) * block_size) as usize];
+        match self.header.chunk_type {
+            ChunkType::Raw => {
+                let offset = self.payload_offset() + (blocks.start - covered.start) * block_size;
+                image.seek(SeekFrom::Start(offset))?;
+                image
+                    .read_exact(&mut data)
+                    .with_context_at(offset, || "Reading raw data")?;
+            }
+            ChunkType::Fill => {
+                let value = self.fill_value(image)?;
+                data.chunks_exact_mut(4)
+                    .for_each(|word| word.copy_from_slice(&value));
+            }
+            ChunkType::DontCare | ChunkType::Crc32 | ChunkType::Metadata => (),
+        }
+        Ok(data)
+    }
+
+    /// Checks the sizes in the chunk header for consistency with its chunk
+    /// type.
+    pub fn issues(&self) -> Vec<ChunkIssue> {
+        let header = &self.header;
+        let blocks = u64::from(header.chunk_size);
+        let payload = match header.chunk_type {
+            ChunkType::Raw => blocks * u64::from(Block::SIZE),
+            ChunkType::Fill | ChunkType::Crc32 => 4,
+            ChunkType::DontCare => 0,
+            ChunkType::Metadata => u64::from(header.total_size)
+                .saturating_sub(u64::from(ChunkHeader::SIZE))
+                .min(u64::from(Metadata::MAX_SIZE)),
+        };
+
+        let mut issues = Vec::new();
+        let expected = u64::from(ChunkHeader::SIZE) + payload;
+        if u64::from(header.total_size) != expected {
+            issues.push(ChunkIssue::TotalSize {
+                actual: header.total_size,
+                expected,
+            });
+        }
+        match header.chunk_type {
+            ChunkType::Crc32 if blocks != 0 => issues.push(ChunkIssue::ChecksumBlocks),
+            ChunkType::Crc32 => (),
+            ChunkType::Metadata if blocks != 0 => issues.push(ChunkIssue::MetadataBlocks),
+            ChunkType::Metadata => (),
+            _ if blocks == 0 => issues.push(ChunkIssue::NoBlocks),
+            _ => (),
+        }
+        issues
+    }
+}
+
+/// An inconsistency in a chunk header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkIssue {
+    /// The total size isn't the header size plus the payload size of the
+    /// chunk type.
+    TotalSize {
+        /// The total size in the header.
+        actual: u32,
+        /// The total size implied by the chunk type and size.
+        expected: u64,
+    },
+    /// A raw, fill or don't care chunk covers no blocks.
+    NoBlocks,
+    /// A checksum chunk covers blocks.
+    ChecksumBlocks,
+    /// A metadata chunk covers blocks.
+    MetadataBlocks,
+}
+
+impl fmt::Display for ChunkIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkIssue::TotalSize { actual, expected } => {
+                write!(f, "total size is {actual} bytes, expected {expected}")
+            }
+            ChunkIssue::NoBlocks => write!(f, "data chunk covers no blocks"),
+            ChunkIssue::ChecksumBlocks => write!(f, "checksum chunk covers blocks"),
+            ChunkIssue::MetadataBlocks => write!(f, "metadata chunk covers blocks"),
+        }
+    }
+}
+
+/// Bytes at an offset of a raw image, formatted like `hexdump -C`.
+///
+/// Runs of identical lines are collapsed to a `*`, and the last line holds
+/// the offset of the end of the bytes.
+pub struct Hexdump<'a> {
+    /// The bytes.
+    pub data: &'a [u8],
+    /// The offset of the first byte.
+    pub offset: u64,
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut previous = None;
+        let mut collapsed = false;
+        for (index, line) in self.data.chunks(16).enumerate() {
+            if previous == Some(line) {
+                if !collapsed {
+                    writeln!(f, "*")?;
+                    collapsed = true;
+                }
+                continue;
+            }
+            previous = Some(line);
+            collapsed = false;
+
+            write!(f, "{:08x} ", self.offset + index as u64 * 16)?;
+            for column in 0..16 {
+                if column % 8 == 0 {
+                    write!(f, " ")?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(f, "{byte:02x} ")?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            let text: String = line
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => char::from(b),
+                    false => '.',
+                })
+                .collect();
+            writeln!(f, " |{text}|")?;
+        }
+        write!(f, "{:08x}", self.offset + self.data.len() as u64)
+    }
+}
+
+/// The position of a block in the chunk structure of a sparse image, for
+/// progress reports like "Raw chunk 137/1430 (64.0 MiB)".
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockProgress {
+    /// The chunk the block belongs to.
+    pub chunk: ChunkEntry,
+    /// The index of the chunk in the image.
+    pub chunk_index: u32,
+    /// The number of chunks in the image, including metadata and checksum
+    /// chunks.
+    pub total_chunks: u32,
+    /// The index of the block within its chunk.
+    pub block_in_chunk: u32,
+}
+
+impl BlockProgress {
+    /// Returns the index of the raw image block.
+    pub fn block_index(&self) -> u64 {
+        self.chunk.start_block + u64::from(self.block_in_chunk)
+    }
+
+    /// Checks whether the block is the first one of its chunk.
+    pub fn is_chunk_start(&self) -> bool {
+        self.block_in_chunk == 0
+    }
+}
+
+impl fmt::Display for BlockProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.chunk.header;
+        let raw_size = u64::from(header.chunk_size) * u64::from(Block::SIZE);
+        write!(
+            f,
+            "{} chunk {}/{} ({})",
+            header.chunk_type,
+            self.chunk_index + 1,
+            self.total_chunks,
+            HumanSize(raw_size)
+        )
+    }
+}
+
+/// Iterates over the chunks of a sparse image without decoding them.
+///
+/// Chunk payloads are skipped over, so only the headers are parsed.
+pub struct Chunks<R: Read> {
+    src: R,
+    header: FileHeader,
+    offset: u64,
+    block: u64,
+    remaining: u32,
+    finished: bool,
+    /// The checksum of the payload of the last chunk, if payloads are
+    /// hashed.
+    payload_crc: Option<u32>,
+    hash_payloads: bool,
+}
+
+impl<R: Read> Chunks<R> {
+    /// Creates a new iterator over the chunks of the sparse image in `r`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let header = FileHeader::read_from(&mut r)?;
+        Ok(Self {
+            src: r,
+            remaining: header.total_chunks,
+            header,
+            offset: u64::from(FileHeader::SIZE),
+            block: 0,
+            finished: false,
+            payload_crc: None,
+            hash_payloads: false,
+        })
+    }
+
+    /// Computes the CRC32 of every chunk payload while skipping over it,
+    /// see `payload_crc`.
+    pub fn hash_payloads(mut self) -> Self {
+        self.hash_payloads = true;
+        self
+    }
+
+    /// Returns the CRC32 of the payload of the chunk returned last, if
+    /// enabled with `hash_payloads`.
+    pub fn payload_crc(&self) -> Option<u32> {
+        self.payload_crc
+    }
+
+    /// Returns the file header of the sparse image.
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Returns the offset of the next chunk in the sparse image.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn next_chunk(&mut self) -> Result<ChunkEntry> {
+        let index = self.header.total_chunks - self.remaining;
+        let header = ChunkHeader::read_from(&mut self.src)
+            .with_context_at(self.offset, || format!("Reading chunk {index} header"))?;
+        let entry = ChunkEntry {
+            header,
+            offset: self.offset,
+            start_block: self.block,
+        };
+
+        let payload = entry.payload_size();
+        let mut payload_src = (&mut self.src).take(payload);
+        let skipped = match self.hash_payloads {
+            true => {
+                let mut sink = HashingSink(Hasher::new());
+                let skipped = io::copy(&mut payload_src, &mut sink)?;
+                self.payload_crc = Some(sink.0.finalize());
+                skipped
+            }
+            false => io::copy(&mut payload_src, &mut io::sink())?,
+        };
+        if skipped < payload {
+            let err = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+            let offset = self.offset + u64::from(ChunkHeader::SIZE);
+            return Err(err.context_at(format!("Skipping chunk {index} payload"), offset));
+        }
+
+        self.offset += u64::from(entry.header.total_size.max(u32::from(ChunkHeader::SIZE)));
+        self.block += u64::from(entry.header.chunk_size);
+        self.remaining -= 1;
+        Ok(entry)
+    }
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = Result<ChunkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished || self.remaining == 0 {
+            return None;
+        }
+
+        let result = self.next_chunk();
+        self.finished = result.is_err();
+        Some(result)
+    }
+}
+
+/// Discards written bytes after adding them to a checksum.
+struct HashingSink(Hasher);
+
+impl Write for HashingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A chunk that differs between two sparse images.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkDifference {
+    /// The index of the chunk in the images.
+    pub index: u32,
+    /// The chunk in the old image, or `None` if it has fewer chunks.
+    pub old: Option<ChunkEntry>,
+    /// The chunk in the new image, or `None` if it has fewer chunks.
+    pub new: Option<ChunkEntry>,
+}
+
+impl ChunkDifference {
+    /// Checks whether only the payloads of the chunks differ, not their
+    /// headers or locations.
+    pub fn payload_only(&self) -> bool {
+        self.old == self.new
+    }
+}
+
+impl fmt::Display for ChunkDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = |f: &mut fmt::Formatter, side, entry: &Option<ChunkEntry>| match entry {
+            Some(c) => write!(
+                f,
+                "\n  {side}: {} chunk of {} blocks at {:#x}, raw offset {:#x}",
+                c.header.chunk_type,
+                c.header.chunk_size,
+                c.offset,
+                c.raw_offset()
+            ),
+            None => write!(f, "\n  {side}: no chunk"),
+        };
+        write!(f, "chunk {}", self.index)?;
+        if self.payload_only() {
+            write!(f, " (payload differs)")?;
+        }
+        entry(f, "old", &self.old)?;
+        entry(f, "new", &self.new)
+    }
+}
+
+/// The structural differences between two sparse images, see `compare`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    /// The file header of the old image.
+    pub old_header: FileHeader,
+    /// The file header of the new image.
+    pub new_header: FileHeader,
+    /// The first chunk whose header or location differs, i.e. where the
+    /// chunk layouts diverge, or `None` if they are the same.
+    pub layout: Option<ChunkDifference>,
+    /// The first chunk that differs in any way, including its payload, or
+    /// `None` if all chunks are identical.
+    pub first: Option<ChunkDifference>,
+}
+
+impl Comparison {
+    /// Checks whether the images are identical.
+    pub fn is_identical(&self) -> bool {
+        self.old_header == self.new_header && self.first.is_none()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (old, new) = (&self.old_header, &self.new_header);
+        writeln!(f, "{:<16}{:>12}  {:>12}", "", "old", "new")?;
+        for (name, old, new) in [
+            (
+                "total blocks:",
+                old.total_blocks.to_string(),
+                new.total_blocks.to_string(),
+            ),
+            (
+                "total chunks:",
+                old.total_chunks.to_string(),
+                new.total_chunks.to_string(),
+            ),
+            (
+                "image checksum:",
+                format!("{:#010x}", old.image_checksum),
+                format!("{:#010x}", new.image_checksum),
+            ),
+        ] {
+            let marker = if old == new { "" } else { "  *" };
+            writeln!(f, "{name:<16}{old:>12}  {new:>12}{marker}")?;
+        }
+
+        match (&self.layout, &self.first) {
+            (_, None) => write!(f, "chunks are identical"),
+            (None, Some(first)) => {
+                writeln!(f, "chunk layouts are identical")?;
+                write!(f, "first differing {first}")
+            }
+            (Some(layout), Some(first)) if layout.index == first.index => {
+                write!(f, "chunk layouts diverge at {layout}")
+            }
+            (Some(layout), Some(first)) => {
+                writeln!(f, "chunk layouts diverge at {layout}")?;
+                write!(f, "first differing {first}")
+            }
+        }
+    }
+}
+
+/// Compares the structure of the sparse images `old` and `new`, e.g. to
+/// find out why two tools encode the same raw image differently.
+///
+/// Chunks are compared by their headers, locations and payload
+/// checksums, without decoding them. Comparing stops where the chunk
+/// layouts diverge, as all later chunks are usually shifted.
+pub fn compare<A: Read, B: Read>(old: A, new: B) -> Result<Comparison> {
+    let mut old = Chunks::new(old).context("Old image")?.hash_payloads();
+    let mut new = Chunks::new(new).context("New image")?.hash_payloads();
+    let mut comparison = Comparison {
+        old_header: old.header().clone(),
+        new_header: new.header().clone(),
+        layout: None,
+        first: None,
+    };
+
+    for index in 0.. {
+        let old_chunk = old.next().transpose().context("Old image")?;
+        let new_chunk = new.next().transpose().context("New image")?;
+        if old_chunk.is_none() && new_chunk.is_none() {
+            break;
+        }
+
+        let payload_differs = old.payload_crc() != new.payload_crc();
+        let difference = ChunkDifference {
+            index,
+            old: old_chunk,
+            new: new_chunk,
+        };
+        if !difference.payload_only() {
+            comparison.first.get_or_insert_with(|| difference.clone());
+            comparison.layout = Some(difference);
+            break;
+        }
+        if payload_differs && comparison.first.is_none() {
+            comparison.first = Some(difference);
+        }
+    }
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::write_sparse;
+    use std::io::Cursor;
+
+    #[test]
+    fn compare_images() {
+        let raw = |byte| Block::Raw([byte; Block::SIZE as usize].into());
+        let old = write_sparse(&[raw(1), Block::Skip, Block::fill_u32(7)], false).unwrap();
+        let comparison = compare(&old[..], &old[..]).unwrap();
+        assert!(comparison.is_identical());
+
+        let payload = write_sparse(&[raw(2), Block::Skip, Block::fill_u32(8)], false).unwrap();
+        let comparison = compare(&old[..], &payload[..]).unwrap();
+        assert!(!comparison.is_identical());
+        assert_eq!(comparison.layout, None);
+        let first = comparison.first.unwrap();
+        assert_eq!(first.index, 0);
+        assert!(first.payload_only());
+
+        let layout =
+            write_sparse(&[raw(1), Block::fill_u32(0), Block::fill_u32(7)], false).unwrap();
+        let comparison = compare(&old[..], &layout[..]).unwrap();
+        assert_eq!(comparison.old_header, comparison.new_header);
+        assert_eq!(comparison.layout.as_ref().unwrap().index, 1);
+        assert_eq!(comparison.first, comparison.layout);
+
+        let shorter = write_sparse(&[raw(1), Block::Skip], false).unwrap();
+        let comparison = compare(&old[..], &shorter[..]).unwrap();
+        let layout = comparison.layout.unwrap();
+        assert_eq!(layout.index, 2);
+        assert_eq!(layout.new, None);
+    }
+
+    #[test]
+    fn decode_chunks() {
+        let raw = |byte| Block::Raw([byte; Block::SIZE as usize].into());
+        let image = write_sparse(
+            &[raw(1), raw(2), Block::fill_u32(0x01020304), Block::Skip],
+            false,
+        )
+        .unwrap();
+        let mut image = Cursor::new(image);
+        let chunks = Chunks::new(&mut image).unwrap();
+        let chunks: Vec<_> = chunks.collect::<Result<_>>().unwrap();
+
+        let data = chunks[0].decode(&mut image, 1..2).unwrap();
+        assert_eq!(data, [2; Block::SIZE as usize]);
+        let data = chunks[1].decode(&mut image, 2..3).unwrap();
+        assert_eq!(data[..8], [4, 3, 2, 1, 4, 3, 2, 1]);
+        assert_eq!(chunks[2].decode(&mut image, 3..4).unwrap(), [0; 4096]);
+        assert!(chunks[0].decode(&mut image, 1..3).is_err());
+
+        let dump = Hexdump {
+            data: b"0123456789abcdef0123456789abcdef0123456789abcdef\x00",
+            offset: 0x1000,
+        };
+        assert_eq!(
+            dump.to_string(),
+            "00001000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             *\n\
+             00001030  00                                                |.|\n\
+             00001031"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Estimating how well the data of sparse images compresses.
+//!
+//! Sparse images are often compressed again for distribution, e.g. with
+//! gzip or zstd. Whether that is worthwhile depends on the data in the raw
+//! chunks: text and executables compress well, while compressed file
+//! system contents or encrypted data don't. An `EntropyReport` computes the
+//! Shannon entropy of every data chunk, from which the size a byte-wise
+//! compressor could reach is estimated, and flags chunks that look
+//! compressed already.
+
+use crate::{
+    dump::ChunkEntry,
+    headers::ChunkType,
+    human::{HumanSize, Percent},
+    read::Reader,
+    result::{Context, Result},
+};
+use std::{
+    fmt,
+    io::{prelude::*, SeekFrom},
+};
+
+/// The entropy in bits per byte from which data is considered compressed
+/// or encrypted already.
+pub const COMPRESSED_ENTROPY: f64 = 7.5;
+
+/// The number of bytes read from a raw chunk at once.
+const READ_SIZE: usize = 1 << 20;
+
+/// Returns the Shannon entropy of data with the byte frequencies `counts`
+/// in bits per byte, between 0 for a single repeated byte and 8 for
+/// random data.
+pub fn entropy(counts: &[u64; 256]) -> f64 {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let total = total as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The entropy of a data chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkEntropy {
+    /// The index of the chunk in the image.
+    pub index: usize,
+    /// The chunk.
+    pub chunk: ChunkEntry,
+    /// The entropy of the chunk payload in bits per byte. Fill chunks have
+    /// an entropy of 0, as their data repeats a single value.
+    pub entropy: f64,
+}
+
+impl ChunkEntropy {
+    /// Returns the size of the chunk payload in bytes.
+    pub fn payload_size(&self) -> u64 {
+        self.chunk.payload_size()
+    }
+
+    /// Returns the estimated size of the chunk payload after compression.
+    ///
+    /// This is the lower bound for compressors coding bytes independently,
+    /// so real compressors, which also exploit repetition, usually do
+    /// better on data that isn't compressed already.
+    pub fn estimated_size(&self) -> u64 {
+        (self.payload_size() as f64 * self.entropy / 8.0).ceil() as u64
+    }
+
+    /// Checks whether the chunk looks compressed or encrypted already.
+    pub fn is_compressed(&self) -> bool {
+        self.entropy >= COMPRESSED_ENTROPY
+    }
+}
+
+/// The entropy of the data chunks of a sparse image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntropyReport {
+    /// The raw and fill chunks of the image, in order.
+    pub chunks: Vec<ChunkEntropy>,
+}
+
+impl EntropyReport {
+    /// Computes the entropy report of the sparse image in `image`.
+    ///
+    /// The payloads of all raw chunks are read.
+    pub fn from_image<R: Read + Seek>(mut image: R) -> Result<Self> {
+        image.rewind()?;
+        let chunks = Reader::new(&mut image, false)?.prescan()?.chunks;
+
+        let mut report = Self::default();
+        let mut buf = vec![0; READ_SIZE];
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let entropy = match chunk.header.chunk_type {
+                ChunkType::Raw => {
+                    let mut counts = [0; 256];
+                    let mut remaining = chunk.payload_size();
+                    image.seek(SeekFrom::Start(chunk.payload_offset()))?;
+                    while remaining > 0 {
+                        let len = remaining.min(READ_SIZE as u64) as usize;
+                        image
+                            .read_exact(&mut buf[..len])
+                            .with_context_at(chunk.payload_offset(), || "Reading raw data")?;
+                        for &byte in &buf[..len] {
+                            counts[usize::from(byte)] += 1;
+                        }
+                        remaining -= len as u64;
+                    }
+                    entropy(&counts)
+                }
+                ChunkType::Fill => 0.0,
+                ChunkType::DontCare | ChunkType::Crc32 | ChunkType::Metadata => continue,
+            };
+            report.chunks.push(ChunkEntropy {
+                index,
+                chunk,
+                entropy,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Returns the total size of the data chunk payloads in bytes.
+    pub fn payload_size(&self) -> u64 {
+        self.chunks.iter().map(ChunkEntropy::payload_size).sum()
+    }
+
+    /// Returns the estimated total size of the data chunk payloads after
+    /// compression.
+    pub fn estimated_size(&self) -> u64 {
+        self.chunks.iter().map(ChunkEntropy::estimated_size).sum()
+    }
+
+    /// Returns the total payload size of the chunks that look compressed
+    /// already.
+    pub fn compressed_size(&self) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|c| c.is_compressed())
+            .map(ChunkEntropy::payload_size)
+            .sum()
+    }
+}
+
+impl fmt::Display for EntropyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let payload = self.payload_size();
+        writeln!(f, "data:           {}", HumanSize(payload))?;
+        writeln!(
+            f,
+            "incompressible: {} ({} of data), compressed or encrypted already",
+            HumanSize(self.compressed_size()),
+            Percent::new(self.compressed_size(), payload)
+        )?;
+        write!(
+            f,
+            "estimated:      {} ({} of data) after compression",
+            HumanSize(self.estimated_size()),
+            Percent::new(self.estimated_size(), payload)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{block::Block, testutil::write_sparse};
+    use std::io::Cursor;
+
+    #[test]
+    fn entropy_bounds() {
+        let mut counts = [0; 256];
+        assert_eq!(entropy(&counts), 0.0);
+        counts[7] = 100;
+        assert_eq!(entropy(&counts), 0.0);
+        counts[8] = 100;
+        assert_eq!(entropy(&counts), 1.0);
+        assert_eq!(entropy(&[3; 256]), 8.0);
+    }
+
+    #[test]
+    fn entropy_report() {
+        let mut all_bytes = [0; Block::SIZE as usize];
+        all_bytes
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8);
+        let mut text = [b'a'; Block::SIZE as usize];
+        text[..2048].fill(b'b');
+        let blocks = [
+            Block::Raw(all_bytes.into()),
+            Block::Skip,
+            Block::fill_u32(0xaabbccdd),
+            Block::Raw(text.into()),
+        ];
+        let image = Cursor::new(write_sparse(&blocks, true).unwrap());
+
+        let report = EntropyReport::from_image(image).unwrap();
+        let entropies: Vec<_> = report.chunks.iter().map(|c| (c.index, c.entropy)).collect();
+        assert_eq!(entropies, [(0, 8.0), (2, 0.0), (3, 1.0)]);
+        assert!(report.chunks[0].is_compressed());
+        assert!(!report.chunks[2].is_compressed());
+        assert_eq!(report.payload_size(), 2 * 4096 + 4);
+        assert_eq!(report.compressed_size(), 4096);
+        assert_eq!(report.estimated_size(), 4096 + 512);
+    }
+}
@@ -11,7 +11,7 @@ pub(crate) trait WriteBlock {
 impl WriteBlock for Hasher {
     fn write_block(&mut self, block: &Block) {
         match block {
-            Block::Raw(buf) => self.update(&**buf),
+            Block::Raw(buf) => self.update(buf),
             Block::Fill(value) => {
                 for _ in 0..(Block::SIZE / 4) {
                     self.update(value);
@@ -37,7 +37,7 @@ mod test {
 
     #[test]
     fn crc_write_raw_block() {
-        let block = Block::Raw(Box::new([b'A'; Block::SIZE as usize]));
+        let block = Block::Raw([b'A'; Block::SIZE as usize].into());
         assert_eq!(block_crc(&block), 0xfea63440);
     }
 
@@ -0,0 +1,218 @@
+//! Encoding raw images with holes using the filesystem's extent map.
+//!
+//! Raw images are often already sparse files: the filesystem only
+//! allocates the regions that hold data and leaves holes elsewhere. The
+//! extent map of such a file, as reported by the `FIEMAP` ioctl, tells
+//! which blocks hold data without reading the image. An `ExtentSource`
+//! turns allocated blocks into raw data and holes into don't-care, so the
+//! don't-care regions of the sparse image exactly match the holes and the
+//! image never has to be scanned for zeros.
+//!
+//! The opposite direction needs no special support: `Decoder` seeks over
+//! don't-care blocks, which leaves holes in the raw image.
+
+use crate::{
+    block::Block,
+    pipeline::BlockSource,
+    platform,
+    result::{ensure, Context, Result},
+};
+use std::{
+    fs::File,
+    io::{prelude::*, SeekFrom},
+    ops::Range,
+};
+
+/// The regions of a raw image that hold data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtentMap {
+    /// The size of the raw image in bytes.
+    pub size: u64,
+    /// The byte ranges of the image that hold data, in ascending order.
+    pub extents: Vec<Range<u64>>,
+}
+
+impl ExtentMap {
+    /// Creates an extent map of an image of `size` bytes from the byte
+    /// ranges that hold data.
+    ///
+    /// Extents must be sorted and must not overlap. Parts of extents beyond
+    /// `size` are ignored.
+    pub fn new(size: u64, extents: Vec<Range<u64>>) -> Result<Self> {
+        let mut end = 0;
+        for extent in &extents {
+            ensure!(
+                extent.start >= end && extent.start < extent.end,
+                "Extent {extent:?} is empty, out of order or overlaps its predecessor"
+            );
+            end = extent.end;
+        }
+        Ok(Self { size, extents })
+    }
+
+    /// Reads the extent map of `file` from the filesystem.
+    ///
+    /// Only supported on Linux and Android, and only by filesystems that
+    /// implement `FIEMAP`, which includes ext4, XFS, Btrfs and F2FS.
+    /// Preallocated but unwritten extents count as holes.
+    pub fn from_file(file: &File) -> Result<Self> {
+        let size = file.metadata()?.len();
+        let extents =
+            platform::data_extents(file).context("Cannot read the extent map of the image")?;
+        Self::new(size, extents)
+    }
+
+    /// Returns the number of blocks of the image.
+    pub fn blocks_count(&self) -> u64 {
+        self.size.div_ceil(u64::from(Block::SIZE))
+    }
+
+    /// Returns the number of bytes of the image that hold data.
+    pub fn data_size(&self) -> u64 {
+        self.extents
+            .iter()
+            .map(|e| e.end.min(self.size).saturating_sub(e.start))
+            .sum()
+    }
+}
+
+/// A block source reading the allocated blocks of a raw image and yielding
+/// don't-care blocks for its holes.
+///
+/// Blocks that are only partially allocated are read as raw data.
+pub struct ExtentSource<R> {
+    src: R,
+    map: ExtentMap,
+    /// The index of the next block.
+    block: u64,
+    /// The index of the extent containing or following the next block.
+    extent: usize,
+    /// Whether `src` is positioned at the next block.
+    positioned: bool,
+}
+
+impl<R: Read + Seek> ExtentSource<R> {
+    /// Creates a source reading the raw image `src` described by `map`.
+    pub fn new(src: R, map: ExtentMap) -> Self {
+        Self {
+            src,
+            map,
+            block: 0,
+            extent: 0,
+            positioned: false,
+        }
+    }
+
+    /// Checks whether the next block overlaps an extent.
+    fn is_mapped(&mut self) -> bool {
+        let size = u64::from(Block::SIZE);
+        let (start, end) = (self.block * size, (self.block + 1) * size);
+        while let Some(extent) = self.map.extents.get(self.extent) {
+            if extent.end > start {
+                return extent.start < end;
+            }
+            self.extent += 1;
+        }
+        false
+    }
+
+    fn read_mapped(&mut self) -> Result<Block> {
+        let pos = self.block * u64::from(Block::SIZE);
+        if !self.positioned {
+            self.src.seek(SeekFrom::Start(pos))?;
+            self.positioned = true;
+        }
+
+        // The last block of an image whose size isn't a multiple of the
+        // block size is padded with zeros.
+        let len = (self.map.size - pos).min(u64::from(Block::SIZE)) as usize;
+        let mut buf = [0; Block::SIZE as usize];
+        self.src
+            .read_exact(&mut buf[..len])
+            .with_context_at(pos, || format!("Reading block {}", self.block))?;
+        Ok(Block::Raw(buf.into()))
+    }
+}
+
+impl<R: Read + Seek> BlockSource for ExtentSource<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        if self.block >= self.map.blocks_count() {
+            return Ok(None);
+        }
+
+        let block = match self.is_mapped() {
+            true => self.read_mapped()?,
+            false => {
+                self.positioned = false;
+                Block::Skip
+            }
+        };
+
+        self.block += 1;
+        Ok(Some(block))
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        Some(self.map.blocks_count() * u64::from(Block::SIZE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn blocks(map: ExtentMap, image: &[u8]) -> Vec<Block> {
+        let mut source = ExtentSource::new(Cursor::new(image), map);
+        let mut blocks = Vec::new();
+        while let Some(block) = source.read_block().unwrap() {
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn holes() {
+        let size = Block::SIZE as usize;
+        let image: Vec<u8> = (0..4 * size + 10).map(|i| (i / size) as u8 + 1).collect();
+        let map = ExtentMap::new(image.len() as u64, vec![100..200, 3 * 4096..100000]).unwrap();
+        assert_eq!(map.blocks_count(), 5);
+        assert_eq!(map.data_size(), 100 + 4096 + 10);
+
+        let padded = |n: u8, len| {
+            let mut buf = [0; Block::SIZE as usize];
+            buf[..len].fill(n);
+            Block::Raw(buf.into())
+        };
+        assert_eq!(
+            blocks(map, &image),
+            [
+                padded(1, size),
+                Block::Skip,
+                Block::Skip,
+                padded(4, size),
+                padded(5, 10),
+            ]
+        );
+
+        assert!(ExtentMap::new(10, vec![0..4, 2..8]).is_err());
+        assert!(ExtentMap::new(10, vec![0..2, 4..4]).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn from_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(64 * 4096).unwrap();
+        file.seek(SeekFrom::Start(40 * 4096 + 5)).unwrap();
+        file.write_all(b"data").unwrap();
+
+        // Not every filesystem supports FIEMAP.
+        let Ok(map) = ExtentMap::from_file(&file) else {
+            return;
+        };
+        assert_eq!(map.size, 64 * 4096);
+        assert!(map.extents.iter().any(|e| e.contains(&(40 * 4096 + 5))));
+        assert!(!map.extents.iter().any(|e| e.contains(&0)));
+    }
+}
@@ -0,0 +1,292 @@
+//! A C ABI for applications not written in Rust, like flashing GUIs.
+//!
+//! Only available with the `ffi` feature, and built as a C library with
+//! `cargo rustc --profile ffi --lib --crate-type cdylib --features ffi`, or
+//! `staticlib` for a static one. `include/android_sparse.h` declares the
+//! functions, which return 0 on success and -1 on failure, in which case
+//! `simg_last_error` describes what went wrong.
+//!
+//! Conversions report their progress to a callback passed along with an
+//! opaque user data pointer, e.g. to update a progress bar. It is called
+//! on the thread running the conversion, and cancels it by returning
+//! `false`. What is being done, warnings and errors are reported to the
+//! log callback registered with `simg_set_log_callback`, from all threads.
+
+use crate::{
+    io::AtomicFile,
+    pipeline,
+    read::{Encoder, Reader},
+    result::{ensure, Context, Error, Result},
+    write::{Decoder, Writer},
+};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fmt::Display,
+    fs::File,
+    io::{self, prelude::*, BufReader},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr,
+    sync::Mutex,
+};
+
+/// Called with the number of bytes of the input read so far and the size
+/// of the input. Returning `false` cancels the conversion.
+pub type SimgProgressCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, done: u64, total: u64) -> bool>;
+
+/// Called with the level and text of a log message. The text is only valid
+/// during the call.
+pub type SimgLogCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, level: c_int, message: *const c_char)>;
+
+/// The log level of errors.
+pub const SIMG_LOG_ERROR: c_int = 0;
+/// The log level of warnings.
+pub const SIMG_LOG_WARNING: c_int = 1;
+/// The log level of details about what is being done.
+pub const SIMG_LOG_INFO: c_int = 2;
+
+/// The log callback and its user data, stored as an address to be `Send`.
+static LOGGER: Mutex<Option<(SimgLogCallback, usize)>> = Mutex::new(None);
+
+thread_local! {
+    /// The error of the last function that failed on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Registers `callback` to receive log messages, along with `user_data`.
+/// A null callback unregisters it.
+///
+/// # Safety
+///
+/// `callback` must be safe to call with `user_data` from any thread until
+/// it is unregistered.
+#[no_mangle]
+pub unsafe extern "C" fn simg_set_log_callback(callback: SimgLogCallback, user_data: *mut c_void) {
+    *LOGGER.lock().unwrap() = callback.map(|_| (callback, user_data as usize));
+}
+
+/// Returns a description of the error of the last function that failed on
+/// this thread, or null if none did. It is valid until the next call on
+/// this thread.
+#[no_mangle]
+pub extern "C" fn simg_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|err| err.as_ref().map_or(ptr::null(), |err| err.as_ptr()))
+}
+
+/// Encodes the raw image at `input` to the sparse image `output`, adding a
+/// checksum if `crc` is set.
+///
+/// # Safety
+///
+/// `input` and `output` must be null-terminated paths. `progress`, if not
+/// null, must be safe to call with `user_data` during the conversion.
+#[no_mangle]
+pub unsafe extern "C" fn simg_encode(
+    input: *const c_char,
+    output: *const c_char,
+    crc: bool,
+    progress: SimgProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let (input, output) = (path(input)?, path(output)?);
+        log(SIMG_LOG_INFO, format_args!("Encoding {}", input.display()));
+        let input = open(input, progress, user_data)?;
+
+        let mut file =
+            AtomicFile::create(output).with_context(|| format!("Creating {}", output.display()))?;
+        let mut writer = Writer::new(&mut file, crc)?.on_fragmentation(8, warn_fragmented);
+        pipeline::copy(&mut Encoder::new(input)?, &mut writer)?;
+        writer.close()?;
+        file.commit()?;
+        Ok(())
+    })
+}
+
+/// Decodes the sparse image at `input` to the raw image `output`, verifying
+/// its checksums.
+///
+/// # Safety
+///
+/// `input` and `output` must be null-terminated paths. `progress`, if not
+/// null, must be safe to call with `user_data` during the conversion.
+#[no_mangle]
+pub unsafe extern "C" fn simg_decode(
+    input: *const c_char,
+    output: *const c_char,
+    progress: SimgProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let (input, output) = (path(input)?, path(output)?);
+        log(SIMG_LOG_INFO, format_args!("Decoding {}", input.display()));
+        let input = open(input, progress, user_data)?;
+
+        let mut file =
+            AtomicFile::create(output).with_context(|| format!("Creating {}", output.display()))?;
+        let mut decoder = Decoder::new(&mut file)?;
+        pipeline::copy(&mut Reader::new(input, true)?, &mut decoder)?;
+        decoder.close()?;
+        file.commit()?;
+        Ok(())
+    })
+}
+
+/// Runs `f`, turning errors and panics into a return value of -1 and the
+/// last error.
+///
+/// Panics can only be caught in builds that unwind, like those of the `ffi`
+/// profile. In release builds they abort the process.
+fn call<F: FnOnce() -> Result<()>>(f: F) -> c_int {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(Error::msg("Internal error")));
+    let err = match result {
+        Ok(()) => None,
+        Err(err) => {
+            let msg = format!("{err:#}");
+            log(SIMG_LOG_ERROR, &msg);
+            Some(c_string(msg))
+        }
+    };
+    let status = if err.is_some() { -1 } else { 0 };
+    LAST_ERROR.set(err);
+    status
+}
+
+fn warn_fragmented(blocks: u64, chunks: u32) {
+    log(
+        SIMG_LOG_WARNING,
+        format_args!(
+            "Image is fragmented ({chunks} chunks for {blocks} blocks) and may flash slowly"
+        ),
+    );
+}
+
+/// Passes `msg` to the log callback, if one is registered.
+fn log(level: c_int, msg: impl Display) {
+    // Copied out so the callback may register another one.
+    let Some((Some(callback), user_data)) = *LOGGER.lock().unwrap() else {
+        return;
+    };
+    let msg = c_string(msg.to_string());
+    unsafe { callback(user_data as *mut c_void, level, msg.as_ptr()) };
+}
+
+fn c_string(msg: String) -> CString {
+    CString::new(msg.replace('\0', " ")).unwrap_or_default()
+}
+
+/// Converts the null-terminated string `path` to a path.
+unsafe fn path<'a>(path: *const c_char) -> Result<&'a Path> {
+    ensure!(!path.is_null(), "Path is null");
+    let path = unsafe { CStr::from_ptr(path) };
+    Ok(Path::new(path.to_str().map_err(Error::new)?))
+}
+
+/// Opens the input at `path`, reporting how much of it is read to
+/// `progress`.
+fn open(path: &Path, progress: SimgProgressCallback, user_data: *mut c_void) -> Result<impl Read> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let total = file.metadata()?.len();
+    let input = ProgressReader {
+        inner: file,
+        done: 0,
+        total,
+        progress,
+        user_data,
+    };
+    // Large reads keep the callback from being called too often.
+    Ok(BufReader::with_capacity(1 << 20, input))
+}
+
+/// Reports the bytes read from `inner` to a progress callback.
+struct ProgressReader<R> {
+    inner: R,
+    done: u64,
+    total: u64,
+    progress: SimgProgressCallback,
+    user_data: *mut c_void,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.done += len as u64;
+        if let Some(progress) = self.progress {
+            if !unsafe { progress(self.user_data, self.done, self.total) } {
+                return Err(io::Error::other("Cancelled by the progress callback"));
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    static MESSAGES: Mutex<Vec<(c_int, String)>> = Mutex::new(Vec::new());
+
+    unsafe extern "C" fn record(_: *mut c_void, level: c_int, message: *const c_char) {
+        let message = unsafe { CStr::from_ptr(message) }.to_str().unwrap();
+        MESSAGES.lock().unwrap().push((level, message.into()));
+    }
+
+    /// Records the progress in the `Vec<u64>` passed as user data, and
+    /// cancels once the input is read.
+    unsafe extern "C" fn progress(user_data: *mut c_void, done: u64, total: u64) -> bool {
+        unsafe { &mut *(user_data as *mut Vec<u64>) }.push(done);
+        done < total
+    }
+
+    unsafe extern "C" fn keep_going(_: *mut c_void, _: u64, _: u64) -> bool {
+        true
+    }
+
+    #[test]
+    fn callbacks() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = |name: &str| CString::new(tmpdir.path().join(name).to_str().unwrap()).unwrap();
+        let raw: Vec<u8> = (0..3 * 4096).map(|i| (i / 4096) as u8).collect();
+        fs::write(tmpdir.path().join("raw.img"), &raw).unwrap();
+        let (input, sparse, output) = (path("raw.img"), path("sparse.simg"), path("out.img"));
+
+        unsafe {
+            simg_set_log_callback(Some(record), ptr::null_mut());
+            let status = simg_encode(
+                input.as_ptr(),
+                sparse.as_ptr(),
+                true,
+                Some(keep_going),
+                ptr::null_mut(),
+            );
+            assert_eq!(status, 0);
+            assert!(simg_last_error().is_null());
+
+            let mut done = Vec::<u64>::new();
+            let user_data = &mut done as *mut _ as *mut c_void;
+            let status = simg_decode(sparse.as_ptr(), output.as_ptr(), Some(progress), user_data);
+            assert_eq!(status, -1);
+            let err = CStr::from_ptr(simg_last_error()).to_str().unwrap();
+            assert!(err.contains("Cancelled"), "{err}");
+            assert!(!tmpdir.path().join("out.img").exists());
+            assert!(!done.is_empty());
+
+            let status = simg_decode(sparse.as_ptr(), output.as_ptr(), None, ptr::null_mut());
+            assert_eq!(status, 0);
+            assert_eq!(fs::read(tmpdir.path().join("out.img")).unwrap(), raw);
+            simg_set_log_callback(None, ptr::null_mut());
+        }
+
+        let messages = MESSAGES.lock().unwrap();
+        assert_eq!(messages[0].0, SIMG_LOG_INFO);
+        assert!(messages[0].1.starts_with("Encoding"));
+        assert!(messages
+            .iter()
+            .any(|(level, msg)| *level == SIMG_LOG_ERROR && msg.contains("Cancelled")));
+    }
+}
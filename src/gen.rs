@@ -0,0 +1,288 @@
+//! Deterministic synthetic images.
+//!
+//! An `ImageSpec` describes an image by its size and the makeup of its
+//! blocks, and generates the same image from the same spec every time. The
+//! blocks are generated on the fly, so even multi-gigabyte images can be
+//! streamed into tests and benchmarks without shipping them as fixtures:
+//!
+//! ```
+//! use android_sparse::gen::{ImageSpec, Layout};
+//!
+//! let spec = ImageSpec {
+//!     size: 16 << 20,
+//!     layout: Layout {
+//!         hole_ratio: 0.5,
+//!         ..Layout::default()
+//!     },
+//!     ..ImageSpec::default()
+//! };
+//! let sparse = spec.sparse_image(true).unwrap();
+//! assert!(sparse.len() < 16 << 20);
+//! ```
+
+use crate::{
+    block::Block,
+    pipeline::BlockSource,
+    result::{ensure, Result},
+    write::Writer,
+};
+use std::io::{Cursor, Seek, Write};
+
+/// The makeup of the blocks of a synthetic image.
+///
+/// Blocks that are neither holes nor fill blocks are raw blocks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+    /// The fraction of blocks filled with a 32-bit value.
+    pub fill_ratio: f64,
+    /// The fraction of blocks that are holes, i.e. don't-care blocks.
+    pub hole_ratio: f64,
+    /// The fraction of the bytes of raw blocks that are random. The rest
+    /// are zeros, which precede the random bytes.
+    pub entropy: f64,
+    /// The maximum number of consecutive blocks of the same kind.
+    pub max_run: u32,
+}
+
+impl Default for Layout {
+    /// 20% fill blocks, 20% holes and random raw blocks, in runs of up to
+    /// 64 blocks.
+    fn default() -> Self {
+        Self {
+            fill_ratio: 0.2,
+            hole_ratio: 0.2,
+            entropy: 1.0,
+            max_run: 64,
+        }
+    }
+}
+
+/// The specification of a synthetic image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImageSpec {
+    /// The raw size of the image in bytes, which is rounded up to whole
+    /// blocks.
+    pub size: u64,
+    /// The makeup of the blocks.
+    pub layout: Layout,
+    /// The seed of the pseudo-random generator. Different seeds generate
+    /// different images of the same makeup.
+    pub seed: u64,
+}
+
+impl ImageSpec {
+    /// Checks that the ratios of the layout are valid.
+    pub fn validate(&self) -> Result<()> {
+        let layout = &self.layout;
+        for (name, ratio) in [
+            ("fill ratio", layout.fill_ratio),
+            ("hole ratio", layout.hole_ratio),
+            ("entropy", layout.entropy),
+        ] {
+            ensure!(
+                (0.0..=1.0).contains(&ratio),
+                "Invalid {name} {ratio}, expected 0 to 1"
+            );
+        }
+        ensure!(
+            (0.0..=1.0).contains(&(layout.fill_ratio + layout.hole_ratio)),
+            "The fill and hole ratios add up to more than 1"
+        );
+        ensure!(layout.max_run > 0, "Runs must be at least one block long");
+        Ok(())
+    }
+
+    /// Returns the number of blocks of the image.
+    pub fn blocks_count(&self) -> u64 {
+        self.size.div_ceil(u64::from(Block::SIZE))
+    }
+
+    /// Generates the blocks of the image.
+    pub fn blocks(&self) -> Result<Blocks> {
+        self.validate()?;
+        Ok(Blocks {
+            layout: self.layout,
+            rng: XorShift::new(self.seed),
+            remaining: self.blocks_count(),
+            run: 0,
+            kind: 0.0,
+        })
+    }
+
+    /// Writes the raw image to `w`.
+    pub fn write_raw<W: Write>(&self, mut w: W) -> Result<()> {
+        for block in self.blocks()? {
+            block.write_decoded(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the image as a sparse image to `writer`.
+    ///
+    /// The writer is not closed, so further blocks may follow.
+    pub fn write_sparse<W: Write + Seek>(&self, writer: &mut Writer<W>) -> Result<()> {
+        for block in self.blocks()? {
+            writer.write_block(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Generates the raw image in memory.
+    pub fn raw_image(&self) -> Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        self.write_raw(&mut raw)?;
+        Ok(raw)
+    }
+
+    /// Generates the sparse image in memory, with a checksum if `crc` is
+    /// set.
+    pub fn sparse_image(&self, crc: bool) -> Result<Vec<u8>> {
+        let mut sparse = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut sparse, crc)?;
+        self.write_sparse(&mut writer)?;
+        writer.close()?;
+        Ok(sparse.into_inner())
+    }
+}
+
+/// The blocks of a synthetic image, see `ImageSpec::blocks`.
+pub struct Blocks {
+    layout: Layout,
+    rng: XorShift,
+    /// The number of blocks yet to generate.
+    remaining: u64,
+    /// The number of blocks left in the current run.
+    run: u32,
+    /// Selects the kind of the blocks in the current run.
+    kind: f64,
+}
+
+impl Iterator for Blocks {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        if self.run == 0 {
+            self.kind = self.rng.next_f64();
+            self.run = 1 + (self.rng.next_u64() % u64::from(self.layout.max_run)) as u32;
+        }
+        self.run -= 1;
+
+        let layout = &self.layout;
+        let block = if self.kind < layout.hole_ratio {
+            Block::Skip
+        } else if self.kind < layout.hole_ratio + layout.fill_ratio {
+            Block::fill_u32(0xdead_beef)
+        } else {
+            // Zeros first, so finding out that a block is not filled takes
+            // longer the lower the entropy.
+            let mut buf = [0; Block::SIZE as usize];
+            let random = (layout.entropy * f64::from(Block::SIZE)) as usize;
+            for chunk in buf[Block::SIZE as usize - random..].chunks_mut(8) {
+                let bytes = self.rng.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+            Block::Raw(buf.into())
+        };
+        Some(block)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = usize::try_from(self.remaining).ok();
+        (remaining.unwrap_or(usize::MAX), remaining)
+    }
+}
+
+impl BlockSource for Blocks {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        Ok(self.next())
+    }
+}
+
+/// A fast, reproducible pseudo-random number generator.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> Self {
+        // A zero state would make xorshift generate only zeros.
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0.0..1.0`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read::{Encoder, Reader};
+
+    #[test]
+    fn deterministic() {
+        let spec = ImageSpec {
+            size: (1 << 20) + 1,
+            layout: Layout {
+                max_run: 8,
+                ..Layout::default()
+            },
+            seed: 7,
+        };
+        assert_eq!(spec.blocks_count(), 257);
+
+        let raw = spec.raw_image().unwrap();
+        assert_eq!(raw.len(), 257 * Block::SIZE as usize);
+        assert_eq!(spec.raw_image().unwrap(), raw);
+        let other = ImageSpec { seed: 8, ..spec };
+        assert_ne!(other.raw_image().unwrap(), raw);
+
+        let sparse = spec.sparse_image(true).unwrap();
+        let blocks: Vec<_> = spec.blocks().unwrap().collect();
+        let read: Vec<_> = Reader::new(&sparse[..], true)
+            .unwrap()
+            .filter(|b| !matches!(b, Ok(Block::Crc32(_))))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(read, blocks);
+        let counts = |kind: fn(&Block) -> bool| blocks.iter().filter(|b| kind(b)).count();
+        assert!(counts(|b| matches!(b, Block::Raw(_))) > 0);
+        assert!(counts(|b| matches!(b, Block::Fill(_))) > 0);
+        assert!(counts(|b| matches!(b, Block::Skip)) > 0);
+
+        // Holes decode to zeros, which encode as fill blocks.
+        let encoded = Encoder::new(&raw[..]).unwrap().count();
+        assert_eq!(encoded, blocks.len());
+    }
+
+    #[test]
+    fn invalid_layout() {
+        let spec = |layout| ImageSpec {
+            size: 4096,
+            layout,
+            seed: 0,
+        };
+        let ratios = Layout {
+            fill_ratio: 0.6,
+            hole_ratio: 0.6,
+            ..Layout::default()
+        };
+        assert!(spec(ratios).blocks().is_err());
+        let entropy = Layout {
+            entropy: 1.5,
+            ..Layout::default()
+        };
+        assert!(spec(entropy).raw_image().is_err());
+    }
+}
@@ -1,28 +1,44 @@
-use anyhow::{Result, ensure, bail};
+//! Sparse file and chunk headers.
+
+use crate::result::{Result, ensure, bail};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::prelude::*;
+use std::{fmt, io::prelude::*};
 
-use crate::block::Block;
+use crate::{block::Block, human::HumanSize, metadata::Metadata};
 
-const FILE_MAGIC: u32 = 0xed26_ff3a;
+pub(crate) const FILE_MAGIC: u32 = 0xed26_ff3a;
 const FILE_FORMAT_VERSION: (u16, u16) = (1, 0);
 
 const CHUNK_MAGIC_RAW: u16 = 0xcac1;
 const CHUNK_MAGIC_FILL: u16 = 0xcac2;
 const CHUNK_MAGIC_DONT_CARE: u16 = 0xcac3;
 const CHUNK_MAGIC_CRC32: u16 = 0xcac4;
+/// A vendor extension, outside the range of chunk types libsparse defines.
+const CHUNK_MAGIC_METADATA: u16 = 0xcaf0;
 
+/// The header at the start of a sparse file.
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) struct FileHeader {
-    pub(crate) total_blocks: u32,
-    pub(crate) total_chunks: u32,
-    pub(crate) image_checksum: u32,
+pub struct FileHeader {
+    /// The number of blocks in the raw image.
+    pub total_blocks: u32,
+    /// The number of chunks in the sparse file.
+    pub total_chunks: u32,
+    /// The CRC32 checksum of the raw image, or 0 if not set.
+    pub image_checksum: u32,
 }
 
 impl FileHeader {
-    pub(crate) const SIZE: u16 = 28;
+    /// The size of a sparse file header in bytes.
+    pub const SIZE: u16 = 28;
+
+    /// Checks whether the image decodes to an empty raw image.
+    pub fn is_empty(&self) -> bool {
+        self.total_blocks == 0
+    }
 
-    pub(crate) fn read_from<R: Read>(mut r: R) -> Result<Self> {
+    /// Reads a sparse file header from `r`, checking its magic, version
+    /// and sizes.
+    pub fn read_from<R: Read>(mut r: R) -> Result<Self> {
         let magic = r.read_u32::<LittleEndian>()?;
         ensure!(magic == FILE_MAGIC, "Invalid file magic: {magic:x}");
 
@@ -46,7 +62,7 @@ impl FileHeader {
     }
 
     /// Writes this sparse file header into `w`.
-    pub(crate) fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
         w.write_u32::<LittleEndian>(FILE_MAGIC)?;
 
         let (maj_version, min_version) = FILE_FORMAT_VERSION;
@@ -65,13 +81,37 @@ impl FileHeader {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+impl fmt::Display for FileHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw_size = u64::from(self.total_blocks) * u64::from(Block::SIZE);
+        write!(
+            f,
+            "{} blocks ({}) in {} chunks, checksum {:#010x}",
+            self.total_blocks,
+            HumanSize(raw_size),
+            self.total_chunks,
+            self.image_checksum
+        )
+    }
+}
+
+/// The type of a sparse chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(u16)]
-pub(crate) enum ChunkType {
+pub enum ChunkType {
+    /// A chunk holding raw data.
     Raw = CHUNK_MAGIC_RAW,
+    /// A chunk holding a 4-byte fill value.
     Fill = CHUNK_MAGIC_FILL,
+    /// A chunk of blocks that can be skipped.
     DontCare = CHUNK_MAGIC_DONT_CARE,
+    /// A chunk holding a CRC32 checksum.
     Crc32 = CHUNK_MAGIC_CRC32,
+    /// A chunk holding image metadata, see `metadata`.
+    ///
+    /// This is an extension of the sparse format. Parsers that don't know
+    /// it must skip it, which libsparse doesn't do.
+    Metadata = CHUNK_MAGIC_METADATA,
 }
 
 impl ChunkType {
@@ -81,22 +121,45 @@ impl ChunkType {
             CHUNK_MAGIC_FILL => Ok(ChunkType::Fill),
             CHUNK_MAGIC_DONT_CARE => Ok(ChunkType::DontCare),
             CHUNK_MAGIC_CRC32 => Ok(ChunkType::Crc32),
+            CHUNK_MAGIC_METADATA => Ok(ChunkType::Metadata),
             _ => bail!("Invalid chunk magic: {magic:x}"),
         }
     }
 }
 
+impl fmt::Display for ChunkType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ChunkType::Raw => "Raw",
+            ChunkType::Fill => "Fill",
+            ChunkType::DontCare => "DontCare",
+            ChunkType::Crc32 => "Crc32",
+            ChunkType::Metadata => "Metadata",
+        };
+        f.pad(name)
+    }
+}
+
+/// The header at the start of a sparse chunk.
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) struct ChunkHeader {
-    pub(crate) chunk_type: ChunkType,
-    pub(crate) chunk_size: u32,
-    pub(crate) total_size: u32,
+pub struct ChunkHeader {
+    /// The type of the chunk.
+    pub chunk_type: ChunkType,
+    /// The number of raw image blocks the chunk covers.
+    pub chunk_size: u32,
+    /// The size of the chunk in the sparse file, including this header.
+    pub total_size: u32,
 }
 
 impl ChunkHeader {
-    pub(crate) const SIZE: u16 = 12;
-
-    pub(crate) fn read_from<R: Read>(mut r: R) -> Result<Self> {
+    /// The size of a chunk header in bytes.
+    pub const SIZE: u16 = 12;
+
+    /// Reads a chunk header from `r`, checking its chunk type.
+    ///
+    /// The sizes in the header are not checked, use `is_plausible` for
+    /// that.
+    pub fn read_from<R: Read>(mut r: R) -> Result<Self> {
         let magic = r.read_u16::<LittleEndian>()?;
         let chunk_type = ChunkType::from_magic(magic)?;
         r.read_u16::<LittleEndian>()?; // reserved1
@@ -108,7 +171,26 @@ impl ChunkHeader {
         })
     }
 
-    pub(crate) fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+    /// Checks whether the sizes in this header are consistent with its
+    /// chunk type.
+    pub fn is_plausible(&self) -> bool {
+        let payload = match u64::from(self.total_size).checked_sub(u64::from(Self::SIZE)) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let blocks = u64::from(self.chunk_size);
+        match self.chunk_type {
+            ChunkType::Raw => blocks > 0 && payload == blocks * u64::from(Block::SIZE),
+            ChunkType::Fill => blocks > 0 && payload == 4,
+            ChunkType::DontCare => blocks > 0 && payload == 0,
+            ChunkType::Crc32 => blocks == 0 && payload == 4,
+            ChunkType::Metadata => blocks == 0 && payload <= u64::from(Metadata::MAX_SIZE),
+        }
+    }
+
+    /// Writes this chunk header into `w`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
         w.write_u16::<LittleEndian>(self.chunk_type as u16)?;
         w.write_u16::<LittleEndian>(0)?; // reserved1
 
@@ -119,6 +201,16 @@ impl ChunkHeader {
     }
 }
 
+impl fmt::Display for ChunkHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} chunk of {} blocks ({} bytes)",
+            self.chunk_type, self.chunk_size, self.total_size
+        )
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -163,6 +255,15 @@ mod test {
         assert_eq!(header, CHUNK_HEADER);
     }
 
+    #[test]
+    fn display_headers() {
+        assert_eq!(
+            FILE_HEADER.to_string(),
+            "262144 blocks (1.0 GiB) in 1430 chunks, checksum 0x00000000"
+        );
+        assert_eq!(CHUNK_HEADER.to_string(), "Raw chunk of 1 blocks (4108 bytes)");
+    }
+
     #[test]
     fn write_chunk_header() {
         let mut bytes = Vec::new();
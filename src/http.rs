@@ -0,0 +1,622 @@
+//! Reading images from HTTP servers.
+//!
+//! An `HttpReader` downloads a file with a plain HTTP/1.1 `GET` request
+//! and hands out its bytes as they arrive, so a `Reader` can decode an
+//! image while it is still being downloaded. Wrapped in an `io::Retry`, a
+//! download whose connection drops resumes where it stopped with a `Range`
+//! request.
+//!
+//! Both `http://` and `https://` URLs are supported. HTTPS uses rustls, and
+//! trusts the certificate authorities Mozilla trusts, as bundled by the
+//! `webpki-roots` crate; the system's certificate store is not consulted.
+//!
+//! ```no_run
+//! # fn main() -> android_sparse::Result<()> {
+//! use android_sparse::{
+//!     http::HttpReader,
+//!     io::{ReadAhead, Retry, RetryPolicy},
+//!     Reader,
+//! };
+//!
+//! let download = HttpReader::open("http://example.com/system.simg")?;
+//! let download = Retry::resumable(download, RetryPolicy::default());
+//! for block in Reader::new(ReadAhead::new(download, 16), true)? {
+//!     // ...
+//! #   block?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    io::Resume,
+    result::{bail, ensure, Context, Error, Result},
+};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::{
+    io::{self, prelude::*, BufReader},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+/// The number of redirects followed when opening a URL.
+const MAX_REDIRECTS: u32 = 5;
+
+/// How long connecting or waiting for data may take before the attempt
+/// fails.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest response header line accepted.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// Returns whether `s` looks like a URL rather than a path.
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// The parts of an `http://` or `https://` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self> {
+        let (tls, rest) = match url.strip_prefix("https://") {
+            Some(rest) => (true, rest),
+            None => (
+                false,
+                url.strip_prefix("http://")
+                    .with_context(|| format!("Not an HTTP URL: {url}"))?,
+            ),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let path = match path.starts_with('?') {
+            true => format!("/{path}"),
+            false => path.to_owned(),
+        };
+
+        // IPv6 addresses are enclosed in brackets.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, Some(port)),
+            _ => (authority, None),
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("Invalid port in {url}"))?,
+            None => default_port(tls),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ensure!(!host.is_empty(), "No host in {url}");
+        Ok(Self {
+            tls,
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+
+    /// Resolves the `Location` of a redirect against this URL.
+    ///
+    /// Redirects from `https://` to `http://` URLs are refused, as they
+    /// would download the rest of the way unencrypted.
+    fn join(&self, location: &str) -> Result<Self> {
+        let url = if is_url(location) {
+            Self::parse(location)?
+        } else if let Some(rest) = location.strip_prefix("//") {
+            let scheme = if self.tls { "https" } else { "http" };
+            Self::parse(&format!("{scheme}://{rest}"))?
+        } else {
+            let path = match location.starts_with('/') {
+                true => location.to_owned(),
+                false => {
+                    // Relative to the directory of the current path.
+                    let path = self.path.split(['?', '#']).next().unwrap_or("/");
+                    let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+                    format!("{dir}{location}")
+                }
+            };
+            Self {
+                path: remove_dot_segments(&path),
+                ..self.clone()
+            }
+        };
+        ensure!(
+            !self.tls || url.tls,
+            "Refusing redirect from HTTPS to unencrypted {location}"
+        );
+        Ok(url)
+    }
+
+    /// Returns the value of the `Host` header.
+    fn host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match self.port == default_port(self.tls) {
+            true => host,
+            false => format!("{host}:{}", self.port),
+        }
+    }
+}
+
+/// Removes `.` and `..` segments from the absolute `path`, like browsers
+/// do for relative redirects.
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = match path.find('?') {
+        Some(index) => path.split_at(index),
+        None => (path, ""),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        match part {
+            "." if last => segments.push(""),
+            "." => (),
+            ".." => {
+                segments.pop();
+                if last {
+                    segments.push("");
+                }
+            }
+            part => segments.push(part),
+        }
+    }
+    format!("/{}{query}", segments.join("/"))
+}
+
+fn default_port(tls: bool) -> u16 {
+    match tls {
+        true => 443,
+        false => 80,
+    }
+}
+
+/// A connection to a server, encrypted for `https://` URLs.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            // Many servers close connections without notifying the client
+            // first. A truncated body is detected by its length instead.
+            Stream::Tls(stream) => match stream.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A response to a `GET` request, positioned at the start of its body.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: BufReader<Stream>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Downloads a file over HTTP, see the module documentation.
+pub struct HttpReader {
+    url: Url,
+    body: Option<BufReader<Stream>>,
+    pos: u64,
+    len: Option<u64>,
+}
+
+impl HttpReader {
+    /// Starts downloading the file at `url`, following redirects.
+    ///
+    /// Fails if the server doesn't respond with the file, e.g. with 404
+    /// Not Found.
+    pub fn open(url: &str) -> Result<Self> {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = get(&url, 0)?;
+            match response.status {
+                200 => {
+                    let len = content_length(&response)?;
+                    return Ok(Self {
+                        url,
+                        body: Some(response.body),
+                        pos: 0,
+                        len,
+                    });
+                }
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = response
+                        .header("Location")
+                        .context("Redirect without a location")?;
+                    url = url.join(location)?;
+                }
+                status => bail!("Server responded with status {status}"),
+            }
+        }
+        bail!("Too many redirects");
+    }
+
+    /// Returns the size of the file, if the server told it.
+    pub fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Checks whether the file is known to be empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == Some(0)
+    }
+
+    /// Returns the number of bytes downloaded so far.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Reconnects, requesting the rest of the file from the current
+    /// position.
+    fn reconnect(&mut self) -> Result<()> {
+        let mut response = get(&self.url, self.pos)?;
+        match response.status {
+            206 => {
+                let range = response
+                    .header("Content-Range")
+                    .context("Partial response without a content range")?;
+                let start = range
+                    .strip_prefix("bytes ")
+                    .and_then(|r| r.split('-').next())
+                    .and_then(|s| s.parse::<u64>().ok());
+                ensure!(
+                    start == Some(self.pos),
+                    "Server resumed at the wrong offset: {range}"
+                );
+            }
+            // The server ignored the range, skip what we already have.
+            200 => {
+                let skipped = io::copy(&mut (&mut response.body).take(self.pos), &mut io::sink())?;
+                ensure!(skipped == self.pos, "File shrank while resuming");
+            }
+            status => bail!("Server responded with status {status} when resuming"),
+        }
+        self.body = Some(response.body);
+        Ok(())
+    }
+
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let body = match self.body.as_mut() {
+            Some(body) => body,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        let len = match self.len {
+            Some(len) => (len - self.pos).min(buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+        let n = body.read(&mut buf[..len])?;
+        if n == 0 && len > 0 && self.len.is_some() {
+            // The connection closed before the whole file arrived.
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(n)
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_body(buf) {
+            Ok(n) => {
+                self.pos += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                // The connection is unusable after an error.
+                if e.kind() != io::ErrorKind::Interrupted {
+                    self.body = None;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Reconnects with a `Range` request. If the server doesn't support
+/// ranges, the download restarts and the bytes before `offset` are
+/// skipped.
+impl Resume for HttpReader {
+    fn resume(&mut self, offset: u64) -> io::Result<()> {
+        self.body = None;
+        self.pos = offset;
+        self.reconnect().map_err(io::Error::other)
+    }
+}
+
+/// Sends a `GET` request for `url`, starting at byte `start` of the file.
+fn get(url: &Url, start: u64) -> Result<Response> {
+    let mut stream = connect(url).with_context(|| format!("Connecting to {}", url.host))?;
+
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host_header()
+    );
+    if start > 0 {
+        request += &format!("Range: bytes={start}-\r\n");
+    }
+    request += "\r\n";
+    stream
+        .write_all(request.as_bytes())
+        .with_context(|| format!("Sending request to {}", url.host))?;
+
+    let mut body = BufReader::new(stream);
+    let status_line = read_line(&mut body)?;
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("Invalid HTTP status line `{status_line}`"))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut body)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("Invalid HTTP header `{line}`"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let response = Response {
+        status,
+        headers,
+        body,
+    };
+    if let Some(encoding) = response.header("Transfer-Encoding") {
+        ensure!(
+            encoding.eq_ignore_ascii_case("identity"),
+            "Transfer encoding `{encoding}` is not supported"
+        );
+    }
+    Ok(response)
+}
+
+fn connect(url: &Url) -> Result<Stream> {
+    let stream = connect_tcp(url)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    if !url.tls {
+        return Ok(Stream::Plain(stream));
+    }
+
+    // The handshake happens along with sending the request.
+    let name = ServerName::try_from(url.host.clone())
+        .map_err(Error::new)
+        .with_context(|| format!("Invalid host name {}", url.host))?;
+    let connection = ClientConnection::new(tls_config(), name).map_err(Error::new)?;
+    Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+}
+
+fn connect_tcp(url: &Url) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(match last_err {
+        Some(e) => e.into(),
+        None => Error::msg("Host not found"),
+    })
+}
+
+/// Returns the TLS settings shared by all connections.
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let config = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .expect("the default protocol versions are supported");
+            Arc::new(config.with_root_certificates(roots).with_no_client_auth())
+        })
+        .clone()
+}
+
+/// Reads a header line without its line ending.
+fn read_line<R: BufRead>(r: &mut R) -> Result<String> {
+    let mut line = String::new();
+    r.take(MAX_LINE).read_line(&mut line)?;
+    ensure!(line.ends_with('\n'), "Truncated HTTP response header");
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Returns the content length of a `200 OK` response, if given.
+fn content_length(response: &Response) -> Result<Option<u64>> {
+    match response.header("Content-Length") {
+        Some(len) => Ok(Some(len.parse().context("Invalid content length")?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::{Retry, RetryPolicy};
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn parse_url() {
+        let url = Url::parse("http://example.com:8080/images/system.simg?x=1").unwrap();
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/images/system.simg?x=1");
+        assert_eq!(url.host_header(), "example.com:8080");
+
+        let url = Url::parse("http://[::1]").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 80));
+        assert_eq!(url.path, "/");
+        assert_eq!(url.host_header(), "[::1]");
+
+        let url = Url::parse("https://example.com/system.simg").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.port, 443);
+        assert_eq!(url.host_header(), "example.com");
+        let url = Url::parse("https://example.com:80/").unwrap();
+        assert_eq!(url.host_header(), "example.com:80");
+
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn join_url() {
+        let url = Url::parse("https://example.com/images/v1/system.simg?x=1").unwrap();
+        let join = |location| url.join(location).unwrap();
+        assert_eq!(join("other.simg").path, "/images/v1/other.simg");
+        assert_eq!(join("../v2/./system.simg").path, "/images/v2/system.simg");
+        assert_eq!(join("../../../a?b=..").path, "/a?b=..");
+        assert_eq!(join("/system.simg").path, "/system.simg");
+        assert_eq!(join("/system.simg").host, "example.com");
+
+        let other = join("//mirror.example.com/system.simg");
+        assert!(other.tls);
+        assert_eq!(other.host, "mirror.example.com");
+        let other = join("https://mirror.example.com:8443/a");
+        assert_eq!(other.port, 8443);
+
+        // Downgrades to plain HTTP are refused, upgrades are fine.
+        assert!(url.join("http://example.com/system.simg").is_err());
+        let url = Url::parse("http://example.com/system.simg").unwrap();
+        assert!(url.join("https://example.com/system.simg").unwrap().tls);
+    }
+
+    /// Serves `content`, dropping connections halfway through unless they
+    /// resume a download.
+    fn serve(content: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut request).unwrap() > 2 {}
+                let start = request
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Range: bytes="))
+                    .map_or(0, |r| r.trim_end_matches('-').parse().unwrap());
+
+                let len = content.len();
+                let header = match start {
+                    0 => format!("HTTP/1.1 200 OK\r\nContent-Length: {len}\r\n\r\n"),
+                    _ => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{len}\r\n\r\n",
+                        len - 1
+                    ),
+                };
+                stream.write_all(header.as_bytes()).unwrap();
+                let end = if start == 0 { len / 2 } else { len };
+                stream.write_all(&content[start..end]).ok();
+            }
+        });
+        format!("http://{addr}/image")
+    }
+
+    #[test]
+    fn resume() {
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let url = serve(content.clone());
+
+        let reader = HttpReader::open(&url).unwrap();
+        assert_eq!(reader.len(), Some(content.len() as u64));
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let mut reader = Retry::resumable(reader, policy);
+        let mut downloaded = Vec::new();
+        reader.read_to_end(&mut downloaded).unwrap();
+        assert_eq!(downloaded, content);
+        assert_eq!(reader.get_ref().position(), content.len() as u64);
+
+        let mut reader = HttpReader::open(&url).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        // The plain HTTP server fails the TLS handshake.
+        let url = url.replace("http://", "https://");
+        assert!(HttpReader::open(&url).is_err());
+    }
+
+    #[test]
+    fn untrusted_certificate() {
+        use rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            ServerConfig, ServerConnection,
+        };
+
+        let cert = CertificateDer::from_pem_slice(include_bytes!("../tests/data/tls/cert.pem"));
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("../tests/data/tls/key.pem"));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.unwrap()], key.unwrap())
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(connection, stream);
+            // Reading drives the handshake, which the client aborts.
+            stream.read_exact(&mut [0; 1]).ok();
+        });
+
+        // The self-signed certificate isn't issued by a trusted authority.
+        let err = HttpReader::open(&format!("https://{addr}/image"))
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("UnknownIssuer"), "{err:#}");
+    }
+}
@@ -0,0 +1,72 @@
+//! Human-readable formatting of sizes and ratios.
+//!
+//! Both types implement `Display` and honor width and alignment, so they
+//! can be used in tables directly.
+
+use std::fmt;
+
+const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// A size in bytes, displayed with a binary unit, e.g. `1.5 MiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanSize(pub u64);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < 1024 {
+            return f.pad(&format!("{} B", self.0));
+        }
+
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        f.pad(&format!("{size:.1} {}", UNITS[unit]))
+    }
+}
+
+/// The ratio of two quantities, displayed as a percentage, e.g. `42.0%`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Percent {
+    /// The part of the whole.
+    pub part: u64,
+    /// The whole, displayed as 100%.
+    pub whole: u64,
+}
+
+impl Percent {
+    /// Creates the ratio of `part` to `whole`.
+    pub fn new(part: u64, whole: u64) -> Self {
+        Self { part, whole }
+    }
+}
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.whole {
+            0 => f.pad("-"),
+            whole => f.pad(&format!("{:.1}%", self.part as f64 * 100.0 / whole as f64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn human_size() {
+        assert_eq!(HumanSize(512).to_string(), "512 B");
+        assert_eq!(HumanSize(1536 * 1024).to_string(), "1.5 MiB");
+        assert_eq!(HumanSize(u64::MAX).to_string(), "16.0 EiB");
+        assert_eq!(format!("{:>9}", HumanSize(4096)), "  4.0 KiB");
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(Percent::new(1, 3).to_string(), "33.3%");
+        assert_eq!(Percent::new(1, 0).to_string(), "-");
+    }
+}
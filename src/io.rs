@@ -0,0 +1,926 @@
+//! I/O adapters for using readers, writers, encoders and decoders with
+//! streams they would not support otherwise, for writing output files
+//! safely, and for copying with progress reports.
+
+use crate::{
+    platform,
+    result::{Error, Result},
+};
+use std::{
+    error::Error as StdError,
+    ffi::OsString,
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, prelude::*, SeekFrom},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::NamedTempFile;
+
+/// Makes a non-seekable writer usable as a `Decoder` destination.
+///
+/// Decoders skip over the holes of a raw image by seeking forward. This
+/// adapter implements forward seeks by writing zeros instead, so the raw
+/// image can be streamed, e.g. to a pipe or socket. Seeking backward is
+/// not supported.
+pub struct ZeroSeek<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> ZeroSeek<W> {
+    /// Creates a new adapter writing to `inner`.
+    pub fn new(inner: W) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    /// Returns a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Consumes this adapter, returning the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ZeroSeek<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for ZeroSeek<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(_) => None,
+        };
+
+        let target = match target {
+            Some(t) if t >= self.pos => t,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "cannot seek backward in a stream",
+                ))
+            }
+        };
+
+        const ZEROS: [u8; 4096] = [0; 4096];
+        while self.pos < target {
+            let n = (target - self.pos).min(ZEROS.len() as u64) as usize;
+            self.write_all(&ZEROS[..n])?;
+        }
+        Ok(self.pos)
+    }
+}
+
+/// A file that is read and written with positioned I/O (pread/pwrite).
+///
+/// Each `PositionedFile` keeps its own position instead of using the
+/// file position, so several of them can share a single `File`, e.g. to
+/// read different parts of an image on worker threads. Seeking only
+/// updates the position and never touches the file.
+#[derive(Clone, Debug)]
+pub struct PositionedFile {
+    file: Arc<File>,
+    pos: u64,
+}
+
+impl PositionedFile {
+    /// Creates a new adapter for `file`, positioned at its start.
+    pub fn new<F: Into<Arc<File>>>(file: F) -> Self {
+        Self {
+            file: file.into(),
+            pos: 0,
+        }
+    }
+
+    /// Returns the shared file.
+    pub fn get_ref(&self) -> &Arc<File> {
+        &self.file
+    }
+}
+
+impl Read for PositionedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = platform::read_at(&self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for PositionedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = platform::write_at(&self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PositionedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.file.metadata()?.len().checked_add_signed(n),
+        };
+
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Opens independent handles to the same file for worker threads.
+///
+/// Readers, encoders, writers and decoders are `Send` if their stream is,
+/// but a single stream cannot be used by several threads at once. This
+/// describes how to get a separate handle to a file on every thread.
+#[derive(Clone, Debug)]
+pub enum ClonePerThread {
+    /// Reopens the file at this path for reading, giving every handle its
+    /// own file position.
+    Reopen(PathBuf),
+    /// Shares this file, e.g. one that was passed in as a file descriptor
+    /// and cannot be reopened. Duplicated handles share their file
+    /// position, so they must only be used with positioned I/O.
+    Dup(Arc<File>),
+}
+
+impl ClonePerThread {
+    /// Opens a handle that can be used independently of all others.
+    pub fn open_positioned(&self) -> io::Result<PositionedFile> {
+        match self {
+            Self::Reopen(path) => Ok(PositionedFile::new(File::open(path)?)),
+            Self::Dup(file) => Ok(PositionedFile::new(Arc::clone(file))),
+        }
+    }
+
+    /// Opens a plain file handle.
+    ///
+    /// For `Dup`, this duplicates the file descriptor, which shares its
+    /// file position with all other handles.
+    pub fn open(&self) -> io::Result<File> {
+        match self {
+            Self::Reopen(path) => File::open(path),
+            Self::Dup(file) => file.try_clone(),
+        }
+    }
+}
+
+/// A file that only appears at its path once it has been written
+/// completely.
+///
+/// Data is written to a temporary file in the destination directory, which
+/// `commit` atomically renames to the final path. If the file is dropped
+/// without being committed, e.g. because writing failed, the temporary file
+/// is removed and an existing file at the final path is left untouched.
+///
+/// Existing paths that are not regular files, like devices or pipes,
+/// cannot be replaced. They are written to directly.
+///
+/// Writers and decoders can write to an `AtomicFile` by reference:
+///
+/// ```no_run
+/// # fn main() -> android_sparse::Result<()> {
+/// # let blocks: Vec<android_sparse::Block> = Vec::new();
+/// use android_sparse::{io::AtomicFile, Writer};
+///
+/// let mut file = AtomicFile::create("system.simg")?;
+/// let mut writer = Writer::new(&mut file, false)?;
+/// for block in &blocks {
+///     writer.write_block(block)?;
+/// }
+/// writer.close()?;
+/// file.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AtomicFile {
+    file: Target,
+    path: PathBuf,
+    /// Whether `commit` fails instead of replacing an existing file.
+    no_clobber: bool,
+    _pending: Option<Pending>,
+}
+
+enum Target {
+    Temp(NamedTempFile),
+    Direct(File),
+}
+
+impl AtomicFile {
+    /// Creates a temporary file that will be renamed to `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.metadata().is_ok_and(|m| !m.is_file()) {
+            let file = OpenOptions::new().write(true).open(path)?;
+            return Ok(Self {
+                file: Target::Direct(file),
+                path: path.into(),
+                no_clobber: false,
+                _pending: None,
+            });
+        }
+        Self::create_temp(path, false)
+    }
+
+    /// Creates a temporary file that will be renamed to `path`, which must
+    /// not exist.
+    ///
+    /// Fails with `io::ErrorKind::AlreadyExists` if it does, and so does
+    /// `commit` if a file has been created at `path` in the meantime.
+    pub fn create_new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.symlink_metadata().is_ok() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        Self::create_temp(path, true)
+    }
+
+    fn create_temp(path: &Path, no_clobber: bool) -> io::Result<Self> {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+
+        let mut prefix = OsString::from(".");
+        prefix.push(path.file_name().unwrap_or_default());
+
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+        // Temporary files are private by default, but the final file should
+        // get the same permissions as any other newly created file.
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+
+        let tmp = builder.tempfile_in(dir)?;
+        Ok(Self {
+            _pending: Some(Pending::new(tmp.path())),
+            file: Target::Temp(tmp),
+            path: path.into(),
+            no_clobber,
+        })
+    }
+
+    /// Returns the final path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the file being written.
+    pub fn as_file(&self) -> &File {
+        match &self.file {
+            Target::Temp(tmp) => tmp.as_file(),
+            Target::Direct(file) => file,
+        }
+    }
+
+    fn as_file_mut(&mut self) -> &mut File {
+        match &mut self.file {
+            Target::Temp(tmp) => tmp.as_file_mut(),
+            Target::Direct(file) => file,
+        }
+    }
+
+    /// Renames the file to its final path, replacing any existing file
+    /// unless created with `create_new`.
+    pub fn commit(self) -> io::Result<File> {
+        match self.file {
+            Target::Temp(tmp) if self.no_clobber => {
+                tmp.persist_noclobber(&self.path).map_err(|e| e.error)
+            }
+            Target::Temp(tmp) => tmp.persist(&self.path).map_err(|e| e.error),
+            Target::Direct(file) => Ok(file),
+        }
+    }
+}
+
+/// The temporary files of all `AtomicFile`s that are neither committed nor
+/// dropped yet.
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Registers a temporary file in `PENDING` while it is alive.
+struct Pending(PathBuf);
+
+impl Pending {
+    fn new(path: &Path) -> Self {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push(path.into());
+        Self(path.into())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = pending.iter().position(|p| *p == self.0) {
+            pending.swap_remove(index);
+        }
+    }
+}
+
+/// Removes the temporary files of all `AtomicFile`s that haven't been
+/// committed yet, returning how many were removed.
+///
+/// This is meant for exiting early, e.g. when the process is interrupted,
+/// as the temporary files are otherwise only removed when their
+/// `AtomicFile` is dropped. The affected `AtomicFile`s fail to commit
+/// afterward.
+pub fn remove_pending() -> usize {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    pending
+        .drain(..)
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .count()
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.as_file_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.as_file_mut().flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.as_file_mut().seek(pos)
+    }
+}
+
+/// Destinations whose written data can be synced to storage.
+///
+/// Writers and decoders use this for `sync_on_close`.
+pub trait SyncData {
+    /// Waits until all written data has reached the storage device, like
+    /// `File::sync_data`.
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+impl SyncData for PositionedFile {
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl SyncData for AtomicFile {
+    fn sync_data(&self) -> io::Result<()> {
+        self.as_file().sync_data()
+    }
+}
+
+impl<T: SyncData + ?Sized> SyncData for &T {
+    fn sync_data(&self) -> io::Result<()> {
+        (**self).sync_data()
+    }
+}
+
+impl<T: SyncData + ?Sized> SyncData for &mut T {
+    fn sync_data(&self) -> io::Result<()> {
+        (**self).sync_data()
+    }
+}
+
+/// Fails if `input` and `output` are the same file.
+///
+/// Decoding or encoding an image onto itself overwrites its data before it
+/// has been read, so tools should check this before writing. Files are
+/// compared by identity rather than path, which catches hard links,
+/// symbolic links and `/dev/fd` paths. Only supported on Unix; elsewhere,
+/// files are always considered distinct.
+pub fn ensure_distinct(input: &File, output: &File) -> io::Result<()> {
+    match platform::same_file(&input.metadata()?, &output.metadata()?) {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the input and output are the same file",
+        )),
+        false => Ok(()),
+    }
+}
+
+/// The error returned when a file is locked by someone else.
+///
+/// Can be told apart from other errors with `Error::downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlreadyLocked;
+
+impl fmt::Display for AlreadyLocked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the file is locked by another writer")
+    }
+}
+
+impl StdError for AlreadyLocked {}
+
+/// Takes an exclusive advisory lock on `file`, failing with `AlreadyLocked`
+/// instead of waiting if someone else holds a lock on it.
+///
+/// The lock (`flock` on Unix) is held until all handles of `file`,
+/// including its clones, are closed. It only keeps out writers that lock
+/// the file too, e.g. concurrent conversions writing the same image.
+pub fn try_lock(file: &File) -> Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Error::new(AlreadyLocked)),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// The size of the buffers `ReadAhead` reads into.
+const READ_AHEAD_BUF_SIZE: usize = 256 * 1024;
+
+/// Reads ahead from a slow source on a background thread.
+///
+/// Consumers that alternate between reading and processing, like a
+/// `Reader` decoding an image as it is downloaded, otherwise leave the
+/// source idle while processing. `ReadAhead` keeps reading into up to a
+/// given number of buffers of 256 KiB in the meantime. Errors of the
+/// source are returned once the data read before them has been consumed.
+pub struct ReadAhead {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl ReadAhead {
+    /// Starts reading from `r`, keeping up to `buffers` buffers filled
+    /// ahead of the consumer.
+    pub fn new<R: Read + Send + 'static>(mut r: R, buffers: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel(buffers.max(1));
+        thread::spawn(move || loop {
+            let mut buf = vec![0; READ_AHEAD_BUF_SIZE];
+            let result = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(buf)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = result.is_err();
+            // The consumer hung up, so nobody needs more data.
+            if tx.send(result).is_err() || failed {
+                break;
+            }
+        });
+        Self {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() && !self.finished {
+            match self.rx.recv() {
+                Ok(result) => {
+                    self.buf = result?;
+                    self.pos = 0;
+                }
+                Err(_) => self.finished = true,
+            }
+        }
+
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// How a `Retry` wrapper retries failed reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times a read is retried in a row before giving up.
+    pub retries: u32,
+    /// How long to wait before the first retry. The delay doubles with
+    /// every further retry.
+    pub backoff: Duration,
+    /// The longest delay between two retries.
+    pub max_backoff: Duration,
+    /// How long retrying a read may take in total before giving up, if
+    /// limited.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// 3 retries, waiting 0.5 seconds before the first one, at most 30
+    /// seconds between two retries and no overall timeout.
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries.
+    pub const NONE: Self = Self {
+        retries: 0,
+        backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+        timeout: None,
+    };
+
+    /// Returns the delay before retry number `attempt`, starting at 0.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Sources that can continue reading at an offset after a failure, e.g.
+/// by reconnecting. See `Retry::resumable`.
+pub trait Resume: Read {
+    /// Prepares to continue reading at byte `offset` of the source.
+    fn resume(&mut self, offset: u64) -> io::Result<()>;
+}
+
+/// Continues reading `R` at `offset` after a failure.
+type ResumeFn<R> = fn(&mut R, u64) -> io::Result<()>;
+
+/// A callback invoked before a failed read is retried.
+type RetryCallback = Box<dyn FnMut(&io::Error, u32) + Send>;
+
+/// Retries failed reads of a flaky source, like a file on a network
+/// filesystem or a download.
+///
+/// Once a read fails, the source is resumed at the offset after the last
+/// byte read successfully, and the read is retried according to a
+/// `RetryPolicy`. Seekable sources are resumed by seeking, others through
+/// `Resume`. Only the last error is returned once the policy gives up.
+pub struct Retry<R> {
+    inner: R,
+    pos: u64,
+    policy: RetryPolicy,
+    resume: ResumeFn<R>,
+    on_retry: Option<RetryCallback>,
+}
+
+impl<R: Read + Seek> Retry<R> {
+    /// Creates a new wrapper reading from `inner` from its current
+    /// position, resuming by seeking.
+    ///
+    /// Streams whose position can't be determined, like pipes, can't be
+    /// resumed, so their reads are never retried.
+    pub fn new(mut inner: R, policy: RetryPolicy) -> Self {
+        let (pos, policy) = match inner.stream_position() {
+            Ok(pos) => (pos, policy),
+            Err(_) => (0, RetryPolicy::NONE),
+        };
+        Self::with_resume(inner, pos, policy, |r, offset| {
+            r.seek(SeekFrom::Start(offset)).map(drop)
+        })
+    }
+}
+
+impl<R: Resume> Retry<R> {
+    /// Creates a new wrapper reading from `inner`, which must be at the
+    /// start of the source, resuming through `Resume`.
+    pub fn resumable(inner: R, policy: RetryPolicy) -> Self {
+        Self::with_resume(inner, 0, policy, R::resume)
+    }
+}
+
+impl<R: Read> Retry<R> {
+    fn with_resume(inner: R, pos: u64, policy: RetryPolicy, resume: ResumeFn<R>) -> Self {
+        Self {
+            inner,
+            pos,
+            policy,
+            resume,
+            on_retry: None,
+        }
+    }
+
+    /// Calls `f` with the error and the number of the retry, starting at
+    /// 1, before a failed read is retried, e.g. to log it.
+    pub fn on_retry<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&io::Error, u32) + Send + 'static,
+    {
+        self.on_retry = Some(Box::new(f));
+        self
+    }
+
+    /// Returns a reference to the wrapped source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Consumes this wrapper, returning the wrapped source.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Retry<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let mut err = match self.inner.read(buf) {
+                Ok(n) => {
+                    self.pos += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+
+            // Keep retrying as long as resuming fails, too.
+            loop {
+                let timed_out = self.policy.timeout.is_some_and(|t| start.elapsed() >= t);
+                if attempt >= self.policy.retries || timed_out {
+                    return Err(err);
+                }
+                thread::sleep(self.policy.delay(attempt));
+                attempt += 1;
+                if let Some(f) = self.on_retry.as_mut() {
+                    f(&err, attempt);
+                }
+                match (self.resume)(&mut self.inner, self.pos) {
+                    Ok(()) => break,
+                    Err(e) => err = e,
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for Retry<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// The size of the buffer `copy_with_progress` copies through.
+const COPY_BUF_SIZE: usize = 1024 * 1024;
+
+/// Copies all bytes from `r` to `w` like `io::copy`, reporting progress.
+///
+/// `progress` is called with the number of bytes copied so far after
+/// every write of up to 1 MiB. If it returns `ControlFlow::Break`, copying
+/// stops and fails with an error of kind `Other`. Returns the number of
+/// bytes copied.
+pub fn copy_with_progress<R, W, F>(r: &mut R, w: &mut W, mut progress: F) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+    F: FnMut(u64) -> ControlFlow<()>,
+{
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let len = match r.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        w.write_all(&buf[..len])?;
+        copied += len as u64;
+
+        if progress(copied).is_break() {
+            return Err(io::Error::other("Copy cancelled"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn zero_seek() {
+        let mut w = ZeroSeek::new(Vec::new());
+        w.write_all(b"ab").unwrap();
+        assert_eq!(w.seek(SeekFrom::Current(3)).unwrap(), 5);
+        w.write_all(b"c").unwrap();
+        assert_eq!(w.seek(SeekFrom::Start(8)).unwrap(), 8);
+        assert_eq!(w.stream_position().unwrap(), 8);
+        assert!(w.seek(SeekFrom::Start(2)).is_err());
+
+        assert_eq!(w.into_inner(), b"ab\0\0\0c\0\0");
+    }
+
+    #[test]
+    fn positioned_file() {
+        let file = Arc::new(tempfile::tempfile().unwrap());
+        let mut a = PositionedFile::new(Arc::clone(&file));
+        let mut b = PositionedFile::new(Arc::clone(&file));
+
+        a.write_all(b"hello").unwrap();
+        b.seek(SeekFrom::Start(8)).unwrap();
+        b.write_all(b"world").unwrap();
+        a.write_all(b"!").unwrap();
+
+        let mut buf = Vec::new();
+        a.seek(SeekFrom::Start(0)).unwrap();
+        a.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello!\0\0world");
+        assert_eq!(b.seek(SeekFrom::End(-5)).unwrap(), 8);
+        assert!(b.seek(SeekFrom::Current(-9)).is_err());
+    }
+
+    #[test]
+    fn atomic_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image");
+        std::fs::write(&path, b"old").unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        file.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let err = AtomicFile::create_new(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let other = dir.path().join("other");
+        let mut file = AtomicFile::create_new(&other).unwrap();
+        file.write_all(b"new").unwrap();
+        std::fs::write(&other, b"old").unwrap();
+        let err = file.commit().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&other).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn distinct_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image");
+        let file = File::create(&path).unwrap();
+        let link = dir.path().join("link");
+        std::fs::hard_link(&path, &link).unwrap();
+
+        let other = tempfile::tempfile().unwrap();
+        assert!(ensure_distinct(&file, &other).is_ok());
+        assert!(ensure_distinct(&file, &File::open(&path).unwrap()).is_err());
+        assert!(ensure_distinct(&file, &File::open(&link).unwrap()).is_err());
+    }
+
+    #[test]
+    fn lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image");
+        let file = File::create(&path).unwrap();
+        try_lock(&file).unwrap();
+
+        let other = File::open(&path).unwrap();
+        let err = try_lock(&other).unwrap_err();
+        assert_eq!(err.downcast_ref::<AlreadyLocked>(), Some(&AlreadyLocked));
+
+        drop(file);
+        try_lock(&other).unwrap();
+    }
+
+    #[test]
+    fn copy_progress() {
+        let data = vec![7; COPY_BUF_SIZE + 5];
+        let mut copied = Vec::new();
+        let mut reports = Vec::new();
+        let len = copy_with_progress(&mut &data[..], &mut copied, |n| {
+            reports.push(n);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(copied, data);
+        assert_eq!(reports, [COPY_BUF_SIZE as u64, data.len() as u64]);
+
+        let mut copied = Vec::new();
+        let result = copy_with_progress(&mut &data[..], &mut copied, |_| ControlFlow::Break(()));
+        assert!(result.is_err());
+        assert_eq!(copied.len(), COPY_BUF_SIZE);
+    }
+
+    #[test]
+    fn read_ahead() {
+        let data: Vec<u8> = (0..READ_AHEAD_BUF_SIZE * 3 + 5).map(|i| i as u8).collect();
+        let mut read = Vec::new();
+        ReadAhead::new(Cursor::new(data.clone()), 2)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        // Data read before an error is returned first.
+        let failing = Cursor::new(data[..10].to_vec()).chain(FailingReader);
+        let mut reader = ReadAhead::new(failing, 2);
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert!(reader.read(&mut buf).is_err());
+    }
+
+    /// Fails every other read, moving the position like a partial read.
+    struct Flaky {
+        inner: Cursor<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            if self.fail {
+                self.inner.seek(SeekFrom::Current(1))?;
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            let len = buf.len().min(7);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for Flaky {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let data: Vec<u8> = (0..100).collect();
+        let flaky = || Flaky {
+            inner: Cursor::new(data.clone()),
+            fail: false,
+        };
+        let policy = RetryPolicy {
+            backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+
+        let retries = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&retries);
+        let mut read = Vec::new();
+        Retry::new(flaky(), policy)
+            .on_retry(move |_, attempt| {
+                assert_eq!(attempt, 1);
+                *counter.lock().unwrap() += 1;
+            })
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(*retries.lock().unwrap() > 0);
+
+        let mut retry = Retry::new(flaky(), RetryPolicy::NONE);
+        let err = retry.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(RetryPolicy::default().delay(2), Duration::from_secs(2));
+        assert_eq!(RetryPolicy::default().delay(40), Duration::from_secs(30));
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+    }
+}
@@ -0,0 +1,523 @@
+//! Conversion jobs running in the background.
+//!
+//! Front ends like GUIs and servers convert images without blocking on
+//! them. A `JobQueue` runs submitted conversions one after another on a
+//! worker thread. Each gets a `JobId`, with which its `Status` is polled,
+//! e.g. to show progress, and which cancels it. Whether a job finished,
+//! failed or was cancelled, the outcome is reported in its status, which
+//! `remove` fetches once the job is done.
+//!
+//! Conversions read from and write to files or streams, e.g. pipes or
+//! sockets. `simg_serve` doesn't queue its requests, though: it encodes or
+//! decodes as requested instead of detecting the input format, and answers
+//! invalid input with an HTTP error before sending any output, so it
+//! handles requests on its own worker threads.
+
+use crate::{
+    convert::{self, Format},
+    human::Percent,
+    io::AtomicFile,
+    result::{Context, Result},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, prelude::*, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Identifies a job of a `JobQueue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A conversion of an image to the respective other format, as done by
+/// `convert::auto_convert`.
+#[derive(Debug)]
+pub struct Conversion {
+    /// The image to convert.
+    pub input: Input,
+    /// Where to write the converted image.
+    pub output: Output,
+}
+
+impl Conversion {
+    /// Creates a conversion of the image file `input` to `output`.
+    pub fn files<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Self {
+        Self {
+            input: Input::File(input.as_ref().to_owned()),
+            output: Output::File(output.as_ref().to_owned()),
+        }
+    }
+
+    /// Creates a conversion of the image read from `input` to `output`.
+    pub fn streams<R, W>(input: R, output: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Self {
+            input: Input::Stream(Box::new(input)),
+            output: Output::Stream(Box::new(output)),
+        }
+    }
+}
+
+/// The image a conversion reads.
+pub enum Input {
+    /// An image file.
+    File(PathBuf),
+    /// A stream yielding the image, whose size is only known once it ended.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::File(path) => f.debug_tuple("File").field(path).finish(),
+            Input::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+/// Where a conversion writes the converted image.
+pub enum Output {
+    /// A file, which is only created or replaced once the conversion
+    /// succeeded.
+    File(PathBuf),
+    /// A stream, which gets everything converted before a failure, so the
+    /// state of the job tells whether the output is complete.
+    Stream(Box<dyn Write + Send>),
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Output::File(path) => f.debug_tuple("File").field(path).finish(),
+            Output::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+/// The outcome of a finished conversion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// The detected format of the input image.
+    pub format: Format,
+    /// The size of the input image in bytes.
+    pub input_size: u64,
+    /// The size of the output image in bytes.
+    pub output_size: u64,
+    /// How long the conversion took.
+    pub duration: Duration,
+}
+
+/// The state of a job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The job waits for the jobs submitted before it.
+    Queued,
+    /// The conversion is running.
+    Running,
+    /// The conversion succeeded.
+    Finished(Summary),
+    /// The conversion failed with this error, including its context.
+    Failed(String),
+    /// The job was cancelled. The output was left untouched.
+    Cancelled,
+}
+
+impl State {
+    /// Checks whether the job is done, i.e. won't change its state anymore.
+    pub fn is_done(&self) -> bool {
+        !matches!(self, State::Queued | State::Running)
+    }
+}
+
+/// The status of a job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// The state of the job.
+    pub state: State,
+    /// The number of bytes of the input read so far.
+    pub bytes_read: u64,
+    /// The size of the input in bytes, once the job is running, or 0 if
+    /// it is read from a stream.
+    pub total_bytes: u64,
+}
+
+impl Status {
+    /// Returns the share of the input read so far.
+    pub fn progress(&self) -> Percent {
+        Percent::new(self.bytes_read, self.total_bytes)
+    }
+}
+
+/// The progress of a job, updated by the worker without locking.
+#[derive(Default)]
+struct Tracker {
+    bytes_read: AtomicU64,
+    total_bytes: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+struct Job {
+    state: State,
+    tracker: Arc<Tracker>,
+}
+
+impl Job {
+    fn status(&self) -> Status {
+        Status {
+            state: self.state.clone(),
+            bytes_read: self.tracker.bytes_read.load(Ordering::Relaxed),
+            total_bytes: self.tracker.total_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// State shared by a queue and its worker.
+#[derive(Default)]
+struct Shared {
+    jobs: Mutex<HashMap<JobId, Job>>,
+    /// Notified whenever a job changes its state.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn set_state(&self, id: JobId, state: State) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.state = state;
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// A queue of conversion jobs, run one after another on a worker thread.
+///
+/// Dropping the queue cancels all jobs that are not done yet and waits for
+/// the running one to stop.
+pub struct JobQueue {
+    shared: Arc<Shared>,
+    next_id: AtomicU64,
+    sender: Option<mpsc::Sender<(JobId, Conversion)>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl JobQueue {
+    /// Creates an empty queue and starts its worker thread.
+    pub fn new() -> Self {
+        let shared = Arc::new(Shared::default());
+        let (sender, receiver) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            thread::spawn(move || work(&shared, receiver))
+        };
+        Self {
+            shared,
+            next_id: AtomicU64::new(1),
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Queues `conversion`, returning the ID of its job.
+    pub fn submit(&self, conversion: Conversion) -> JobId {
+        let id = JobId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let job = Job {
+            state: State::Queued,
+            tracker: Arc::default(),
+        };
+        self.shared.jobs.lock().unwrap().insert(id, job);
+        if let Some(sender) = &self.sender {
+            // The worker only stops once the queue is dropped.
+            let _ = sender.send((id, conversion));
+        }
+        id
+    }
+
+    /// Returns the status of the job `id`, if there is such a job.
+    pub fn status(&self, id: JobId) -> Option<Status> {
+        self.shared.jobs.lock().unwrap().get(&id).map(Job::status)
+    }
+
+    /// Cancels the job `id`, returning whether it wasn't done yet.
+    ///
+    /// Queued jobs are cancelled right away, running ones once their
+    /// conversion notices, see `wait`.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&id) else {
+            return false;
+        };
+        match job.state {
+            State::Queued => {
+                job.state = State::Cancelled;
+                self.shared.changed.notify_all();
+                true
+            }
+            State::Running => {
+                job.tracker.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Waits until the job `id` is done, returning its final status, if
+    /// there is such a job.
+    pub fn wait(&self, id: JobId) -> Option<Status> {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        loop {
+            let status = jobs.get(&id)?.status();
+            if status.state.is_done() {
+                return Some(status);
+            }
+            jobs = self.shared.changed.wait(jobs).unwrap();
+        }
+    }
+
+    /// Forgets the job `id` if it is done, returning its final status.
+    ///
+    /// Long-running services should remove jobs once their outcome has
+    /// been reported, as the queue keeps all of them otherwise.
+    pub fn remove(&self, id: JobId) -> Option<Status> {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        if !jobs.get(&id)?.state.is_done() {
+            return None;
+        }
+        jobs.remove(&id).map(|job| job.status())
+    }
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for JobQueue {
+    fn drop(&mut self) {
+        let ids: Vec<_> = self.shared.jobs.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            self.cancel(id);
+        }
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs the jobs received from `jobs` until the queue is dropped.
+fn work(shared: &Shared, jobs: mpsc::Receiver<(JobId, Conversion)>) {
+    for (id, conversion) in jobs {
+        let tracker = {
+            let mut jobs = shared.jobs.lock().unwrap();
+            match jobs.get_mut(&id) {
+                Some(job) if job.state == State::Queued => {
+                    job.state = State::Running;
+                    job.tracker.clone()
+                }
+                // Cancelled while queued.
+                _ => continue,
+            }
+        };
+        shared.changed.notify_all();
+
+        let state = match run(conversion, &tracker) {
+            Ok(summary) => State::Finished(summary),
+            Err(_) if tracker.cancelled.load(Ordering::Relaxed) => State::Cancelled,
+            Err(err) => State::Failed(format!("{err:#}")),
+        };
+        shared.set_state(id, state);
+    }
+}
+
+fn run(conversion: Conversion, tracker: &Tracker) -> Result<Summary> {
+    let start = Instant::now();
+    let (input, input_size): (Box<dyn Read + Send>, _) = match conversion.input {
+        Input::File(path) => {
+            let input = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+            let input_size = input.metadata()?.len();
+            tracker.total_bytes.store(input_size, Ordering::Relaxed);
+            (Box::new(BufReader::new(input)), Some(input_size))
+        }
+        Input::Stream(input) => (input, None),
+    };
+    let input = Tracked {
+        inner: input,
+        tracker,
+    };
+
+    let (format, output_size) = match conversion.output {
+        Output::File(path) => {
+            let mut output = AtomicFile::create(&path)
+                .with_context(|| format!("Creating {}", path.display()))?;
+            let format = convert::auto_convert(input, BufWriter::new(&mut output))?;
+            let output_size = output.as_file().metadata()?.len();
+            output.commit()?;
+            (format, output_size)
+        }
+        Output::Stream(output) => {
+            let mut output = Counted {
+                inner: output,
+                len: 0,
+            };
+            let format = convert::auto_convert(input, BufWriter::new(&mut output))?;
+            (format, output.len)
+        }
+    };
+
+    Ok(Summary {
+        format,
+        input_size: input_size.unwrap_or(tracker.bytes_read.load(Ordering::Relaxed)),
+        output_size,
+        duration: start.elapsed(),
+    })
+}
+
+/// Counts the bytes read from `inner`, and fails reading once the job is
+/// cancelled.
+struct Tracked<'a, R> {
+    inner: R,
+    tracker: &'a Tracker,
+}
+
+impl<R: Read> Read for Tracked<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tracker.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("Conversion cancelled"));
+        }
+        let len = self.inner.read(buf)?;
+        self.tracker
+            .bytes_read
+            .fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+/// Counts the bytes written to `inner`.
+struct Counted<W> {
+    inner: W,
+    len: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn convert_jobs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = |name| tmpdir.path().join(name);
+        let raw: Vec<u8> = (0..3 * 4096).map(|i| (i / 4096) as u8).collect();
+        fs::write(path("raw.img"), &raw).unwrap();
+
+        let queue = JobQueue::new();
+        let encode = queue.submit(Conversion::files(path("raw.img"), path("sparse.simg")));
+        let missing = queue.submit(Conversion::files(path("missing.img"), path("missing.simg")));
+
+        let status = queue.wait(encode).unwrap();
+        let State::Finished(summary) = &status.state else {
+            panic!("unexpected state {:?}", status.state);
+        };
+        assert_eq!(summary.format, Format::Raw);
+        assert_eq!(summary.input_size, raw.len() as u64);
+        assert_eq!(status.progress().to_string(), "100.0%");
+        assert!(!queue.cancel(encode));
+
+        let status = queue.wait(missing).unwrap();
+        assert!(matches!(status.state, State::Failed(msg) if msg.starts_with("Opening")));
+        assert!(!path("missing.simg").exists());
+
+        let decode = queue.submit(Conversion::files(path("sparse.simg"), path("decoded.img")));
+        queue.wait(decode).unwrap();
+        assert!(matches!(
+            queue.remove(decode).unwrap().state,
+            State::Finished(_)
+        ));
+        assert_eq!(queue.status(decode), None);
+        assert_eq!(fs::read(path("decoded.img")).unwrap(), raw);
+    }
+
+    #[test]
+    fn convert_streams() {
+        let raw: Vec<u8> = (0..3 * 4096).map(|i| (i / 4096) as u8).collect();
+        let mut sparse = tempfile::tempfile().unwrap();
+
+        let queue = JobQueue::new();
+        let input = io::Cursor::new(raw.clone());
+        let encode = queue.submit(Conversion::streams(input, sparse.try_clone().unwrap()));
+        let status = queue.wait(encode).unwrap();
+        let State::Finished(summary) = &status.state else {
+            panic!("unexpected state {:?}", status.state);
+        };
+        assert_eq!(summary.format, Format::Raw);
+        assert_eq!(summary.input_size, raw.len() as u64);
+        assert_eq!(summary.output_size, sparse.metadata().unwrap().len());
+        assert_eq!(status.total_bytes, 0);
+
+        sparse.rewind().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let decode = queue.submit(Conversion::streams(sparse, Sender(sender)));
+        assert!(matches!(
+            queue.wait(decode).unwrap().state,
+            State::Finished(_)
+        ));
+        assert_eq!(receiver.iter().flatten().collect::<Vec<_>>(), raw);
+    }
+
+    /// Passes what is written to a channel.
+    struct Sender(mpsc::Sender<Vec<u8>>);
+
+    impl Write for Sender {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cancel_reading() {
+        let tracker = Tracker::default();
+        let mut input = Tracked {
+            inner: &[1, 2, 3][..],
+            tracker: &tracker,
+        };
+        let mut buf = [0; 2];
+        assert_eq!(input.read(&mut buf).unwrap(), 2);
+        tracker.cancelled.store(true, Ordering::Relaxed);
+        assert!(input.read(&mut buf).is_err());
+        assert_eq!(tracker.bytes_read.load(Ordering::Relaxed), 2);
+    }
+}
@@ -12,15 +12,95 @@
 
 #![deny(missing_docs)]
 
+pub mod adapter;
+#[cfg(feature = "anonymize")]
+pub mod anonymize;
 pub mod block;
+#[cfg(feature = "uniffi")]
+pub mod bindings;
+#[cfg(feature = "bmap")]
+pub mod bmap;
+#[cfg(feature = "care_map")]
+pub mod care_map;
+pub mod carve;
+pub mod checksum;
+pub mod chunk;
+pub mod classify;
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod cli;
+pub mod compress;
+pub mod convert;
+pub mod corpus;
+pub mod diff;
+pub mod dump;
+pub mod entropy;
+pub mod extents;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod gen;
+pub mod headers;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod human;
+pub mod io;
+pub mod jobs;
+pub mod lint;
+pub mod merge;
+pub mod metadata;
+pub mod pipeline;
+pub mod platform;
+pub mod prelude;
+pub mod profile;
+#[cfg(feature = "qcow2")]
+pub mod qcow2;
 pub mod read;
+pub mod result;
+pub mod script;
+pub mod session;
+pub mod settings;
+pub mod sidecar;
+#[cfg(feature = "sign")]
+pub mod sign;
+pub mod space;
+pub mod splice;
+pub mod split;
+pub mod strings;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+#[cfg(feature = "cli")]
+pub mod tools;
+#[cfg(feature = "verity")]
+pub mod verity;
+pub mod view;
+#[cfg(feature = "vhd")]
+pub mod vhd;
+#[cfg(feature = "vhdx")]
+pub mod vhdx;
+#[cfg(feature = "vmdk")]
+pub mod vmdk;
 pub mod write;
 
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+#[cfg(any(
+    feature = "qcow2",
+    feature = "vhd",
+    feature = "vhdx",
+    feature = "vmdk"
+))]
+mod container;
 mod ext;
-mod headers;
+mod par;
 
 pub use self::{
-    block::Block,
-    read::{Encoder, Reader},
+    adapter::IterSource,
+    block::{Block, BlockBuf, CrcPlacement, CrcPolicy},
+    convert::auto_convert,
+    merge::compose,
+    pipeline::{BlockSink, BlockSource},
+    read::{BlockCountPolicy, Encoder, EncoderOptions, EncoderStats, Reader},
+    result::{Error, Result},
     write::{Decoder, Writer},
 };
@@ -0,0 +1,270 @@
+//! Strict validation of sparse images against device limits.
+//!
+//! `lint` checks an image's structure more strictly than `Reader` does,
+//! detects quirks of vendor tools that some parsers choke on, and checks
+//! the constraints of a device `Profile`, e.g. the maximum download size
+//! of a fastboot bootloader. The result is a `Report` of findings, which
+//! fails if any finding is an error.
+
+use crate::{
+    block::Block, dump::Chunks, headers::ChunkType, human::HumanSize, profile::Profile,
+    read::Reader, result::Result,
+};
+use std::{
+    fmt,
+    io::{prelude::*, SeekFrom},
+};
+
+/// How severe a finding is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The image may be rejected by some parsers.
+    Warning,
+    /// The image is invalid or exceeds the limits of the profile.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in a sparse image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    /// How severe the problem is.
+    pub severity: Severity,
+    /// The index and offset of the chunk the problem is in, if any.
+    pub chunk: Option<(u32, u64)>,
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        if let Some((index, offset)) = self.chunk {
+            write!(f, "chunk {index} at {offset:#x}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// The findings of linting a sparse image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The findings, in the order they were found.
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Checks whether no finding is an error.
+    pub fn passed(&self) -> bool {
+        self.findings.iter().all(|f| f.severity < Severity::Error)
+    }
+
+    fn add(&mut self, severity: Severity, chunk: Option<(u32, u64)>, message: String) {
+        self.findings.push(Finding {
+            severity,
+            chunk,
+            message,
+        });
+    }
+}
+
+/// Lints the sparse image in `r` against `profile`.
+///
+/// Fails only if `r` is not a sparse image at all; all other problems are
+/// reported as findings. The checksum is verified if the image has one,
+/// which requires reading the image twice.
+pub fn lint<R: Read + Seek>(mut r: R, profile: &Profile) -> Result<Report> {
+    let start = r.stream_position()?;
+    let mut report = Report::default();
+
+    let mut chunks = Chunks::new(&mut r)?;
+    let header = chunks.header().clone();
+    if let Err(err) = profile.check_supported() {
+        report.add(Severity::Error, None, err.to_string());
+    }
+    if header.image_checksum != 0 {
+        report.add(
+            Severity::Warning,
+            None,
+            "file header checksum is set, libsparse always leaves it 0".into(),
+        );
+    }
+
+    let total_blocks = u64::from(header.total_blocks);
+    let mut blocks = 0;
+    let mut checksum_chunk = None;
+    let mut mergeable = 0;
+    let mut last_type = None;
+    let mut index = 0;
+    while let Some(chunk) = chunks.next() {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let location = Some((index, chunks.offset()));
+                report.add(Severity::Error, location, format!("{err:#}"));
+                return Ok(report);
+            }
+        };
+        let location = Some((index, chunk.offset));
+        let chunk_type = chunk.header.chunk_type;
+
+        for issue in chunk.issues() {
+            report.add(Severity::Error, location, issue.to_string());
+        }
+        if let Some(crc_index) = checksum_chunk {
+            let message = format!("follows the checksum chunk {crc_index}");
+            report.add(Severity::Error, location, message);
+        }
+        match chunk_type {
+            ChunkType::Crc32 => checksum_chunk = Some(index),
+            ChunkType::Metadata => {
+                let message = "metadata chunk, which libsparse rejects".into();
+                report.add(Severity::Warning, location, message);
+            }
+            ChunkType::Raw | ChunkType::DontCare if last_type == Some(chunk_type) => {
+                mergeable += 1;
+            }
+            _ => (),
+        }
+        last_type = Some(chunk_type);
+
+        let end = chunk.start_block + u64::from(chunk.header.chunk_size);
+        if blocks <= total_blocks && end > total_blocks {
+            let message = format!(
+                "covers blocks {}..{end}, beyond the {total_blocks} blocks of the image",
+                chunk.start_block
+            );
+            report.add(Severity::Error, location, message);
+        }
+        blocks = end;
+        index += 1;
+    }
+    let chunks_end = start + chunks.offset();
+
+    if blocks != total_blocks {
+        let message =
+            format!("file header announces {total_blocks} blocks, but chunks cover {blocks}");
+        report.add(Severity::Error, None, message);
+    }
+    if mergeable > 0 {
+        let message = format!(
+            "{mergeable} raw or don't care chunks directly follow a chunk of the same type"
+        );
+        report.add(Severity::Warning, None, message);
+    }
+
+    let end = r.seek(SeekFrom::End(0))?;
+    if end > chunks_end {
+        let trailing = HumanSize(end - chunks_end);
+        let message = format!("{trailing} of trailing data after the last chunk");
+        report.add(Severity::Warning, None, message);
+    }
+
+    let size = end - start;
+    if let Some(max) = profile.max_size.filter(|max| size > *max) {
+        let message = format!(
+            "image is {}, more than the {} of profile {}",
+            HumanSize(size),
+            HumanSize(max),
+            profile.name
+        );
+        report.add(Severity::Error, None, message);
+    }
+    if let Some(max) = profile.max_chunks.filter(|max| header.total_chunks > *max) {
+        let message = format!(
+            "image has {} chunks, more than the {max} of profile {}",
+            header.total_chunks, profile.name
+        );
+        report.add(Severity::Error, None, message);
+    }
+    match checksum_chunk {
+        Some(_) if report.passed() => {
+            r.seek(SeekFrom::Start(start))?;
+            if let Err(err) = verify_checksum(&mut r) {
+                report.add(Severity::Error, None, format!("{err:#}"));
+            }
+        }
+        Some(_) => (),
+        None if profile.require_crc => {
+            let message = format!(
+                "image has no checksum, required by profile {}",
+                profile.name
+            );
+            report.add(Severity::Error, None, message);
+        }
+        None => (),
+    }
+
+    Ok(report)
+}
+
+fn verify_checksum<R: Read>(r: R) -> Result<()> {
+    for block in Reader::new(r, true)? {
+        if let Block::Crc32(_) = block? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::write_sparse;
+    use std::io::Cursor;
+
+    fn image(blocks: &[Block], crc: bool) -> Cursor<Vec<u8>> {
+        Cursor::new(write_sparse(blocks, crc).unwrap())
+    }
+
+    #[test]
+    fn profile_limits() {
+        let blocks = [Block::Fill([1; 4]), Block::Skip, Block::Fill([2; 4])];
+        let report = lint(image(&blocks, true), &Profile::new("generic")).unwrap();
+        assert_eq!(report, Report::default());
+
+        let strict = Profile {
+            max_size: Some(64),
+            max_chunks: Some(2),
+            require_crc: true,
+            ..Profile::new("strict")
+        };
+        let report = lint(image(&blocks, false), &strict).unwrap();
+        assert!(!report.passed());
+        let messages: Vec<_> = report.findings.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "error: image is 72 B, more than the 64 B of profile strict",
+                "error: image has 3 chunks, more than the 2 of profile strict",
+                "error: image has no checksum, required by profile strict",
+            ]
+        );
+    }
+
+    #[test]
+    fn structure() {
+        let mut image = image(&[Block::Skip, Block::Fill([1; 4])], true).into_inner();
+        // Corrupt the fill value, then append trailing data.
+        image[28 + 12 + 12] = 2;
+        image.extend([0; 4096]);
+
+        let report = lint(Cursor::new(image), &Profile::new("generic")).unwrap();
+        let messages: Vec<_> = report.findings.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "warning: 4.0 KiB of trailing data after the last chunk",
+                "error: Checksum does not match",
+            ]
+        );
+    }
+}
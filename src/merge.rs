@@ -0,0 +1,99 @@
+//! Merging of several sparse images into one.
+
+use crate::{
+    block::Block,
+    pipeline::{next_data_block, BlockSink, BlockSource},
+    write::Writer,
+};
+use crate::result::Result;
+use std::io::{Seek, Write};
+
+/// Merges the blocks of `sources` into `dst`.
+///
+/// Blocks are combined by their position in the raw image. At each
+/// position, the block of the last source that does not skip it wins, so
+/// later sources overlay earlier ones. Sources may be of different sizes.
+///
+/// This reassembles images that were split with `split::split`. Returns
+/// the number of blocks written. `dst` is not closed.
+pub fn merge<S, K>(sources: &mut [S], dst: &mut K) -> Result<u64>
+where
+    S: BlockSource,
+    K: BlockSink + ?Sized,
+{
+    let mut exhausted = vec![false; sources.len()];
+    let mut count = 0;
+
+    loop {
+        let mut merged = None;
+        for (src, done) in sources.iter_mut().zip(exhausted.iter_mut()) {
+            if *done {
+                continue;
+            }
+
+            match next_data_block(src)? {
+                Some(Block::Skip) => merged = merged.or(Some(Block::Skip)),
+                Some(block) => merged = Some(block),
+                None => *done = true,
+            }
+        }
+
+        match merged {
+            Some(block) => dst.write_block(&block)?,
+            None => return Ok(count),
+        }
+        count += 1;
+    }
+}
+
+/// Overlays the sparse image `patch` onto `base`, writing the composed
+/// image to `writer`, which is returned for closing.
+///
+/// The raw and fill blocks of `patch` replace those of `base`, while its
+/// don't-care blocks leave the blocks of `base` in place. This applies an
+/// incremental update that only holds the changed blocks to a full image
+/// offline. The composed image is as large as the larger of both.
+pub fn compose<B, P, W>(mut base: B, mut patch: P, mut writer: Writer<W>) -> Result<Writer<W>>
+where
+    B: BlockSource,
+    P: BlockSource,
+    W: Write + Seek,
+{
+    let mut sources: [&mut dyn BlockSource; 2] = [&mut base, &mut patch];
+    merge(&mut sources, &mut writer)?;
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read::Reader, testutil::write_sparse};
+    use std::io::Cursor;
+
+    #[test]
+    fn compose_images() {
+        let raw = |byte| Block::Raw([byte; Block::SIZE as usize].into());
+        let (three, four) = (Block::fill_u32(3), Block::fill_u32(4));
+        let base = write_sparse(&[raw(1), raw(2), three.clone(), Block::Skip], true).unwrap();
+        let patch = write_sparse(
+            &[Block::Skip, four.clone(), Block::Skip, raw(5), raw(6)],
+            true,
+        )
+        .unwrap();
+
+        let writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        let writer = compose(
+            Reader::new(&base[..], true).unwrap(),
+            Reader::new(&patch[..], true).unwrap(),
+            writer,
+        )
+        .unwrap();
+        let composed = writer.close().unwrap().into_inner();
+
+        let blocks: Vec<_> = Reader::new(&composed[..], false)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(blocks, [raw(1), four, three, raw(5), raw(6)]);
+    }
+}
@@ -0,0 +1,154 @@
+//! Provenance metadata stored in sparse images.
+//!
+//! Build systems often want to record where an image came from, e.g. a
+//! build id and timestamp. This crate stores such metadata in a metadata
+//! chunk (`ChunkType::Metadata`), an extension of the sparse format that
+//! covers no blocks. `Writer::write_metadata` emits it and `Reader` exposes
+//! it via `Reader::metadata`. Parsers that skip unknown chunk types ignore
+//! it, but libsparse rejects such images, so only add metadata to images
+//! that are not flashed with stock tools.
+//!
+//! The payload consists of `key=value` lines in UTF-8.
+
+use crate::{
+    chunk,
+    headers::{ChunkHeader, ChunkType, FileHeader},
+    result::{ensure, Context, Result},
+};
+use std::io::{prelude::*, SeekFrom};
+
+/// Key-value metadata of a sparse image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// The maximum size of the serialized metadata in bytes.
+    pub const MAX_SIZE: u32 = 4096;
+
+    /// Creates empty metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the entry `key` to `value`, replacing any existing value.
+    ///
+    /// Keys must be non-empty and may not contain `=` or line breaks, and
+    /// values may not contain line breaks.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        ensure!(
+            !key.is_empty() && !key.contains(['=', '\n']),
+            "Invalid metadata key: {key:?}"
+        );
+        ensure!(!value.contains('\n'), "Invalid metadata value: {value:?}");
+
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.entries.push((key, value)),
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the entry `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterates over all entries in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Sorts the entries by key.
+    pub fn sort(&mut self) {
+        self.entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    /// Checks whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Serializes the entries into a chunk payload.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for (key, value) in &self.entries {
+            writeln!(bytes, "{key}={value}")?;
+        }
+        ensure!(
+            bytes.len() <= Self::MAX_SIZE as usize,
+            "Metadata too large: {} bytes",
+            bytes.len()
+        );
+        Ok(bytes)
+    }
+
+    /// Parses a chunk payload.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(bytes)
+            .ok()
+            .context("Metadata is not valid UTF-8")?;
+
+        let mut metadata = Self::new();
+        for line in text.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Invalid metadata line: {line:?}"))?;
+            metadata.insert(key, value)?;
+        }
+        Ok(metadata)
+    }
+
+    /// Reads the payload of the metadata chunk with header `header` from
+    /// `r`.
+    pub(crate) fn read_payload<R: Read>(r: R, header: &ChunkHeader) -> Result<Self> {
+        ensure!(header.is_plausible(), "Invalid metadata chunk: {header}");
+        let len = header.total_size - u32::from(ChunkHeader::SIZE);
+        let mut bytes = Vec::new();
+        r.take(u64::from(len)).read_to_end(&mut bytes)?;
+        ensure!(bytes.len() == len as usize, "Truncated metadata chunk");
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Reads the metadata of the sparse image in `r`, if it has any.
+///
+/// Chunk payloads are seeked over, so this is fast even for large images.
+pub fn read<R: Read + Seek>(mut r: R) -> Result<Option<Metadata>> {
+    let header = FileHeader::read_from(&mut r)?;
+    for _ in 0..header.total_chunks {
+        let chunk = chunk::read_chunk_header(&mut r)?;
+        if chunk.chunk_type == ChunkType::Metadata {
+            return Metadata::read_payload(r, &chunk).map(Some);
+        }
+        let payload = chunk.total_size - u32::from(ChunkHeader::SIZE);
+        r.seek(SeekFrom::Current(i64::from(payload)))?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut metadata = Metadata::new();
+        metadata.insert("build_id", "AP1A.240305.019").unwrap();
+        metadata.insert("timestamp", "1700000000").unwrap();
+        metadata.insert("build_id", "AP2A").unwrap();
+        assert!(metadata.insert("a=b", "c").is_err());
+        assert!(metadata.insert("a", "b\nc").is_err());
+
+        let bytes = metadata.to_bytes().unwrap();
+        assert_eq!(bytes, b"build_id=AP2A\ntimestamp=1700000000\n");
+        assert_eq!(Metadata::from_bytes(&bytes).unwrap(), metadata);
+        assert_eq!(metadata.get("timestamp"), Some("1700000000"));
+        assert!(Metadata::from_bytes(b"no separator").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Generic interfaces for producing and consuming sparse blocks.
+//!
+//! Readers and encoders are block sources, writers and decoders are block
+//! sinks. Third-party types implementing these traits can be plugged into
+//! everything that is generic over them, e.g. `copy`.
+
+use crate::{
+    block::Block,
+    read::{Encoder, Reader},
+    write::{Decoder, Writer},
+};
+use crate::result::Result;
+use std::io::prelude::*;
+
+/// A source of sparse blocks.
+pub trait BlockSource {
+    /// Reads the next block from this source.
+    ///
+    /// Returns `None` once the source is exhausted.
+    fn read_block(&mut self) -> Result<Option<Block>>;
+
+    /// Returns the size of the raw image in bytes, if it is known upfront.
+    fn raw_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Turns this source into an iterator over its blocks.
+    fn blocks(self) -> Blocks<Self>
+    where
+        Self: Sized,
+    {
+        Blocks { src: self }
+    }
+}
+
+/// A sink for sparse blocks.
+pub trait BlockSink {
+    /// Writes a sparse block to this sink.
+    fn write_block(&mut self, block: &Block) -> Result<()>;
+
+    /// Ends the current chunk, so the next block starts a new one.
+    ///
+    /// Sinks that don't write chunks ignore this.
+    fn end_chunk(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Finishes writing and flushes any buffered data.
+    fn close(self) -> Result<()>
+    where
+        Self: Sized;
+}
+
+/// An iterator over the blocks of a `BlockSource`.
+///
+/// Created by `BlockSource::blocks`.
+pub struct Blocks<S> {
+    src: S,
+}
+
+impl<S: BlockSource> Iterator for Blocks<S> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.src.read_block().transpose()
+    }
+}
+
+/// Writes all blocks from `src` to `dst`.
+///
+/// Returns the number of blocks copied. `dst` is not closed.
+pub fn copy<S, K>(src: &mut S, dst: &mut K) -> Result<u64>
+where
+    S: BlockSource + ?Sized,
+    K: BlockSink + ?Sized,
+{
+    let mut count = 0;
+    while let Some(block) = src.read_block()? {
+        dst.write_block(&block)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads the next block from `src` that covers raw image data, i.e. skips
+/// over checksum blocks.
+pub(crate) fn next_data_block<S>(src: &mut S) -> Result<Option<Block>>
+where
+    S: BlockSource + ?Sized,
+{
+    loop {
+        match src.read_block()? {
+            Some(Block::Crc32(_)) => (),
+            other => return Ok(other),
+        }
+    }
+}
+
+impl<S: BlockSource + ?Sized> BlockSource for &mut S {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        (**self).read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        (**self).raw_size()
+    }
+}
+
+impl<S: BlockSource + ?Sized> BlockSource for Box<S> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        (**self).read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        (**self).raw_size()
+    }
+}
+
+impl<R: Read> BlockSource for Reader<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.next().transpose()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+}
+
+impl<R: Read> BlockSource for Encoder<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.next().transpose()
+    }
+}
+
+impl<W: Write + Seek> BlockSink for Writer<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        Writer::write_block(self, block)
+    }
+
+    fn end_chunk(&mut self) -> Result<()> {
+        Writer::end_chunk(self)
+    }
+
+    fn close(self) -> Result<()> {
+        Writer::close(self).map(drop)
+    }
+}
+
+impl<W: Write + Seek> BlockSink for Decoder<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        Decoder::write_block(self, block)
+    }
+
+    fn close(self) -> Result<()> {
+        Decoder::close(self).map(drop)
+    }
+}
@@ -0,0 +1,287 @@
+//! Platform-specific filesystem operations.
+//!
+//! Some filesystems support features that make converting images a lot
+//! faster: holes keep skipped regions from taking up space, reflinks share
+//! raw data between a sparse image and its decoded image, and `FIEMAP`
+//! reports the holes of a raw image without reading it. `Capabilities`
+//! probes which of them a filesystem supports, so tools can pick the
+//! fastest strategy and warn about slow ones.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, prelude::*},
+    ops::Range,
+    path::Path,
+};
+
+/// The features a filesystem supports.
+///
+/// Features that cannot be probed on the current platform are reported as
+/// unsupported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether holes can be punched into files, so skipped blocks take up
+    /// no space.
+    pub holes: bool,
+    /// Whether data can be shared between files (e.g. on Btrfs or XFS), so
+    /// raw data can be cloned from a sparse image.
+    pub reflink: bool,
+    /// Whether the allocated extents of files can be queried, so the holes
+    /// of raw images are found without reading them.
+    pub fiemap: bool,
+}
+
+impl Capabilities {
+    /// Probes the filesystem of the directory `dir` by trying each feature
+    /// on scratch files created in it.
+    pub fn probe<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let mut src = tempfile::tempfile_in(dir)?;
+        src.write_all(&[1; 2 * 4096])?;
+
+        let reflink = match tempfile::tempfile_in(dir) {
+            Ok(dst) => clone_range(&src, 0, &dst, 0, 4096).is_ok(),
+            Err(_) => false,
+        };
+        let fiemap = data_extents(&src).is_ok();
+        let holes = punch_hole(&src, 0, 4096).is_ok();
+
+        Ok(Self {
+            holes,
+            reflink,
+            fiemap,
+        })
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |supported| match supported {
+            true => "yes",
+            false => "no",
+        };
+        write!(
+            f,
+            "holes: {}, reflink: {}, FIEMAP: {}",
+            yes_no(self.holes),
+            yes_no(self.reflink),
+            yes_no(self.fiemap)
+        )
+    }
+}
+
+/// Clones `len` bytes at `src_off` in `src` to `dst_off` in `dst`, sharing
+/// the underlying storage instead of copying it.
+///
+/// Only succeeds if both files live on the same reflink-capable filesystem
+/// (e.g. Btrfs or XFS) and all offsets are aligned to its block size.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn clone_range(
+    src: &File,
+    src_off: u64,
+    dst: &File,
+    dst_off: u64,
+    len: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = libc::file_clone_range {
+        src_fd: i64::from(src.as_raw_fd()),
+        src_offset: src_off,
+        src_length: len,
+        dest_offset: dst_off,
+    };
+
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn clone_range(
+    _src: &File,
+    _src_off: u64,
+    _dst: &File,
+    _dst_off: u64,
+    _len: u64,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Deallocates `len` bytes at `off` in `file`, which then read as zeros,
+/// keeping the file size.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn punch_hole(file: &File, off: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let (off, len) = (off.try_into(), len.try_into());
+    let (Ok(off), Ok(len)) = (off, len) else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, off, len) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn punch_hole(_file: &File, _off: u64, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns the byte ranges of `file` the filesystem allocated and wrote
+/// data to, in ascending order, using the `FS_IOC_FIEMAP` ioctl.
+///
+/// Preallocated but unwritten extents read as zeros, so they are left out
+/// like holes. Dirty data is flushed first, so delayed allocations are
+/// reported too.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn data_extents(file: &File) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    // From <linux/fiemap.h>, which libc doesn't provide.
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+    const EXTENT_COUNT: usize = 256;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; EXTENT_COUNT],
+    }
+
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: EXTENT_COUNT as u32,
+            reserved: 0,
+            extents: [Extent::default(); EXTENT_COUNT],
+        };
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let extents = &map.extents[..map.mapped_extents as usize];
+        for extent in extents {
+            if extent.flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+                continue;
+            }
+            let range = extent.logical..extent.logical + extent.length;
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+
+        match extents.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => return Ok(ranges),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn data_extents(_file: &File) -> io::Result<Vec<Range<u64>>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Checks whether the metadata `a` and `b` belong to the same file, e.g.
+/// reached through hard links, symbolic links or duplicated descriptors.
+#[cfg(unix)]
+pub(crate) fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+/// Reads up to `buf.len()` bytes at `off` in `file`, without touching its
+/// file position.
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, off)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, off)
+}
+
+/// Writes up to `buf.len()` bytes at `off` in `file`, without touching its
+/// file position.
+#[cfg(unix)]
+pub(crate) fn write_at(file: &File, buf: &[u8], off: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.write_at(buf, off)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_at(file: &File, buf: &[u8], off: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_write(buf, off)
+}
+
+/// Reads exactly `buf.len()` bytes at `off` in `file`, without touching
+/// its file position.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, off)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut off: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, off) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                off += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
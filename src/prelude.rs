@@ -0,0 +1,23 @@
+//! The commonly used traits and types, for glob importing.
+//!
+//! ```
+//! use android_sparse::prelude::*;
+//!
+//! # fn main() -> Result<()> {
+//! let raw = vec![0xa5; 3 * Block::SIZE as usize];
+//! let mut sparse = std::io::Cursor::new(Vec::new());
+//! let mut writer = Writer::new(&mut sparse, true)?;
+//! pipeline::copy(&mut Encoder::new(&raw[..])?, &mut writer)?;
+//! writer.close()?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::{
+    adapter::{BlockCipher, CipherSink, CipherSource, IterSource, Rechunk},
+    block::{Block, BlockBuf, CrcPolicy},
+    pipeline::{self, BlockSink, BlockSource},
+    read::{Encoder, EncoderOptions, Reader},
+    result::{Error, Result},
+    write::{Decoder, Writer},
+};
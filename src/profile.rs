@@ -0,0 +1,216 @@
+//! Constraints of the devices that flash sparse images.
+//!
+//! Bootloaders limit the images they accept: fastboot downloads an image
+//! into a buffer of fixed size, and some parsers only handle a limited
+//! number of chunks or insist on a checksum. A `Profile` describes these
+//! constraints for a device, so `lint::lint` can check images against them
+//! and `split::split_for` can make images fit them.
+//!
+//! A `Registry` holds the built-in profiles plus those loaded from a
+//! profile file. Profile files use the subset of TOML described in
+//! `settings`, with a table per profile:
+//!
+//! ```toml
+//! # The download buffer of this bootloader is small.
+//! [my-board]
+//! max_size = "64M"      # bytes, or with a K, M or G suffix
+//! max_chunks = 4096
+//! block_size = 4096
+//! require_crc = true
+//! ```
+//!
+//! All keys are optional.
+
+use crate::{
+    block::Block,
+    human::HumanSize,
+    result::{bail, ensure, Context, Result},
+    settings::{self, Value},
+};
+use std::{fmt, fs, path::Path};
+
+/// The constraints of a device that flashes sparse images.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    /// The name of the profile.
+    pub name: String,
+    /// The maximum size of a sparse image in bytes, e.g. the fastboot
+    /// download size.
+    pub max_size: Option<u64>,
+    /// The maximum number of chunks in a sparse image.
+    pub max_chunks: Option<u32>,
+    /// The block size the device requires.
+    pub block_size: u32,
+    /// Whether images must contain a checksum chunk.
+    pub require_crc: bool,
+}
+
+impl Profile {
+    /// Creates a profile named `name` without any constraints.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            max_size: None,
+            max_chunks: None,
+            block_size: Block::SIZE,
+            require_crc: false,
+        }
+    }
+
+    /// Checks whether images of this crate can satisfy this profile.
+    ///
+    /// Only images with 4096-byte blocks are supported.
+    pub fn check_supported(&self) -> Result<()> {
+        ensure!(
+            self.block_size == Block::SIZE,
+            "Profile {} requires {}-byte blocks, only {}-byte blocks are supported",
+            self.name,
+            self.block_size,
+            Block::SIZE
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.name)?;
+        match self.max_size {
+            Some(size) => write!(f, " max size {}", HumanSize(size))?,
+            None => write!(f, " no size limit")?,
+        }
+        match self.max_chunks {
+            Some(chunks) => write!(f, ", max {chunks} chunks")?,
+            None => write!(f, ", no chunk limit")?,
+        }
+        if self.block_size != Block::SIZE {
+            write!(f, ", {}-byte blocks", self.block_size)?;
+        }
+        if self.require_crc {
+            write!(f, ", checksum required")?;
+        }
+        Ok(())
+    }
+}
+
+/// A set of profiles, looked up by name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registry {
+    profiles: Vec<Profile>,
+}
+
+impl Registry {
+    /// Creates a registry of the built-in profiles.
+    ///
+    /// `generic` imposes no constraints. `fastboot` has the limits of
+    /// typical fastboot bootloaders: a 256 MiB download buffer and 65536
+    /// chunks.
+    pub fn builtin() -> Self {
+        let fastboot = Profile {
+            max_size: Some(256 << 20),
+            max_chunks: Some(65536),
+            ..Profile::new("fastboot")
+        };
+        Self {
+            profiles: vec![Profile::new("generic"), fastboot],
+        }
+    }
+
+    /// Adds `profile`, replacing any profile of the same name.
+    pub fn insert(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    /// Adds the profiles in the profile file at `path`.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read profile file {}", path.display()))?;
+        self.parse(&content)
+            .with_context(|| format!("Invalid profile file {}", path.display()))
+    }
+
+    /// Adds the profiles in the profile file `content`.
+    pub fn parse(&mut self, content: &str) -> Result<()> {
+        for profile in settings::parse_tables(content, Profile::new, set)? {
+            self.insert(profile);
+        }
+        Ok(())
+    }
+
+    /// Returns the profile `name`.
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Iterates over all profiles, built-in ones first.
+    pub fn iter(&self) -> impl Iterator<Item = &Profile> {
+        self.profiles.iter()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Sets the constraint `key` of `profile` to `value`.
+fn set(profile: &mut Profile, key: &str, value: Value) -> Result<()> {
+    match key {
+        "max_size" => profile.max_size = Some(value.as_size()?),
+        "max_chunks" => profile.max_chunks = Some(value.as_int()?.try_into()?),
+        "block_size" => profile.block_size = value.as_int()?.try_into()?,
+        "require_crc" => profile.require_crc = value.as_bool()?,
+        key => bail!("Unknown key `{key}`"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_profiles() {
+        let mut registry = Registry::builtin();
+        registry
+            .parse(
+                "# Boards\n\
+                 [board]\n\
+                 max_size = \"64M\" # download buffer\n\
+                 max_chunks = 4_096\n\
+                 require_crc = true\n\
+                 \n\
+                 [\"fastboot\"]\n\
+                 max_size = 1024\n",
+            )
+            .unwrap();
+
+        let board = registry.get("board").unwrap();
+        assert_eq!(board.max_size, Some(64 << 20));
+        assert_eq!(board.max_chunks, Some(4096));
+        assert!(board.require_crc);
+        assert_eq!(
+            board.to_string(),
+            "board: max size 64.0 MiB, max 4096 chunks, checksum required"
+        );
+
+        // Profiles of the same name replace built-in ones.
+        assert_eq!(
+            registry.get("fastboot"),
+            Some(&Profile {
+                max_size: Some(1024),
+                ..Profile::new("fastboot")
+            })
+        );
+        assert_eq!(registry.iter().count(), 3);
+
+        assert!(registry.parse("max_size = 1").is_err());
+        assert!(registry.parse("[a]\nmax_size = \"1T\"").is_err());
+        assert!(registry.parse("[a]\nunknown = 1").is_err());
+    }
+}
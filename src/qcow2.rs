@@ -0,0 +1,397 @@
+//! Conversion between sparse images and QEMU's qcow2 format.
+//!
+//! Skip blocks map to unallocated clusters, so images converted to qcow2
+//! stay as small as their sparse originals. Clusters filled with zeros are
+//! stored as zero clusters, all other clusters as data. Converting back
+//! turns unallocated clusters into skip blocks again.
+//!
+//! Only plain images are supported: no backing files, encryption,
+//! compression, snapshots or external data files.
+
+use crate::{
+    block::Block,
+    container::{self, ClusterMap, Clusters, MappedReader, Mapping},
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{ensure, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{prelude::*, SeekFrom};
+
+/// The magic at the start of qcow2 images.
+pub const MAGIC: [u8; 4] = *b"QFI\xfb";
+
+const VERSION: u32 = 3;
+const HEADER_SIZE: u32 = 104;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+/// Refcounts are 16 bits wide.
+const REFCOUNT_ORDER: u32 = 4;
+
+const L2_ENTRIES: usize = (CLUSTER_SIZE / 8) as usize;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SIZE / 2;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+/// Incompatible features that don't affect reading: the dirty bit.
+const KNOWN_INCOMPATIBLE_FEATURES: u64 = 1;
+
+/// Writes sparse blocks to a qcow2 image.
+///
+/// The virtual size of the image is the number of blocks written. Checksum
+/// blocks are ignored.
+pub struct Qcow2Writer<W: Write + Seek> {
+    dst: W,
+    start: u64,
+    clusters: Clusters,
+    /// The L2 entries of all complete clusters.
+    l2: Vec<u64>,
+    /// The index of the next free host cluster.
+    next_cluster: u64,
+    num_blocks: u64,
+    finished: bool,
+}
+
+impl<W: Write + Seek> Qcow2Writer<W> {
+    /// Creates a new writer that writes to `w`.
+    pub fn new(mut w: W) -> Result<Self> {
+        // The header is written at the end in `finish`, once the layout of
+        // the image is known. Data clusters start right after it.
+        let start = w.stream_position()?;
+        w.seek(SeekFrom::Start(start + CLUSTER_SIZE))?;
+
+        Ok(Self {
+            dst: w,
+            start,
+            clusters: Clusters::new(CLUSTER_SIZE),
+            l2: Vec::new(),
+            next_cluster: 1,
+            num_blocks: 0,
+            finished: false,
+        })
+    }
+
+    /// Writes a sparse block to this writer.
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        if !matches!(block, Block::Crc32(_)) {
+            self.num_blocks += 1;
+        }
+        match self.clusters.push(block) {
+            Some(blocks) => self.write_cluster(&blocks),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes writing the qcow2 image.
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_cluster(&mut self, blocks: &[Block]) -> Result<()> {
+        let entry = match container::classify(blocks) {
+            Mapping::Unallocated => 0,
+            Mapping::Zero => ZERO,
+            Mapping::Data(_) => {
+                let offset = self.allocate(1);
+                container::write_cluster(&mut self.dst, blocks, CLUSTER_SIZE)?;
+                offset | COPIED
+            }
+        };
+        self.l2.push(entry);
+        Ok(())
+    }
+
+    /// Reserves `count` host clusters, returning the offset of the first
+    /// one relative to the start of the image.
+    fn allocate(&mut self, count: u64) -> u64 {
+        let offset = self.next_cluster * CLUSTER_SIZE;
+        self.next_cluster += count;
+        offset
+    }
+
+    fn write_table(&mut self, table: &[u64], width: usize) -> Result<()> {
+        container::write_table::<_, BigEndian>(&mut self.dst, table, width, CLUSTER_SIZE)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        assert!(!self.finished);
+        self.finished = true;
+
+        if let Some(blocks) = self.clusters.take() {
+            self.write_cluster(&blocks)?;
+        }
+
+        // L2 tables, skipping those without any allocated clusters.
+        let l2 = std::mem::take(&mut self.l2);
+        let mut l1 = Vec::new();
+        for table in l2.chunks(L2_ENTRIES) {
+            if table.iter().all(|e| *e == 0) {
+                l1.push(0);
+                continue;
+            }
+            l1.push(self.allocate(1) | COPIED);
+            self.write_table(table, 8)?;
+        }
+
+        let l1_clusters = (l1.len() as u64 * 8).div_ceil(CLUSTER_SIZE);
+        let l1_offset = self.allocate(l1_clusters);
+        self.write_table(&l1, 8)?;
+
+        // The refcount structures need refcounts themselves, so grow them
+        // until they cover all clusters including their own.
+        let used = self.next_cluster;
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let total = used + blocks + table_clusters;
+            let needed_blocks = total.div_ceil(REFCOUNTS_PER_BLOCK);
+            let needed_table = (needed_blocks * 8).div_ceil(CLUSTER_SIZE);
+            if (needed_blocks, needed_table) == (blocks, table_clusters) {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table);
+        }
+        let total = used + blocks + table_clusters;
+
+        let blocks_offset = self.allocate(blocks);
+        let refcounts = vec![1; total as usize];
+        self.write_table(&refcounts, 2)?;
+        let padding = blocks * CLUSTER_SIZE - (total * 2).next_multiple_of(CLUSTER_SIZE);
+        container::write_zeros(&mut self.dst, padding)?;
+
+        let table_offset = self.allocate(table_clusters);
+        let table: Vec<_> = (0..blocks)
+            .map(|i| blocks_offset + i * CLUSTER_SIZE)
+            .collect();
+        self.write_table(&table, 8)?;
+
+        self.dst.seek(SeekFrom::Start(self.start))?;
+        self.dst.write_all(&MAGIC)?;
+        self.dst.write_u32::<BigEndian>(VERSION)?;
+        self.dst.write_u64::<BigEndian>(0)?; // backing_file_offset
+        self.dst.write_u32::<BigEndian>(0)?; // backing_file_size
+        self.dst.write_u32::<BigEndian>(CLUSTER_BITS)?;
+        self.dst
+            .write_u64::<BigEndian>(self.num_blocks * u64::from(Block::SIZE))?;
+        self.dst.write_u32::<BigEndian>(0)?; // crypt_method
+        self.dst.write_u32::<BigEndian>(l1.len() as u32)?;
+        self.dst.write_u64::<BigEndian>(l1_offset)?;
+        self.dst.write_u64::<BigEndian>(table_offset)?;
+        self.dst.write_u32::<BigEndian>(table_clusters as u32)?;
+        self.dst.write_u32::<BigEndian>(0)?; // nb_snapshots
+        self.dst.write_u64::<BigEndian>(0)?; // snapshots_offset
+        self.dst.write_u64::<BigEndian>(0)?; // incompatible_features
+        self.dst.write_u64::<BigEndian>(0)?; // compatible_features
+        self.dst.write_u64::<BigEndian>(0)?; // autoclear_features
+        self.dst.write_u32::<BigEndian>(REFCOUNT_ORDER)?;
+        self.dst.write_u32::<BigEndian>(HEADER_SIZE)?;
+        // End of header extensions.
+        self.dst.write_u64::<BigEndian>(0)?;
+
+        self.dst
+            .seek(SeekFrom::Start(self.start + total * CLUSTER_SIZE))?;
+        self.dst.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for Qcow2Writer<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish().ok();
+        }
+    }
+}
+
+impl<W: Write + Seek> BlockSink for Qcow2Writer<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        Qcow2Writer::write_block(self, block)
+    }
+
+    fn close(self) -> Result<()> {
+        Qcow2Writer::close(self)
+    }
+}
+
+/// Reads sparse blocks from a qcow2 image.
+///
+/// Unallocated clusters are read as skip blocks, zero clusters as fill
+/// blocks and data clusters as raw blocks. A virtual size that isn't a
+/// multiple of the block size is padded with zeros.
+pub struct Qcow2Reader<R: Read + Seek> {
+    inner: MappedReader<R, Qcow2Map>,
+}
+
+impl<R: Read + Seek> Qcow2Reader<R> {
+    /// Creates a new reader that reads from `r`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let start = r.stream_position()?;
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        ensure!(magic == MAGIC, "Not a qcow2 image");
+
+        let version = r.read_u32::<BigEndian>()?;
+        ensure!(
+            version == 2 || version == 3,
+            "Unsupported qcow2 version: {version}"
+        );
+
+        let backing_file_offset = r.read_u64::<BigEndian>()?;
+        ensure!(backing_file_offset == 0, "Backing files are not supported");
+        r.read_u32::<BigEndian>()?; // backing_file_size
+
+        let cluster_bits = r.read_u32::<BigEndian>()?;
+        ensure!(
+            (12..=21).contains(&cluster_bits),
+            "Unsupported cluster size: {}",
+            1u64 << cluster_bits.min(63)
+        );
+
+        let size = r.read_u64::<BigEndian>()?;
+        let crypt_method = r.read_u32::<BigEndian>()?;
+        ensure!(crypt_method == 0, "Encrypted images are not supported");
+
+        let l1_size = r.read_u32::<BigEndian>()?;
+        let l1_offset = r.read_u64::<BigEndian>()?;
+
+        if version == 3 {
+            r.seek(SeekFrom::Start(start + 72))?;
+            let incompatible = r.read_u64::<BigEndian>()?;
+            ensure!(
+                incompatible & !KNOWN_INCOMPATIBLE_FEATURES == 0,
+                "Unsupported incompatible features: {incompatible:#x}"
+            );
+        }
+
+        let l2_entries = 1u64 << (cluster_bits - 3);
+        let clusters = size.div_ceil(1 << cluster_bits);
+        ensure!(
+            u64::from(l1_size) >= clusters.div_ceil(l2_entries),
+            "L1 table too small for virtual size"
+        );
+
+        let l1 =
+            container::read_table::<_, BigEndian>(&mut r, start + l1_offset, l1_size as usize, 8)?;
+        let map = Qcow2Map {
+            start,
+            cluster_bits,
+            l1,
+            l2: None,
+        };
+
+        Ok(Self {
+            inner: MappedReader::new(r, map, start, size),
+        })
+    }
+
+    /// Returns the virtual size of the image in bytes.
+    pub fn size(&self) -> u64 {
+        self.inner.size
+    }
+}
+
+impl<R: Read + Seek> BlockSource for Qcow2Reader<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.inner.read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        self.inner.raw_size()
+    }
+}
+
+struct Qcow2Map {
+    start: u64,
+    cluster_bits: u32,
+    l1: Vec<u64>,
+    /// The index and entries of the L2 table last read.
+    l2: Option<(usize, Vec<u64>)>,
+}
+
+impl ClusterMap for Qcow2Map {
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn lookup<R: Read + Seek>(&mut self, src: &mut R, index: u64) -> Result<Mapping> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (index >> l2_bits) as usize;
+        let l2_index = (index & ((1 << l2_bits) - 1)) as usize;
+
+        let l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+
+        if self.l2.as_ref().map(|(i, _)| *i) != Some(l1_index) {
+            let offset = self.start + l2_offset;
+            let table = container::read_table::<_, BigEndian>(src, offset, 1 << l2_bits, 8)?;
+            self.l2 = Some((l1_index, table));
+        }
+
+        let entry = self.l2.as_ref().unwrap().1[l2_index];
+        ensure!(
+            entry & COMPRESSED == 0,
+            "Compressed clusters are not supported"
+        );
+
+        Ok(match entry & OFFSET_MASK {
+            _ if entry & ZERO != 0 => Mapping::Zero,
+            0 => Mapping::Unallocated,
+            offset => Mapping::Data(offset),
+        })
+    }
+}
+
+/// Checks whether the image in `r` is a qcow2 image, rewinding `r`
+/// afterward.
+pub fn is_qcow2<R: Read + Seek>(r: R) -> Result<bool> {
+    container::has_magic(r, &MAGIC)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCKS_PER_CLUSTER: usize = (CLUSTER_SIZE / Block::SIZE as u64) as usize;
+
+    #[test]
+    fn roundtrip() {
+        let mut raw = [0; Block::SIZE as usize];
+        raw[7] = 7;
+
+        // A data cluster, a zero cluster, an unallocated cluster, then a
+        // partial cluster mixing all of them.
+        let mut blocks = vec![Block::Raw(raw.into()); BLOCKS_PER_CLUSTER];
+        blocks.extend(vec![Block::Fill([0; 4]); BLOCKS_PER_CLUSTER]);
+        blocks.extend(vec![Block::Skip; BLOCKS_PER_CLUSTER]);
+        blocks.extend([Block::Fill([1; 4]), Block::Skip]);
+
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = Qcow2Writer::new(&mut image).unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        // Header, two data clusters, one L2 table, the L1 table and one
+        // refcount block and table each.
+        assert_eq!(image.get_ref().len() as u64, 7 * CLUSTER_SIZE);
+
+        image.set_position(0);
+        assert!(is_qcow2(&mut image).unwrap());
+
+        let mut reader = Qcow2Reader::new(&mut image).unwrap();
+        let mut expected = blocks;
+        let n = expected.len();
+        expected[n - 2] = Block::Raw([1; Block::SIZE as usize].into());
+        expected[n - 1] = Block::Raw([0; Block::SIZE as usize].into());
+
+        let read: Vec<_> = (&mut reader).blocks().map(Result::unwrap).collect();
+        assert_eq!(read, expected);
+        assert_eq!(reader.raw_size(), Some(n as u64 * u64::from(Block::SIZE)));
+    }
+}
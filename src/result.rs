@@ -0,0 +1,270 @@
+//! The error and result types of this crate.
+//!
+//! Errors carry a chain of context messages, like "Reading raw chunk 3
+//! payload at offset 0x1a2b3c: failed to fill whole buffer". `Display`
+//! shows the outermost message, or the whole chain separated by colons with
+//! `{:#}`, and `source` walks the chain, so errors convert losslessly into
+//! `anyhow::Error` and similar types. Context messages may record the
+//! offset in the image an operation failed at, see `Error::offset`.
+
+use std::{
+    error::Error as StdError,
+    fmt::{self, Display},
+    io,
+};
+
+/// A `Result` with this crate's `Error` type.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error reading, writing or converting images.
+pub struct Error(Box<Repr>);
+
+enum Repr {
+    Io(io::Error),
+    Message(String),
+    Other(Box<dyn StdError + Send + Sync>),
+    Context {
+        context: String,
+        offset: Option<u64>,
+        source: Error,
+    },
+}
+
+impl Error {
+    /// Creates an error with the message `msg`.
+    pub fn msg<M: Display>(msg: M) -> Self {
+        Self(Box::new(Repr::Message(msg.to_string())))
+    }
+
+    /// Wraps the foreign error `err`.
+    pub fn new<E: StdError + Send + Sync + 'static>(err: E) -> Self {
+        Self(Box::new(Repr::Other(Box::new(err))))
+    }
+
+    /// Wraps this error with the message `context`.
+    pub fn context<C: Display>(self, context: C) -> Self {
+        Self(Box::new(Repr::Context {
+            context: context.to_string(),
+            offset: None,
+            source: self,
+        }))
+    }
+
+    /// Wraps this error with the message `context` about an operation at
+    /// `offset` in an image.
+    pub fn context_at<C: Display>(self, context: C, offset: u64) -> Self {
+        Self(Box::new(Repr::Context {
+            context: context.to_string(),
+            offset: Some(offset),
+            source: self,
+        }))
+    }
+
+    /// Returns the offset in the image of the innermost operation that
+    /// failed, if it is known.
+    pub fn offset(&self) -> Option<u64> {
+        match &*self.0 {
+            Repr::Context { offset, source, .. } => source.offset().or(*offset),
+            Repr::Io(_) | Repr::Message(_) | Repr::Other(_) => None,
+        }
+    }
+
+    /// Returns the typed error of type `E` that caused this error, if any,
+    /// e.g. `io::AlreadyLocked`.
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        match &*self.0 {
+            Repr::Other(err) => err.downcast_ref(),
+            Repr::Context { source, .. } => source.downcast_ref(),
+            Repr::Io(_) | Repr::Message(_) => None,
+        }
+    }
+
+    /// Returns the I/O error that caused this error, if any.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match &*self.0 {
+            Repr::Io(err) => Some(err),
+            Repr::Context { source, .. } => source.io_error(),
+            Repr::Message(_) | Repr::Other(_) => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.0 {
+            Repr::Io(err) => err.fmt(f)?,
+            Repr::Message(msg) => f.write_str(msg)?,
+            Repr::Other(err) => err.fmt(f)?,
+            Repr::Context {
+                context, offset, ..
+            } => {
+                f.write_str(context)?;
+                if let Some(offset) = offset {
+                    write!(f, " at offset {offset:#x}")?;
+                }
+            }
+        }
+
+        if f.alternate() {
+            let mut source = self.source();
+            while let Some(err) = source {
+                write!(f, ": {err}")?;
+                source = err.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self}")?;
+
+        let mut source = self.source();
+        if source.is_some() {
+            write!(f, "\n\nCaused by:")?;
+        }
+        let mut index = 0;
+        while let Some(err) = source {
+            write!(f, "\n    {index}: {err}")?;
+            source = err.source();
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match &*self.0 {
+            // The wrapped error's message is our own, so continue with its
+            // source.
+            Repr::Io(err) => err.source(),
+            Repr::Other(err) => err.source(),
+            Repr::Message(_) => None,
+            Repr::Context { source, .. } => Some(source),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self(Box::new(Repr::Io(err)))
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        Self::new(err)
+    }
+}
+
+impl From<std::num::TryFromIntError> for Error {
+    fn from(err: std::num::TryFromIntError) -> Self {
+        Self::new(err)
+    }
+}
+
+#[cfg(feature = "sign")]
+impl From<ed25519_dalek::SignatureError> for Error {
+    fn from(err: ed25519_dalek::SignatureError) -> Self {
+        Self::new(err)
+    }
+}
+
+#[cfg(feature = "anonymize")]
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Self {
+        Self::new(err)
+    }
+}
+
+/// Adds context to errors.
+pub trait Context<T> {
+    /// Wraps the error with the message `context`.
+    fn context<C: Display>(self, context: C) -> Result<T>;
+
+    /// Wraps the error with the message returned by `f`, which is only
+    /// called if there is an error.
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+
+    /// Wraps the error with the message returned by `f` about an operation
+    /// at `offset` in an image.
+    fn with_context_at<C: Display, F: FnOnce() -> C>(self, offset: u64, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|err| err.into().context(f()))
+    }
+
+    fn with_context_at<C: Display, F: FnOnce() -> C>(self, offset: u64, f: F) -> Result<T> {
+        self.map_err(|err| err.into().context_at(f(), offset))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.ok_or_else(|| Error::msg(context))
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.ok_or_else(|| Error::msg(f()))
+    }
+
+    fn with_context_at<C: Display, F: FnOnce() -> C>(self, offset: u64, f: F) -> Result<T> {
+        self.ok_or_else(|| Error::msg(format!("{} at offset {offset:#x}", f())))
+    }
+}
+
+/// Returns early with an error formatted from the arguments.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::result::Error::msg(format!($($arg)*)))
+    };
+}
+
+/// Returns early with an error formatted from the remaining arguments if
+/// the condition doesn't hold.
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            $crate::result::bail!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn context_chain() {
+        let err: Result<()> = Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        let err = err
+            .with_context_at(0x1a2b3c, || "Reading raw chunk 3 payload")
+            .context("Reading image")
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "Reading image");
+        assert_eq!(
+            format!("{err:#}"),
+            "Reading image: Reading raw chunk 3 payload at offset 0x1a2b3c: unexpected end of file"
+        );
+        assert_eq!(err.offset(), Some(0x1a2b3c));
+        assert_eq!(
+            err.io_error().map(io::Error::kind),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            None::<u8>.context("Missing value").unwrap_err().to_string(),
+            "Missing value"
+        );
+    }
+}
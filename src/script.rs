@@ -0,0 +1,152 @@
+//! Shell scripts decoding sparse images with `dd`.
+//!
+//! Devices often have no sparse image decoder, but do have `dd`. Decoding
+//! can then be planned off-device: `dd_script` emits a POSIX shell script
+//! that decodes the sparse image it was generated from using nothing but
+//! coreutils. Raw chunks are copied straight out of the sparse image, fill
+//! chunks are written as a single block that is then doubled in place, and
+//! don't-care chunks are left untouched, as a decoder would. Checksums are
+//! not verified.
+//!
+//! The script takes the sparse image and the output, a file or block
+//! device, as arguments:
+//!
+//! ```text
+//! $ sh decode.sh system.simg /dev/block/by-name/system
+//! ```
+
+use crate::{
+    block::Block,
+    headers::{ChunkType, FileHeader},
+    human::HumanSize,
+    read::Reader,
+    result::Result,
+};
+use std::io::prelude::*;
+
+/// The functions of the script, which the chunks are decoded with.
+const PRELUDE: &str = r#"set -e
+if [ $# -ne 2 ]; then
+    echo "usage: $0 SPARSE_IMAGE OUTPUT" >&2
+    exit 2
+fi
+img=$1
+out=$2
+
+# Copies $3 blocks at byte offset $1 of the sparse image to block $2.
+copy() {
+    dd if="$img" of="$out" bs=4096 iflag=skip_bytes skip="$1" seek="$2" count="$3" \
+        conv=notrunc status=none
+}
+
+# Writes $2 blocks of zeros at block $1.
+zero() {
+    dd if=/dev/zero of="$out" bs=4096 seek="$1" count="$2" conv=notrunc status=none
+}
+
+# Writes $3 blocks of the 4-byte pattern $1, as printf escapes, at block $2.
+fill() {
+    pattern=$1
+    i=0
+    while [ $i -lt 10 ]; do
+        pattern=$pattern$pattern
+        i=$((i + 1))
+    done
+    printf "$pattern" |
+        dd of="$out" bs=4096 iflag=fullblock seek="$2" count=1 conv=notrunc status=none
+    written=1
+    while [ $written -lt "$3" ]; do
+        count=$written
+        if [ $((written + count)) -gt "$3" ]; then
+            count=$(($3 - written))
+        fi
+        dd if="$out" of="$out" bs=4096 skip="$2" seek=$(($2 + written)) count=$count \
+            conv=notrunc status=none
+        written=$((written + count))
+    done
+}
+"#;
+
+/// Writes a shell script decoding the sparse image in `image` with `dd` to
+/// `w`.
+///
+/// The script refers to the chunks by their offsets in `image`, so it only
+/// decodes this exact image.
+pub fn dd_script<R, W>(mut image: R, mut w: W) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    image.rewind()?;
+    let header = FileHeader::read_from(&mut image)?;
+    image.rewind()?;
+    let chunks = Reader::new(&mut image, false)?.prescan()?.chunks;
+
+    let raw_size = u64::from(header.total_blocks) * u64::from(Block::SIZE);
+    writeln!(w, "#!/bin/sh")?;
+    writeln!(
+        w,
+        "# Decodes a sparse image of {} blocks ({}) with dd.",
+        header.total_blocks,
+        HumanSize(raw_size)
+    )?;
+    w.write_all(PRELUDE.as_bytes())?;
+    writeln!(w)?;
+    writeln!(w, "if [ ! -b \"$out\" ]; then")?;
+    writeln!(w, "    : > \"$out\"")?;
+    writeln!(w, "    truncate -s {raw_size} \"$out\"")?;
+    writeln!(w, "fi")?;
+
+    for chunk in &chunks {
+        let blocks = chunk.header.chunk_size;
+        let start = chunk.start_block;
+        match chunk.header.chunk_type {
+            ChunkType::Raw => writeln!(w, "copy {} {start} {blocks}", chunk.payload_offset())?,
+            ChunkType::Fill => {
+                let value = chunk.fill_value(&mut image)?;
+                if value == [0; 4] {
+                    writeln!(w, "zero {start} {blocks}")?;
+                } else {
+                    let pattern: String = value.iter().map(|b| format!("\\{b:03o}")).collect();
+                    writeln!(w, "fill '{pattern}' {start} {blocks}")?;
+                }
+            }
+            ChunkType::DontCare => writeln!(w, "# don't care: {blocks} blocks at {start}")?,
+            ChunkType::Crc32 | ChunkType::Metadata => {}
+        }
+    }
+    Ok(w.flush()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::write_sparse;
+    use std::io::Cursor;
+
+    #[test]
+    fn script_lines() {
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::fill_u32(0xaabbccdd),
+            Block::fill_u32(0xaabbccdd),
+            Block::fill_u32(0),
+            Block::Skip,
+        ];
+        let image = Cursor::new(write_sparse(&blocks, true).unwrap());
+
+        let mut script = Vec::new();
+        dd_script(image, &mut script).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        let lines: Vec<_> = script.lines().collect();
+        assert_eq!(
+            lines[lines.len() - 4..],
+            [
+                "copy 40 0 1",
+                r"fill '\335\314\273\252' 1 2",
+                "zero 3 1",
+                "# don't care: 1 blocks at 4",
+            ]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Building several related images as a single transaction.
+//!
+//! Build tools usually produce a set of images that only make sense
+//! together, e.g. super, boot and vbmeta images. A `BuildSession` stages
+//! all of them in a temporary directory next to their final location and
+//! moves them into place only once every image has been written. If the
+//! session is dropped without being committed, or committing fails, none
+//! of the existing images in the output directory are changed.
+
+use crate::write::Writer;
+use crate::result::{ensure, Context, Result};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tempfile::TempDir;
+
+/// A callback invoked whenever a session's writer finishes a chunk.
+type ProgressCallback = Box<dyn FnMut(&Progress) + Send>;
+
+/// The progress of a build session.
+#[derive(Clone, Debug)]
+pub struct Progress<'a> {
+    /// The name of the image that made progress.
+    pub image: &'a str,
+    /// The number of blocks written to that image so far.
+    pub image_blocks: u64,
+    /// The number of blocks written to all images of the session so far.
+    pub total_blocks: u64,
+}
+
+/// State shared by all writers of a session.
+struct Shared {
+    total_blocks: u64,
+    on_progress: Option<ProgressCallback>,
+}
+
+/// An image staged in a session.
+struct Staged {
+    name: String,
+    /// The number of blocks written so far. Writers hold a reference to
+    /// it until they are dropped.
+    blocks: Arc<AtomicU64>,
+}
+
+/// Writes a set of images that are committed all at once.
+///
+/// ```no_run
+/// # fn main() -> android_sparse::Result<()> {
+/// # let blocks: Vec<android_sparse::Block> = Vec::new();
+/// use android_sparse::session::BuildSession;
+///
+/// let mut session = BuildSession::new("out")?;
+/// for name in ["system.simg", "vendor.simg"] {
+///     let mut writer = session.create_image(name, true)?;
+///     for block in &blocks {
+///         writer.write_block(block)?;
+///     }
+///     writer.close()?;
+/// }
+/// session.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BuildSession {
+    out_dir: PathBuf,
+    dir: TempDir,
+    images: Vec<Staged>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl BuildSession {
+    /// Starts a session that commits images to the existing directory
+    /// `out_dir`.
+    pub fn new<P: AsRef<Path>>(out_dir: P) -> Result<Self> {
+        let out_dir = out_dir.as_ref();
+        // The staging directory must be on the same file system as the
+        // output directory, so images can be renamed into place.
+        let dir = tempfile::Builder::new()
+            .prefix(".simg-session")
+            .tempdir_in(out_dir)
+            .with_context(|| format!("Cannot create session in {}", out_dir.display()))?;
+        for subdir in ["staged", "backup", "tmp"] {
+            fs::create_dir(dir.path().join(subdir))?;
+        }
+
+        Ok(Self {
+            out_dir: out_dir.into(),
+            dir,
+            images: Vec::new(),
+            shared: Arc::new(Mutex::new(Shared {
+                total_blocks: 0,
+                on_progress: None,
+            })),
+        })
+    }
+
+    /// Calls `f` whenever a writer of this session has written a chunk.
+    pub fn on_progress<F>(self, f: F) -> Self
+    where
+        F: FnMut(&Progress) + Send + 'static,
+    {
+        self.shared.lock().unwrap().on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Returns a directory for intermediate files, which is removed
+    /// together with the session.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.dir.path().join("tmp")
+    }
+
+    /// Creates a writer for the sparse image `name` in the output directory.
+    ///
+    /// The writer must be closed before the session is committed.
+    pub fn create_image(&mut self, name: &str, crc: bool) -> Result<Writer<File>> {
+        let file = self.create_file(name)?;
+        let image_blocks = Arc::clone(&self.images.last().unwrap().blocks);
+        let shared = Arc::clone(&self.shared);
+        let image = name.to_string();
+
+        let writer = Writer::new(file, crc)?.on_chunk(move |entry| {
+            let blocks = u64::from(entry.header.chunk_size);
+            let image_blocks = image_blocks.fetch_add(blocks, Ordering::Relaxed) + blocks;
+
+            let mut shared = shared.lock().unwrap();
+            shared.total_blocks += blocks;
+            let total_blocks = shared.total_blocks;
+            if let Some(f) = shared.on_progress.as_mut() {
+                f(&Progress {
+                    image: &image,
+                    image_blocks,
+                    total_blocks,
+                });
+            }
+        });
+        Ok(writer)
+    }
+
+    /// Creates the file `name` in the output directory, for images that
+    /// are not sparse images, e.g. vbmeta images.
+    ///
+    /// Writing to the file must be finished before the session is
+    /// committed.
+    pub fn create_file(&mut self, name: &str) -> Result<File> {
+        ensure!(
+            !name.is_empty() && Path::new(name).file_name() == Some(name.as_ref()),
+            "Invalid image name: {name:?}"
+        );
+        ensure!(
+            self.images.iter().all(|i| i.name != name),
+            "Image {name} is already part of this session"
+        );
+
+        let file = File::create(self.staged_path(name))?;
+        self.images.push(Staged {
+            name: name.into(),
+            blocks: Arc::new(AtomicU64::new(0)),
+        });
+        Ok(file)
+    }
+
+    /// Moves all images of this session to the output directory, replacing
+    /// existing files.
+    ///
+    /// Either all images are moved, or, if that fails, the output directory
+    /// is restored to its previous state. Returns the paths of the images.
+    pub fn commit(self) -> Result<Vec<PathBuf>> {
+        for image in &self.images {
+            ensure!(
+                Arc::strong_count(&image.blocks) == 1,
+                "Image {} is still being written",
+                image.name
+            );
+        }
+
+        let mut committed = Vec::new();
+        for image in &self.images {
+            match self.replace(&image.name) {
+                Ok(had_backup) => committed.push((&image.name, had_backup)),
+                Err(err) => {
+                    self.rollback(&committed);
+                    return Err(err).with_context(|| format!("Cannot commit {}", image.name));
+                }
+            }
+        }
+
+        Ok(committed
+            .iter()
+            .map(|(name, _)| self.out_dir.join(name))
+            .collect())
+    }
+
+    /// Moves the staged image `name` into place, returning whether an
+    /// existing image was backed up.
+    fn replace(&self, name: &str) -> io::Result<bool> {
+        let dst = self.out_dir.join(name);
+        let backup = self.backup_path(name);
+
+        let had_backup = match fs::rename(&dst, &backup) {
+            Ok(()) => true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        if let Err(err) = fs::rename(self.staged_path(name), &dst) {
+            if had_backup {
+                fs::rename(&backup, &dst).ok();
+            }
+            return Err(err);
+        }
+        Ok(had_backup)
+    }
+
+    /// Restores the images replaced by `committed`, in reverse order.
+    fn rollback(&self, committed: &[(&String, bool)]) {
+        for (name, had_backup) in committed.iter().rev() {
+            let dst = self.out_dir.join(name);
+            match had_backup {
+                true => fs::rename(self.backup_path(name), &dst).ok(),
+                false => fs::remove_file(&dst).ok(),
+            };
+        }
+    }
+
+    fn staged_path(&self, name: &str) -> PathBuf {
+        self.dir.path().join("staged").join(name)
+    }
+
+    fn backup_path(&self, name: &str) -> PathBuf {
+        self.dir.path().join("backup").join(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::Block;
+
+    #[test]
+    fn commit_all_or_nothing() {
+        let out = tempfile::tempdir().unwrap();
+        fs::write(out.path().join("boot.simg"), b"old").unwrap();
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&progress);
+        let mut session = BuildSession::new(out.path())
+            .unwrap()
+            .on_progress(move |p| {
+                log.lock()
+                    .unwrap()
+                    .push((p.image.to_string(), p.total_blocks))
+            });
+
+        for name in ["system.simg", "boot.simg"] {
+            let mut writer = session.create_image(name, false).unwrap();
+            writer.write_block(&Block::Fill([1; 4])).unwrap();
+            writer.close().unwrap();
+        }
+        let mut vbmeta = session.create_file("vbmeta.img").unwrap();
+        io::Write::write_all(&mut vbmeta, b"vbmeta").unwrap();
+        assert!(session.create_file("../escape").is_err());
+        assert!(session.create_file("boot.simg").is_err());
+
+        // Failing to commit discards the whole session.
+        let writer = session.create_image("vendor.simg", false).unwrap();
+        assert!(session.commit().is_err());
+        drop(writer);
+        assert_eq!(fs::read(out.path().join("boot.simg")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 1);
+
+        let mut session = BuildSession::new(out.path()).unwrap();
+        for name in ["system.simg", "boot.simg"] {
+            session.create_image(name, false).unwrap().close().unwrap();
+        }
+        let paths = session.commit().unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_ne!(fs::read(out.path().join("boot.simg")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 2);
+
+        assert_eq!(
+            *progress.lock().unwrap(),
+            [("system.simg".to_string(), 1), ("boot.simg".to_string(), 2)]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Parsing the subset of TOML that profile and config files use.
+//!
+//! Files consist of `key = value` lines, grouped into tables by `[name]`
+//! lines where the file describes several things, e.g. profiles. A `#`
+//! starts a comment unless it is within a string. Values are integers,
+//! booleans or strings in double or single quotes, which are taken
+//! verbatim. Anything outside this subset is an error rather than being
+//! misread: arrays, inline tables, arrays of tables, multi-line strings
+//! and backslashes in double-quoted strings, which would start escape
+//! sequences in TOML. For compatibility with older config files, values
+//! may also be bare words, e.g. `buffer_size = 1M`.
+//!
+//! ```
+//! use android_sparse::settings;
+//!
+//! let tables = settings::parse_tables(
+//!     "[board]\nmax_size = \"64M\" # bytes\n",
+//!     |name| (name.to_string(), 0),
+//!     |(_, size), key, value| {
+//!         assert_eq!(key, "max_size");
+//!         *size = value.as_size()?;
+//!         Ok(())
+//!     },
+//! )
+//! .unwrap();
+//! assert_eq!(tables, [("board".to_string(), 64 << 20)]);
+//! ```
+
+use crate::result::{bail, ensure, Context, Result};
+
+/// The value of a `key = value` line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Value<'a>(&'a str);
+
+impl<'a> Value<'a> {
+    /// Returns the string this value quotes.
+    pub fn as_str(self) -> Result<&'a str> {
+        unquote(self.0).with_context(|| format!("Expected a string, found `{}`", self.0))
+    }
+
+    /// Returns the value without its quotes, if any.
+    pub fn text(self) -> &'a str {
+        unquote(self.0).unwrap_or(self.0)
+    }
+
+    /// Parses the value as an integer, see `parse_int`.
+    pub fn as_int(self) -> Result<u64> {
+        parse_int(self.0)
+    }
+
+    /// Parses the value as a boolean.
+    pub fn as_bool(self) -> Result<bool> {
+        match self.0 {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => bail!("Invalid boolean: {}", self.0),
+        }
+    }
+
+    /// Parses the value as a size in bytes, an integer with an optional K,
+    /// M or G suffix, in quotes or not.
+    pub fn as_size(self) -> Result<u64> {
+        let value = self.text();
+        let (digits, shift) = match value.as_bytes().last().map(u8::to_ascii_uppercase) {
+            Some(b'K') => (&value[..value.len() - 1], 10),
+            Some(b'M') => (&value[..value.len() - 1], 20),
+            Some(b'G') => (&value[..value.len() - 1], 30),
+            _ => (value, 0),
+        };
+        parse_int(digits)?
+            .checked_mul(1 << shift)
+            .with_context(|| format!("Size too large: {value}"))
+    }
+}
+
+/// Parses `content` as settings outside of any table, calling `set` with
+/// the key and value of each.
+///
+/// Errors are prefixed with the number of the offending line.
+pub fn parse<'a, F>(content: &'a str, mut set: F) -> Result<()>
+where
+    F: FnMut(&'a str, Value<'a>) -> Result<()>,
+{
+    parse_lines(content, |line| match line {
+        Line::Table(_) => bail!("Tables are not supported"),
+        Line::Setting(key, value) => set(key, value),
+    })
+}
+
+/// Parses `content` as tables of settings, returning the tables in order.
+///
+/// Each table is created by `new` from its name and filled in by calling
+/// `set` with it and the key and value of each of its settings. Settings
+/// before the first table are rejected, and errors are prefixed with the
+/// number of the offending line.
+pub fn parse_tables<'a, T, N, S>(content: &'a str, mut new: N, mut set: S) -> Result<Vec<T>>
+where
+    N: FnMut(&'a str) -> T,
+    S: FnMut(&mut T, &'a str, Value<'a>) -> Result<()>,
+{
+    let mut tables = Vec::new();
+    parse_lines(content, |line| match line {
+        Line::Table(name) => {
+            tables.push(new(name));
+            Ok(())
+        }
+        Line::Setting(key, value) => {
+            let table = tables.last_mut().context("Setting outside of a table")?;
+            set(table, key, value)
+        }
+    })?;
+    Ok(tables)
+}
+
+/// Parses a decimal integer, or a hexadecimal one with a `0x` prefix,
+/// either of which may contain `_` separators.
+pub fn parse_int(value: &str) -> Result<u64> {
+    let value = value.replace('_', "");
+    Ok(match value.strip_prefix("0x") {
+        Some(digits) => u64::from_str_radix(digits, 16)?,
+        None => value.parse()?,
+    })
+}
+
+/// A line that isn't empty or only a comment.
+enum Line<'a> {
+    Table(&'a str),
+    Setting(&'a str, Value<'a>),
+}
+
+fn parse_lines<'a, F>(content: &'a str, mut f: F) -> Result<()>
+where
+    F: FnMut(Line<'a>) -> Result<()>,
+{
+    for (number, line) in content.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        parse_line(line)
+            .and_then(&mut f)
+            .with_context(|| format!("Line {}", number + 1))?;
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Result<Line<'_>> {
+    if let Some(name) = line.strip_prefix('[') {
+        ensure!(!name.starts_with('['), "Arrays of tables are not supported");
+        let name = name.strip_suffix(']').context("Expected `[name]`")?.trim();
+        let name = unquote(name).unwrap_or(name);
+        ensure!(!name.is_empty(), "Empty table name");
+        return Ok(Line::Table(name));
+    }
+
+    let (key, value) = line.split_once('=').context("Expected `key = value`")?;
+    let key = key.trim();
+    ensure!(!key.is_empty(), "Empty key");
+    let value = value.trim();
+    check_value(value)?;
+    Ok(Line::Setting(key, Value(value)))
+}
+
+/// Fails if `value` is valid TOML outside of the supported subset, or
+/// isn't a single value.
+fn check_value(value: &str) -> Result<()> {
+    ensure!(!value.is_empty(), "Empty value");
+    match value.as_bytes()[0] {
+        b'[' => bail!("Arrays are not supported"),
+        b'{' => bail!("Inline tables are not supported"),
+        quote @ (b'"' | b'\'') => {
+            let quote = char::from(quote);
+            ensure!(
+                !value.starts_with(&quote.to_string().repeat(3)),
+                "Multi-line strings are not supported"
+            );
+            let end = value[1..].find(quote).context("Unterminated string")?;
+            ensure!(end + 2 == value.len(), "Unexpected text after string");
+            ensure!(
+                quote == '\'' || !value.contains('\\'),
+                "Escape sequences are not supported (use single quotes)"
+            );
+        }
+        _ => ensure!(
+            !value.contains(['"', '\'']),
+            "Unexpected quote in `{value}`"
+        ),
+    }
+    Ok(())
+}
+
+/// Returns the string in double or single quotes `value`, taken verbatim.
+fn unquote(value: &str) -> Option<&str> {
+    ['"', '\''].into_iter().find_map(|quote| {
+        value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+    })
+}
+
+/// Removes a `#` comment from `line`, unless it is within a string in
+/// double or single quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        let mut settings = Vec::new();
+        parse(
+            "# comment\n\
+             name = \"a # b\" # comment\n\
+             pattern = 'x#y'\n\
+             size = 64K\n\
+             \n\
+             count = 0x1_0\n",
+            |key, value| {
+                settings.push((key, value));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(settings.len(), 4);
+        assert_eq!(settings[0].1.as_str().unwrap(), "a # b");
+        assert_eq!(settings[1].1.as_str().unwrap(), "x#y");
+        assert_eq!(settings[2].1.as_size().unwrap(), 64 << 10);
+        assert!(settings[2].1.as_str().is_err());
+        assert_eq!(settings[3].1.as_int().unwrap(), 16);
+
+        let err = parse("a = 1\nb", |_, _| Ok(())).unwrap_err();
+        assert_eq!(format!("{err:#}"), "Line 2: Expected `key = value`");
+        assert!(parse("[a]", |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn unsupported_toml() {
+        let err = |content| format!("{:#}", parse(content, |_, _| Ok(())).unwrap_err());
+        assert_eq!(err("a = [1, 2]"), "Line 1: Arrays are not supported");
+        assert_eq!(
+            err("a = { b = 1 }"),
+            "Line 1: Inline tables are not supported"
+        );
+        assert_eq!(
+            err("a = \"\"\"b\"\"\""),
+            "Line 1: Multi-line strings are not supported"
+        );
+        assert_eq!(
+            err("a = '''b"),
+            "Line 1: Multi-line strings are not supported"
+        );
+        assert_eq!(
+            err(r#"a = "b\" # c""#),
+            "Line 1: Escape sequences are not supported (use single quotes)"
+        );
+        assert_eq!(err(r#"a = "b\tc""#), err(r#"a = "b\" # c""#));
+        assert_eq!(err("a = 'b' 'c'"), "Line 1: Unexpected text after string");
+        assert_eq!(err("a = 'b"), "Line 1: Unterminated string");
+        assert_eq!(err("a = b'c'"), "Line 1: Unexpected quote in `b'c'`");
+        assert_eq!(err("a ="), "Line 1: Empty value");
+
+        let err = parse_tables("[[a]]", |_| (), |_, _, _| Ok(())).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Line 1: Arrays of tables are not supported"
+        );
+    }
+
+    #[test]
+    fn parse_table_settings() {
+        let tables = parse_tables(
+            "[a]\nx = true\n['b']\n[a]\nx = false\n",
+            |name| (name, None),
+            |(_, x), key, value| {
+                assert_eq!(key, "x");
+                *x = Some(value.as_bool()?);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(tables, [("a", Some(true)), ("b", None), ("a", Some(false))]);
+
+        let new = |_| ();
+        assert!(parse_tables("x = 1", new, |_, _, _| Ok(())).is_err());
+        assert!(parse_tables("[a", new, |_, _, _| Ok(())).is_err());
+        assert!(parse_tables("[\"\"]", new, |_, _, _| Ok(())).is_err());
+    }
+}
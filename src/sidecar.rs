@@ -0,0 +1,312 @@
+//! Per-chunk checksums of sparse images, for fast partial verification.
+//!
+//! Verifying the checksum of a sparse image reads all of it, which takes a
+//! while for large archives. Instead, a `Writer` can record a CRC32 of
+//! every chunk as stored in the image to a sidecar file, and `verify`
+//! spot-checks a sample of the chunks against it, reading only those.
+//!
+//! The sidecar is a text file with a line per chunk, holding the offset of
+//! the chunk header in the image, the size of the chunk including its
+//! header, and the CRC32 of these bytes. Lines starting with `#` are
+//! comments. Metadata chunks are not listed.
+//!
+//! ```text
+//! 0x1c 4108 0x7e8f2c3a
+//! 0x1028 16 0x5d0b4e51
+//! ```
+
+use crate::{
+    dump::ChunkEntry,
+    gen::XorShift,
+    headers::{ChunkHeader, ChunkType},
+    read::Reader,
+    result::{bail, ensure, Context, Error, Result},
+};
+use crc32fast::Hasher;
+use std::{
+    fmt,
+    io::{prelude::*, SeekFrom},
+    str::FromStr,
+};
+
+/// The checksum of a chunk as stored in a sparse image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkCrc {
+    /// The offset of the chunk header in the sparse image.
+    pub offset: u64,
+    /// The size of the chunk in bytes, including its header.
+    pub size: u32,
+    /// The CRC32 of the bytes of the chunk.
+    pub crc: u32,
+}
+
+impl ChunkCrc {
+    /// Computes the checksum of the chunk of `size` bytes at `offset` in
+    /// `image`.
+    pub fn compute<R: Read + Seek>(mut image: R, offset: u64, size: u32) -> Result<Self> {
+        image.seek(SeekFrom::Start(offset))?;
+        let mut hasher = Hasher::new();
+        let mut buf = [0; 64 * 1024];
+        let mut remaining = size as usize;
+        while remaining > 0 {
+            let len = remaining.min(buf.len());
+            image
+                .read_exact(&mut buf[..len])
+                .with_context_at(offset, || "Reading chunk")?;
+            hasher.update(&buf[..len]);
+            remaining -= len;
+        }
+        Ok(Self {
+            offset,
+            size,
+            crc: hasher.finalize(),
+        })
+    }
+}
+
+impl fmt::Display for ChunkCrc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} {} {:#010x}", self.offset, self.size, self.crc)
+    }
+}
+
+impl FromStr for ChunkCrc {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = |value: &str| value.strip_prefix("0x").unwrap_or(value).to_owned();
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [offset, size, crc] = fields[..] else {
+            bail!("Expected offset, size and checksum, found `{s}`");
+        };
+        Ok(Self {
+            offset: u64::from_str_radix(&hex(offset), 16)?,
+            size: size.parse()?,
+            crc: u32::from_str_radix(&hex(crc), 16)?,
+        })
+    }
+}
+
+/// Computes the checksums of all chunks of the sparse image in `image`,
+/// e.g. to create a sidecar for an existing image.
+pub fn from_image<R: Read + Seek>(mut image: R) -> Result<Vec<ChunkCrc>> {
+    image.rewind()?;
+    let chunks = Reader::new(&mut image, false)?.prescan()?.chunks;
+    chunks
+        .iter()
+        .filter(|c| c.header.chunk_type != ChunkType::Metadata)
+        .map(|c| ChunkCrc::compute(&mut image, c.offset, c.header.total_size))
+        .collect()
+}
+
+/// Reads the chunk checksums of a sidecar.
+pub fn read<R: BufRead>(r: R) -> Result<Vec<ChunkCrc>> {
+    let mut crcs = Vec::new();
+    for (number, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let crc = line
+            .parse()
+            .with_context(|| format!("Line {}", number + 1))?;
+        crcs.push(crc);
+    }
+    Ok(crcs)
+}
+
+/// Writes `crcs` as a sidecar to `w`.
+pub fn write<W: Write>(mut w: W, crcs: &[ChunkCrc]) -> Result<()> {
+    for crc in crcs {
+        writeln!(w, "{crc}")?;
+    }
+    Ok(())
+}
+
+/// Which chunks `verify` checks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Selection {
+    /// All chunks.
+    All,
+    /// Up to `count` distinct chunks picked at random. The same seed picks
+    /// the same chunks.
+    Sample {
+        /// The number of chunks to check.
+        count: usize,
+        /// The seed of the pseudo-random generator.
+        seed: u64,
+    },
+    /// The chunks at the given indexes into the sidecar.
+    Indexes(Vec<usize>),
+}
+
+impl Selection {
+    /// Returns the indexes of the selected chunks out of `len` chunks.
+    fn indexes(&self, len: usize) -> Result<Vec<usize>> {
+        match self {
+            Selection::All => Ok((0..len).collect()),
+            Selection::Sample { count, seed } => {
+                // A partial Fisher-Yates shuffle picks distinct chunks.
+                let mut rng = XorShift::new(*seed);
+                let mut indexes: Vec<_> = (0..len).collect();
+                let count = (*count).min(len);
+                for i in 0..count {
+                    let j = i + (rng.next_u64() % (len - i) as u64) as usize;
+                    indexes.swap(i, j);
+                }
+                indexes.truncate(count);
+                Ok(indexes)
+            }
+            Selection::Indexes(indexes) => {
+                for &index in indexes {
+                    ensure!(
+                        index < len,
+                        "Chunk {index} out of range, the sidecar lists {len} chunks"
+                    );
+                }
+                Ok(indexes.clone())
+            }
+        }
+    }
+}
+
+/// Checks the chunks of `image` selected from `crcs` against their
+/// checksums, returning the number of chunks checked.
+///
+/// Fails at the first chunk whose checksum doesn't match, with the error
+/// reporting its offset.
+pub fn verify<R: Read + Seek>(
+    mut image: R,
+    crcs: &[ChunkCrc],
+    selection: &Selection,
+) -> Result<usize> {
+    let indexes = selection.indexes(crcs.len())?;
+    for &index in &indexes {
+        let expected = &crcs[index];
+        let actual = ChunkCrc::compute(&mut image, expected.offset, expected.size)
+            .with_context(|| format!("Chunk {index}"))?;
+        if actual.crc != expected.crc {
+            return Err(Error::msg("Checksum does not match")
+                .context_at(format!("Chunk {index}"), expected.offset));
+        }
+    }
+    Ok(indexes.len())
+}
+
+/// Records the checksums of the chunks a `Writer` writes to a sidecar.
+pub(crate) struct Recorder {
+    out: Box<dyn Write + Send>,
+    /// The checksum of the payload of the current chunk.
+    payload: Hasher,
+    /// Whether the current chunk was started before recording, so its
+    /// payload is incomplete.
+    partial: bool,
+}
+
+impl Recorder {
+    pub(crate) fn new<W: Write + Send + 'static>(out: W, partial: bool) -> Self {
+        Self {
+            out: Box::new(out),
+            payload: Hasher::new(),
+            partial,
+        }
+    }
+
+    /// Adds `bytes` to the payload of the current chunk.
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.payload.update(bytes);
+    }
+
+    /// Discards the payload of the current chunk, e.g. when it is
+    /// rewritten.
+    pub(crate) fn reset(&mut self) {
+        self.payload = Hasher::new();
+    }
+
+    /// Records the checksum of the finished chunk `entry`.
+    pub(crate) fn finish_chunk(&mut self, entry: &ChunkEntry) -> Result<()> {
+        let payload = std::mem::replace(&mut self.payload, Hasher::new());
+        if std::mem::take(&mut self.partial) {
+            return Ok(());
+        }
+
+        let mut header = Vec::with_capacity(ChunkHeader::SIZE.into());
+        entry.header.write_to(&mut header)?;
+        let mut hasher = Hasher::new();
+        hasher.update(&header);
+        hasher.combine(&payload);
+
+        let crc = ChunkCrc {
+            offset: entry.offset,
+            size: entry.header.total_size,
+            crc: hasher.finalize(),
+        };
+        writeln!(self.out, "{crc}").context("Writing chunk checksum sidecar")?;
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{block::Block, write::Writer};
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    /// A sidecar destination that can be inspected after the writer is
+    /// done with it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chunk_crcs() {
+        let sidecar = Shared::default();
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut image, true)
+            .unwrap()
+            .max_chunks(4)
+            .chunk_crcs(sidecar.clone());
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::fill_u32(7),
+            Block::Skip,
+            Block::Raw([2; Block::SIZE as usize].into()),
+        ];
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        // The limit converts the last chunks to raw data while writing.
+        let recorded = read(&sidecar.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(recorded, from_image(&mut image).unwrap());
+        assert_eq!(recorded.len(), 4);
+
+        let all = verify(&mut image, &recorded, &Selection::All).unwrap();
+        assert_eq!(all, 4);
+        let sample = Selection::Sample { count: 2, seed: 1 };
+        assert_eq!(verify(&mut image, &recorded, &sample).unwrap(), 2);
+        assert!(verify(&mut image, &recorded, &Selection::Indexes(vec![4])).is_err());
+
+        image.get_mut()[recorded[1].offset as usize + 12] ^= 1;
+        let err = verify(&mut image, &recorded, &Selection::Indexes(vec![0, 1])).unwrap_err();
+        assert_eq!(err.offset(), Some(recorded[1].offset));
+    }
+}
@@ -0,0 +1,119 @@
+//! Detached signatures over the decoded content of sparse images.
+//!
+//! Signatures are computed over the SHA-256 digest of the raw image a
+//! sparse image decodes to. They are therefore independent of how the
+//! image was encoded: re-encoding an image keeps its signature valid.
+//!
+//! Signing uses Ed25519. Keys and signatures are stored as raw bytes, i.e.
+//! 32-byte key files and 64-byte signature files.
+
+use crate::{
+    block::Block,
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+/// Computes the SHA-256 digest of the decoded content of sparse blocks.
+#[derive(Clone, Default)]
+pub struct ContentHasher {
+    hasher: Sha256,
+}
+
+impl ContentHasher {
+    /// Creates a new content hasher.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the digest of the blocks written so far.
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl BlockSink for ContentHasher {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        match block {
+            Block::Raw(buf) => self.hasher.update(&buf[..]),
+            Block::Fill(value) => {
+                let mut buf = [0; Block::SIZE as usize];
+                for chunk in buf.chunks_exact_mut(4) {
+                    chunk.copy_from_slice(value);
+                }
+                self.hasher.update(buf);
+            }
+            Block::Skip => self.hasher.update([0; Block::SIZE as usize]),
+            Block::Crc32(_) => (),
+        }
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the digest of the decoded content of the blocks in `src`.
+pub fn digest<S: BlockSource>(mut src: S) -> Result<[u8; 32]> {
+    let mut hasher = ContentHasher::new();
+    crate::pipeline::copy(&mut src, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Signs the decoded content of the blocks in `src`.
+pub fn sign<S: BlockSource>(src: S, key: &SigningKey) -> Result<Signature> {
+    Ok(sign_digest(&digest(src)?, key))
+}
+
+/// Verifies a signature over the decoded content of the blocks in `src`.
+pub fn verify<S: BlockSource>(src: S, key: &VerifyingKey, signature: &Signature) -> Result<()> {
+    verify_digest(&digest(src)?, key, signature)
+}
+
+/// Signs a content digest as returned by `digest`.
+pub fn sign_digest(digest: &[u8; 32], key: &SigningKey) -> Signature {
+    use ed25519_dalek::Signer;
+    key.sign(digest)
+}
+
+/// Verifies a signature over a content digest as returned by `digest`.
+pub fn verify_digest(digest: &[u8; 32], key: &VerifyingKey, signature: &Signature) -> Result<()> {
+    key.verify_strict(digest, signature)
+        .context("Signature does not match")
+}
+
+/// Reads a 32-byte Ed25519 signing key from `path`.
+pub fn read_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey> {
+    let bytes = read_exact_file::<32>(path.as_ref(), "signing key")?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Reads a 32-byte Ed25519 verifying key from `path`.
+pub fn read_verifying_key<P: AsRef<Path>>(path: P) -> Result<VerifyingKey> {
+    let bytes = read_exact_file::<32>(path.as_ref(), "verifying key")?;
+    VerifyingKey::from_bytes(&bytes).context("Invalid verifying key")
+}
+
+/// Reads a 64-byte detached signature from `path`.
+pub fn read_signature<P: AsRef<Path>>(path: P) -> Result<Signature> {
+    let bytes = read_exact_file::<64>(path.as_ref(), "signature")?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+/// Writes a detached signature to `path`.
+pub fn write_signature<P: AsRef<Path>>(path: P, signature: &Signature) -> Result<()> {
+    fs::write(path, signature.to_bytes())?;
+    Ok(())
+}
+
+fn read_exact_file<const N: usize>(path: &Path, what: &str) -> Result<[u8; N]> {
+    let bytes = fs::read(path)?;
+    bytes
+        .try_into()
+        .ok()
+        .with_context(|| format!("Invalid {what} size, expected {N} bytes"))
+}
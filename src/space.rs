@@ -0,0 +1,121 @@
+//! Planning the storage space a decoded image takes up.
+//!
+//! A decoded image always has the full raw size, but on filesystems that
+//! support holes its don't-care regions take up no space, as long as they
+//! are left as holes or punched out afterward. A `SpaceReport` tells both
+//! sizes from the chunk headers of a sparse image, without decoding it, and
+//! how large a partition must be to hold the image.
+
+use crate::{
+    block::Block,
+    dump::Chunks,
+    headers::ChunkType,
+    human::{HumanSize, Percent},
+    result::Result,
+};
+use std::{fmt, io::Read};
+
+/// The storage space a decoded image takes up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// The size of the raw image in bytes, i.e. the space it takes up
+    /// without holes.
+    pub raw_size: u64,
+    /// The number of bytes of blocks that hold data, i.e. the space the
+    /// image takes up with its don't-care blocks as holes.
+    ///
+    /// Fill blocks always count as data, even if they are filled with
+    /// zeros, as do raw blocks of zeros, since their data isn't inspected.
+    pub allocated: u64,
+    /// The offset in the raw image after the last block that holds data.
+    ///
+    /// Partitions that are smaller than the raw image but at least this
+    /// large can hold all data, e.g. if the filesystem on it is resized.
+    pub data_end: u64,
+}
+
+impl SpaceReport {
+    /// Computes the space report of the sparse image in `r`.
+    ///
+    /// Only chunk headers are read, payloads are skipped over.
+    pub fn from_image<R: Read>(r: R) -> Result<Self> {
+        let chunks = Chunks::new(r)?;
+        let raw_size = u64::from(chunks.header().total_blocks) * u64::from(Block::SIZE);
+        let mut report = Self {
+            raw_size,
+            ..Self::default()
+        };
+
+        for chunk in chunks {
+            let chunk = chunk?;
+            if matches!(chunk.header.chunk_type, ChunkType::Raw | ChunkType::Fill) {
+                let size = u64::from(chunk.header.chunk_size) * u64::from(Block::SIZE);
+                report.allocated += size;
+                report.data_end = chunk.raw_offset() + size;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns the number of bytes of the raw image that can be holes.
+    pub fn holes(&self) -> u64 {
+        self.raw_size.saturating_sub(self.allocated)
+    }
+
+    /// Returns the size of a partition holding the whole raw image, rounded
+    /// up to a multiple of `alignment` bytes, e.g. 1 MiB like most
+    /// partitioning tools align partitions.
+    pub fn partition_size(&self, alignment: u64) -> u64 {
+        self.raw_size.next_multiple_of(alignment.max(1))
+    }
+}
+
+impl fmt::Display for SpaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "without holes:  {}", HumanSize(self.raw_size))?;
+        writeln!(
+            f,
+            "with holes:     {} ({} of raw)",
+            HumanSize(self.allocated),
+            Percent::new(self.allocated, self.raw_size)
+        )?;
+        write!(f, "data ends at:   {:#x}", self.data_end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::write::Writer;
+    use std::io::Cursor;
+
+    #[test]
+    fn space_report() {
+        let mut sparse = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut sparse, true).unwrap();
+        let blocks = [
+            Block::Skip,
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::fill_u32(0),
+            Block::fill_u32(0),
+            Block::Skip,
+        ];
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        let report = SpaceReport::from_image(&sparse.get_ref()[..]).unwrap();
+        assert_eq!(
+            report,
+            SpaceReport {
+                raw_size: 5 * 4096,
+                allocated: 3 * 4096,
+                data_end: 4 * 4096,
+            }
+        );
+        assert_eq!(report.holes(), 2 * 4096);
+        assert_eq!(report.partition_size(1 << 20), 1 << 20);
+        assert_eq!(report.partition_size(0), 5 * 4096);
+    }
+}
@@ -0,0 +1,393 @@
+//! Replacing bytes at raw offsets of a sparse image.
+//!
+//! Splicing overwrites bytes of the raw image at a given offset with new
+//! content, without shifting anything, e.g. to stamp a serial number or
+//! patch a flag inside a sparse image. `splice_in_place` writes the
+//! content straight into the raw chunks that store these bytes, leaving
+//! the rest of the image untouched. `splice` handles any image by
+//! rewriting it, where only the chunks holding the bytes change.
+//!
+//! Several edits are applied in one pass with `apply` and
+//! `apply_in_place`. `read_edits` reads them from a patch list, a text
+//! file with a line per edit holding the raw offset, decimal or
+//! hexadecimal, and the new bytes, in hexadecimal or as a quoted string.
+//! Lines starting with `#` are comments.
+//!
+//! ```text
+//! # build fingerprint
+//! 0x1000 "release-keys"
+//! 8196 deadbeef
+//! ```
+
+use crate::{
+    block::Block,
+    headers::{ChunkType, FileHeader},
+    pipeline::{next_data_block, BlockSink, BlockSource},
+    read::Reader,
+    result::{bail, ensure, Context, Error, Result},
+};
+use std::{
+    fmt,
+    io::{prelude::*, SeekFrom},
+    ops::Range,
+    str::FromStr,
+};
+
+/// An edit replacing bytes of a raw image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edit {
+    /// The offset of the bytes in the raw image.
+    pub offset: u64,
+    /// The new bytes.
+    pub data: Vec<u8>,
+}
+
+impl Edit {
+    /// Returns the range of raw image bytes the edit replaces.
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.data.len() as u64
+    }
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} ", self.offset)?;
+        self.data
+            .iter()
+            .try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl FromStr for Edit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((offset, data)) = s.trim().split_once(char::is_whitespace) else {
+            bail!("Expected offset and data, found `{s}`");
+        };
+        let offset = match offset.strip_prefix("0x") {
+            Some(digits) => u64::from_str_radix(digits, 16)?,
+            None => offset.parse()?,
+        };
+
+        let data = data.trim();
+        let data = match data.strip_prefix('"') {
+            Some(text) => match text.strip_suffix('"') {
+                Some(text) => text.as_bytes().to_vec(),
+                None => bail!("Unterminated string `{data}`"),
+            },
+            None => {
+                let digits: String = data.split_whitespace().collect();
+                ensure!(
+                    digits.len().is_multiple_of(2) && digits.bytes().all(|b| b.is_ascii_hexdigit()),
+                    "Expected hex bytes or a quoted string, found `{data}`"
+                );
+                (0..digits.len())
+                    .step_by(2)
+                    .map(|i| Ok(u8::from_str_radix(&digits[i..i + 2], 16)?))
+                    .collect::<Result<_>>()?
+            }
+        };
+        Ok(Self { offset, data })
+    }
+}
+
+/// Reads the edits of a patch list.
+pub fn read_edits<R: BufRead>(r: R) -> Result<Vec<Edit>> {
+    let mut edits = Vec::new();
+    for (number, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let edit = line
+            .parse()
+            .with_context(|| format!("Line {}", number + 1))?;
+        edits.push(edit);
+    }
+    Ok(edits)
+}
+
+/// Splices `data` into the raw image of `src` at `offset`, writing the
+/// result to `dst`.
+///
+/// See `apply`, which this calls with a single edit.
+pub fn splice<S, K>(src: S, offset: u64, data: &[u8], dst: &mut K) -> Result<()>
+where
+    S: BlockSource,
+    K: BlockSink + ?Sized,
+{
+    let edit = Edit {
+        offset,
+        data: data.to_vec(),
+    };
+    apply(src, &[edit], dst)
+}
+
+/// Applies `edits` to the raw image of `src` in one pass, writing the
+/// result to `dst`.
+///
+/// Blocks holding edited bytes are written as raw blocks, all others as
+/// read. Fill and don't-care chunks holding edited bytes are split around
+/// them, and don't-care blocks are filled with zeros, so the output may
+/// have a few more chunks. Fails if edits overlap or extend beyond the end
+/// of the image. `dst` is not closed.
+pub fn apply<S, K>(mut src: S, edits: &[Edit], dst: &mut K) -> Result<()>
+where
+    S: BlockSource,
+    K: BlockSink + ?Sized,
+{
+    let edits = sorted(edits)?;
+    let mut next = 0;
+    let mut start = 0;
+    while let Some(block) = next_data_block(&mut src)? {
+        let block_range = start..start + u64::from(Block::SIZE);
+        start = block_range.end;
+
+        let mut buf = None;
+        for edit in &edits[next..] {
+            if edit.offset >= block_range.end {
+                break;
+            }
+            let Some(overlap) = overlap(&block_range, &edit.range()) else {
+                continue;
+            };
+            let buf = buf.get_or_insert_with(|| {
+                let mut buf = [0; Block::SIZE as usize];
+                block.decode_into(&mut buf);
+                buf
+            });
+            let edited = overlap.start - block_range.start..overlap.end - block_range.start;
+            let bytes = overlap.start - edit.offset..overlap.end - edit.offset;
+            buf[edited.start as usize..edited.end as usize]
+                .copy_from_slice(&edit.data[bytes.start as usize..bytes.end as usize]);
+        }
+        while next < edits.len() && edits[next].range().end <= block_range.end {
+            next += 1;
+        }
+
+        match buf {
+            Some(buf) => dst.write_block(&Block::Raw(buf.into()))?,
+            None => dst.write_block(&block)?,
+        }
+    }
+
+    if let Some(edit) = edits.get(next) {
+        let range = edit.range();
+        bail!(
+            "Cannot splice bytes {:#x}..{:#x} into an image of {start:#x} bytes",
+            range.start,
+            range.end
+        );
+    }
+    Ok(())
+}
+
+/// Splices `data` into the raw image of the sparse image `image` at
+/// `offset`, overwriting the raw chunks that store these bytes in place.
+///
+/// See `apply_in_place`, which this calls with a single edit.
+pub fn splice_in_place<F>(image: F, offset: u64, data: &[u8]) -> Result<()>
+where
+    F: Read + Write + Seek,
+{
+    let edit = Edit {
+        offset,
+        data: data.to_vec(),
+    };
+    apply_in_place(image, &[edit])
+}
+
+/// Applies `edits` to the raw image of the sparse image `image`,
+/// overwriting the raw chunks that store the edited bytes in place.
+///
+/// Nothing but the edited bytes is written. This requires all of them to
+/// be stored in raw chunks, and the image not to have a checksum, which
+/// would have to be recomputed. Use `apply` otherwise. All of this is
+/// checked before anything is written.
+pub fn apply_in_place<F>(mut image: F, edits: &[Edit]) -> Result<()>
+where
+    F: Read + Write + Seek,
+{
+    let edits = sorted(edits)?;
+    image.rewind()?;
+    let header = FileHeader::read_from(&mut image)?;
+    image.rewind()?;
+    let scan = Reader::new(&mut image, false)?.prescan()?;
+    ensure!(
+        !scan.checksum && header.image_checksum == 0,
+        "Cannot splice in place into an image with a checksum"
+    );
+
+    let mut writes = Vec::new();
+    for edit in edits {
+        let range = edit.range();
+        let mut covered = range.start;
+        for chunk in &scan.chunks {
+            let start = chunk.start_block * u64::from(Block::SIZE);
+            let chunk_range =
+                start..start + u64::from(chunk.header.chunk_size) * u64::from(Block::SIZE);
+            let Some(overlap) = overlap(&chunk_range, &range) else {
+                continue;
+            };
+            if chunk.header.chunk_type != ChunkType::Raw {
+                bail!(
+                    "Bytes {:#x}..{:#x} are stored in a {:?} chunk, not as raw data",
+                    overlap.start,
+                    overlap.end,
+                    chunk.header.chunk_type
+                );
+            }
+            let position = chunk.payload_offset() + (overlap.start - start);
+            let bytes =
+                (overlap.start - edit.offset) as usize..(overlap.end - edit.offset) as usize;
+            writes.push((position, &edit.data[bytes]));
+            covered = overlap.end;
+        }
+        ensure!(
+            covered == range.end,
+            "Cannot splice bytes {:#x}..{:#x} beyond the end of the image",
+            range.start,
+            range.end
+        );
+    }
+
+    for (position, bytes) in writes {
+        image.seek(SeekFrom::Start(position))?;
+        image
+            .write_all(bytes)
+            .with_context_at(position, || "Writing spliced data")?;
+    }
+    Ok(image.flush()?)
+}
+
+/// Returns the non-empty edits of `edits` sorted by offset, failing if any
+/// of them overlap.
+fn sorted(edits: &[Edit]) -> Result<Vec<&Edit>> {
+    let mut sorted: Vec<_> = edits.iter().filter(|e| !e.data.is_empty()).collect();
+    sorted.sort_by_key(|e| e.offset);
+    for pair in sorted.windows(2) {
+        ensure!(
+            pair[0].range().end <= pair[1].offset,
+            "Edits at {:#x} and {:#x} overlap",
+            pair[0].offset,
+            pair[1].offset
+        );
+    }
+    Ok(sorted)
+}
+
+/// Returns the intersection of `a` and `b`, if they overlap.
+fn overlap(a: &Range<u64>, b: &Range<u64>) -> Option<Range<u64>> {
+    let start = a.start.max(b.start);
+    let end = a.end.min(b.end);
+    (start < end).then_some(start..end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{testutil::write_sparse, write::Writer};
+    use std::io::Cursor;
+
+    fn decode(image: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for block in Reader::new(image, true).unwrap() {
+            block.unwrap().write_decoded(&mut raw).unwrap();
+        }
+        raw
+    }
+
+    #[test]
+    fn splice_images() {
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::Raw([2; Block::SIZE as usize].into()),
+            Block::fill_u32(3),
+            Block::Skip,
+        ];
+        let image = |crc| write_sparse(&blocks, crc).unwrap();
+        let spliced = |offset: usize, data: &[u8]| {
+            let mut raw = decode(&image(false));
+            raw[offset..offset + data.len()].copy_from_slice(data);
+            raw
+        };
+
+        // Across the boundary of two raw blocks, in place.
+        let mut file = Cursor::new(image(false));
+        splice_in_place(&mut file, 4090, b"serial-1234").unwrap();
+        assert_eq!(decode(file.get_ref()), spliced(4090, b"serial-1234"));
+
+        // Into a fill block, and with a checksum, by rewriting.
+        let original = image(true);
+        let mut file = Cursor::new(original.clone());
+        assert!(splice_in_place(&mut file, 0, b"x").is_err());
+        assert!(splice_in_place(Cursor::new(image(false)), 8192, b"x").is_err());
+        assert_eq!(file.into_inner(), original);
+
+        let reader = Reader::new(&original[..], false).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), true).unwrap();
+        splice(reader, 8200, b"flag", &mut writer).unwrap();
+        let output = writer.close().unwrap().into_inner();
+        assert_eq!(decode(&output), spliced(8200, b"flag"));
+
+        let reader = Reader::new(&original[..], false).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        assert!(splice(reader, 4 * 4096 - 1, b"xy", &mut writer).is_err());
+    }
+
+    #[test]
+    fn patch_lists() {
+        let list = "# fingerprint\n0x10 \"release\"\n\n4094 dead beef\n";
+        let edits = read_edits(list.as_bytes()).unwrap();
+        assert_eq!(
+            edits,
+            [
+                Edit {
+                    offset: 0x10,
+                    data: b"release".to_vec(),
+                },
+                Edit {
+                    offset: 4094,
+                    data: vec![0xde, 0xad, 0xbe, 0xef],
+                },
+            ]
+        );
+        assert_eq!(edits[1].to_string().parse::<Edit>().unwrap(), edits[1]);
+        assert!(read_edits("0x10 abc\n".as_bytes()).is_err());
+        assert!(read_edits("0x10 \"open\n".as_bytes()).is_err());
+        assert!("12".parse::<Edit>().is_err());
+
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::Raw([2; Block::SIZE as usize].into()),
+            Block::fill_u32(3),
+        ];
+        let original = write_sparse(&blocks, false).unwrap();
+        let mut expected = decode(&original);
+        for edit in &edits {
+            let start = edit.offset as usize;
+            expected[start..start + edit.data.len()].copy_from_slice(&edit.data);
+        }
+
+        let mut file = Cursor::new(original.clone());
+        apply_in_place(&mut file, &edits).unwrap();
+        assert_eq!(decode(file.get_ref()), expected);
+
+        let mut edits = edits;
+        edits.reverse();
+        let reader = Reader::new(&original[..], false).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        apply(reader, &edits, &mut writer).unwrap();
+        assert_eq!(decode(&writer.close().unwrap().into_inner()), expected);
+
+        edits.push(Edit {
+            offset: 3 * 4096,
+            data: vec![0],
+        });
+        let reader = Reader::new(&original[..], false).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), false).unwrap();
+        assert!(apply(reader, &edits, &mut writer).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! Splitting of sparse images into several smaller ones.
+//!
+//! Every part is a complete sparse image that covers the whole raw image,
+//! with the blocks stored in other parts skipped. Writing all parts to the
+//! same destination in order, or merging them with `merge::merge`, yields
+//! the original image. This is the scheme libsparse uses for images that
+//! exceed the download size of a fastboot device.
+
+use crate::{
+    block::Block,
+    headers::{ChunkHeader, ChunkType, FileHeader},
+    io::AtomicFile,
+    pipeline::{next_data_block, BlockSource},
+    profile::Profile,
+    write::Writer,
+};
+use crate::result::{ensure, Result};
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+};
+
+/// Splits the image in `src` into sparse images of at most `max_size`
+/// bytes.
+///
+/// The parts are written to `<prefix>.0`, `<prefix>.1`, and so on. Returns
+/// the paths of the written parts. Checksum blocks are dropped.
+pub fn split<S: BlockSource>(src: S, max_size: u64, prefix: &Path) -> Result<Vec<PathBuf>> {
+    let mut parts = FileParts {
+        prefix,
+        current: None,
+        paths: Vec::new(),
+    };
+    split_into(src, &Limits::size(max_size), &mut parts)?;
+    Ok(parts.paths)
+}
+
+/// Splits the image in `src` into sparse images that satisfy `profile`.
+///
+/// Parts have at most the maximum size and number of chunks of the
+/// profile, and a checksum if it requires one. Otherwise, this works like
+/// `split`, so an image that already satisfies the profile is written as a
+/// single part.
+pub fn split_for<S: BlockSource>(src: S, profile: &Profile, prefix: &Path) -> Result<Vec<PathBuf>> {
+    profile.check_supported()?;
+    let limits = Limits {
+        max_size: profile.max_size.unwrap_or(u64::MAX),
+        max_chunks: profile.max_chunks.unwrap_or(u32::MAX),
+        crc: profile.require_crc,
+    };
+
+    let mut parts = FileParts {
+        prefix,
+        current: None,
+        paths: Vec::new(),
+    };
+    split_into(src, &limits, &mut parts)?;
+    Ok(parts.paths)
+}
+
+/// Splits the image in `src` at the raw image offsets `boundaries`, e.g.
+/// the partition boundaries of a disk image.
+///
+/// Each region between two boundaries, plus the ones before the first and
+/// after the last boundary, becomes a part of its own, even if it holds no
+/// data. Like all parts, it skips the blocks before and after its region.
+/// Boundaries must be multiples of the block size and in ascending order.
+/// The parts are written to `<prefix>.0`, `<prefix>.1`, and so on. Returns
+/// the paths of the written parts. Checksum blocks are dropped.
+pub fn split_at<S: BlockSource>(
+    mut src: S,
+    boundaries: &[u64],
+    prefix: &Path,
+) -> Result<Vec<PathBuf>> {
+    let block_size = u64::from(Block::SIZE);
+    let raw_size = src.raw_size();
+    let mut previous = 0;
+    for &boundary in boundaries {
+        ensure!(
+            boundary % block_size == 0,
+            "Boundary {boundary:#x} is not a multiple of the block size"
+        );
+        ensure!(
+            boundary >= previous,
+            "Boundary {boundary:#x} precedes boundary {previous:#x}"
+        );
+        ensure!(
+            raw_size.is_none_or(|size| boundary <= size),
+            "Boundary {boundary:#x} lies beyond the end of the image"
+        );
+        previous = boundary;
+    }
+
+    let mut parts = FileParts {
+        prefix,
+        current: None,
+        paths: Vec::new(),
+    };
+    let total_blocks = raw_size.map(|s| s / block_size);
+    let ends = boundaries.iter().map(|b| Some(b / block_size));
+    let mut start = 0;
+    let mut next = next_data_block(&mut src)?;
+
+    for (index, end) in ends.chain([None]).enumerate() {
+        let mut part = Part::new(Writer::new(parts.create(index)?, false)?);
+        part.skip(start)?;
+
+        let mut position = start;
+        while end.is_none_or(|end| position < end) {
+            let Some(block) = next.take() else {
+                break;
+            };
+            part.write_block(&block)?;
+            position += 1;
+            next = next_data_block(&mut src)?;
+        }
+
+        // Without knowing the size of the image, parts end at their region.
+        let total = total_blocks.or(end).unwrap_or(position);
+        part.skip(total.saturating_sub(position))?;
+        part.writer.close()?;
+        parts.finish(index)?;
+        start = end.unwrap_or(position);
+    }
+
+    Ok(parts.paths)
+}
+
+/// Splits the image in `src` into sparse images of at most `max_size`
+/// bytes, writing each to the destination `next_output` returns.
+///
+/// `next_output` is called with the index of each part once it is
+/// complete, so callers decide where parts go, e.g. files of their own
+/// naming, sockets or uploads. Parts are spooled to a temporary file until
+/// then, since sparse images can only be written once all of their chunks
+/// are known. Returns the number of parts. Checksum blocks are dropped.
+pub fn split_with<S, W, F>(src: S, max_size: u64, next_output: F) -> Result<usize>
+where
+    S: BlockSource,
+    W: Write,
+    F: FnMut(usize) -> io::Result<W>,
+{
+    let mut parts = CallbackParts {
+        next_output,
+        spool: None,
+    };
+    split_into(src, &Limits::size(max_size), &mut parts)
+}
+
+/// The limits of the parts of a split image.
+struct Limits {
+    max_size: u64,
+    max_chunks: u32,
+    /// Whether parts get a checksum chunk.
+    crc: bool,
+}
+
+impl Limits {
+    fn size(max_size: u64) -> Self {
+        Self {
+            max_size,
+            max_chunks: u32::MAX,
+            crc: false,
+        }
+    }
+}
+
+/// The destinations of the parts of a split image.
+trait Parts {
+    /// Returns the file to write part `index` to.
+    fn create(&mut self, index: usize) -> Result<File>;
+
+    /// Finishes part `index` once it was written.
+    fn finish(&mut self, index: usize) -> Result<()>;
+}
+
+/// Writes parts to files named after a common prefix.
+struct FileParts<'a> {
+    prefix: &'a Path,
+    current: Option<AtomicFile>,
+    paths: Vec<PathBuf>,
+}
+
+impl Parts for FileParts<'_> {
+    fn create(&mut self, index: usize) -> Result<File> {
+        let file = AtomicFile::create(part_path(self.prefix, index))?;
+        let clone = file.as_file().try_clone()?;
+        self.current = Some(file);
+        Ok(clone)
+    }
+
+    fn finish(&mut self, _index: usize) -> Result<()> {
+        if let Some(file) = self.current.take() {
+            self.paths.push(file.path().to_path_buf());
+            file.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Spools parts to a temporary file, then copies them to the destination
+/// returned by a callback.
+struct CallbackParts<F> {
+    next_output: F,
+    spool: Option<File>,
+}
+
+impl<W, F> Parts for CallbackParts<F>
+where
+    W: Write,
+    F: FnMut(usize) -> io::Result<W>,
+{
+    fn create(&mut self, _index: usize) -> Result<File> {
+        let spool = tempfile::tempfile()?;
+        let clone = spool.try_clone()?;
+        self.spool = Some(spool);
+        Ok(clone)
+    }
+
+    fn finish(&mut self, index: usize) -> Result<()> {
+        if let Some(mut spool) = self.spool.take() {
+            spool.rewind()?;
+            let mut output = (self.next_output)(index)?;
+            io::copy(&mut spool, &mut output)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn split_into<S: BlockSource, P: Parts>(
+    mut src: S,
+    limits: &Limits,
+    parts: &mut P,
+) -> Result<usize> {
+    let total_blocks = src.raw_size().map(|s| s / u64::from(Block::SIZE));
+    // A part ends with a chunk skipping the rest of the image, and its
+    // checksum chunk.
+    let mut reserved_size = 0;
+    let mut reserved_chunks = 0;
+    if total_blocks.is_some() {
+        reserved_size += u64::from(ChunkHeader::SIZE);
+        reserved_chunks += 1;
+    }
+    if limits.crc {
+        reserved_size += u64::from(ChunkHeader::SIZE) + 4;
+        reserved_chunks += 1;
+    }
+    let max_size = limits.max_size;
+    let max_chunks = limits.max_chunks;
+
+    let mut index = 0;
+    let mut position = 0;
+    let mut next = next_data_block(&mut src)?;
+
+    while let Some(mut block) = next.take() {
+        let mut part = Part::new(Writer::new(parts.create(index)?, limits.crc)?);
+        part.skip(position)?;
+
+        let mut count = 0;
+        loop {
+            let too_large = part.size_with(&block).saturating_add(reserved_size) > max_size;
+            let too_many = part.chunks_with(&block).saturating_add(reserved_chunks) > max_chunks;
+            if too_large || too_many {
+                ensure!(
+                    count > 0,
+                    "Maximum size {max_size} or {max_chunks} chunks is too small to hold a block"
+                );
+                next = Some(block);
+                break;
+            }
+
+            part.write_block(&block)?;
+            position += 1;
+            count += 1;
+
+            match next_data_block(&mut src)? {
+                Some(b) => block = b,
+                None => break,
+            }
+        }
+
+        if let Some(total) = total_blocks {
+            part.skip(total.saturating_sub(position))?;
+        }
+        part.writer.close()?;
+        parts.finish(index)?;
+        index += 1;
+    }
+
+    Ok(index)
+}
+
+fn part_path(prefix: &Path, index: usize) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    path.into()
+}
+
+/// A part being written, keeping track of its size.
+struct Part {
+    writer: Writer<File>,
+    size: u64,
+    chunks: u32,
+    chunk: Option<(ChunkType, Option<[u8; 4]>)>,
+}
+
+impl Part {
+    fn new(writer: Writer<File>) -> Self {
+        Self {
+            writer,
+            size: u64::from(FileHeader::SIZE),
+            chunks: 0,
+            chunk: None,
+        }
+    }
+
+    /// Returns the size of the part after writing `block`.
+    fn size_with(&self, block: &Block) -> u64 {
+        let (chunk, payload) = chunk_of(block);
+        let merged = self.chunk == Some(chunk);
+
+        match block {
+            Block::Raw(_) => {
+                self.size
+                    + payload
+                    + if merged {
+                        0
+                    } else {
+                        u64::from(ChunkHeader::SIZE)
+                    }
+            }
+            _ if merged => self.size,
+            _ => self.size + payload + u64::from(ChunkHeader::SIZE),
+        }
+    }
+
+    /// Returns the number of chunks of the part after writing `block`.
+    fn chunks_with(&self, block: &Block) -> u32 {
+        let merged = self.chunk == Some(chunk_of(block).0);
+        self.chunks + u32::from(!merged)
+    }
+
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        self.size = self.size_with(block);
+        self.chunks = self.chunks_with(block);
+        self.chunk = Some(chunk_of(block).0);
+        self.writer.write_block(block)
+    }
+
+    fn skip(&mut self, blocks: u64) -> Result<()> {
+        for _ in 0..blocks {
+            self.write_block(&Block::Skip)?;
+        }
+        Ok(())
+    }
+}
+
+fn chunk_of(block: &Block) -> ((ChunkType, Option<[u8; 4]>), u64) {
+    match block {
+        Block::Raw(_) => ((ChunkType::Raw, None), u64::from(Block::SIZE)),
+        Block::Fill(value) => ((ChunkType::Fill, Some(*value)), 4),
+        Block::Skip => ((ChunkType::DontCare, None), 0),
+        Block::Crc32(_) => ((ChunkType::Crc32, None), 4),
+    }
+}
@@ -0,0 +1,134 @@
+//! Printable strings in the raw data of sparse images.
+//!
+//! Looking for strings in a large image usually means decoding it and
+//! running `strings` or `grep` on the raw image, most of which is holes
+//! and fills. `scan` reads only the payloads of raw chunks and reports the
+//! strings found with their offsets in the raw image. Strings continue
+//! across raw chunks that cover consecutive blocks.
+
+use crate::{
+    headers::ChunkType,
+    read::Reader,
+    result::{Context, Result},
+};
+use std::io::{prelude::*, SeekFrom};
+
+/// The minimum length of strings reported by `strings`, like GNU strings.
+pub const DEFAULT_MIN_LEN: usize = 4;
+
+/// The number of bytes read from a raw chunk at once.
+const READ_SIZE: usize = 1 << 20;
+
+/// Checks whether `byte` can be part of a string, i.e. is printable ASCII
+/// or a tab.
+pub fn is_printable(byte: u8) -> bool {
+    byte == b'\t' || (b' '..=b'~').contains(&byte)
+}
+
+/// Calls `f` with the raw offset and text of every string of at least
+/// `min_len` printable characters in the raw chunks of the sparse image in
+/// `image`, in order.
+///
+/// Fill chunks are skipped, even if their fill value is printable.
+pub fn scan<R, F>(mut image: R, min_len: usize, mut f: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(u64, &str) -> Result<()>,
+{
+    image.rewind()?;
+    let chunks = Reader::new(&mut image, false)?.prescan()?.chunks;
+
+    let mut text = String::new();
+    let mut start = 0;
+    // The raw offset following the bytes scanned last.
+    let mut end = None;
+    let mut buf = vec![0; READ_SIZE];
+    let raw_chunks = chunks
+        .iter()
+        .filter(|c| c.header.chunk_type == ChunkType::Raw);
+    for chunk in raw_chunks {
+        if end != Some(chunk.raw_offset()) {
+            emit(&mut text, start, min_len, &mut f)?;
+        }
+
+        let mut offset = chunk.raw_offset();
+        let mut remaining = chunk.payload_size();
+        image.seek(SeekFrom::Start(chunk.payload_offset()))?;
+        while remaining > 0 {
+            let len = remaining.min(READ_SIZE as u64) as usize;
+            image
+                .read_exact(&mut buf[..len])
+                .with_context_at(chunk.payload_offset(), || "Reading raw data")?;
+            for (pos, &byte) in (offset..).zip(&buf[..len]) {
+                if !is_printable(byte) {
+                    emit(&mut text, start, min_len, &mut f)?;
+                } else {
+                    if text.is_empty() {
+                        start = pos;
+                    }
+                    text.push(char::from(byte));
+                }
+            }
+            offset += len as u64;
+            remaining -= len as u64;
+        }
+        end = Some(offset);
+    }
+    emit(&mut text, start, min_len, &mut f)
+}
+
+/// Passes `text` to `f` if it is long enough, and clears it.
+fn emit<F>(text: &mut String, start: u64, min_len: usize, f: &mut F) -> Result<()>
+where
+    F: FnMut(u64, &str) -> Result<()>,
+{
+    if !text.is_empty() && text.len() >= min_len {
+        f(start, text)?;
+    }
+    text.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{block::Block, testutil::write_sparse};
+    use std::io::Cursor;
+
+    fn raw_block(strings: &[(usize, &str)]) -> Block {
+        let mut data = [0; Block::SIZE as usize];
+        for (offset, s) in strings {
+            data[*offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        }
+        Block::Raw(data.into())
+    }
+
+    #[test]
+    fn raw_chunks_only() {
+        let blocks = [
+            raw_block(&[(10, "hello"), (100, "abc"), (4090, "spans!")]),
+            raw_block(&[(0, " blocks")]),
+            Block::fill_u32(u32::from_le_bytes(*b"fill")),
+            raw_block(&[(4092, "ends")]),
+            Block::Skip,
+            raw_block(&[(0, "after hole")]),
+        ];
+        let image = Cursor::new(write_sparse(&blocks, false).unwrap());
+
+        let mut found = Vec::new();
+        scan(image, DEFAULT_MIN_LEN, |offset, text| {
+            found.push((offset, text.to_string()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            found,
+            [
+                (10, "hello".into()),
+                (4090, "spans! blocks".into()),
+                (3 * 4096 + 4092, "ends".into()),
+                (5 * 4096, "after hole".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Helpers for testing code built on this crate.
+//!
+//! `BlockGen` generates reproducible pseudo-random block sequences, and
+//! `check_roundtrip` verifies the invariants that hold for every sequence:
+//! writing and reading a sparse image returns the same blocks, and the raw
+//! image they decode to survives being encoded, written, read and decoded
+//! again. Downstream crates can use these in their own tests to check
+//! custom sources and sinks against the same invariants.
+
+use crate::{
+    block::Block,
+    gen::XorShift,
+    read::{Encoder, Reader},
+    write::{Decoder, Writer},
+};
+use crate::result::{ensure, Result};
+use std::io::{Cursor, Read};
+
+/// A generator of pseudo-random block sequences.
+///
+/// The same seed always generates the same sequence. The generated blocks
+/// favor runs of equal block types and few distinct fill values, like real
+/// images do.
+pub struct BlockGen {
+    rng: XorShift,
+}
+
+impl BlockGen {
+    /// Creates a generator seeded with `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// Generates the next block, which is never a checksum block.
+    pub fn next_block(&mut self) -> Block {
+        match self.rng.next_u64() % 8 {
+            0..=2 => {
+                let mut buf = [0; Block::SIZE as usize];
+                // Some raw blocks are mostly zeros, like sparse file systems.
+                let len = match self.rng.next_u64() % 4 {
+                    0 => 16,
+                    _ => buf.len(),
+                };
+                for chunk in buf[..len].chunks_exact_mut(8) {
+                    chunk.copy_from_slice(&self.rng.next_u64().to_le_bytes());
+                }
+                Block::Raw(buf.into())
+            }
+            3..=4 => {
+                let values = [0, 0xffff_ffff, 0xdead_beef, self.rng.next_u64() as u32];
+                Block::fill_u32(values[(self.rng.next_u64() % 4) as usize])
+            }
+            _ => Block::Skip,
+        }
+    }
+
+    /// Generates a sequence of `count` blocks.
+    ///
+    /// Each block is repeated a few times to form runs.
+    pub fn blocks(&mut self, count: usize) -> Vec<Block> {
+        let mut blocks = Vec::with_capacity(count);
+        while blocks.len() < count {
+            let block = self.next_block();
+            let run = 1 + (self.rng.next_u64() % 4) as usize;
+            for _ in 0..run.min(count - blocks.len()) {
+                blocks.push(block.clone());
+            }
+        }
+        blocks
+    }
+}
+
+/// Decodes `blocks` to the raw image they represent.
+pub fn decode(blocks: &[Block]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for block in blocks {
+        block.write_decoded(&mut raw).unwrap();
+    }
+    raw
+}
+
+/// Writes `blocks` to a sparse image in memory.
+pub fn write_sparse(blocks: &[Block], crc: bool) -> Result<Vec<u8>> {
+    let mut sparse = Cursor::new(Vec::new());
+    let mut writer = Writer::new(&mut sparse, crc)?;
+    for block in blocks {
+        writer.write_block(block)?;
+    }
+    writer.close()?;
+    Ok(sparse.into_inner())
+}
+
+/// Checks the round-trip invariants for `blocks`, which must not contain
+/// checksum blocks.
+///
+/// 1. Writing `blocks` to a sparse image, with and without checksum, and
+///    reading it back yields `blocks` again.
+/// 2. Encoding the raw image `blocks` decode to, writing, reading and
+///    decoding it yields the same raw image.
+pub fn check_roundtrip(blocks: &[Block]) -> Result<()> {
+    for crc in [false, true] {
+        let sparse = write_sparse(blocks, crc)?;
+        let read = read_sparse(&sparse[..], crc)?;
+        ensure!(
+            read == blocks,
+            "Blocks read back differ from blocks written (crc: {crc})"
+        );
+    }
+
+    let raw = decode(blocks);
+    let encoded = Encoder::new(&raw[..])?.collect::<Result<Vec<_>>>()?;
+    let sparse = write_sparse(&encoded, true)?;
+
+    let mut decoded = Cursor::new(Vec::new());
+    let mut decoder = Decoder::new(&mut decoded)?;
+    for block in Reader::new(&sparse[..], true)? {
+        decoder.write_block(&block?)?;
+    }
+    decoder.close()?;
+    ensure!(
+        decoded.into_inner() == raw,
+        "Raw image differs after encoding and decoding"
+    );
+
+    Ok(())
+}
+
+/// Like `check_roundtrip`, but panics if an invariant doesn't hold.
+pub fn assert_roundtrip(blocks: &[Block]) {
+    if let Err(err) = check_roundtrip(blocks) {
+        panic!("Round trip of {} blocks failed: {err:#}", blocks.len());
+    }
+}
+
+fn read_sparse<R: Read>(r: R, crc: bool) -> Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    for block in Reader::new(r, crc)? {
+        match block? {
+            Block::Crc32(_) => (),
+            block => blocks.push(block),
+        }
+    }
+    Ok(blocks)
+}
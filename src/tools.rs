@@ -0,0 +1,443 @@
+//! Helpers for command line tools working with images.
+//!
+//! These are the building blocks of the `simg` tools: logging to stderr,
+//! progress bars, opening inputs and atomically creating outputs, including
+//! inherited descriptors passed as `/dev/fd/N`, wildcard expansion, size
+//! parsing, device profile lookup and running jobs on several threads. Only
+//! available with the `cli` feature.
+
+use crate::{
+    io::AtomicFile,
+    platform,
+    profile::{Profile, Registry},
+    result::{ensure, Context, Error, Result},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    env,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU8, Ordering},
+    thread,
+};
+
+pub use crate::par::parallel;
+
+/// How much the tools report on stderr.
+///
+/// Results always go to stdout, so `Quiet` makes the tools usable in
+/// scripts and `Verbose` helps debugging them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors.
+    Quiet,
+    /// Errors, warnings and progress bars.
+    #[default]
+    Normal,
+    /// Also what is being done and why.
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+impl Verbosity {
+    /// Returns the verbosity of this process.
+    pub fn get() -> Self {
+        match VERBOSITY.load(Ordering::Relaxed) {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Normal,
+            _ => Verbosity::Verbose,
+        }
+    }
+
+    /// Makes this the verbosity of this process.
+    pub fn set(self) {
+        VERBOSITY.store(self as u8, Ordering::Relaxed);
+    }
+}
+
+/// Sets the verbosity from `-q`/`--quiet` and `-v`/`--verbose` flags, of
+/// which `quiet` wins.
+///
+/// Leaves the verbosity unchanged if neither flag is set, so flags given
+/// to a subcommand add to those given to the top-level command.
+pub fn init_logging(quiet: bool, verbose: bool) {
+    if quiet {
+        Verbosity::Quiet.set();
+    } else if verbose {
+        Verbosity::Verbose.set();
+    }
+}
+
+/// Prints `msg` to stderr as `<level>: <msg>` if the verbosity is at least
+/// `min`.
+fn log(level: &str, min: Verbosity, msg: impl Display) {
+    if Verbosity::get() >= min {
+        eprintln!("{level}: {msg}");
+    }
+}
+
+/// Reports an error that doesn't end the program, e.g. of one image out
+/// of many.
+pub fn error(msg: impl Display) {
+    log("Error", Verbosity::Quiet, msg);
+}
+
+/// Reports a warning, unless quiet.
+pub fn warn(msg: impl Display) {
+    log("Warning", Verbosity::Normal, msg);
+}
+
+/// Reports details for debugging, if verbose.
+pub fn info(msg: impl Display) {
+    log("Info", Verbosity::Verbose, msg);
+}
+
+/// Removes partial outputs when the process is interrupted or terminated.
+///
+/// Outputs created with `create_output` are written to hidden temporary
+/// files (`.<name>XXXXXX.tmp`) and only renamed once complete, so an
+/// interrupted conversion never leaves a half-written image under its
+/// final name. On `SIGINT` or `SIGTERM`, this removes the temporary files
+/// as well and exits with status 128 plus the signal number, like shells
+/// report processes killed by a signal. Only supported on Linux and
+/// Android; elsewhere, the temporary files are left behind.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn cleanup_on_interrupt() -> Result<()> {
+    use std::{mem, os::fd::FromRawFd, process, ptr, sync::atomic::AtomicI32};
+
+    // Only async-signal-safe functions may be called in signal handlers,
+    // so the handler wakes up a thread that does the actual work.
+    static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let fd = WAKE_FD.load(Ordering::Relaxed);
+        // SAFETY: write is async-signal-safe, and the buffer is valid.
+        unsafe { libc::write(fd, [signal as u8].as_ptr().cast(), 1) };
+    }
+
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both descriptors.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    WAKE_FD.store(fds[1], Ordering::Relaxed);
+    // SAFETY: The read end of the pipe was just created and is owned here.
+    let mut wake = unsafe { File::from_raw_fd(fds[0]) };
+
+    thread::spawn(move || {
+        let mut signal = [0];
+        if wake.read(&mut signal).is_ok_and(|n| n == 1) {
+            let removed = crate::io::remove_pending();
+            error(format_args!(
+                "Interrupted, removed {removed} partial output(s)"
+            ));
+            process::exit(128 + i32::from(signal[0]));
+        }
+    });
+
+    // SAFETY: All-zero bytes are a valid `sigaction` with an empty mask.
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Reads and writes interrupted by the signal resume instead of failing
+    // while the cleanup thread wakes up.
+    action.sa_flags = libc::SA_RESTART;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: The handler only calls async-signal-safe functions.
+        if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// Removes partial outputs when the process is interrupted or terminated.
+///
+/// Only supported on Linux and Android; elsewhere, this does nothing.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn cleanup_on_interrupt() -> Result<()> {
+    Ok(())
+}
+
+/// Creates a progress bar for processing `len` bytes.
+///
+/// Messages set on the bar, e.g. the chunk being processed, are shown
+/// after the byte counts. The bar is hidden if quiet.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    if Verbosity::get() == Verbosity::Quiet {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len);
+    let template = "{elapsed} {bar:80} {bytes} / {total_bytes} {msg}";
+    bar.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏  "),
+    );
+    bar
+}
+
+/// Opens an input image.
+///
+/// `/dev/fd/N` refers to the inherited descriptor N, which may also be a
+/// pipe or socket. URLs are rejected in builds without the `http` feature,
+/// which tools need to download inputs.
+pub fn open_input<P: AsRef<Path>>(path: P) -> Result<File> {
+    #[cfg(not(feature = "http"))]
+    ensure!(
+        !path
+            .as_ref()
+            .to_str()
+            .is_some_and(|p| p.starts_with("http://") || p.starts_with("https://")),
+        "URLs are not supported by this build (enable the `http` feature)"
+    );
+    match inherited_fd(path.as_ref()) {
+        Some(file) => Ok(file?),
+        None => Ok(File::open(path)?),
+    }
+}
+
+/// Fails if the output image at `path` is the input image `input`.
+///
+/// Files are compared by identity, so links to the input are caught too.
+/// Outputs that don't exist yet are always distinct.
+pub fn check_distinct<P: AsRef<Path>>(input: &File, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let output = match inherited_fd(path) {
+        Some(file) => file?.metadata()?,
+        None => match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        },
+    };
+    ensure!(
+        !platform::same_file(&input.metadata()?, &output),
+        "{} is the input image (use --allow-same-file to write to it anyway)",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Creates an output image, refusing to overwrite it unless `force` is set.
+///
+/// The image only appears at `path` once it is committed, so interrupted
+/// conversions never leave a partial image behind. `/dev/fd/N` refers to
+/// the inherited descriptor N, which is written to directly.
+pub fn create_output<P: AsRef<Path>>(path: P, force: bool) -> Result<Output> {
+    let path = path.as_ref();
+    if let Some(file) = inherited_fd(path) {
+        return Ok(Output::Inherited(file?));
+    }
+
+    if force {
+        return Ok(Output::Atomic(AtomicFile::create(path)?));
+    }
+    match AtomicFile::create_new(path) {
+        Ok(file) => Ok(Output::Atomic(file)),
+        Err(err) => Err(output_error(path, err)),
+    }
+}
+
+/// Explains failing to create the output image at `path` because it
+/// already exists.
+fn output_error(path: &Path, err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::AlreadyExists => Error::msg(format!(
+            "{} already exists (use --force to overwrite it)",
+            path.display()
+        )),
+        _ => err.into(),
+    }
+}
+
+/// Returns the inherited descriptor a `/dev/fd/N` path refers to.
+///
+/// The descriptor is used as-is rather than opening the path again, which
+/// fails for sockets and isn't possible where `/dev/fd` doesn't exist.
+#[cfg(unix)]
+fn inherited_fd(path: &Path) -> Option<io::Result<File>> {
+    use std::os::fd::BorrowedFd;
+
+    let fd = path.strip_prefix("/dev/fd").ok()?.to_str()?.parse().ok()?;
+    // SAFETY: Descriptors handed to us stay open for our whole lifetime.
+    // If the descriptor isn't open, duplicating it fails with EBADF.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    Some(fd.try_clone_to_owned().map(File::from))
+}
+
+#[cfg(not(unix))]
+fn inherited_fd(_path: &Path) -> Option<io::Result<File>> {
+    None
+}
+
+/// An output image.
+pub enum Output {
+    /// A file that is replaced atomically.
+    Atomic(AtomicFile),
+    /// An inherited descriptor that is written to directly.
+    Inherited(File),
+}
+
+impl Output {
+    /// Returns the file being written.
+    pub fn as_file(&self) -> &File {
+        match self {
+            Output::Atomic(file) => file.as_file(),
+            Output::Inherited(file) => file,
+        }
+    }
+
+    /// Checks whether the output supports seeking, which pipes and sockets
+    /// don't.
+    pub fn is_seekable(&self) -> bool {
+        self.as_file().stream_position().is_ok()
+    }
+
+    /// Finishes writing the output.
+    ///
+    /// Unless created with `force`, this fails if someone else has created
+    /// the output image in the meantime, instead of replacing it.
+    pub fn commit(self) -> Result<()> {
+        if let Output::Atomic(file) = self {
+            let path = file.path().to_owned();
+            file.commit().map_err(|err| output_error(&path, err))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.as_file().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.as_file().flush()
+    }
+}
+
+/// Expands `*` and `?` wildcards in the file name of `pattern`.
+///
+/// Paths without wildcards are returned as-is. This is for shells that
+/// don't expand wildcards themselves, and to report patterns that match
+/// nothing.
+pub fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) if n.contains(['*', '?']) => n,
+        _ => return Ok(vec![path.into()]),
+    };
+
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        if let Some(candidate) = file_name.to_str() {
+            if wildcard_match(name.as_bytes(), candidate.as_bytes()) {
+                matches.push(path.with_file_name(candidate));
+            }
+        }
+    }
+
+    ensure!(!matches.is_empty(), "No images match {pattern}");
+    matches.sort();
+    Ok(matches)
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The last `*` seen and the name position it is matched up to, to
+    // backtrack to when the rest of the pattern fails to match.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Loads the built-in device profiles plus those in the profile file at
+/// `path`, or at `$SIMG_PROFILES` if no path is given.
+pub fn load_profiles(path: Option<&str>) -> Result<Registry> {
+    let mut registry = Registry::builtin();
+    let path = path
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("SIMG_PROFILES").map(PathBuf::from));
+    if let Some(path) = path {
+        registry.load(path)?;
+    }
+    Ok(registry)
+}
+
+/// Looks up the device profile `name` like `load_profiles` does.
+pub fn load_profile(name: &str, path: Option<&str>) -> Result<Profile> {
+    load_profiles(path)?
+        .get(name)
+        .cloned()
+        .with_context(|| format!("Unknown profile: {name} (see simg_lint --list-profiles)"))
+}
+
+/// Parses a size with an optional K, M or G suffix.
+pub fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let (digits, shift) = match value.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&value[..value.len() - 1], 10),
+        Some(b'M') => (&value[..value.len() - 1], 20),
+        Some(b'G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+
+    let size: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size: {value}"))?;
+    size.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match(b"system_*.simg", b"system_a.simg"));
+        assert!(wildcard_match(b"system_*.simg", b"system_.simg"));
+        assert!(wildcard_match(b"*", b""));
+        assert!(wildcard_match(b"s?stem*", b"system.img"));
+        assert!(wildcard_match(b"*.img*", b"a.img.img"));
+        assert!(!wildcard_match(b"system_?.simg", b"system_.simg"));
+        assert!(!wildcard_match(b"*.simg", b"system.img"));
+        assert!(!wildcard_match(b"", b"system.img"));
+    }
+
+    #[test]
+    fn wildcards_pathological() {
+        // Naive backtracking tries every split of the name between the
+        // stars, which takes exponential time here.
+        let name = [b'a'; 10_000];
+        assert!(!wildcard_match(b"*a*a*a*a*a*a*a*b", &name));
+        assert!(wildcard_match(b"*a*a*a*a*a*a*a*", &name));
+    }
+}
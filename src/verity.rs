@@ -0,0 +1,138 @@
+//! dm-verity hash tree generation.
+//!
+//! Computes the hash tree of the raw image a sparse image decodes to,
+//! without decoding it first. The layout of the tree is the same as the one
+//! produced by `avbtool` and `veritysetup`: SHA-256 digests of salted 4 KiB
+//! blocks, each level padded to a multiple of the block size, with the
+//! topmost level stored first.
+//!
+//! Since the digest of a fill or skip block only depends on its fill value,
+//! it is computed only once per distinct value.
+
+use crate::{
+    block::Block,
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+const BLOCK_SIZE: usize = Block::SIZE as usize;
+const DIGEST_SIZE: usize = 32;
+
+/// A dm-verity hash tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashTree {
+    /// The root hash of the tree.
+    pub root_hash: [u8; DIGEST_SIZE],
+    /// The hash tree levels, topmost level first.
+    pub tree: Vec<u8>,
+    /// The size of the hashed raw image in bytes.
+    pub image_size: u64,
+}
+
+/// Builds a dm-verity hash tree from sparse blocks.
+pub struct HashTreeBuilder {
+    salt: Vec<u8>,
+    level0: Vec<u8>,
+    fill_digests: HashMap<[u8; 4], [u8; DIGEST_SIZE]>,
+    num_blocks: u64,
+}
+
+impl HashTreeBuilder {
+    /// Creates a new builder that salts digests with `salt`.
+    pub fn new(salt: &[u8]) -> Self {
+        Self {
+            salt: salt.to_vec(),
+            level0: Vec::new(),
+            fill_digests: HashMap::new(),
+            num_blocks: 0,
+        }
+    }
+
+    /// Computes the hash tree of the blocks written so far.
+    pub fn finish(self) -> HashTree {
+        let image_size = self.num_blocks * BLOCK_SIZE as u64;
+        if self.num_blocks <= 1 {
+            // A single block is its own tree. Hash it directly.
+            let root_hash = match self.level0.get(..DIGEST_SIZE) {
+                Some(d) => d.try_into().unwrap(),
+                None => salted_digest(&self.salt, &[0; BLOCK_SIZE]),
+            };
+            return HashTree {
+                root_hash,
+                tree: Vec::new(),
+                image_size,
+            };
+        }
+
+        let mut levels = vec![pad_to_block(self.level0)];
+        while levels.last().unwrap().len() > BLOCK_SIZE {
+            let prev = levels.last().unwrap();
+            let level = prev
+                .chunks(BLOCK_SIZE)
+                .flat_map(|b| salted_digest(&self.salt, b))
+                .collect();
+            levels.push(pad_to_block(level));
+        }
+
+        let root_hash = salted_digest(&self.salt, levels.last().unwrap());
+        let tree = levels.into_iter().rev().flatten().collect();
+
+        HashTree {
+            root_hash,
+            tree,
+            image_size,
+        }
+    }
+
+    fn fill_digest(&mut self, value: [u8; 4]) -> [u8; DIGEST_SIZE] {
+        let salt = &self.salt;
+        *self.fill_digests.entry(value).or_insert_with(|| {
+            let mut buf = [0; BLOCK_SIZE];
+            for chunk in buf.chunks_exact_mut(4) {
+                chunk.copy_from_slice(&value);
+            }
+            salted_digest(salt, &buf)
+        })
+    }
+}
+
+impl BlockSink for HashTreeBuilder {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        let digest = match block {
+            Block::Raw(buf) => salted_digest(&self.salt, &buf[..]),
+            Block::Fill(value) => self.fill_digest(*value),
+            Block::Skip => self.fill_digest([0; 4]),
+            Block::Crc32(_) => return Ok(()),
+        };
+
+        self.level0.extend_from_slice(&digest);
+        self.num_blocks += 1;
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the dm-verity hash tree of the blocks in `src`.
+pub fn hash_tree<S: BlockSource>(mut src: S, salt: &[u8]) -> Result<HashTree> {
+    let mut builder = HashTreeBuilder::new(salt);
+    crate::pipeline::copy(&mut src, &mut builder)?;
+    Ok(builder.finish())
+}
+
+fn salted_digest(salt: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn pad_to_block(mut level: Vec<u8>) -> Vec<u8> {
+    let padded = level.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    level.resize(padded, 0);
+    level
+}
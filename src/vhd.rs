@@ -0,0 +1,458 @@
+//! Conversion between sparse images and Microsoft's VHD format.
+//!
+//! Sparse images are written as dynamic VHDs, which Hyper-V and Virtual
+//! PC can attach directly. Blocks of 2 MiB that are skipped or zero
+//! entirely are left unallocated. Both dynamic and fixed VHDs can be read;
+//! unallocated blocks are read as skip blocks.
+//!
+//! Differencing disks are not supported. The newer VHDX format is handled
+//! by [`vhdx`](crate::vhdx).
+
+use crate::{
+    block::Block,
+    container::{self, ClusterMap, Clusters, MappedReader, Mapping},
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{bail, ensure, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{prelude::*, SeekFrom};
+
+/// The cookie at the start of the VHD footer.
+pub const FOOTER_COOKIE: [u8; 8] = *b"conectix";
+const HEADER_COOKIE: [u8; 8] = *b"cxsparse";
+
+const SECTOR_SIZE: u64 = 512;
+const FOOTER_SIZE: u64 = 512;
+const HEADER_SIZE: u64 = 1024;
+const BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+/// The bitmap preceding each block's data, one bit per sector, padded to a
+/// whole sector.
+const BITMAP_SIZE: u64 = SECTOR_SIZE;
+const UNALLOCATED: u64 = 0xffff_ffff;
+const MAX_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+
+const VERSION: u32 = 0x0001_0000;
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+/// Writes sparse blocks to a dynamic VHD.
+///
+/// Checksum blocks are ignored.
+pub struct VhdWriter<W: Write + Seek> {
+    dst: W,
+    start: u64,
+    size: u64,
+    clusters: Clusters,
+    /// The sector offsets of all blocks, or `UNALLOCATED`.
+    bat: Vec<u64>,
+    next_block: usize,
+    /// The offset of the next data block, relative to the start.
+    next_offset: u64,
+    deterministic: bool,
+    finished: bool,
+}
+
+impl<W: Write + Seek> VhdWriter<W> {
+    /// Creates a new writer that writes a VHD with a virtual size of `size`
+    /// bytes to `w`.
+    ///
+    /// The size is fixed upfront, as the block allocation table precedes
+    /// the data. Blocks not written until the writer is closed are left
+    /// unallocated.
+    pub fn new(mut w: W, size: u64) -> Result<Self> {
+        ensure!(
+            size.is_multiple_of(u64::from(Block::SIZE)),
+            "VHD size must be a multiple of the block size"
+        );
+        ensure!(size <= MAX_SIZE, "VHDs cannot be larger than 2040 GiB");
+
+        // The footer copy, header and table are written at the end in
+        // `finish`. Data blocks start right after them.
+        let entries = size.div_ceil(BLOCK_SIZE) as usize;
+        let table_size = (entries as u64 * 4).next_multiple_of(SECTOR_SIZE);
+        let data_offset = FOOTER_SIZE + HEADER_SIZE + table_size;
+
+        let start = w.stream_position()?;
+        w.seek(SeekFrom::Start(start + data_offset))?;
+
+        Ok(Self {
+            dst: w,
+            start,
+            size,
+            clusters: Clusters::new(BLOCK_SIZE),
+            bat: vec![UNALLOCATED; entries],
+            next_block: 0,
+            next_offset: data_offset,
+            deterministic: false,
+            finished: false,
+        })
+    }
+
+    /// Derives the unique ID of the disk from its size instead of
+    /// generating a random one, so identical inputs yield byte-for-byte
+    /// identical VHDs.
+    ///
+    /// Hypervisors may then be unable to tell such disks apart.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Writes a sparse block to this writer.
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        match self.clusters.push(block) {
+            Some(blocks) => self.write_data_block(&blocks),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes writing the VHD.
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_data_block(&mut self, blocks: &[Block]) -> Result<()> {
+        ensure!(
+            self.next_block < self.bat.len(),
+            "More blocks written than fit into the VHD"
+        );
+
+        // Zero blocks are left unallocated too, as VHDs have no way to
+        // mark them explicitly.
+        if let Mapping::Data(_) = container::classify(blocks) {
+            self.bat[self.next_block] = self.next_offset / SECTOR_SIZE;
+            self.dst.write_all(&[0xff; BITMAP_SIZE as usize])?;
+            container::write_cluster(&mut self.dst, blocks, BLOCK_SIZE)?;
+            self.next_offset += BITMAP_SIZE + BLOCK_SIZE;
+        }
+
+        self.next_block += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        assert!(!self.finished);
+        self.finished = true;
+
+        if let Some(blocks) = self.clusters.take() {
+            // The last block may extend past the end of the disk.
+            let remaining = self.size - self.next_block as u64 * BLOCK_SIZE;
+            ensure!(
+                blocks.len() as u64 * u64::from(Block::SIZE) <= remaining,
+                "More blocks written than fit into the VHD"
+            );
+            self.write_data_block(&blocks)?;
+        }
+
+        let footer = footer(self.size, self.deterministic);
+        self.dst.write_all(&footer)?;
+        let end = self.dst.stream_position()?;
+
+        self.dst.seek(SeekFrom::Start(self.start))?;
+        self.dst.write_all(&footer)?;
+        self.dst.write_all(&dynamic_header(self.bat.len() as u32))?;
+        container::write_table::<_, BigEndian>(&mut self.dst, &self.bat, 4, SECTOR_SIZE)?;
+
+        self.dst.seek(SeekFrom::Start(end))?;
+        self.dst.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for VhdWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish().ok();
+        }
+    }
+}
+
+impl<W: Write + Seek> BlockSink for VhdWriter<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        VhdWriter::write_block(self, block)
+    }
+
+    fn close(self) -> Result<()> {
+        VhdWriter::close(self)
+    }
+}
+
+fn footer(size: u64, deterministic: bool) -> [u8; FOOTER_SIZE as usize] {
+    let mut buf = [0; FOOTER_SIZE as usize];
+    buf[0..8].copy_from_slice(&FOOTER_COOKIE);
+    BigEndian::write_u32(&mut buf[8..], 2); // features: reserved bit
+    BigEndian::write_u32(&mut buf[12..], VERSION);
+    BigEndian::write_u64(&mut buf[16..], FOOTER_SIZE); // data offset
+    buf[28..32].copy_from_slice(b"simg");
+    BigEndian::write_u32(&mut buf[32..], VERSION);
+    buf[36..40].copy_from_slice(b"Wi2k");
+    BigEndian::write_u64(&mut buf[40..], size); // original size
+    BigEndian::write_u64(&mut buf[48..], size); // current size
+
+    let (cylinders, heads, sectors) = geometry(size);
+    BigEndian::write_u16(&mut buf[56..], cylinders);
+    buf[58] = heads;
+    buf[59] = sectors;
+
+    BigEndian::write_u32(&mut buf[60..], DISK_TYPE_DYNAMIC);
+    buf[68..84].copy_from_slice(&container::unique_id(size, deterministic));
+
+    let checksum = checksum(&buf);
+    BigEndian::write_u32(&mut buf[64..], checksum);
+    buf
+}
+
+fn dynamic_header(entries: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut buf = [0; HEADER_SIZE as usize];
+    buf[0..8].copy_from_slice(&HEADER_COOKIE);
+    BigEndian::write_u64(&mut buf[8..], u64::MAX); // data offset, unused
+    BigEndian::write_u64(&mut buf[16..], FOOTER_SIZE + HEADER_SIZE); // table offset
+    BigEndian::write_u32(&mut buf[24..], VERSION);
+    BigEndian::write_u32(&mut buf[28..], entries);
+    BigEndian::write_u32(&mut buf[32..], BLOCK_SIZE as u32);
+
+    let checksum = checksum(&buf);
+    BigEndian::write_u32(&mut buf[36..], checksum);
+    buf
+}
+
+/// Computes the one's complement of the sum of all bytes, with the
+/// checksum field still zeroed.
+fn checksum(buf: &[u8]) -> u32 {
+    !buf.iter()
+        .fold(0u32, |sum, b| sum.wrapping_add(u32::from(*b)))
+}
+
+/// Computes the CHS geometry of a disk of `size` bytes, as specified by the
+/// VHD format.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let total = (size / SECTOR_SIZE).min(65535 * 16 * 255);
+
+    let (sectors, heads, cylinder_times_heads) = if total >= 65535 * 16 * 63 {
+        (255, 16, total / 255)
+    } else {
+        let mut sectors = 17;
+        let mut cth = total / sectors;
+        let mut heads = cth.div_ceil(1024).max(4);
+        if cth >= heads * 1024 || heads > 16 {
+            sectors = 31;
+            heads = 16;
+            cth = total / sectors;
+        }
+        if cth >= heads * 1024 {
+            sectors = 63;
+            heads = 16;
+            cth = total / sectors;
+        }
+        (sectors, heads, cth)
+    };
+
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors as u8,
+    )
+}
+
+/// Reads sparse blocks from a VHD.
+///
+/// Unallocated blocks of dynamic VHDs are read as skip blocks, everything
+/// else as raw blocks.
+pub struct VhdReader<R: Read + Seek> {
+    inner: MappedReader<R, VhdMap>,
+}
+
+impl<R: Read + Seek> VhdReader<R> {
+    /// Creates a new reader that reads from `r`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let start = r.stream_position()?;
+        let footer_offset = r.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        r.read_exact(&mut footer)?;
+        ensure!(footer[0..8] == FOOTER_COOKIE, "Not a VHD image");
+        let stored = BigEndian::read_u32(&footer[64..]);
+        footer[64..68].fill(0);
+        ensure!(checksum(&footer) == stored, "Invalid VHD footer checksum");
+
+        let size = BigEndian::read_u64(&footer[48..]);
+        let map = match BigEndian::read_u32(&footer[60..]) {
+            DISK_TYPE_FIXED => VhdMap::Fixed,
+            DISK_TYPE_DYNAMIC => {
+                let header_offset = BigEndian::read_u64(&footer[16..]);
+                VhdMap::dynamic(&mut r, start, header_offset)?
+            }
+            DISK_TYPE_DIFFERENCING => bail!("Differencing VHDs are not supported"),
+            disk_type => bail!("Unknown VHD disk type: {disk_type}"),
+        };
+        if let VhdMap::Fixed = map {
+            ensure!(start + size <= footer_offset, "VHD is truncated");
+        }
+
+        Ok(Self {
+            inner: MappedReader::new(r, map, start, size),
+        })
+    }
+
+    /// Returns the virtual size of the disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.inner.size
+    }
+}
+
+impl<R: Read + Seek> BlockSource for VhdReader<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.inner.read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        self.inner.raw_size()
+    }
+}
+
+enum VhdMap {
+    /// All data is stored in order, followed by the footer.
+    Fixed,
+    Dynamic {
+        block_size: u64,
+        bitmap_size: u64,
+        bat: Vec<u64>,
+    },
+}
+
+impl VhdMap {
+    /// Reads the dynamic disk header at `offset` and the block allocation
+    /// table, both relative to `start`.
+    fn dynamic<R: Read + Seek>(r: &mut R, start: u64, offset: u64) -> Result<Self> {
+        let mut header = [0; HEADER_SIZE as usize];
+        r.seek(SeekFrom::Start(start + offset))?;
+        r.read_exact(&mut header)?;
+        ensure!(
+            header[0..8] == HEADER_COOKIE,
+            "Invalid VHD dynamic disk header"
+        );
+
+        let block_size = u64::from(BigEndian::read_u32(&header[32..]));
+        ensure!(
+            block_size > 0 && block_size.is_multiple_of(u64::from(Block::SIZE)),
+            "Unsupported VHD block size: {block_size}"
+        );
+
+        let table_offset = BigEndian::read_u64(&header[16..]);
+        let entries = BigEndian::read_u32(&header[28..]) as usize;
+        let bat = container::read_table::<_, BigEndian>(r, start + table_offset, entries, 4)?;
+
+        Ok(VhdMap::Dynamic {
+            block_size,
+            bitmap_size: (block_size / SECTOR_SIZE / 8).next_multiple_of(SECTOR_SIZE),
+            bat,
+        })
+    }
+}
+
+impl ClusterMap for VhdMap {
+    fn cluster_size(&self) -> u64 {
+        match self {
+            VhdMap::Fixed => BLOCK_SIZE,
+            VhdMap::Dynamic { block_size, .. } => *block_size,
+        }
+    }
+
+    fn lookup<R: Read + Seek>(&mut self, _src: &mut R, index: u64) -> Result<Mapping> {
+        let (bitmap_size, bat) = match self {
+            VhdMap::Fixed => return Ok(Mapping::Data(index * BLOCK_SIZE)),
+            VhdMap::Dynamic {
+                bitmap_size, bat, ..
+            } => (*bitmap_size, bat),
+        };
+
+        // The sector bitmaps are ignored, unwritten sectors of allocated
+        // blocks are zeroed anyway.
+        match bat.get(index as usize) {
+            Some(&UNALLOCATED) | None => Ok(Mapping::Unallocated),
+            Some(sector) => Ok(Mapping::Data(sector * SECTOR_SIZE + bitmap_size)),
+        }
+    }
+}
+
+/// Checks whether the image in `r` is a VHD, rewinding `r` afterward.
+///
+/// Only the footer at the end identifies fixed VHDs, so `r` must be
+/// seekable.
+pub fn is_vhd<R: Read + Seek>(mut r: R) -> Result<bool> {
+    let start = r.stream_position()?;
+    let len = r.seek(SeekFrom::End(0))?;
+    let result = match len.checked_sub(start + FOOTER_SIZE) {
+        Some(_) => {
+            r.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+            container::has_magic(&mut r, &FOOTER_COOKIE)?
+        }
+        None => false,
+    };
+    r.seek(SeekFrom::Start(start))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCKS_PER_BLOCK: usize = (BLOCK_SIZE / Block::SIZE as u64) as usize;
+
+    #[test]
+    fn roundtrip() {
+        // A data block, an unallocated block, then a partial block.
+        let mut blocks = vec![Block::Fill([1; 4]); BLOCKS_PER_BLOCK];
+        blocks.extend(vec![Block::Skip; BLOCKS_PER_BLOCK]);
+        blocks.extend([Block::Skip, Block::Fill([2; 4])]);
+        let size = blocks.len() as u64 * u64::from(Block::SIZE);
+
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = VhdWriter::new(&mut image, size).unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        let table_size = SECTOR_SIZE;
+        let data_size = 2 * (BITMAP_SIZE + BLOCK_SIZE);
+        let expected_size = FOOTER_SIZE + HEADER_SIZE + table_size + data_size + FOOTER_SIZE;
+        assert_eq!(image.get_ref().len() as u64, expected_size);
+
+        image.set_position(0);
+        assert!(is_vhd(&mut image).unwrap());
+
+        let mut reader = VhdReader::new(&mut image).unwrap();
+        assert_eq!(reader.size(), size);
+
+        let mut expected: Vec<_> = blocks.iter().map(raw).collect();
+        expected[BLOCKS_PER_BLOCK..2 * BLOCKS_PER_BLOCK].fill(Block::Skip);
+        let read: Vec<_> = (&mut reader).blocks().map(Result::unwrap).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn deterministic() {
+        let write = || {
+            let mut image = Cursor::new(Vec::new());
+            let writer = VhdWriter::new(&mut image, BLOCK_SIZE).unwrap();
+            writer.deterministic(true).close().unwrap();
+            image.into_inner()
+        };
+        assert_eq!(write(), write());
+    }
+
+    #[test]
+    fn vhd_geometry() {
+        assert_eq!(geometry(1024 * 1024 * 1024), (2080, 16, 63));
+        assert_eq!(geometry(127 * 1024 * 1024 * 1024), (65278, 16, 255));
+    }
+
+    fn raw(block: &Block) -> Block {
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        Block::Raw(buf.into())
+    }
+}
@@ -0,0 +1,689 @@
+//! Conversion between sparse images and Microsoft's VHDX format.
+//!
+//! VHDX is the format Hyper-V creates by default. Sparse images are
+//! written as dynamic VHDXs with blocks of 2 MiB. Blocks that are skipped
+//! entirely are left unallocated, and blocks that are zero entirely are
+//! marked as zero without storing them. Dynamic and fixed VHDXs can be
+//! read; unallocated blocks are read as skip blocks.
+//!
+//! Differencing disks are not supported, nor are disks whose log still
+//! has to be replayed, e.g. after Hyper-V crashed while writing to them.
+
+use crate::{
+    block::Block,
+    container::{self, ClusterMap, Clusters, MappedReader, Mapping},
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{bail, ensure, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{prelude::*, SeekFrom};
+
+/// The signature at the start of a VHDX.
+pub const SIGNATURE: [u8; 8] = *b"vhdxfile";
+const HEADER_SIGNATURE: [u8; 4] = *b"head";
+const REGION_TABLE_SIGNATURE: [u8; 4] = *b"regi";
+const METADATA_SIGNATURE: [u8; 8] = *b"metadata";
+
+const MIB: u64 = 1024 * 1024;
+/// The file identifier, headers and region tables take up the first MiB.
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * 1024, 256 * 1024];
+const FILE_IDENTIFIER_SIZE: usize = 64 * 1024;
+const HEADER_SIZE: usize = 4 * 1024;
+const REGION_TABLE_SIZE: usize = 64 * 1024;
+/// The offset of the metadata items within the metadata region.
+const METADATA_ITEMS_OFFSET: u32 = 64 * 1024;
+
+/// The layout of written VHDXs: an empty log, the metadata and the block
+/// allocation table, followed by the data blocks.
+const LOG_OFFSET: u64 = MIB;
+const LOG_SIZE: u64 = MIB;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_SIZE: u64 = MIB;
+const BAT_OFFSET: u64 = 3 * MIB;
+const BLOCK_SIZE: u64 = 2 * MIB;
+const SECTOR_SIZE: u32 = 512;
+const MAX_SIZE: u64 = 64 * 1024 * 1024 * MIB;
+
+/// The states of payload blocks in the block allocation table.
+const BLOCK_NOT_PRESENT: u64 = 0;
+const BLOCK_UNDEFINED: u64 = 1;
+const BLOCK_ZERO: u64 = 2;
+const BLOCK_UNMAPPED: u64 = 3;
+const BLOCK_FULLY_PRESENT: u64 = 6;
+const BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+const BAT_REGION: [u8; 16] = guid(0x2dc27766_f623_4200_9d64_115e9bfd4a08);
+const METADATA_REGION: [u8; 16] = guid(0x8b7ca206_4790_4b9a_b8fe_575f050f886e);
+const FILE_PARAMETERS: [u8; 16] = guid(0xcaa16737_fa36_4d43_b3b6_33f0aa44e76b);
+const VIRTUAL_DISK_SIZE: [u8; 16] = guid(0x2fa54224_cd1b_4876_b211_5dbed83bf4b8);
+const VIRTUAL_DISK_ID: [u8; 16] = guid(0xbeca12ab_b2e6_4523_93ef_c309e000c746);
+const LOGICAL_SECTOR_SIZE: [u8; 16] = guid(0x8141bf1d_a96f_4709_ba47_f233a8faab5f);
+const PHYSICAL_SECTOR_SIZE: [u8; 16] = guid(0xcda348c7_445d_4471_9cc9_e9885251c556);
+
+/// Metadata item flags.
+const ITEM_VIRTUAL_DISK: u32 = 2;
+const ITEM_REQUIRED: u32 = 4;
+/// The file parameter flag of differencing disks.
+const HAS_PARENT: u32 = 2;
+
+/// Writes sparse blocks to a dynamic VHDX.
+///
+/// Checksum blocks are ignored.
+pub struct VhdxWriter<W: Write + Seek> {
+    dst: W,
+    start: u64,
+    size: u64,
+    clusters: Clusters,
+    /// The entries of the block allocation table.
+    bat: Vec<u64>,
+    next_block: u64,
+    /// The offset of the next data block, relative to the start.
+    next_offset: u64,
+    deterministic: bool,
+    finished: bool,
+}
+
+impl<W: Write + Seek> VhdxWriter<W> {
+    /// Creates a new writer that writes a VHDX with a virtual size of
+    /// `size` bytes to `w`.
+    ///
+    /// The size is fixed upfront, as the block allocation table precedes
+    /// the data. Blocks not written until the writer is closed are left
+    /// unallocated.
+    pub fn new(mut w: W, size: u64) -> Result<Self> {
+        ensure!(
+            size.is_multiple_of(u64::from(Block::SIZE)),
+            "VHDX size must be a multiple of the block size"
+        );
+        ensure!(size <= MAX_SIZE, "VHDXs cannot be larger than 64 TiB");
+
+        // The headers, metadata and table are written at the end in
+        // `finish`. Data blocks start right after them.
+        let entries = bat_entries(
+            size.div_ceil(BLOCK_SIZE),
+            chunk_ratio(BLOCK_SIZE, SECTOR_SIZE),
+        );
+        let data_offset = BAT_OFFSET + bat_region_size(entries);
+
+        let start = w.stream_position()?;
+        w.seek(SeekFrom::Start(start + data_offset))?;
+
+        Ok(Self {
+            dst: w,
+            start,
+            size,
+            clusters: Clusters::new(BLOCK_SIZE),
+            bat: vec![BLOCK_NOT_PRESENT; entries as usize],
+            next_block: 0,
+            next_offset: data_offset,
+            deterministic: false,
+            finished: false,
+        })
+    }
+
+    /// Derives the IDs of the disk from its size instead of generating
+    /// random ones, so identical inputs yield byte-for-byte identical
+    /// VHDXs.
+    ///
+    /// Hypervisors may then be unable to tell such disks apart.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Writes a sparse block to this writer.
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        match self.clusters.push(block) {
+            Some(blocks) => self.write_data_block(&blocks),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes writing the VHDX.
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_data_block(&mut self, blocks: &[Block]) -> Result<()> {
+        let ratio = chunk_ratio(BLOCK_SIZE, SECTOR_SIZE);
+        let index = (self.next_block + self.next_block / ratio) as usize;
+        ensure!(
+            index < self.bat.len(),
+            "More blocks written than fit into the VHDX"
+        );
+
+        self.bat[index] = match container::classify(blocks) {
+            Mapping::Unallocated => BLOCK_NOT_PRESENT,
+            Mapping::Zero => BLOCK_ZERO,
+            Mapping::Data(_) => {
+                container::write_cluster(&mut self.dst, blocks, BLOCK_SIZE)?;
+                let entry = BLOCK_FULLY_PRESENT | (self.next_offset / MIB) << 20;
+                self.next_offset += BLOCK_SIZE;
+                entry
+            }
+        };
+
+        self.next_block += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        assert!(!self.finished);
+        self.finished = true;
+
+        if let Some(blocks) = self.clusters.take() {
+            // The last block may extend past the end of the disk.
+            let remaining = self.size - self.next_block * BLOCK_SIZE;
+            ensure!(
+                blocks.len() as u64 * u64::from(Block::SIZE) <= remaining,
+                "More blocks written than fit into the VHDX"
+            );
+            self.write_data_block(&blocks)?;
+        }
+        let end = self.dst.stream_position()?;
+
+        let mut identifier = [0; FILE_IDENTIFIER_SIZE];
+        identifier[..8].copy_from_slice(&SIGNATURE);
+        let creator = "android-sparse".encode_utf16().flat_map(u16::to_le_bytes);
+        for (byte, value) in identifier[8..].iter_mut().zip(creator) {
+            *byte = value;
+        }
+        self.dst.seek(SeekFrom::Start(self.start))?;
+        self.dst.write_all(&identifier)?;
+
+        let file_id = container::unique_id(self.size, self.deterministic);
+        for (sequence, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+            self.dst.seek(SeekFrom::Start(self.start + offset))?;
+            self.dst.write_all(&header(sequence as u64, file_id))?;
+        }
+
+        let table = region_table(bat_region_size(self.bat.len() as u64));
+        for offset in REGION_TABLE_OFFSETS {
+            self.dst.seek(SeekFrom::Start(self.start + offset))?;
+            self.dst.write_all(&table)?;
+        }
+
+        // The log is empty, so it only has to be there.
+        self.dst.seek(SeekFrom::Start(self.start + LOG_OFFSET))?;
+        container::write_zeros(&mut self.dst, LOG_SIZE)?;
+        let disk_id = container::unique_id(self.size, self.deterministic);
+        self.dst.write_all(&metadata(self.size, disk_id))?;
+        container::write_table::<_, LittleEndian>(&mut self.dst, &self.bat, 8, MIB)?;
+
+        self.dst.seek(SeekFrom::Start(end))?;
+        self.dst.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for VhdxWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish().ok();
+        }
+    }
+}
+
+impl<W: Write + Seek> BlockSink for VhdxWriter<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        VhdxWriter::write_block(self, block)
+    }
+
+    fn close(self) -> Result<()> {
+        VhdxWriter::close(self)
+    }
+}
+
+/// Returns the number of payload blocks per sector bitmap block, whose
+/// entries are interleaved with theirs in the block allocation table.
+fn chunk_ratio(block_size: u64, sector_size: u32) -> u64 {
+    (1 << 23) * u64::from(sector_size) / block_size
+}
+
+/// Returns the number of entries in the block allocation table of a disk
+/// with `blocks` payload blocks.
+fn bat_entries(blocks: u64, chunk_ratio: u64) -> u64 {
+    blocks + blocks.saturating_sub(1) / chunk_ratio
+}
+
+fn bat_region_size(entries: u64) -> u64 {
+    (entries * 8).next_multiple_of(MIB).max(MIB)
+}
+
+fn header(sequence: u64, id: [u8; 16]) -> [u8; HEADER_SIZE] {
+    let mut buf = [0; HEADER_SIZE];
+    buf[0..4].copy_from_slice(&HEADER_SIGNATURE);
+    LittleEndian::write_u64(&mut buf[8..], sequence);
+    // The file and data write GUIDs. The log GUID stays zero, as there is
+    // nothing to replay.
+    buf[16..32].copy_from_slice(&id);
+    buf[32..48].copy_from_slice(&id);
+    LittleEndian::write_u16(&mut buf[66..], 1); // version
+    LittleEndian::write_u32(&mut buf[68..], LOG_SIZE as u32);
+    LittleEndian::write_u64(&mut buf[72..], LOG_OFFSET);
+
+    let checksum = crc32c(&buf);
+    LittleEndian::write_u32(&mut buf[4..], checksum);
+    buf
+}
+
+fn region_table(bat_size: u64) -> [u8; REGION_TABLE_SIZE] {
+    let mut buf = [0; REGION_TABLE_SIZE];
+    buf[0..4].copy_from_slice(&REGION_TABLE_SIGNATURE);
+    LittleEndian::write_u32(&mut buf[8..], 2);
+
+    let regions = [
+        (BAT_REGION, BAT_OFFSET, bat_size),
+        (METADATA_REGION, METADATA_OFFSET, METADATA_SIZE),
+    ];
+    for (entry, (id, offset, len)) in buf[16..].chunks_mut(32).zip(regions) {
+        entry[0..16].copy_from_slice(&id);
+        LittleEndian::write_u64(&mut entry[16..], offset);
+        LittleEndian::write_u32(&mut entry[24..], len as u32);
+        LittleEndian::write_u32(&mut entry[28..], 1); // required
+    }
+
+    let checksum = crc32c(&buf);
+    LittleEndian::write_u32(&mut buf[4..], checksum);
+    buf
+}
+
+/// Builds the metadata region, with the metadata table followed by the
+/// items.
+fn metadata(size: u64, disk_id: [u8; 16]) -> Vec<u8> {
+    let mut file_parameters = [0; 8];
+    LittleEndian::write_u32(&mut file_parameters, BLOCK_SIZE as u32);
+    let virtual_disk = ITEM_VIRTUAL_DISK | ITEM_REQUIRED;
+    let items: [(_, &[u8], _); 5] = [
+        (FILE_PARAMETERS, &file_parameters, ITEM_REQUIRED),
+        (VIRTUAL_DISK_SIZE, &size.to_le_bytes(), virtual_disk),
+        (VIRTUAL_DISK_ID, &disk_id, virtual_disk),
+        (
+            LOGICAL_SECTOR_SIZE,
+            &SECTOR_SIZE.to_le_bytes(),
+            virtual_disk,
+        ),
+        (
+            PHYSICAL_SECTOR_SIZE,
+            &SECTOR_SIZE.to_le_bytes(),
+            virtual_disk,
+        ),
+    ];
+
+    let mut buf = vec![0; METADATA_SIZE as usize];
+    buf[0..8].copy_from_slice(&METADATA_SIGNATURE);
+    LittleEndian::write_u16(&mut buf[10..], items.len() as u16);
+    let mut offset = METADATA_ITEMS_OFFSET;
+    for (entry, (id, data, flags)) in buf[32..].chunks_mut(32).zip(items) {
+        entry[0..16].copy_from_slice(&id);
+        LittleEndian::write_u32(&mut entry[16..], offset);
+        LittleEndian::write_u32(&mut entry[20..], data.len() as u32);
+        LittleEndian::write_u32(&mut entry[24..], flags);
+        offset += data.len() as u32;
+    }
+    let mut offset = METADATA_ITEMS_OFFSET as usize;
+    for (_, data, _) in items {
+        buf[offset..offset + data.len()].copy_from_slice(data);
+        offset += data.len();
+    }
+    buf
+}
+
+/// Encodes a GUID in the mixed-endian layout VHDX stores them in, where
+/// the first three fields are little-endian.
+const fn guid(id: u128) -> [u8; 16] {
+    let b = id.to_be_bytes();
+    [
+        b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13],
+        b[14], b[15],
+    ]
+}
+
+/// Computes the CRC-32C (Castagnoli) checksum of headers and region
+/// tables, with the checksum field still zeroed.
+fn crc32c(buf: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = match crc & 1 {
+                    1 => (crc >> 1) ^ 0x82f6_3b78,
+                    _ => crc >> 1,
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !buf.iter().fold(!0, |crc, b| {
+        TABLE[((crc ^ u32::from(*b)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Checks the CRC-32C checksum stored at offset 4 of `buf`.
+fn verify_checksum(buf: &mut [u8]) -> bool {
+    let stored = LittleEndian::read_u32(&buf[4..]);
+    buf[4..8].fill(0);
+    crc32c(buf) == stored
+}
+
+/// Reads sparse blocks from a VHDX.
+///
+/// Unallocated blocks are read as skip blocks, zero blocks as zero fill
+/// blocks and everything else as raw blocks.
+pub struct VhdxReader<R: Read + Seek> {
+    inner: MappedReader<R, VhdxMap>,
+}
+
+impl<R: Read + Seek> VhdxReader<R> {
+    /// Creates a new reader that reads from `r`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let start = r.stream_position()?;
+        ensure!(
+            container::has_magic(&mut r, &SIGNATURE)?,
+            "Not a VHDX image"
+        );
+
+        let header = read_header(&mut r, start)?;
+        ensure!(
+            header[48..64].iter().all(|b| *b == 0),
+            "The VHDX log has to be replayed first, e.g. by attaching the disk in Hyper-V"
+        );
+
+        let (mut bat_region, mut metadata_region) = (None, None);
+        let table = read_region_table(&mut r, start)?;
+        let entries = LittleEndian::read_u32(&table[8..]) as usize;
+        ensure!(entries <= 2047, "Invalid VHDX region table");
+        for entry in table[16..].chunks(32).take(entries) {
+            let region = (
+                LittleEndian::read_u64(&entry[16..]),
+                LittleEndian::read_u32(&entry[24..]),
+            );
+            match entry[0..16].try_into().unwrap() {
+                BAT_REGION => bat_region = Some(region),
+                METADATA_REGION => metadata_region = Some(region),
+                _ if LittleEndian::read_u32(&entry[28..]) & 1 != 0 => {
+                    bail!("Unsupported VHDX region")
+                }
+                _ => (),
+            }
+        }
+        let (Some((bat_offset, bat_len)), Some(metadata_region)) = (bat_region, metadata_region)
+        else {
+            bail!("VHDX is missing its block allocation table or metadata");
+        };
+
+        let metadata = Metadata::read(&mut r, start, metadata_region)?;
+        let chunk_ratio = chunk_ratio(metadata.block_size, metadata.sector_size);
+        let entries = bat_entries(metadata.size.div_ceil(metadata.block_size), chunk_ratio);
+        ensure!(
+            entries * 8 <= u64::from(bat_len),
+            "VHDX block allocation table is too small"
+        );
+        let bat = container::read_table::<_, LittleEndian>(
+            &mut r,
+            start + bat_offset,
+            entries as usize,
+            8,
+        )?;
+
+        let map = VhdxMap {
+            block_size: metadata.block_size,
+            chunk_ratio,
+            bat,
+        };
+        Ok(Self {
+            inner: MappedReader::new(r, map, start, metadata.size),
+        })
+    }
+
+    /// Returns the virtual size of the disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.inner.size
+    }
+}
+
+impl<R: Read + Seek> BlockSource for VhdxReader<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.inner.read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        self.inner.raw_size()
+    }
+}
+
+/// Reads the current header, i.e. the valid one with the higher sequence
+/// number.
+fn read_header<R: Read + Seek>(r: &mut R, start: u64) -> Result<[u8; HEADER_SIZE]> {
+    let mut current: Option<[u8; HEADER_SIZE]> = None;
+    for offset in HEADER_OFFSETS {
+        let mut header = [0; HEADER_SIZE];
+        r.seek(SeekFrom::Start(start + offset))?;
+        r.read_exact(&mut header)?;
+        if header[0..4] != HEADER_SIGNATURE || !verify_checksum(&mut header) {
+            continue;
+        }
+        let sequence = LittleEndian::read_u64(&header[8..]);
+        if current.is_none_or(|c| LittleEndian::read_u64(&c[8..]) < sequence) {
+            current = Some(header);
+        }
+    }
+    match current {
+        Some(header) => Ok(header),
+        None => bail!("Invalid VHDX headers"),
+    }
+}
+
+/// Reads the first valid copy of the region table.
+fn read_region_table<R: Read + Seek>(r: &mut R, start: u64) -> Result<Vec<u8>> {
+    let mut table = vec![0; REGION_TABLE_SIZE];
+    for offset in REGION_TABLE_OFFSETS {
+        r.seek(SeekFrom::Start(start + offset))?;
+        r.read_exact(&mut table)?;
+        if table[0..4] == REGION_TABLE_SIGNATURE && verify_checksum(&mut table) {
+            return Ok(table);
+        }
+    }
+    bail!("Invalid VHDX region table")
+}
+
+/// The metadata items describing the virtual disk.
+struct Metadata {
+    block_size: u64,
+    sector_size: u32,
+    size: u64,
+}
+
+impl Metadata {
+    /// Reads the metadata region at `offset` with a length of `len` bytes.
+    fn read<R: Read + Seek>(r: &mut R, start: u64, (offset, len): (u64, u32)) -> Result<Self> {
+        ensure!(
+            u64::from(len) <= 16 * METADATA_SIZE,
+            "VHDX metadata is too large"
+        );
+        let mut buf = vec![0; len as usize];
+        r.seek(SeekFrom::Start(start + offset))?;
+        r.read_exact(&mut buf)?;
+        ensure!(
+            buf.len() >= 32 && buf[0..8] == METADATA_SIGNATURE,
+            "Invalid VHDX metadata"
+        );
+
+        let (mut block_size, mut sector_size, mut size) = (None, None, None);
+        let entries = usize::from(LittleEndian::read_u16(&buf[10..]));
+        for entry in buf[32..].chunks(32).take(entries) {
+            let item_offset = LittleEndian::read_u32(&entry[16..]) as usize;
+            let item_len = LittleEndian::read_u32(&entry[20..]) as usize;
+            let Some(item) = buf.get(item_offset..item_offset + item_len) else {
+                bail!("Invalid VHDX metadata item");
+            };
+            match entry[0..16].try_into().unwrap() {
+                FILE_PARAMETERS if item_len >= 8 => {
+                    if LittleEndian::read_u32(&item[4..]) & HAS_PARENT != 0 {
+                        bail!("Differencing VHDXs are not supported");
+                    }
+                    block_size = Some(u64::from(LittleEndian::read_u32(item)));
+                }
+                LOGICAL_SECTOR_SIZE if item_len >= 4 => {
+                    sector_size = Some(LittleEndian::read_u32(item))
+                }
+                VIRTUAL_DISK_SIZE if item_len >= 8 => size = Some(LittleEndian::read_u64(item)),
+                VIRTUAL_DISK_ID | PHYSICAL_SECTOR_SIZE => (),
+                _ if LittleEndian::read_u32(&entry[24..]) & ITEM_REQUIRED != 0 => {
+                    bail!("Unsupported VHDX metadata item")
+                }
+                _ => (),
+            }
+        }
+
+        let (Some(block_size), Some(sector_size), Some(size)) = (block_size, sector_size, size)
+        else {
+            bail!("VHDX metadata is incomplete");
+        };
+        ensure!(
+            block_size.is_power_of_two() && (MIB..=256 * MIB).contains(&block_size),
+            "Unsupported VHDX block size: {block_size}"
+        );
+        ensure!(
+            matches!(sector_size, 512 | 4096),
+            "Unsupported VHDX sector size: {sector_size}"
+        );
+        Ok(Self {
+            block_size,
+            sector_size,
+            size,
+        })
+    }
+}
+
+struct VhdxMap {
+    block_size: u64,
+    chunk_ratio: u64,
+    bat: Vec<u64>,
+}
+
+impl ClusterMap for VhdxMap {
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn lookup<R: Read + Seek>(&mut self, _src: &mut R, index: u64) -> Result<Mapping> {
+        let entry = self.bat[(index + index / self.chunk_ratio) as usize];
+        match entry & 7 {
+            BLOCK_NOT_PRESENT | BLOCK_UNDEFINED | BLOCK_UNMAPPED => Ok(Mapping::Unallocated),
+            BLOCK_ZERO => Ok(Mapping::Zero),
+            BLOCK_FULLY_PRESENT => Ok(Mapping::Data((entry >> 20) * MIB)),
+            BLOCK_PARTIALLY_PRESENT => bail!("Differencing VHDXs are not supported"),
+            state => bail!("Invalid VHDX block state: {state}"),
+        }
+    }
+}
+
+/// Checks whether the image in `r` is a VHDX, rewinding `r` afterward.
+pub fn is_vhdx<R: Read + Seek>(r: R) -> Result<bool> {
+    container::has_magic(r, &SIGNATURE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCKS_PER_BLOCK: usize = (BLOCK_SIZE / Block::SIZE as u64) as usize;
+
+    #[test]
+    fn roundtrip() {
+        // A data block, an unallocated block, a zero block, then a partial
+        // block.
+        let mut blocks = vec![Block::Fill([1; 4]); BLOCKS_PER_BLOCK];
+        blocks.extend(vec![Block::Skip; BLOCKS_PER_BLOCK]);
+        blocks.extend(vec![Block::Fill([0; 4]); BLOCKS_PER_BLOCK]);
+        blocks.extend([Block::Skip, Block::Fill([2; 4])]);
+        let size = blocks.len() as u64 * u64::from(Block::SIZE);
+
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = VhdxWriter::new(&mut image, size).unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        let expected_size = BAT_OFFSET + MIB + 2 * BLOCK_SIZE;
+        assert_eq!(image.get_ref().len() as u64, expected_size);
+
+        image.set_position(0);
+        assert!(is_vhdx(&mut image).unwrap());
+
+        let mut reader = VhdxReader::new(&mut image).unwrap();
+        assert_eq!(reader.size(), size);
+
+        let mut expected: Vec<_> = blocks.iter().map(raw).collect();
+        expected[BLOCKS_PER_BLOCK..2 * BLOCKS_PER_BLOCK].fill(Block::Skip);
+        expected[2 * BLOCKS_PER_BLOCK..3 * BLOCKS_PER_BLOCK].fill(Block::Fill([0; 4]));
+        let read: Vec<_> = (&mut reader).blocks().map(Result::unwrap).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn pending_log() {
+        let mut image = Cursor::new(Vec::new());
+        VhdxWriter::new(&mut image, BLOCK_SIZE)
+            .unwrap()
+            .close()
+            .unwrap();
+
+        // Both headers are damaged, but the second copy only fails its
+        // checksum.
+        let mut damaged = image.clone().into_inner();
+        damaged[HEADER_OFFSETS[0] as usize] = 0;
+        damaged[HEADER_OFFSETS[1] as usize + 100] = 1;
+        assert!(VhdxReader::new(Cursor::new(damaged)).is_err());
+
+        let mut buf = image.into_inner();
+        let offset = HEADER_OFFSETS[1] as usize;
+        let mut pending = header(2, [1; 16]);
+        pending[48] = 1;
+        pending[4..8].fill(0);
+        let checksum = crc32c(&pending);
+        LittleEndian::write_u32(&mut pending[4..], checksum);
+        buf[offset..offset + HEADER_SIZE].copy_from_slice(&pending);
+        let err = VhdxReader::new(Cursor::new(buf)).err().unwrap();
+        assert!(err.to_string().contains("log"));
+    }
+
+    #[test]
+    fn deterministic() {
+        let write = || {
+            let mut image = Cursor::new(Vec::new());
+            let writer = VhdxWriter::new(&mut image, BLOCK_SIZE).unwrap();
+            writer.deterministic(true).close().unwrap();
+            image.into_inner()
+        };
+        assert_eq!(write(), write());
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(
+            BAT_REGION,
+            *b"\x66\x77\xc2\x2d\x23\xf6\x00\x42\x9d\x64\x11\x5e\x9b\xfd\x4a\x08"
+        );
+        assert_eq!(bat_entries(2048, 2048), 2048);
+        assert_eq!(bat_entries(2049, 2048), 2050);
+    }
+
+    fn raw(block: &Block) -> Block {
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        Block::Raw(buf.into())
+    }
+}
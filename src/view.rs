@@ -0,0 +1,310 @@
+//! Random access to the raw image a sparse image decodes to.
+//!
+//! FUSE and NBD servers, or tools inspecting the file system in an image,
+//! read the raw image at arbitrary offsets. A `RawView` serves these reads
+//! straight from the sparse image, finding the chunk covering a block in
+//! the index built by `Reader::prescan`. Decoded blocks are kept in a
+//! `BlockCache`, so hot regions like superblocks and allocation bitmaps
+//! aren't read and decoded again on every access.
+
+use crate::{
+    block::{Block, BlockBuf},
+    dump::ChunkEntry,
+    headers::ChunkType,
+    read::Reader,
+    result::{Context, Result},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, prelude::*, SeekFrom},
+};
+
+/// The number of blocks a `RawView` caches by default, 4 MiB worth.
+pub const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+/// How often a `BlockCache` had the requested block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of lookups that found the block.
+    pub hits: u64,
+    /// The number of lookups that didn't.
+    pub misses: u64,
+}
+
+/// A least recently used cache of decoded blocks, keyed by block index.
+pub struct BlockCache {
+    capacity: usize,
+    /// The blocks and when they were last used.
+    blocks: HashMap<u64, (Block, u64)>,
+    /// The indices of the cached blocks by when they were last used.
+    uses: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    /// Creates an empty cache holding up to `capacity` blocks. A cache
+    /// with a capacity of 0 holds nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: HashMap::new(),
+            uses: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the number of blocks the cache holds at most.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Checks whether no blocks are cached.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the hits and misses of all lookups so far.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Looks up block `index`, marking it as most recently used.
+    pub fn get(&mut self, index: u64) -> Option<&Block> {
+        self.clock += 1;
+        let Some((block, used)) = self.blocks.get_mut(&index) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.uses.remove(used);
+        self.uses.insert(self.clock, index);
+        *used = self.clock;
+        Some(block)
+    }
+
+    /// Caches `block` as block `index`, evicting the least recently used
+    /// block if the cache is full.
+    pub fn insert(&mut self, index: u64, block: Block) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.blocks.remove(&index) {
+            self.uses.remove(&used);
+        } else if self.blocks.len() == self.capacity {
+            if let Some((_, evicted)) = self.uses.pop_first() {
+                self.blocks.remove(&evicted);
+            }
+        }
+        self.blocks.insert(index, (block, self.clock));
+        self.uses.insert(self.clock, index);
+    }
+
+    /// Removes all cached blocks.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.uses.clear();
+    }
+}
+
+/// A read-only, seekable view of the raw image a sparse image decodes to.
+///
+/// Reading decodes only the blocks covering the requested bytes, so the
+/// sparse image must be seekable. Checksums are not verified.
+pub struct RawView<R> {
+    image: R,
+    /// The chunks holding blocks, ordered by their first block.
+    chunks: Vec<ChunkEntry>,
+    blocks: u64,
+    pos: u64,
+    cache: BlockCache,
+}
+
+impl<R: Read + Seek> RawView<R> {
+    /// Creates a view of the sparse image in `image`, indexing its chunks.
+    pub fn new(mut image: R) -> Result<Self> {
+        image.rewind()?;
+        let scan = Reader::new(&mut image, false)?.prescan()?;
+        let chunks = scan
+            .chunks
+            .into_iter()
+            .filter(|c| {
+                matches!(
+                    c.header.chunk_type,
+                    ChunkType::Raw | ChunkType::Fill | ChunkType::DontCare
+                )
+            })
+            .collect();
+        Ok(Self {
+            image,
+            chunks,
+            blocks: scan.raw_blocks + scan.fill_blocks + scan.skip_blocks,
+            pos: 0,
+            cache: BlockCache::new(DEFAULT_CACHE_BLOCKS),
+        })
+    }
+
+    /// Caches up to `blocks` decoded blocks instead of
+    /// `DEFAULT_CACHE_BLOCKS`.
+    pub fn cache_capacity(mut self, blocks: usize) -> Self {
+        self.cache = BlockCache::new(blocks);
+        self
+    }
+
+    /// Returns the size of the raw image in bytes.
+    pub fn len(&self) -> u64 {
+        self.blocks * u64::from(Block::SIZE)
+    }
+
+    /// Checks whether the raw image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.blocks == 0
+    }
+
+    /// Returns the cache of decoded blocks.
+    pub fn cache(&self) -> &BlockCache {
+        &self.cache
+    }
+
+    /// Returns the wrapped sparse image.
+    pub fn into_inner(self) -> R {
+        self.image
+    }
+
+    /// Returns block `index` of the raw image. Don't-care blocks are
+    /// returned as skip blocks.
+    pub fn block(&mut self, index: u64) -> Result<Block> {
+        self.with_block(index, Block::clone)
+    }
+
+    /// Calls `f` with block `index`, decoding and caching it on a miss.
+    fn with_block<T, F: FnOnce(&Block) -> T>(&mut self, index: u64, f: F) -> Result<T> {
+        if let Some(block) = self.cache.get(index) {
+            return Ok(f(block));
+        }
+        let block = self.decode(index)?;
+        let result = f(&block);
+        self.cache.insert(index, block);
+        Ok(result)
+    }
+
+    fn decode(&mut self, index: u64) -> Result<Block> {
+        let i = self.chunks.partition_point(|c| c.blocks().end <= index);
+        let chunk = self
+            .chunks
+            .get(i)
+            .filter(|c| c.blocks().contains(&index))
+            .with_context(|| format!("Block {index} is past the end of the image"))?;
+        Ok(match chunk.header.chunk_type {
+            ChunkType::Raw => {
+                let data = chunk.decode(&mut self.image, index..index + 1)?;
+                Block::Raw(BlockBuf::try_from(&data[..])?)
+            }
+            ChunkType::Fill => Block::Fill(chunk.fill_value(&mut self.image)?),
+            _ => Block::Skip,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for RawView<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = u64::from(Block::SIZE);
+        let index = self.pos / block_size;
+        let offset = (self.pos % block_size) as usize;
+        let len = buf.len().min(Block::SIZE as usize - offset);
+
+        let mut data = [0; Block::SIZE as usize];
+        self.with_block(index, |block| block.decode_into(&mut data))
+            .map_err(io::Error::other)?;
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for RawView<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of image")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{decode, write_sparse};
+    use std::io::Cursor;
+
+    fn image() -> (Cursor<Vec<u8>>, Vec<u8>) {
+        let blocks = [
+            Block::Raw([1; Block::SIZE as usize].into()),
+            Block::Raw([2; Block::SIZE as usize].into()),
+            Block::fill_u32(0xaabbccdd),
+            Block::Skip,
+            Block::Raw([3; Block::SIZE as usize].into()),
+        ];
+        let image = write_sparse(&blocks, false).unwrap();
+        (Cursor::new(image), decode(&blocks))
+    }
+
+    #[test]
+    fn random_access() {
+        let (image, raw) = image();
+        let mut view = RawView::new(image).unwrap();
+        assert_eq!(view.len(), raw.len() as u64);
+
+        let mut all = Vec::new();
+        view.read_to_end(&mut all).unwrap();
+        assert_eq!(all, raw);
+
+        let mut buf = [0; 10];
+        for offset in [4090, 2 * 4096 + 1, 3 * 4096 + 5, 5 * 4096 - 10] {
+            view.seek(SeekFrom::Start(offset)).unwrap();
+            view.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, raw[offset as usize..offset as usize + 10]);
+        }
+        assert_eq!(view.read(&mut buf).unwrap(), 0);
+        assert_eq!(view.block(2).unwrap(), Block::fill_u32(0xaabbccdd));
+        assert!(view.block(5).is_err());
+        view.rewind().unwrap();
+        assert!(view.seek(SeekFrom::Current(-1)).is_err());
+
+        let stats = view.cache().stats();
+        assert_eq!(stats.misses, 6);
+        assert!(stats.hits > 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = BlockCache::new(2);
+        cache.insert(0, Block::Skip);
+        cache.insert(1, Block::Skip);
+        assert!(cache.get(0).is_some());
+        cache.insert(2, Block::Skip);
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(0).is_some());
+        assert!(cache.get(2).is_some());
+        assert_eq!(cache.len(), 2);
+
+        let mut cache = BlockCache::new(0);
+        cache.insert(0, Block::Skip);
+        assert!(cache.is_empty());
+    }
+}
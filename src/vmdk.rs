@@ -0,0 +1,473 @@
+//! Conversion between sparse images and VMware's VMDK format.
+//!
+//! Sparse images are written as monolithic sparse VMDKs: a single hosted
+//! sparse extent with an embedded descriptor, which VMware products and
+//! QEMU can attach directly. Grains of 64 KiB that are skipped or zero
+//! entirely are left unallocated. Reading also understands zeroed grains.
+//!
+//! Stream-optimized (compressed) VMDKs and descriptor files referencing
+//! separate extents are not supported.
+
+use crate::{
+    block::Block,
+    container::{self, ClusterMap, Clusters, MappedReader, Mapping},
+    pipeline::{BlockSink, BlockSource},
+};
+use crate::result::{ensure, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{prelude::*, SeekFrom},
+};
+
+/// The magic at the start of sparse extents.
+pub const MAGIC: [u8; 4] = *b"KDMV";
+
+const SECTOR_SIZE: u64 = 512;
+const VERSION: u32 = 1;
+/// Grains are 64 KiB, 128 sectors.
+const GRAIN_SECTORS: u64 = 128;
+const GRAIN_SIZE: u64 = GRAIN_SECTORS * SECTOR_SIZE;
+const GTES_PER_GT: u64 = 512;
+const GT_SECTORS: u64 = GTES_PER_GT * 4 / SECTOR_SIZE;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+
+const FLAG_NL_DETECT: u32 = 1 << 0;
+const FLAG_REDUNDANT_GD: u32 = 1 << 1;
+const FLAG_ZERO_GRAIN: u32 = 1 << 2;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+const GTE_ZERO: u64 = 1;
+/// The grain directory offset of stream-optimized extents, which store it
+/// in a footer instead.
+const GD_AT_END: u64 = u64::MAX;
+
+/// Writes sparse blocks to a monolithic sparse VMDK.
+///
+/// Checksum blocks are ignored.
+pub struct VmdkWriter<W: Write + Seek> {
+    dst: W,
+    start: u64,
+    capacity: u64,
+    file_name: String,
+    clusters: Clusters,
+    /// The sector offsets of all grains, 0 for unallocated ones.
+    gt: Vec<u64>,
+    next_grain: usize,
+    /// The offset of the next grain in sectors, relative to the start.
+    next_sector: u64,
+    deterministic: bool,
+    finished: bool,
+}
+
+impl<W: Write + Seek> VmdkWriter<W> {
+    /// Creates a new writer that writes a VMDK with a virtual size of
+    /// `size` bytes to `w`.
+    ///
+    /// The extent is named `file_name` in the embedded descriptor, which
+    /// should be the name of the file written to. The size is fixed
+    /// upfront, as the grain tables precede the data. Blocks not written
+    /// until the writer is closed are left unallocated.
+    pub fn new(mut w: W, size: u64, file_name: &str) -> Result<Self> {
+        ensure!(
+            size.is_multiple_of(u64::from(Block::SIZE)),
+            "VMDK size must be a multiple of the block size"
+        );
+        ensure!(
+            !file_name.contains(['"', '\n']),
+            "Invalid VMDK file name: {file_name}"
+        );
+
+        // The header, descriptor and grain tables are written at the end in
+        // `finish`. Grains start right after them.
+        let capacity = size / SECTOR_SIZE;
+        let grains = capacity.div_ceil(GRAIN_SECTORS);
+        let layout = Layout::new(grains);
+
+        let start = w.stream_position()?;
+        w.seek(SeekFrom::Start(start + layout.overhead * SECTOR_SIZE))?;
+
+        Ok(Self {
+            dst: w,
+            start,
+            capacity,
+            file_name: file_name.to_string(),
+            clusters: Clusters::new(GRAIN_SIZE),
+            gt: vec![0; (layout.num_gts * GTES_PER_GT) as usize],
+            next_grain: 0,
+            next_sector: layout.overhead,
+            deterministic: false,
+            finished: false,
+        })
+    }
+
+    /// Derives the content ID of the disk from its size instead of
+    /// generating a random one, so identical inputs yield byte-for-byte
+    /// identical VMDKs.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Writes a sparse block to this writer.
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        match self.clusters.push(block) {
+            Some(blocks) => self.write_grain(&blocks),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes writing the VMDK.
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_grain(&mut self, blocks: &[Block]) -> Result<()> {
+        let grains = self.capacity.div_ceil(GRAIN_SECTORS) as usize;
+        ensure!(
+            self.next_grain < grains,
+            "More blocks written than fit into the VMDK"
+        );
+
+        // Zero grains are left unallocated too, which reads the same and
+        // works with all VMDK versions.
+        if let Mapping::Data(_) = container::classify(blocks) {
+            self.gt[self.next_grain] = self.next_sector;
+            container::write_cluster(&mut self.dst, blocks, GRAIN_SIZE)?;
+            self.next_sector += GRAIN_SECTORS;
+        }
+
+        self.next_grain += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        assert!(!self.finished);
+        self.finished = true;
+
+        if let Some(blocks) = self.clusters.take() {
+            let remaining = self.capacity - self.next_grain as u64 * GRAIN_SECTORS;
+            ensure!(
+                blocks.len() as u64 * u64::from(Block::SIZE) <= remaining * SECTOR_SIZE,
+                "More blocks written than fit into the VMDK"
+            );
+            self.write_grain(&blocks)?;
+        }
+        let end = self.dst.stream_position()?;
+
+        let layout = Layout::new(self.capacity.div_ceil(GRAIN_SECTORS));
+        self.dst.seek(SeekFrom::Start(self.start))?;
+        self.dst.write_all(&header(self.capacity, &layout))?;
+
+        let descriptor = descriptor(self.capacity, &self.file_name, self.deterministic);
+        ensure!(
+            descriptor.len() as u64 <= DESCRIPTOR_SECTORS * SECTOR_SIZE,
+            "VMDK file name too long"
+        );
+        self.dst.write_all(descriptor.as_bytes())?;
+
+        // The redundant copy of the grain directory and tables comes first,
+        // in the same layout as the primary one.
+        for gd_offset in [layout.rgd_offset, layout.gd_offset] {
+            let gt_offset = gd_offset + layout.gd_sectors;
+            let gd: Vec<_> = (0..layout.num_gts)
+                .map(|i| gt_offset + i * GT_SECTORS)
+                .collect();
+
+            self.dst
+                .seek(SeekFrom::Start(self.start + gd_offset * SECTOR_SIZE))?;
+            container::write_table::<_, LittleEndian>(&mut self.dst, &gd, 4, SECTOR_SIZE)?;
+            container::write_table::<_, LittleEndian>(&mut self.dst, &self.gt, 4, SECTOR_SIZE)?;
+        }
+
+        self.dst.seek(SeekFrom::Start(end))?;
+        self.dst.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for VmdkWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish().ok();
+        }
+    }
+}
+
+impl<W: Write + Seek> BlockSink for VmdkWriter<W> {
+    fn write_block(&mut self, block: &Block) -> Result<()> {
+        VmdkWriter::write_block(self, block)
+    }
+
+    fn close(self) -> Result<()> {
+        VmdkWriter::close(self)
+    }
+}
+
+/// The location of the metadata of a sparse extent, in sectors.
+struct Layout {
+    num_gts: u64,
+    gd_sectors: u64,
+    rgd_offset: u64,
+    gd_offset: u64,
+    /// The sectors taken by metadata, after which the grains start.
+    overhead: u64,
+}
+
+impl Layout {
+    fn new(grains: u64) -> Self {
+        let num_gts = grains.div_ceil(GTES_PER_GT);
+        let gd_sectors = (num_gts * 4).div_ceil(SECTOR_SIZE);
+        let metadata_sectors = gd_sectors + num_gts * GT_SECTORS;
+
+        let rgd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+        let gd_offset = rgd_offset + metadata_sectors;
+        let overhead = (gd_offset + metadata_sectors).next_multiple_of(GRAIN_SECTORS);
+
+        Self {
+            num_gts,
+            gd_sectors,
+            rgd_offset,
+            gd_offset,
+            overhead,
+        }
+    }
+}
+
+fn header(capacity: u64, layout: &Layout) -> [u8; SECTOR_SIZE as usize] {
+    let mut buf = [0; SECTOR_SIZE as usize];
+    buf[0..4].copy_from_slice(&MAGIC);
+    LittleEndian::write_u32(&mut buf[4..], VERSION);
+    LittleEndian::write_u32(&mut buf[8..], FLAG_NL_DETECT | FLAG_REDUNDANT_GD);
+    LittleEndian::write_u64(&mut buf[12..], capacity);
+    LittleEndian::write_u64(&mut buf[20..], GRAIN_SECTORS);
+    LittleEndian::write_u64(&mut buf[28..], DESCRIPTOR_OFFSET);
+    LittleEndian::write_u64(&mut buf[36..], DESCRIPTOR_SECTORS);
+    LittleEndian::write_u32(&mut buf[44..], GTES_PER_GT as u32);
+    LittleEndian::write_u64(&mut buf[48..], layout.rgd_offset);
+    LittleEndian::write_u64(&mut buf[56..], layout.gd_offset);
+    LittleEndian::write_u64(&mut buf[64..], layout.overhead);
+    // Characters to detect corruption by newline conversion.
+    buf[73..77].copy_from_slice(b"\n \r\n");
+    buf
+}
+
+/// Generates the embedded descriptor, padded to its full size.
+fn descriptor(capacity: u64, file_name: &str, deterministic: bool) -> String {
+    // A random content ID, so hypervisors can tell converted disks apart.
+    let cid = match deterministic {
+        true => crc32fast::hash(&capacity.to_le_bytes()),
+        false => RandomState::new().build_hasher().finish() as u32,
+    };
+    let cylinders = (capacity / (16 * 63)).min(16383);
+
+    let mut descriptor = format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={cid:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"monolithicSparse\"\n\
+         \n\
+         # Extent description\n\
+         RW {capacity} SPARSE \"{file_name}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{cylinders}\"\n\
+         ddb.geometry.heads = \"16\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"ide\"\n"
+    );
+
+    let len = (DESCRIPTOR_SECTORS * SECTOR_SIZE) as usize;
+    if descriptor.len() < len {
+        descriptor.extend(std::iter::repeat_n('\0', len - descriptor.len()));
+    }
+    descriptor
+}
+
+/// Reads sparse blocks from a monolithic sparse VMDK.
+///
+/// Unallocated grains are read as skip blocks, zeroed grains as fill
+/// blocks and all other grains as raw blocks.
+pub struct VmdkReader<R: Read + Seek> {
+    inner: MappedReader<R, VmdkMap>,
+}
+
+impl<R: Read + Seek> VmdkReader<R> {
+    /// Creates a new reader that reads from `r`.
+    pub fn new(mut r: R) -> Result<Self> {
+        let start = r.stream_position()?;
+
+        let mut header = [0; SECTOR_SIZE as usize];
+        r.read_exact(&mut header)?;
+        ensure!(header[0..4] == MAGIC, "Not a VMDK sparse extent");
+
+        let version = LittleEndian::read_u32(&header[4..]);
+        ensure!(
+            (1..=3).contains(&version),
+            "Unsupported VMDK version: {version}"
+        );
+
+        let flags = LittleEndian::read_u32(&header[8..]);
+        let gd_offset = LittleEndian::read_u64(&header[56..]);
+        ensure!(
+            flags & (FLAG_COMPRESSED | FLAG_MARKERS) == 0 && gd_offset != GD_AT_END,
+            "Stream-optimized VMDKs are not supported"
+        );
+
+        let capacity = LittleEndian::read_u64(&header[12..]);
+        let grain_sectors = LittleEndian::read_u64(&header[20..]);
+        let grain_size = grain_sectors.saturating_mul(SECTOR_SIZE);
+        ensure!(
+            grain_size > 0 && grain_size.is_multiple_of(u64::from(Block::SIZE)),
+            "Unsupported VMDK grain size: {grain_size}"
+        );
+
+        let gtes_per_gt = u64::from(LittleEndian::read_u32(&header[44..]));
+        ensure!(gtes_per_gt > 0, "Invalid VMDK grain table size");
+
+        let num_gts = capacity.div_ceil(grain_sectors).div_ceil(gtes_per_gt);
+        let gd = container::read_table::<_, LittleEndian>(
+            &mut r,
+            start + gd_offset * SECTOR_SIZE,
+            num_gts as usize,
+            4,
+        )?;
+
+        let map = VmdkMap {
+            start,
+            grain_size,
+            gtes_per_gt,
+            zero_grains: flags & FLAG_ZERO_GRAIN != 0,
+            gd,
+            gt: None,
+        };
+
+        Ok(Self {
+            inner: MappedReader::new(r, map, start, capacity * SECTOR_SIZE),
+        })
+    }
+
+    /// Returns the virtual size of the disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.inner.size
+    }
+}
+
+impl<R: Read + Seek> BlockSource for VmdkReader<R> {
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        self.inner.read_block()
+    }
+
+    fn raw_size(&self) -> Option<u64> {
+        self.inner.raw_size()
+    }
+}
+
+struct VmdkMap {
+    start: u64,
+    grain_size: u64,
+    gtes_per_gt: u64,
+    zero_grains: bool,
+    gd: Vec<u64>,
+    /// The index and entries of the grain table last read.
+    gt: Option<(usize, Vec<u64>)>,
+}
+
+impl ClusterMap for VmdkMap {
+    fn cluster_size(&self) -> u64 {
+        self.grain_size
+    }
+
+    fn lookup<R: Read + Seek>(&mut self, src: &mut R, index: u64) -> Result<Mapping> {
+        let gd_index = (index / self.gtes_per_gt) as usize;
+        let gt_index = (index % self.gtes_per_gt) as usize;
+
+        let gt_sector = self.gd[gd_index];
+        if gt_sector == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+
+        if self.gt.as_ref().map(|(i, _)| *i) != Some(gd_index) {
+            let offset = self.start + gt_sector * SECTOR_SIZE;
+            let len = self.gtes_per_gt as usize;
+            let table = container::read_table::<_, LittleEndian>(src, offset, len, 4)?;
+            self.gt = Some((gd_index, table));
+        }
+
+        Ok(match self.gt.as_ref().unwrap().1[gt_index] {
+            0 => Mapping::Unallocated,
+            GTE_ZERO if self.zero_grains => Mapping::Zero,
+            sector => Mapping::Data(sector * SECTOR_SIZE),
+        })
+    }
+}
+
+/// Checks whether the image in `r` is a VMDK sparse extent, rewinding `r`
+/// afterward.
+pub fn is_vmdk<R: Read + Seek>(r: R) -> Result<bool> {
+    container::has_magic(r, &MAGIC)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCKS_PER_GRAIN: usize = (GRAIN_SIZE / Block::SIZE as u64) as usize;
+
+    #[test]
+    fn roundtrip() {
+        // An unallocated grain, a data grain, then a partial grain.
+        let mut blocks = vec![Block::Fill([0; 4]); BLOCKS_PER_GRAIN];
+        blocks.extend(vec![Block::Fill([1; 4]); BLOCKS_PER_GRAIN]);
+        blocks.extend([Block::Skip, Block::Fill([2; 4])]);
+        let size = blocks.len() as u64 * u64::from(Block::SIZE);
+
+        let mut image = Cursor::new(Vec::new());
+        let mut writer = VmdkWriter::new(&mut image, size, "test.vmdk").unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+
+        // The metadata fits into a single grain, followed by two grains.
+        assert_eq!(image.get_ref().len() as u64, 3 * GRAIN_SIZE);
+        let descriptor = &image.get_ref()[SECTOR_SIZE as usize..];
+        assert!(descriptor.starts_with(b"# Disk DescriptorFile\n"));
+
+        image.set_position(0);
+        assert!(is_vmdk(&mut image).unwrap());
+
+        let mut reader = VmdkReader::new(&mut image).unwrap();
+        assert_eq!(reader.size(), size);
+
+        let mut expected: Vec<_> = blocks.iter().map(raw).collect();
+        expected[..BLOCKS_PER_GRAIN].fill(Block::Skip);
+        let read: Vec<_> = (&mut reader).blocks().map(Result::unwrap).collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn deterministic() {
+        let write = || {
+            let mut image = Cursor::new(Vec::new());
+            let writer = VmdkWriter::new(&mut image, GRAIN_SIZE, "test.vmdk").unwrap();
+            writer.deterministic(true).close().unwrap();
+            image.into_inner()
+        };
+        assert_eq!(write(), write());
+    }
+
+    fn raw(block: &Block) -> Block {
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        Block::Raw(buf.into())
+    }
+}
@@ -1,113 +1,514 @@
 //! Sparse image writing and decoding to raw images.
 
 use crate::{
-    block::Block,
+    block::{Block, BlockBuf, CrcPlacement, CrcPolicy},
+    chunk,
+    dump::ChunkEntry,
     ext::WriteBlock,
     headers::{ChunkHeader, ChunkType, FileHeader},
+    io::{try_lock, PositionedFile, SyncData},
+    metadata::Metadata,
+    platform,
+    read::BlockCountPolicy,
+    sidecar::Recorder,
 };
-use anyhow::Result;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::result::{bail, ensure, Context, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
-use std::io::{prelude::*, BufWriter, SeekFrom};
+use std::{
+    error::Error as StdError,
+    fmt,
+    fs::File,
+    io::{self, prelude::*, BufReader, BufWriter, IoSlice, SeekFrom},
+    sync::Arc,
+    thread,
+};
+
+/// The buffer size `BufWriter` uses by default.
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// The number of raw blocks a `Decoder` batches into a single write by
+/// default.
+const DEFAULT_RAW_BATCH_SIZE: usize = 32;
+
+/// The number of expanded fill blocks a `Decoder` keeps by default.
+const DEFAULT_FILL_CACHE_SIZE: usize = 8;
+
+/// The number of chunks a `Writer` writes before it checks for
+/// fragmentation, so small images with a few short chunks don't count as
+/// fragmented.
+const FRAGMENTATION_MIN_CHUNKS: u32 = 64;
+
+/// A callback invoked for every chunk a `Writer` finishes.
+type ChunkCallback = Box<dyn FnMut(&ChunkEntry) + Send>;
+
+/// A callback invoked once a `Writer` finds its image fragmented, with the
+/// numbers of blocks and chunks written.
+type FragmentationCallback = Box<dyn FnOnce(u64, u32) + Send>;
+
+/// Syncs the destination of a writer or decoder to storage.
+type SyncFn<W> = fn(&W) -> io::Result<()>;
+
+/// The error returned when a checksum block written to a `Writer` does not
+/// hold the checksum of the blocks written before it.
+///
+/// Can be told apart from other errors with `Error::downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// The checksum of the blocks written before the checksum block.
+    pub expected: u32,
+    /// The checksum in the checksum block.
+    pub found: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "checksum block {:#010x} does not match the checksum {:#010x} of the blocks before it",
+            self.found, self.expected
+        )
+    }
+}
+
+impl StdError for ChecksumMismatch {}
 
 /// Writes sparse blocks to a sparse image.
 pub struct Writer<W: Write + Seek> {
-    dst: BufWriter<W>,
+    /// Taken by `close`.
+    dst: Option<BufWriter<W>>,
     current_chunk: Option<ChunkHeader>,
     current_fill: Option<[u8; 4]>,
     num_blocks: u32,
     num_chunks: u32,
+    /// The size of the file header and the finished chunks in bytes.
+    sparse_size: u64,
     crc: Option<Hasher>,
+    crc_policy: CrcPolicy,
+    crc_placement: CrcPlacement,
+    crc_interval: Option<u32>,
+    /// The number of blocks covered by the last checksum chunk.
+    last_checkpoint: u64,
+    max_chunks: Option<u32>,
+    min_blocks_per_chunk: Option<u32>,
+    on_chunk: Option<ChunkCallback>,
+    on_fragmentation: Option<(u32, FragmentationCallback)>,
+    chunk_crcs: Option<Recorder>,
+    sync: Option<SyncFn<W>>,
+    deterministic: bool,
+    abort_on_drop: bool,
+    must_close: bool,
     finished: bool,
 }
 
 impl<W: Write + Seek> Writer<W> {
     /// Creates a new writer that writes to `w`.
     pub fn new(w: W, crc: bool) -> Result<Self> {
-        let mut dst = BufWriter::new(w);
+        Self::with_capacity(DEFAULT_BUF_SIZE, w, crc)
+    }
+
+    /// Creates a new writer that writes to `w`, buffering up to `capacity`
+    /// bytes.
+    pub fn with_capacity(capacity: usize, w: W, crc: bool) -> Result<Self> {
+        let mut dst = BufWriter::with_capacity(capacity, w);
         // We cannot write the file header until we know the total number of
         // blocks and chunks. So we skip it here and write it at the end in
         // `finish`.
         dst.seek(SeekFrom::Current(i64::from(FileHeader::SIZE)))?;
 
         Ok(Self {
-            dst,
+            dst: Some(dst),
             current_chunk: None,
             current_fill: None,
             num_blocks: 0,
             num_chunks: 0,
+            sparse_size: u64::from(FileHeader::SIZE),
             crc: if crc { Some(Hasher::new()) } else { None },
+            crc_policy: CrcPolicy::default(),
+            crc_placement: CrcPlacement::default(),
+            crc_interval: None,
+            last_checkpoint: 0,
+            max_chunks: None,
+            min_blocks_per_chunk: None,
+            on_chunk: None,
+            on_fragmentation: None,
+            chunk_crcs: None,
+            sync: None,
+            deterministic: false,
+            abort_on_drop: false,
+            must_close: false,
             finished: false,
         })
     }
 
+    /// Calls `f` whenever a chunk has been written, with the chunk's header
+    /// and its location in the sparse and raw images.
+    ///
+    /// This allows building indexes or reporting progress without reading
+    /// the sparse image back.
+    pub fn on_chunk<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&ChunkEntry) + Send + 'static,
+    {
+        self.on_chunk = Some(Box::new(f));
+        self
+    }
+
+    /// Writes the checksum of every chunk to `sidecar` once the chunk has
+    /// been written, for spot-checking the image with `sidecar::verify`.
+    ///
+    /// Metadata chunks and chunks finished before this is called, including
+    /// a chunk continued by `append_to`, are left out. See `sidecar` for
+    /// the format.
+    pub fn chunk_crcs<S: Write + Send + 'static>(mut self, sidecar: S) -> Self {
+        self.chunk_crcs = Some(Recorder::new(sidecar, self.current_chunk.is_some()));
+        self
+    }
+
+    /// Limits the number of chunks in the sparse image to `max`, including
+    /// the checksum chunk.
+    ///
+    /// Some bootloaders refuse images with too many chunks. Once the limit
+    /// is reached, all remaining blocks are stored in the last chunk,
+    /// converting it and them to raw data as needed. This can make the
+    /// image much larger, as skipped blocks are then stored as zeros.
+    /// Writing fails if not even a single data chunk fits.
+    pub fn max_chunks(mut self, max: u32) -> Self {
+        self.max_chunks = Some(max);
+        self
+    }
+
+    /// Calls `f` once the image gets fragmented, i.e. its chunks cover
+    /// fewer than `blocks_per_chunk` blocks on average, with the numbers of
+    /// blocks and chunks written so far.
+    ///
+    /// Devices flash heavily fragmented images very slowly, so callers can
+    /// warn about them or start over with `min_blocks_per_chunk`. Images
+    /// with fewer than 64 chunks never count as fragmented.
+    pub fn on_fragmentation<F>(mut self, blocks_per_chunk: u32, f: F) -> Self
+    where
+        F: FnOnce(u64, u32) + Send + 'static,
+    {
+        self.on_fragmentation = Some((blocks_per_chunk, Box::new(f)));
+        self
+    }
+
+    /// Keeps the chunks of the sparse image at `blocks` blocks on average,
+    /// to bound its fragmentation.
+    ///
+    /// A block only starts a new chunk if the chunks written so far cover
+    /// at least `blocks` blocks each on average. Otherwise it is appended
+    /// to the current chunk, converting it and the block to raw data as
+    /// with `max_chunks`. This normalizes runs of short chunks into raw
+    /// chunks of up to `blocks` blocks, at the cost of storing the
+    /// skipped blocks among them as zeros.
+    pub fn min_blocks_per_chunk(mut self, blocks: u32) -> Self {
+        self.min_blocks_per_chunk = Some(blocks);
+        self
+    }
+
+    /// Sets which blocks contribute to the checksum that is written.
+    ///
+    /// Defaults to `CrcPolicy::IncludeSkipped`, like libsparse. Set the
+    /// policy before writing any blocks. Writers created with `append_to`
+    /// have checksummed the existing blocks with the default policy.
+    pub fn crc_policy(mut self, policy: CrcPolicy) -> Self {
+        self.crc_policy = policy;
+        self
+    }
+
+    /// Sets where the checksum is stored, if checksum writing is enabled.
+    ///
+    /// Defaults to `CrcPlacement::Chunk`, like libsparse. A checksum in the
+    /// file header covers all blocks of the image.
+    pub fn crc_placement(mut self, placement: CrcPlacement) -> Self {
+        self.crc_placement = placement;
+        self
+    }
+
+    /// Writes a checksum chunk holding the checksum of the blocks written
+    /// so far after every `blocks` blocks, so readers detect corruption
+    /// before reaching the end of the image. This enables checksum writing.
+    ///
+    /// Checksum blocks written with `write_block` are then kept as checksum
+    /// chunks, instead of leaving only the one written by `finish`. No
+    /// checksum chunks are added once the chunk limit set with
+    /// `max_chunks` is near. Images with them cannot be continued with
+    /// `append_to`.
+    pub fn crc_interval(mut self, blocks: u32) -> Self {
+        self.crc_interval = Some(blocks.max(1));
+        self.crc.get_or_insert_with(Hasher::new);
+        self.last_checkpoint = self.blocks_written();
+        self
+    }
+
+    /// Guarantees byte-for-byte identical output for identical input.
+    ///
+    /// The sparse image only depends on the blocks written, so this only
+    /// affects data whose order or content would otherwise be arbitrary:
+    /// metadata entries are written sorted by key, regardless of their
+    /// insertion order. Note that the writer does not truncate its
+    /// destination, so it must be empty for the output to be identical.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
     /// Writes a sparse block to this writer.
     ///
     /// The sparse block is converted into the sparse file format and
     /// written to this decoder's destination.
+    ///
+    /// Checksum blocks, e.g. those a `Reader` yields, must hold the
+    /// checksum of the blocks written so far if checksum writing is
+    /// enabled, and fail with `ChecksumMismatch` otherwise. They are only
+    /// kept with `crc_interval`, as `finish` writes the checksum chunk.
+    /// Without checksum writing, they are written as given.
     pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        ensure!(!self.finished, "Cannot write to a finished image");
+        if let Block::Crc32(checksum) = block {
+            if !self.check_checksum_block(*checksum)? {
+                return Ok(());
+            }
+        } else if self.checkpoint_due() {
+            let checksum = self.current_crc().unwrap();
+            self.put_block(&Block::Crc32(checksum))?;
+        }
+        self.put_block(block)
+    }
+
+    /// Writes `block` without checking checksum blocks.
+    fn put_block(&mut self, block: &Block) -> Result<()> {
         if !self.can_merge(block) {
+            if self.chunk_limit_reached() || self.too_fragmented(block) {
+                return self.coalesce(block);
+            }
             self.finish_chunk()?;
             self.start_chunk(block)?;
         }
 
+        let dst = self.dst.as_mut().unwrap();
         let chunk = self.current_chunk.as_mut().unwrap();
 
         match block {
             Block::Raw(buf) => {
-                self.dst.write_all(&**buf)?;
+                dst.write_all(buf)?;
                 chunk.total_size += Block::SIZE;
+                if let Some(recorder) = self.chunk_crcs.as_mut() {
+                    recorder.update(buf);
+                }
             }
             Block::Fill(value) => {
                 if self.current_fill.is_none() {
-                    self.dst.write_all(value)?;
+                    dst.write_all(value)?;
                     self.current_fill = Some(*value);
+                    if let Some(recorder) = self.chunk_crcs.as_mut() {
+                        recorder.update(value);
+                    }
                 }
             }
             Block::Skip => (),
             Block::Crc32(checksum) => {
-                self.dst.write_u32::<LittleEndian>(*checksum)?;
+                dst.write_u32::<LittleEndian>(*checksum)?;
+                if let Some(recorder) = self.chunk_crcs.as_mut() {
+                    recorder.update(&checksum.to_le_bytes());
+                }
+                self.last_checkpoint = u64::from(self.num_blocks);
                 // CRC chunk size must remain 0, so drop out here already.
                 return Ok(());
             }
         }
 
         if let Some(hasher) = self.crc.as_mut() {
-            hasher.write_block(block);
+            if self.crc_policy.covers(block) {
+                hasher.write_block(block);
+            }
         }
 
         chunk.chunk_size += 1;
         Ok(())
     }
 
+    /// Ends the current chunk, so the next block starts a new chunk even
+    /// if it could be merged into the current one.
+    ///
+    /// Has no effect once the chunk limit set with `max_chunks` is reached.
+    pub fn end_chunk(&mut self) -> Result<()> {
+        ensure!(!self.finished, "Cannot write to a finished image");
+        if self.chunk_limit_reached() {
+            return Ok(());
+        }
+        self.finish_chunk()
+    }
+
+    /// Writes a metadata chunk holding `metadata` to this writer.
+    ///
+    /// Metadata is usually written before the first block. See `metadata`
+    /// for the compatibility of images with metadata chunks.
+    pub fn write_metadata(&mut self, metadata: &Metadata) -> Result<()> {
+        ensure!(!self.finished, "Cannot write to a finished image");
+        ensure!(
+            !self.chunk_limit_reached(),
+            "Chunk limit reached, cannot write metadata chunk"
+        );
+        self.finish_chunk()?;
+
+        let mut sorted;
+        let metadata = if self.deterministic {
+            sorted = metadata.clone();
+            sorted.sort();
+            &sorted
+        } else {
+            metadata
+        };
+
+        let offset = self.dst().stream_position()?;
+        let header = chunk::write_metadata_chunk(self.dst(), metadata)?;
+        self.num_chunks += 1;
+        self.sparse_size += u64::from(header.total_size);
+
+        if let Some(f) = self.on_chunk.as_mut() {
+            f(&ChunkEntry {
+                offset,
+                start_block: u64::from(self.num_blocks),
+                header,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the checksum of the blocks written so far.
+    ///
+    /// Returns `None` if checksum writing is disabled.
+    pub fn current_crc(&self) -> Option<u32> {
+        self.crc.as_ref().map(|hasher| hasher.clone().finalize())
+    }
+
+    /// Checks whether no blocks have been written to this writer yet.
+    pub fn is_empty(&self) -> bool {
+        self.num_blocks == 0
+    }
+
+    /// Returns the number of blocks written so far, including those of the
+    /// current chunk.
+    pub fn blocks_written(&self) -> u64 {
+        let current = self.current_chunk.as_ref().map_or(0, |c| c.chunk_size);
+        u64::from(self.num_blocks) + u64::from(current)
+    }
+
+    /// Returns the number of chunks written so far, including the current
+    /// chunk.
+    ///
+    /// Consecutive blocks of the same kind share a chunk, so the current
+    /// chunk may still grow, but the count only increases when a block
+    /// starts a new one.
+    pub fn chunks_written(&self) -> u32 {
+        self.num_chunks + u32::from(self.current_chunk.is_some())
+    }
+
+    /// Returns the size in bytes of the sparse image written so far,
+    /// including the file header and the current chunk.
+    ///
+    /// This is the size the image would have if it were finished now,
+    /// without a checksum chunk, so adaptive callers can e.g. start a new
+    /// image before this one grows too large. It counts data still in the
+    /// write buffer.
+    pub fn bytes_written_sparse(&self) -> u64 {
+        let current = self.current_chunk.as_ref().map_or(0, |c| c.total_size);
+        self.sparse_size + u64::from(current)
+    }
+
+    /// Leaves the image unfinished if this writer is dropped without being
+    /// closed, instead of finishing it.
+    ///
+    /// By default, dropping a writer finishes the image and ignores any
+    /// errors, so an image that is cut short, e.g. by an early return,
+    /// looks complete. With this set, the file header isn't written, so
+    /// readers reject the incomplete image.
+    pub fn set_abort_on_drop(&mut self, abort: bool) {
+        self.abort_on_drop = abort;
+    }
+
+    /// Panics in debug builds if this writer is dropped before the image
+    /// has been finished, to catch code paths that forget to call `close`.
+    ///
+    /// Release builds finish or abort the image as usual, see
+    /// `set_abort_on_drop`.
+    pub fn set_must_close(&mut self, must: bool) {
+        self.must_close = must;
+    }
+
+    /// Checks whether the image has been finished with `finish`, so no
+    /// more blocks can be written.
+    ///
+    /// The image is only complete if finishing succeeded.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Finishes writing the sparse image and flushes any buffered data,
+    /// without giving up the writer, e.g. in code that only borrows it.
+    ///
+    /// Writing blocks afterward fails. `close` still returns the
+    /// destination.
+    pub fn finish(&mut self) -> Result<()> {
+        ensure!(!self.finished, "The image is already finished");
+        self.finish_image()
+    }
+
     /// Finishes writing the sparse image and flushes any buffered data.
     ///
-    /// Consumes the reader as using it afterward would be invalid.
-    pub fn close(mut self) -> Result<()> {
-        self.finish()
+    /// If no blocks were written, this writes a valid image of 0 blocks
+    /// and 0 chunks, plus the checksum chunk of the empty image if
+    /// checksums are enabled.
+    ///
+    /// Returns the destination, so it can be used further without reopening
+    /// it, e.g. to append a signature or sync it to disk.
+    ///
+    /// Consumes the writer as using it afterward would be invalid.
+    pub fn close(mut self) -> Result<W> {
+        if !self.finished {
+            self.finish_image()?;
+        }
+        let dst = self.dst.take().unwrap();
+        dst.into_inner().map_err(|err| err.into_error().into())
+    }
+
+    /// Returns the destination, which is there until `close` takes it.
+    fn dst(&mut self) -> &mut BufWriter<W> {
+        self.dst.as_mut().unwrap()
     }
 
-    fn finish(&mut self) -> Result<()> {
+    fn finish_image(&mut self) -> Result<()> {
         assert!(!self.finished);
+        // The checksum is the last block written, even if writing it fails.
+        let checksum = self.write_checksum();
         self.finished = true;
-
-        self.write_checksum()?;
+        let checksum = checksum?;
         self.finish_chunk()?;
 
-        // Like libsparse, we always set the checksum value in the file header
-        // to 0. If checksum writing is enabled, we append a Crc32 chunk at
-        // the end of the file instead.
-        let image_checksum = 0;
+        // Like libsparse, we set the checksum value in the file header to 0
+        // unless asked to store the checksum there.
+        let image_checksum = match checksum {
+            Some(checksum) if self.crc_placement.in_header() => checksum,
+            _ => 0,
+        };
         let header = FileHeader {
             total_blocks: self.num_blocks,
             total_chunks: self.num_chunks,
             image_checksum,
         };
 
-        self.dst.seek(SeekFrom::Start(0))?;
-        header.write_to(&mut self.dst)?;
+        self.dst().seek(SeekFrom::Start(0))?;
+        header.write_to(self.dst())?;
 
-        self.dst.flush()?;
+        self.dst().flush()?;
+        if let Some(recorder) = self.chunk_crcs.as_mut() {
+            recorder.flush()?;
+        }
+        if let Some(sync) = self.sync {
+            sync(self.dst().get_ref()).context("Syncing sparse image")?;
+        }
         Ok(())
     }
 
@@ -124,6 +525,139 @@ impl<W: Write + Seek> Writer<W> {
         }
     }
 
+    fn chunk_limit_reached(&self) -> bool {
+        !self.has_room_for(1)
+    }
+
+    /// Checks whether `chunks` more chunks fit within the chunk limit.
+    fn has_room_for(&self, chunks: u64) -> bool {
+        let max = match self.max_chunks {
+            Some(max) => u64::from(max),
+            None => return true,
+        };
+
+        // Keep room for the checksum chunk written in `finish`.
+        let reserved = u64::from(self.crc.is_some() && self.crc_placement.in_chunk());
+        let used = u64::from(self.num_chunks) + u64::from(self.current_chunk.is_some());
+        used + chunks + reserved <= max
+    }
+
+    /// Checks that a checksum block written by the caller holds the
+    /// checksum of the blocks written before it, returning whether to keep
+    /// it.
+    ///
+    /// Without checksum writing, there is nothing to check against, so the
+    /// block is kept as is.
+    fn check_checksum_block(&self, found: u32) -> Result<bool> {
+        let expected = match self.current_crc() {
+            Some(expected) => expected,
+            None => return Ok(true),
+        };
+        if found != expected {
+            return Err(Error::new(ChecksumMismatch { expected, found }));
+        }
+        Ok(self.crc_interval.is_some() && !self.at_checkpoint())
+    }
+
+    /// Checks whether the current chunk is a checksum chunk, i.e. no
+    /// blocks have been written since the last one.
+    fn at_checkpoint(&self) -> bool {
+        matches!(&self.current_chunk, Some(c) if c.chunk_type == ChunkType::Crc32)
+    }
+
+    /// Checks whether a checksum chunk is due before the next block.
+    ///
+    /// The block after it needs a chunk of its own, so there must be room
+    /// for both.
+    fn checkpoint_due(&self) -> bool {
+        match self.crc_interval {
+            Some(blocks) => {
+                self.blocks_written() >= self.last_checkpoint + u64::from(blocks)
+                    && self.has_room_for(2)
+            }
+            None => false,
+        }
+    }
+
+    /// Checks whether starting a new chunk with `block` would exceed the
+    /// fragmentation allowed by `min_blocks_per_chunk`.
+    fn too_fragmented(&self, block: &Block) -> bool {
+        let blocks = match self.min_blocks_per_chunk {
+            // The checksum chunk is always written separately.
+            Some(blocks) if !matches!(block, Block::Crc32(_)) => u64::from(blocks),
+            _ => return false,
+        };
+        self.current_chunk.is_some()
+            && u64::from(self.chunks_written()) * blocks > self.blocks_written()
+    }
+
+    /// Appends `block` to the current chunk as raw data, converting the
+    /// chunk to a raw chunk first if necessary.
+    fn coalesce(&mut self, block: &Block) -> Result<()> {
+        let chunk = match self.current_chunk.as_ref() {
+            Some(c) => c,
+            None => bail!("Chunk limit too small to write any blocks"),
+        };
+        ensure!(
+            !matches!(block, Block::Crc32(_)),
+            "Chunk limit reached, cannot write checksum chunk"
+        );
+
+        if chunk.chunk_type != ChunkType::Raw {
+            self.convert_to_raw()?;
+        }
+
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        self.put_block(&Block::Raw(buf.into()))
+    }
+
+    /// Rewrites the current fill or don't care chunk as a raw chunk.
+    fn convert_to_raw(&mut self) -> Result<()> {
+        let dst = self.dst.as_mut().unwrap();
+        let chunk = self.current_chunk.as_mut().unwrap();
+        let block = match chunk.chunk_type {
+            ChunkType::Fill => Block::Fill(self.current_fill.take().unwrap()),
+            ChunkType::DontCare => Block::Skip,
+            _ => bail!("Cannot convert {:?} chunk to raw data", chunk.chunk_type),
+        };
+
+        let total_size = chunk
+            .chunk_size
+            .checked_mul(Block::SIZE)
+            .and_then(|size| size.checked_add(u32::from(ChunkHeader::SIZE)))
+            .context("Chunk too large to convert to raw data")?;
+
+        // Overwrite the fill value, if any, with the decoded blocks.
+        let payload = i64::from(chunk.total_size) - i64::from(ChunkHeader::SIZE);
+        dst.seek(SeekFrom::Current(-payload))?;
+
+        let mut buf = [0; Block::SIZE as usize];
+        block.decode_into(&mut buf);
+        if let Some(recorder) = self.chunk_crcs.as_mut() {
+            recorder.reset();
+        }
+        for _ in 0..chunk.chunk_size {
+            dst.write_all(&buf)?;
+            if let Some(recorder) = self.chunk_crcs.as_mut() {
+                recorder.update(&buf);
+            }
+        }
+
+        // Raw data always contributes to the checksum, even if the skipped
+        // blocks it replaces didn't.
+        let covered = self.crc_policy.covers(&block);
+        if let Some(hasher) = self.crc.as_mut().filter(|_| !covered) {
+            for _ in 0..chunk.chunk_size {
+                hasher.update(&buf);
+            }
+        }
+
+        chunk.chunk_type = ChunkType::Raw;
+        chunk.total_size = total_size;
+        Ok(())
+    }
+
     fn start_chunk(&mut self, block: &Block) -> Result<()> {
         assert!(self.current_chunk.is_none());
 
@@ -144,7 +678,7 @@ impl<W: Write + Seek> Writer<W> {
         // blocks in the chunk. So we skip it here and write it later in
         // `finish_chunk`.
         let header_size = i64::from(ChunkHeader::SIZE);
-        self.dst.seek(SeekFrom::Current(header_size))?;
+        self.dst().seek(SeekFrom::Current(header_size))?;
 
         self.current_chunk = Some(chunk);
 
@@ -157,90 +691,688 @@ impl<W: Write + Seek> Writer<W> {
             None => return Ok(()),
         };
 
-        let pos = self.dst.stream_position()?;
+        let pos = self.dst().stream_position()?;
         let header_off = i64::from(chunk.total_size);
-        self.dst.seek(SeekFrom::Current(-header_off))?;
-        chunk.write_to(&mut self.dst)?;
-        self.dst.seek(SeekFrom::Start(pos))?;
+        self.dst().seek(SeekFrom::Current(-header_off))?;
+        chunk.write_to(self.dst())?;
+        self.dst().seek(SeekFrom::Start(pos))?;
 
         self.current_fill = None;
         self.num_chunks += 1;
-        self.num_blocks += chunk.chunk_size;
+        self.sparse_size += u64::from(chunk.total_size);
+
+        let entry = ChunkEntry {
+            offset: pos - u64::from(chunk.total_size),
+            start_block: u64::from(self.num_blocks),
+            header: chunk,
+        };
+        self.num_blocks += entry.header.chunk_size;
+        self.check_fragmentation();
+        if let Some(recorder) = self.chunk_crcs.as_mut() {
+            recorder.finish_chunk(&entry)?;
+        }
+        if let Some(f) = self.on_chunk.as_mut() {
+            f(&entry);
+        }
 
         Ok(())
     }
 
-    fn write_checksum(&mut self) -> Result<()> {
+    /// Calls the `on_fragmentation` callback if the image is fragmented.
+    fn check_fragmentation(&mut self) {
+        let fragmented = match &self.on_fragmentation {
+            Some((blocks, _)) => {
+                self.num_chunks >= FRAGMENTATION_MIN_CHUNKS
+                    && u64::from(self.num_chunks) * u64::from(*blocks) > u64::from(self.num_blocks)
+            }
+            None => false,
+        };
+        if fragmented {
+            let (_, f) = self.on_fragmentation.take().unwrap();
+            f(u64::from(self.num_blocks), self.num_chunks);
+        }
+    }
+
+    /// Writes the checksum chunk, if the checksum is stored in one,
+    /// returning the checksum.
+    fn write_checksum(&mut self) -> Result<Option<u32>> {
         let checksum = match self.crc.take() {
             Some(hasher) => hasher.finalize(),
-            None => return Ok(()),
+            None => return Ok(None),
+        };
+
+        // A checksum chunk written at an interval may already cover all
+        // blocks.
+        if self.crc_placement.in_chunk() && !self.at_checkpoint() {
+            self.put_block(&Block::Crc32(checksum))?;
+        }
+        Ok(Some(checksum))
+    }
+}
+
+impl<W: Write + Seek + SyncData> Writer<W> {
+    /// Syncs the written data to storage before `close` returns, so the
+    /// image is safe to copy with `dd` or to unplug removable media right
+    /// after.
+    ///
+    /// Without this, the data may still be in the page cache once `close`
+    /// returns. Errors writing it out are then lost.
+    pub fn sync_on_close(mut self, sync: bool) -> Self {
+        self.sync = sync.then_some(W::sync_data as SyncFn<W>);
+        self
+    }
+}
+
+impl Writer<PositionedFile> {
+    /// Creates a new writer that writes to `file` with positioned I/O.
+    ///
+    /// The writer does not use the file position, so `file` can be shared
+    /// with other readers and writers, e.g. on worker threads.
+    pub fn from_file_positioned<F: Into<Arc<File>>>(file: F, crc: bool) -> Result<Self> {
+        Self::new(PositionedFile::new(file), crc)
+    }
+}
+
+impl Writer<File> {
+    /// Takes an exclusive advisory lock on the destination file, which is
+    /// held until the writer is dropped.
+    ///
+    /// Fails with `io::AlreadyLocked` if another writer holds a lock on the
+    /// file, so concurrent conversions can't interleave their writes to the
+    /// same image. See `io::try_lock` for details.
+    pub fn lock(mut self) -> Result<Self> {
+        try_lock(self.dst().get_ref())?;
+        Ok(self)
+    }
+
+    /// Opens the finished sparse image in `file` to append more blocks to
+    /// it.
+    ///
+    /// `file` must be open for reading and writing. A trailing checksum
+    /// chunk is removed, and if `crc` is set, recomputed over all blocks
+    /// when closing, which requires reading the whole image first. The last
+    /// chunk is continued if the appended blocks fit into it, and the file
+    /// header is updated when closing.
+    ///
+    /// This allows image builders to emit data in stages.
+    pub fn append_to(mut file: File, crc: bool) -> Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut src = BufReader::new(&file);
+        let header = FileHeader::read_from(&mut src)?;
+
+        let mut hasher = if crc { Some(Hasher::new()) } else { None };
+        let mut checksum = None;
+        let mut last_chunk = None;
+        let mut num_blocks = 0_u32;
+        let mut end = u64::from(FileHeader::SIZE);
+
+        for index in 0..header.total_chunks {
+            ensure!(
+                checksum.is_none(),
+                "Checksum chunk before the end of the image"
+            );
+            let chunk =
+                chunk::read_chunk_header(&mut src).with_context(|| format!("Chunk {index}"))?;
+
+            let mut fill = None;
+            match chunk.chunk_type {
+                ChunkType::Raw => match hasher.as_mut() {
+                    Some(hasher) => {
+                        let mut buf = [0; Block::SIZE as usize];
+                        for _ in 0..chunk.chunk_size {
+                            src.read_exact(&mut buf)?;
+                            hasher.update(&buf);
+                        }
+                    }
+                    None => {
+                        let payload = chunk.total_size - u32::from(ChunkHeader::SIZE);
+                        src.seek_relative(i64::from(payload))?;
+                    }
+                },
+                ChunkType::Fill => {
+                    let mut value = [0; 4];
+                    src.read_exact(&mut value)?;
+                    hash_blocks(hasher.as_mut(), &Block::Fill(value), chunk.chunk_size);
+                    fill = Some(value);
+                }
+                ChunkType::DontCare => {
+                    hash_blocks(hasher.as_mut(), &Block::Skip, chunk.chunk_size);
+                }
+                ChunkType::Crc32 => {
+                    checksum = Some(src.read_u32::<LittleEndian>()?);
+                    continue;
+                }
+                ChunkType::Metadata => {
+                    // Metadata chunks cover no blocks and are kept as is.
+                    let payload = chunk.total_size - u32::from(ChunkHeader::SIZE);
+                    src.seek_relative(i64::from(payload))?;
+                    end += u64::from(chunk.total_size);
+                    last_chunk = None;
+                    continue;
+                }
+            }
+
+            num_blocks = num_blocks
+                .checked_add(chunk.chunk_size)
+                .context("Too many blocks in image")?;
+            end += u64::from(chunk.total_size);
+            last_chunk = Some((chunk, fill));
+        }
+
+        ensure!(
+            num_blocks == header.total_blocks,
+            "Chunks cover {num_blocks} blocks, but the header announces {}",
+            header.total_blocks
+        );
+        if let (Some(hasher), Some(checksum)) = (hasher.as_ref(), checksum) {
+            ensure!(
+                hasher.clone().finalize() == checksum,
+                "Checksum mismatch in image to append to"
+            );
+        }
+
+        drop(src);
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+
+        // Reopen the last chunk, so appended blocks can be merged into it.
+        let num_chunks = header.total_chunks - u32::from(checksum.is_some());
+        let (current_chunk, current_fill, num_chunks, sparse_size) = match last_chunk {
+            Some((chunk, fill)) => {
+                num_blocks -= chunk.chunk_size;
+                let sparse_size = end - u64::from(chunk.total_size);
+                (Some(chunk), fill, num_chunks - 1, sparse_size)
+            }
+            None => (None, None, num_chunks, end),
         };
 
-        let block = Block::Crc32(checksum);
-        self.write_block(&block)
+        Ok(Self {
+            dst: Some(BufWriter::with_capacity(DEFAULT_BUF_SIZE, file)),
+            current_chunk,
+            current_fill,
+            num_blocks,
+            num_chunks,
+            sparse_size,
+            crc: hasher,
+            crc_policy: CrcPolicy::default(),
+            crc_placement: CrcPlacement::default(),
+            crc_interval: None,
+            last_checkpoint: 0,
+            max_chunks: None,
+            min_blocks_per_chunk: None,
+            on_chunk: None,
+            on_fragmentation: None,
+            chunk_crcs: None,
+            sync: None,
+            deterministic: false,
+            abort_on_drop: false,
+            must_close: false,
+            finished: false,
+        })
+    }
+}
+
+/// Feeds `count` copies of `block` to `hasher`, if any.
+fn hash_blocks(hasher: Option<&mut Hasher>, block: &Block, count: u32) {
+    if let Some(hasher) = hasher {
+        for _ in 0..count {
+            hasher.write_block(block);
+        }
     }
 }
 
 impl<W: Write + Seek> Drop for Writer<W> {
     fn drop(&mut self) {
-        if !self.finished {
-            self.finish().ok();
+        // Closed images are finished and have given up their destination.
+        if self.dst.is_none() {
+            return;
+        }
+        let unfinished = !self.finished;
+        if unfinished && !self.abort_on_drop {
+            self.finish_image().ok();
         }
+        debug_assert!(
+            !(unfinished && self.must_close) || thread::panicking(),
+            "Writer dropped without being closed"
+        );
     }
 }
 
 /// Decodes sparse blocks and writes them to a raw image.
 pub struct Decoder<W: Write + Seek> {
-    dst: BufWriter<W>,
+    /// Taken by `close`.
+    dst: Option<BufWriter<W>>,
+    raw_batch: Vec<BlockBuf>,
+    raw_queued: usize,
+    raw_batch_size: usize,
+    fill_cache: FillCache,
+    reflink: Option<Reflink>,
+    preserve_skipped: bool,
+    /// The number of blocks the image is expected to have and how to
+    /// handle a different number, see `block_count_policy`.
+    block_count: Option<(u32, BlockCountPolicy)>,
+    /// The number of blocks written so far, excluding checksum blocks.
+    blocks_written: u64,
+    sync: Option<SyncFn<W>>,
+    abort_on_drop: bool,
+    must_close: bool,
     finished: bool,
 }
 
+/// Expanded fill blocks, most recently used first.
+///
+/// Images are usually dominated by a handful of fill values, so keeping
+/// their expanded blocks around avoids materializing them for every fill
+/// chunk.
+struct FillCache {
+    blocks: Vec<([u8; 4], BlockBuf)>,
+    capacity: usize,
+}
+
+impl FillCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            blocks: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Returns the expanded block filled with `value`.
+    fn get(&mut self, value: [u8; 4]) -> &BlockBuf {
+        match self.blocks.iter().position(|(v, _)| *v == value) {
+            Some(index) => self.blocks[..=index].rotate_right(1),
+            None => {
+                let mut buf = BlockBuf::zeroed();
+                Block::Fill(value).decode_into(buf.as_mut_array());
+                if self.blocks.len() == self.capacity {
+                    self.blocks.pop();
+                }
+                self.blocks.insert(0, (value, buf));
+            }
+        }
+        &self.blocks[0].1
+    }
+}
+
+/// Handles for cloning raw blocks from a sparse image into the raw image.
+struct Reflink {
+    src: File,
+    dst: File,
+    pending: Option<CloneRange>,
+}
+
+/// A contiguous range of raw blocks that is yet to be cloned.
+struct CloneRange {
+    src_off: u64,
+    dst_off: u64,
+    len: u64,
+}
+
+impl<W: Write + Seek + SyncData> Decoder<W> {
+    /// Syncs the decoded data to storage before `close` returns, see
+    /// `Writer::sync_on_close`.
+    pub fn sync_on_close(mut self, sync: bool) -> Self {
+        self.sync = sync.then_some(W::sync_data as SyncFn<W>);
+        self
+    }
+}
+
+impl Decoder<File> {
+    /// Takes an exclusive advisory lock on the destination file, which is
+    /// held until the decoder is dropped.
+    ///
+    /// Fails with `io::AlreadyLocked` if another writer holds a lock on the
+    /// file. See `Writer::lock`.
+    pub fn lock(mut self) -> Result<Self> {
+        try_lock(self.dst().get_ref())?;
+        Ok(self)
+    }
+
+    /// Enables cloning raw blocks from `src` instead of copying them.
+    ///
+    /// `src` must be the sparse image the blocks passed to
+    /// `write_block_from` are read from. If it is located on the same
+    /// reflink-capable filesystem (e.g. Btrfs or XFS) as the raw image,
+    /// raw blocks that are block-aligned in `src` share their storage with
+    /// the sparse image. As soon as cloning fails, this decoder falls back
+    /// to copying the data.
+    pub fn reflink_from(&mut self, src: &File) -> Result<()> {
+        self.reflink = Some(Reflink {
+            src: src.try_clone()?,
+            dst: self.dst().get_ref().try_clone()?,
+            pending: None,
+        });
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Decoder<W> {
     /// Creates a new decoder that writes to `w`.
     pub fn new(w: W) -> Result<Self> {
-        let dst = BufWriter::new(w);
+        Self::with_capacity(DEFAULT_BUF_SIZE, w)
+    }
+
+    /// Creates a new decoder that writes to `w`, buffering up to `capacity`
+    /// bytes.
+    pub fn with_capacity(capacity: usize, w: W) -> Result<Self> {
+        let dst = BufWriter::with_capacity(capacity, w);
         Ok(Self {
-            dst,
+            dst: Some(dst),
+            raw_batch: Vec::new(),
+            raw_queued: 0,
+            raw_batch_size: DEFAULT_RAW_BATCH_SIZE,
+            fill_cache: FillCache::new(DEFAULT_FILL_CACHE_SIZE),
+            reflink: None,
+            preserve_skipped: false,
+            block_count: None,
+            blocks_written: 0,
+            sync: None,
+            abort_on_drop: false,
+            must_close: false,
             finished: false,
         })
     }
 
+    /// Leaves the destination untouched at skipped blocks instead of
+    /// making sure they read as zeros.
+    ///
+    /// This is useful when writing to an existing image or a block device,
+    /// e.g. when writing several parts of a split image to the same
+    /// destination.
+    pub fn preserve_skipped(mut self, preserve: bool) -> Self {
+        self.preserve_skipped = preserve;
+        self
+    }
+
+    /// Sets how to handle images of a different number of blocks than
+    /// `total_blocks`, usually the number the file header announces.
+    ///
+    /// Excess blocks are rejected or dropped as they are written, while
+    /// missing blocks are reported or padded with zeros by `finish`. This
+    /// is the counterpart of `Reader::block_count_policy` for blocks that
+    /// don't come from a `Reader`, e.g. from an `Encoder` or `Rechunk`.
+    /// Checksum blocks are not counted. By default, all blocks are written.
+    pub fn block_count_policy(mut self, total_blocks: u32, policy: BlockCountPolicy) -> Self {
+        self.block_count = Some((total_blocks, policy));
+        self
+    }
+
+    /// Keeps the expanded blocks of up to `size` distinct fill values,
+    /// evicting the least recently used value first.
+    ///
+    /// Defaults to 8, which covers the fill values of typical images. At
+    /// least one block is always kept.
+    pub fn fill_cache_size(mut self, size: usize) -> Self {
+        self.fill_cache = FillCache::new(size.max(1));
+        self
+    }
+
+    /// Writes up to `blocks` consecutive raw blocks to the destination
+    /// with a single vectored write.
+    ///
+    /// Defaults to 32 blocks. Batches larger than the buffer capacity
+    /// bypass the buffer, which cuts the number of system calls when
+    /// decoding images with a lot of raw data. A size of 0 or 1 disables
+    /// batching.
+    pub fn raw_batch_size(mut self, blocks: usize) -> Self {
+        self.raw_batch_size = blocks;
+        self
+    }
+
     /// Writes a sparse block to this decoder.
     ///
     /// The sparse block is decoded into its raw form and written to
     /// this decoder's destination.
     pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        ensure!(!self.finished, "Cannot write to a finished image");
+        if self.check_block_count(block)? {
+            self.decode_block(block)?;
+        }
+        Ok(())
+    }
+
+    fn decode_block(&mut self, block: &Block) -> Result<()> {
+        self.flush_clones()?;
+        if !matches!(block, Block::Raw(_)) {
+            self.flush_raw()?;
+        }
+
         match block {
-            Block::Raw(buf) => self.dst.write_all(&**buf)?,
+            Block::Raw(buf) => self.queue_raw(buf)?,
             Block::Fill(value) => {
-                let count = Block::SIZE as usize / 4;
-                for _ in 0..count {
-                    self.dst.write_all(value)?;
-                }
+                let dst = self.dst.as_mut().unwrap();
+                dst.write_all(self.fill_cache.get(*value))?
+            }
+            Block::Skip if self.preserve_skipped => {
+                self.dst().seek(SeekFrom::Current(i64::from(Block::SIZE)))?;
             }
             Block::Skip => {
                 let offset = i64::from(Block::SIZE) - 1;
-                self.dst.seek(SeekFrom::Current(offset))?;
-                self.dst.write_all(&[0])?;
+                self.dst().seek(SeekFrom::Current(offset))?;
+                self.dst().write_all(&[0])?;
             }
             Block::Crc32(_) => (),
         }
         Ok(())
     }
 
+    /// Writes a sparse block that was read from offset `src_offset` of the
+    /// sparse image to this decoder.
+    ///
+    /// Raw blocks are cloned from the sparse image if reflinking has been
+    /// enabled with `reflink_from` and `src_offset` is block-aligned. All
+    /// other blocks are written like with `write_block`.
+    pub fn write_block_from(&mut self, block: &Block, src_offset: u64) -> Result<()> {
+        ensure!(!self.finished, "Cannot write to a finished image");
+        if !self.check_block_count(block)? {
+            return Ok(());
+        }
+        let block_size = u64::from(Block::SIZE);
+        let aligned = src_offset.is_multiple_of(block_size);
+
+        match (block, self.reflink.as_mut()) {
+            (Block::Raw(_), Some(reflink)) if aligned => {
+                if let Some(range) = reflink.pending.as_mut() {
+                    if range.src_off + range.len == src_offset {
+                        range.len += block_size;
+                        return Ok(());
+                    }
+                }
+
+                self.flush_clones()?;
+                self.flush_raw()?;
+
+                let dst_off = self.dst().stream_position()?;
+                if !dst_off.is_multiple_of(block_size) {
+                    return self.decode_block(block);
+                }
+
+                if let Some(reflink) = self.reflink.as_mut() {
+                    reflink.pending = Some(CloneRange {
+                        src_off: src_offset,
+                        dst_off,
+                        len: block_size,
+                    });
+                }
+                Ok(())
+            }
+            _ => self.decode_block(block),
+        }
+    }
+
+    /// Checks `block` against the expected number of blocks, returning
+    /// whether to write it.
+    fn check_block_count(&mut self, block: &Block) -> Result<bool> {
+        let (total, policy) = match self.block_count {
+            Some(count) if !matches!(block, Block::Crc32(_)) => count,
+            _ => return Ok(true),
+        };
+        if self.blocks_written < u64::from(total) {
+            self.blocks_written += 1;
+            return Ok(true);
+        }
+        match policy {
+            BlockCountPolicy::Error => bail!("Image has more than the expected {total} blocks"),
+            BlockCountPolicy::Truncate | BlockCountPolicy::PadWithZeros => Ok(false),
+        }
+    }
+
+    /// Handles missing blocks once all blocks have been written.
+    fn pad_blocks(&mut self) -> Result<()> {
+        let (total, policy) = match self.block_count {
+            Some((total, policy)) if self.blocks_written < u64::from(total) => (total, policy),
+            _ => return Ok(()),
+        };
+        match policy {
+            BlockCountPolicy::Error => bail!(
+                "Image has {} blocks, but {total} are expected",
+                self.blocks_written
+            ),
+            BlockCountPolicy::Truncate => (),
+            BlockCountPolicy::PadWithZeros => {
+                for _ in self.blocks_written..u64::from(total) {
+                    self.decode_block(&Block::Fill([0; 4]))?;
+                }
+                self.blocks_written = u64::from(total);
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves the raw image incomplete if this decoder is dropped without
+    /// being closed, instead of writing out the blocks it still holds.
+    ///
+    /// See `Writer::set_abort_on_drop`.
+    pub fn set_abort_on_drop(&mut self, abort: bool) {
+        self.abort_on_drop = abort;
+    }
+
+    /// Panics in debug builds if this decoder is dropped before the image
+    /// has been finished, see `Writer::set_must_close`.
+    pub fn set_must_close(&mut self, must: bool) {
+        self.must_close = must;
+    }
+
+    /// Checks whether the image has been finished with `finish`, so no
+    /// more blocks can be written.
+    ///
+    /// The image is only complete if finishing succeeded.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Finishes writing the raw image and flushes any buffered data,
+    /// without giving up the decoder.
+    ///
+    /// Writing blocks afterward fails. `close` still returns the
+    /// destination.
+    pub fn finish(&mut self) -> Result<()> {
+        ensure!(!self.finished, "The image is already finished");
+        self.finish_image()
+    }
+
     /// Finishes writing the raw image and flushes any buffered data.
     ///
-    /// Consumes the reader as using it afterward would be invalid.
-    pub fn close(mut self) -> Result<()> {
-        self.finish()
+    /// Returns the destination, so it can be used further without reopening
+    /// it, e.g. to get its final length or sync it to disk.
+    ///
+    /// Consumes the decoder as using it afterward would be invalid.
+    pub fn close(mut self) -> Result<W> {
+        if !self.finished {
+            self.finish_image()?;
+        }
+        self.reflink = None;
+        let dst = self.dst.take().unwrap();
+        dst.into_inner().map_err(|err| err.into_error().into())
+    }
+
+    fn flush_clones(&mut self) -> Result<()> {
+        let range = match self.reflink.as_mut().and_then(|r| r.pending.take()) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let reflink = self.reflink.as_ref().unwrap();
+        let cloned = platform::clone_range(
+            &reflink.src,
+            range.src_off,
+            &reflink.dst,
+            range.dst_off,
+            range.len,
+        );
+
+        if cloned.is_ok() {
+            self.dst().seek(SeekFrom::Current(range.len as i64))?;
+            return Ok(());
+        }
+
+        // Cloning is not supported between these files, so copy this range
+        // and stop trying from here on.
+        let reflink = self.reflink.take().unwrap();
+        let mut buf = [0; Block::SIZE as usize];
+        for off in (0..range.len).step_by(buf.len()) {
+            platform::read_exact_at(&reflink.src, &mut buf, range.src_off + off)?;
+            self.dst().write_all(&buf)?;
+        }
+        Ok(())
     }
 
-    fn finish(&mut self) -> Result<()> {
+    /// Queues the raw block `buf` to be written with the next batch.
+    fn queue_raw(&mut self, buf: &BlockBuf) -> Result<()> {
+        if self.raw_batch_size <= 1 {
+            self.dst().write_all(buf)?;
+            return Ok(());
+        }
+
+        if self.raw_queued == self.raw_batch.len() {
+            self.raw_batch.push(BlockBuf::zeroed());
+        }
+        self.raw_batch[self.raw_queued].copy_from_slice(buf);
+        self.raw_queued += 1;
+
+        if self.raw_queued >= self.raw_batch_size {
+            self.flush_raw()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the queued raw blocks to the destination.
+    fn flush_raw(&mut self) -> Result<()> {
+        let mut slices: Vec<_> = self.raw_batch[..self.raw_queued]
+            .iter()
+            .map(|buf| IoSlice::new(buf))
+            .collect();
+        self.raw_queued = 0;
+
+        let dst = self.dst.as_mut().unwrap();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match dst.write_vectored(slices) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the destination, which is there until `close` takes it.
+    fn dst(&mut self) -> &mut BufWriter<W> {
+        self.dst.as_mut().unwrap()
+    }
+
+    fn finish_image(&mut self) -> Result<()> {
         assert!(!self.finished);
         self.finished = true;
 
-        let writer = self.dst.get_mut();
-        writer.flush()?;
+        self.pad_blocks()?;
+        self.flush_raw()?;
+        self.flush_clones()?;
+        self.dst().flush()?;
+        if let Some(sync) = self.sync {
+            sync(self.dst().get_ref()).context("Syncing raw image")?;
+        }
 
         Ok(())
     }
@@ -248,8 +1380,17 @@ impl<W: Write + Seek> Decoder<W> {
 
 impl<W: Write + Seek> Drop for Decoder<W> {
     fn drop(&mut self) {
-        if !self.finished {
-            self.finish().ok();
+        // Closed images are finished and have given up their destination.
+        if self.dst.is_none() {
+            return;
+        }
+        let unfinished = !self.finished;
+        if unfinished && !self.abort_on_drop {
+            self.finish_image().ok();
         }
+        debug_assert!(
+            !(unfinished && self.must_close) || thread::panicking(),
+            "Decoder dropped without being closed"
+        );
     }
 }
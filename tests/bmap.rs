@@ -0,0 +1,68 @@
+extern crate android_sparse as sparse;
+
+mod util;
+
+use self::util::{data, data_file};
+use sparse::{
+    bmap::{self, Bmap, BmapSource},
+    Block, BlockSource, Reader,
+};
+use std::io::Cursor;
+
+#[test]
+fn bmap_ranges() {
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let bmap = bmap::bmap(reader).unwrap();
+
+    assert_eq!(bmap.image_size, 5 * u64::from(Block::SIZE));
+    assert_eq!(bmap.blocks_count(), 5);
+    assert_eq!(bmap.mapped_blocks_count(), 3);
+
+    let ranges: Vec<_> = bmap.ranges.iter().map(|r| r.blocks.clone()).collect();
+    assert_eq!(ranges, [0..2, 4..5]);
+}
+
+#[test]
+fn bmap_xml() {
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let xml = bmap::bmap(reader).unwrap().to_xml();
+
+    assert!(xml.contains("<BmapFileChecksum> a2d0e51e31980f89d1cb0a180514ac9106031a087f8c286b072df940c80b4ac8 </BmapFileChecksum>"));
+    assert!(xml.contains(
+        "<Range chksum=\"25c547ebd5c630c570b965686b3db33400eb1a22a13c7d15cfe811c04425011a\"> 0-1 </Range>"
+    ));
+    assert!(xml.contains(
+        "<Range chksum=\"cb5bf41dcdd1d345d6c5ab400abcdae7c5cf6cd9532025945d8208ce8c3b4fb5\"> 4 </Range>"
+    ));
+}
+
+#[test]
+fn bmap_parse() {
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let bmap = bmap::bmap(reader).unwrap();
+    assert_eq!(Bmap::parse(&bmap.to_xml()).unwrap(), bmap);
+
+    let tampered = bmap.to_xml().replace("> 4 </Range>", "> 3-4 </Range>");
+    assert!(Bmap::parse(&tampered).is_err());
+}
+
+#[test]
+fn bmap_source() {
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let bmap = bmap::bmap(reader).unwrap();
+    let raw = data("decoded.img");
+
+    let blocks: Vec<_> = BmapSource::new(Cursor::new(&raw), bmap.clone())
+        .blocks()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let types: Vec<_> = blocks.iter().map(|b| matches!(b, Block::Raw(_))).collect();
+    assert_eq!(types, [true, true, false, false, true]);
+    assert_eq!(blocks[2], Block::Skip);
+
+    let mut corrupted = raw.clone();
+    corrupted[4 * Block::SIZE as usize] ^= 1;
+    let src = BmapSource::new(Cursor::new(corrupted), bmap);
+    let result: Result<Vec<_>, _> = src.blocks().collect();
+    assert!(result.is_err());
+}
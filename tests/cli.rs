@@ -4,7 +4,14 @@ mod util;
 
 use self::util::{data, data_path};
 use assert_cmd::prelude::*;
-use std::{fs, process::Command};
+use std::{
+    fs,
+    io::{prelude::*, BufReader},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
 
 #[test]
 fn img2simg() {
@@ -39,6 +46,23 @@ fn img2simg_crc() {
     assert_eq!(fs::read(&dst).unwrap(), data("crc.simg"));
 }
 
+#[test]
+fn img2simg_compressed() {
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    for name in ["hello.img.gz", "hello.img.xz", "hello.img.zst"] {
+        let dst = tmpdir.path().join(format!("{name}.simg"));
+        Command::cargo_bin("img2simg")
+            .unwrap()
+            .arg(data_path(name))
+            .arg(&dst)
+            .assert()
+            .success();
+
+        assert_eq!(fs::read(&dst).unwrap(), data("hello.simg"), "{name}");
+    }
+}
+
 #[test]
 fn simg2img() {
     let src = data_path("hello.simg");
@@ -55,6 +79,32 @@ fn simg2img() {
     assert_eq!(fs::read(&dst).unwrap(), data("decoded.img"));
 }
 
+#[test]
+fn simg2img_verbosity() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.img");
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg("--quiet")
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .assert()
+        .success()
+        .stderr("");
+
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["-v", "decode", "--force"])
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.lines().any(|line| line.starts_with("Info: Decoding ")));
+}
+
 #[test]
 fn simg2img_crc() {
     let src = data_path("crc.simg");
@@ -71,6 +121,25 @@ fn simg2img_crc() {
     assert_eq!(fs::read(&dst).unwrap(), data("decoded.img"));
 }
 
+#[test]
+fn simg2img_concatenated() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let src = tmpdir.path().join("hello.simg");
+    let dst = tmpdir.path().join("hello.img");
+    fs::write(&src, [data("crc.simg"), data("hello.simg")].concat()).unwrap();
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--concatenated", "--crc"])
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .success();
+
+    let decoded = data("decoded.img");
+    assert_eq!(fs::read(&dst).unwrap(), [&decoded[..], &decoded].concat());
+}
+
 #[test]
 fn simg2img_invalid_crc() {
     let src = data_path("invalid_crc.simg");
@@ -79,10 +148,1177 @@ fn simg2img_invalid_crc() {
 
     Command::cargo_bin("simg2img")
         .unwrap()
+        .env_remove("RUST_BACKTRACE")
+        .env_remove("RUST_LIB_BACKTRACE")
         .arg("--crc")
         .arg(&src)
         .arg(&dst)
         .assert()
         .failure()
         .stderr("Error: Checksum does not match\n");
+
+    // The partially decoded image must not be left behind.
+    assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn simg2img_keeps_output_on_failure() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.img");
+    fs::write(&dst, "previous").unwrap();
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--crc", "--force"])
+        .arg(data_path("invalid_crc.simg"))
+        .arg(&dst)
+        .assert()
+        .failure();
+
+    assert_eq!(fs::read(&dst).unwrap(), b"previous");
+    assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn simg2img_refuses_same_file() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let src = tmpdir.path().join("hello.simg");
+    let link = tmpdir.path().join("hello.img");
+    fs::copy(data_path("hello.simg"), &src).unwrap();
+    fs::hard_link(&src, &link).unwrap();
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg("--force")
+        .arg(&src)
+        .arg(&link)
+        .assert()
+        .failure();
+    assert_eq!(fs::read(&src).unwrap(), data("hello.simg"));
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--force", "--allow-same-file"])
+        .arg(&src)
+        .arg(&link)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&link).unwrap(), data("decoded.img"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn simg2img_interrupted() {
+    for (signal, code) in [("-INT", 130), ("-TERM", 143)] {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dst = tmpdir.path().join("hello.img");
+
+        let mut child = Command::cargo_bin("simg2img")
+            .unwrap()
+            .arg(&dst)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // Send only part of the image, so decoding waits for more.
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&data("hello.simg")[..64]).unwrap();
+
+        while fs::read_dir(tmpdir.path()).unwrap().count() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let status = Command::new("kill")
+            .args([signal, &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        assert_eq!(child.wait().unwrap().code(), Some(code));
+        assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+    }
+}
+
+fn http_post(addr: &str, path: &str, body: &[u8]) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let mut body = &response[split + 4..];
+
+    if !head.contains("Transfer-Encoding: chunked") {
+        return (head, body.to_vec());
+    }
+
+    let mut decoded = Vec::new();
+    loop {
+        let eol = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..eol]).unwrap(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        decoded.extend_from_slice(&body[eol + 2..eol + 2 + size]);
+        body = &body[eol + 4 + size..];
+    }
+    (head, decoded)
+}
+
+/// Kills the wrapped background process when dropped.
+struct Background(Child);
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+#[test]
+fn simg_serve() {
+    let mut server = Background(
+        Command::cargo_bin("simg_serve")
+            .unwrap()
+            .arg("--listen")
+            .arg("127.0.0.1:0")
+            // Requests are handled one after another.
+            .args(["--jobs", "1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+
+    let mut line = String::new();
+    BufReader::new(server.0.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line
+        .trim()
+        .strip_prefix("Listening on ")
+        .unwrap()
+        .to_string();
+
+    let (head, body) = http_post(&addr, "/encode", &data("hello.img"));
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, data("hello.simg"));
+
+    let (head, body) = http_post(&addr, "/encode?crc", &data("hello.img"));
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, data("crc.simg"));
+
+    let (head, body) = http_post(&addr, "/decode?crc", &data("crc.simg"));
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, data("decoded.img"));
+
+    let (head, _) = http_post(&addr, "/decode", &data("hello.img"));
+    assert!(head.starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+fn simg_lint() {
+    Command::cargo_bin("simg_lint")
+        .unwrap()
+        .args(["--profile", "fastboot"])
+        .arg(data_path("crc.simg"))
+        .assert()
+        .success();
+
+    let output = Command::cargo_bin("simg_lint")
+        .unwrap()
+        .args(["--max-chunks", "3"])
+        .arg(data_path("hello.simg"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("hello.simg: FAIL\n"));
+    assert!(stdout.contains("error: image has 4 chunks, more than the 3 of profile generic"));
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let profiles = tmpdir.path().join("profiles.toml");
+    fs::write(&profiles, "[board]\nrequire_crc = true\n").unwrap();
+    Command::cargo_bin("simg_lint")
+        .unwrap()
+        .args(["--profile", "board", "--profiles"])
+        .arg(&profiles)
+        .arg(data_path("hello.simg"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_convert() {
+    assert_cmd::Command::cargo_bin("simg")
+        .unwrap()
+        .arg("convert")
+        .write_stdin(data("hello.simg"))
+        .assert()
+        .success()
+        .stdout(data("decoded.img"));
+
+    assert_cmd::Command::cargo_bin("simg")
+        .unwrap()
+        .arg("convert")
+        .write_stdin(data("hello.img"))
+        .assert()
+        .success()
+        .stdout(data("hello.simg"));
+}
+
+#[test]
+fn simg_verify() {
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("verify")
+        .arg(data_path("crc.simg"))
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("verify")
+        .arg(data_path("invalid_crc.simg"))
+        .assert()
+        .failure();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["verify", "--jobs", "1"])
+        .arg(data_path("invalid_crc.simg"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_verify_chunk_crcs() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let image = tmpdir.path().join("hello.simg");
+    let crcs = tmpdir.path().join("hello.simg.crcs");
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["encode", "--chunk-crcs"])
+        .arg(data_path("hello.img"))
+        .arg(&image)
+        .assert()
+        .success();
+
+    let verify = |args: &[&str]| {
+        Command::cargo_bin("simg")
+            .unwrap()
+            .args(["verify", "--chunk-crcs"])
+            .arg(&crcs)
+            .args(args)
+            .arg(&image)
+            .assert()
+    };
+    verify(&["--sample", "2"]).success();
+
+    // Corrupt the raw data of the first chunk.
+    let mut data = fs::read(&image).unwrap();
+    data[28 + 12] ^= 1;
+    fs::write(&image, data).unwrap();
+    verify(&["--chunk", "0"]).failure();
+    verify(&["--chunk", "1"]).success();
+}
+
+#[test]
+fn simg_split_and_flash() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let prefix = tmpdir.path().join("hello.simg");
+    let device = tmpdir.path().join("device.img");
+    fs::write(&device, vec![0; 20480]).unwrap();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["split", "--size", "6000"])
+        .arg(data_path("hello.simg"))
+        .arg(&prefix)
+        .assert()
+        .success();
+
+    let parts = [
+        prefix.with_extension("simg.0"),
+        prefix.with_extension("simg.1"),
+    ];
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("flash")
+        .arg(&device)
+        .args(&parts)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(&device).unwrap(), data("decoded.img"));
+}
+
+#[test]
+fn simg_diff() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.simg");
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("diff")
+        .arg(data_path("hello.simg"))
+        .arg(data_path("crc.simg"))
+        .assert()
+        .success();
+
+    let mut raw = data("decoded.img");
+    raw[0x4001] = 0;
+    fs::write(tmpdir.path().join("hello.img"), raw).unwrap();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("encode")
+        .arg(tmpdir.path().join("hello.img"))
+        .arg(&dst)
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("diff")
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .assert()
+        .code(1)
+        .stdout("blocks 4..5 (bytes 0x4000..0x5000)\n");
+}
+
+#[test]
+fn simg_diff_patches() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = |name| tmpdir.path().join(name);
+
+    let mut raw = data("decoded.img");
+    raw[0x4001] = 0;
+    fs::write(path("new.img"), &raw).unwrap();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("encode")
+        .arg(path("new.img"))
+        .arg(path("new.simg"))
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("diff")
+        .arg("--forward")
+        .arg(path("forward.simg"))
+        .arg("--reverse")
+        .arg(path("reverse.simg"))
+        .arg(data_path("hello.simg"))
+        .arg(path("new.simg"))
+        .assert()
+        .success()
+        .stdout("1 blocks differ\n");
+
+    // Each patch turns one image into the other.
+    for (base, patch, expected) in [
+        (data_path("hello.simg"), "forward.simg", &raw),
+        (path("new.simg"), "reverse.simg", &data("decoded.img")),
+    ] {
+        Command::cargo_bin("simg")
+            .unwrap()
+            .args(["merge", "--force", "-o"])
+            .arg(path("out.simg"))
+            .arg(base)
+            .arg(path(patch))
+            .assert()
+            .success();
+        Command::cargo_bin("simg")
+            .unwrap()
+            .args(["decode", "--force"])
+            .arg(path("out.simg"))
+            .arg(path("out.img"))
+            .assert()
+            .success();
+        assert_eq!(&fs::read(path("out.img")).unwrap(), expected);
+    }
+}
+
+#[test]
+fn simg_splice() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = |name| tmpdir.path().join(name);
+    fs::write(path("serial"), "serial-1234").unwrap();
+    fs::copy(data_path("hello.simg"), path("hello.simg")).unwrap();
+
+    // The first block is raw data, the second a fill block.
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["splice", "--offset", "0x10"])
+        .arg("--data")
+        .arg(path("serial"))
+        .arg(path("hello.simg"))
+        .assert()
+        .success();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["splice", "--offset", "4096"])
+        .arg("--data")
+        .arg(path("serial"))
+        .arg("-o")
+        .arg(path("spliced.simg"))
+        .arg(path("hello.simg"))
+        .assert()
+        .success();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["splice", "--offset", "4096"])
+        .arg("--data")
+        .arg(path("serial"))
+        .arg(path("hello.simg"))
+        .assert()
+        .failure();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("decode")
+        .arg(path("spliced.simg"))
+        .arg(path("spliced.img"))
+        .assert()
+        .success();
+    let mut expected = data("decoded.img");
+    expected[0x10..0x1b].copy_from_slice(b"serial-1234");
+    expected[0x1000..0x100b].copy_from_slice(b"serial-1234");
+    assert_eq!(fs::read(path("spliced.img")).unwrap(), expected);
+
+    fs::write(path("edits"), "# edits\n0x20 \"abc\"\n0x1ffe 0102 0304\n").unwrap();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("splice")
+        .arg("--patch")
+        .arg(path("edits"))
+        .arg("-o")
+        .arg(path("patched.simg"))
+        .arg(path("spliced.simg"))
+        .assert()
+        .success();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["splice", "--offset", "0"])
+        .arg("--patch")
+        .arg(path("edits"))
+        .arg(path("spliced.simg"))
+        .assert()
+        .failure();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("decode")
+        .arg(path("patched.simg"))
+        .arg(path("patched.img"))
+        .assert()
+        .success();
+    expected[0x20..0x23].copy_from_slice(b"abc");
+    expected[0x1ffe..0x2002].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(fs::read(path("patched.img")).unwrap(), expected);
+}
+
+#[test]
+fn simg_probe() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .arg("probe")
+        .arg(tmpdir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains(": holes: "));
+    assert!(report.contains(", reflink: "));
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("probe")
+        .arg(tmpdir.path().join("missing"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_completions() {
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["completions", "bash"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.contains("encode) opts=\"-c --crc --no-crc -f --force"));
+}
+
+#[test]
+fn simg_config() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let config = tmpdir.path().join("config");
+    let dst = tmpdir.path().join("hello.simg");
+    fs::write(&config, "# defaults\ncrc = true\nbuffer_size = 64K\n").unwrap();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .env("SIMG_CONFIG", &config)
+        .arg("encode")
+        .arg(data_path("hello.img"))
+        .arg(&dst)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(&dst).unwrap(), data("crc.simg"));
+
+    // Switches take precedence over the config file.
+    Command::cargo_bin("simg")
+        .unwrap()
+        .env("SIMG_CONFIG", &config)
+        .args(["encode", "--no-crc", "--force"])
+        .arg(data_path("hello.img"))
+        .arg(&dst)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&dst).unwrap(), data("hello.simg"));
+
+    fs::write(&config, "crc = maybe\n").unwrap();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .env("SIMG_CONFIG", &config)
+        .args(["encode", "--force"])
+        .arg(data_path("hello.img"))
+        .arg(&dst)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn img2simg_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let src = tmpdir.path().join("raw");
+    let dst = tmpdir.path().join("sparse");
+    fs::create_dir_all(src.join("vendor")).unwrap();
+    fs::copy(data_path("hello.img"), src.join("system.img")).unwrap();
+    fs::copy(data_path("hello.img"), src.join("vendor").join("odm.img")).unwrap();
+    fs::write(src.join("notes.txt"), "not an image").unwrap();
+
+    let output = Command::cargo_bin("img2simg")
+        .unwrap()
+        .args(["--recursive", "--jobs", "2"])
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let summary = String::from_utf8(output.stdout).unwrap();
+    assert!(summary.contains("system.img"));
+    assert!(!summary.contains("notes.txt"));
+
+    assert_eq!(fs::read(dst.join("system.simg")).unwrap(), data("hello.simg"));
+    assert_eq!(
+        fs::read(dst.join("vendor").join("odm.simg")).unwrap(),
+        data("hello.simg")
+    );
+}
+
+#[test]
+fn simg2img_output_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let src = tmpdir.path().join("sparse");
+    let dst = tmpdir.path().join("raw");
+    fs::create_dir(&src).unwrap();
+    fs::copy(data_path("hello.simg"), src.join("system.simg")).unwrap();
+    fs::copy(data_path("crc.simg"), src.join("vendor.simg")).unwrap();
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--crc", "--jobs", "2", "--output-dir"])
+        .arg(&dst)
+        .arg(src.join("*.simg"))
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(dst.join("system.img")).unwrap(), data("decoded.img"));
+    assert_eq!(fs::read(dst.join("vendor.img")).unwrap(), data("decoded.img"));
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg("--output-dir")
+        .arg(&dst)
+        .arg(src.join("*.missing"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn img2simg_watch() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let src = tmpdir.path().join("raw");
+    let dst = tmpdir.path().join("sparse");
+    fs::create_dir(&src).unwrap();
+
+    let _watcher = Background(
+        Command::cargo_bin("img2simg")
+            .unwrap()
+            .arg("--watch")
+            .arg(&src)
+            .arg(&dst)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    fs::copy(data_path("hello.img"), src.join("system.img")).unwrap();
+
+    let output = dst.join("system.simg");
+    for _ in 0..100 {
+        if output.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(fs::read(&output).unwrap(), data("hello.simg"));
+}
+
+#[cfg(unix)]
+#[test]
+fn inherited_fds() {
+    assert_cmd::Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["/dev/fd/0", "/dev/fd/1"])
+        .write_stdin(data("hello.simg"))
+        .assert()
+        .success()
+        .stdout(data("decoded.img"));
+
+    assert_cmd::Command::cargo_bin("img2simg")
+        .unwrap()
+        .args(["/dev/fd/0", "/dev/fd/1"])
+        .write_stdin(data("hello.img"))
+        .assert()
+        .success()
+        .stdout(data("hello.simg"));
+
+    // Descriptors can also be given as options, replacing positional
+    // images.
+    assert_cmd::Command::cargo_bin("img2simg")
+        .unwrap()
+        .args(["--output-fd", "1"])
+        .arg(data_path("hello.img"))
+        .assert()
+        .success()
+        .stdout(data("hello.simg"));
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.img");
+    assert_cmd::Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--input-fd", "0"])
+        .arg(&dst)
+        .write_stdin(data("hello.simg"))
+        .assert()
+        .success();
+    assert_eq!(fs::read(&dst).unwrap(), data("decoded.img"));
+
+    assert_cmd::Command::cargo_bin("simg")
+        .unwrap()
+        .args(["decode", "--input-fd", "0", "--output-fd", "1"])
+        .write_stdin(data("hello.simg"))
+        .assert()
+        .success()
+        .stdout(data("decoded.img"));
+
+    assert_cmd::Command::cargo_bin("simg2img")
+        .unwrap()
+        .args(["--input-fd", "0"])
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_stats() {
+    Command::cargo_bin("simg_stats")
+        .unwrap()
+        .current_dir(data_path(""))
+        .args(["hello.simg", "crc.simg"])
+        .assert()
+        .success()
+        .stdout(
+            "image,raw_size,sparse_size,ratio,raw_chunks,fill_chunks,dontcare_chunks,crc32_chunks,\
+raw_blocks,fill_blocks,dontcare_blocks,fill_values,zero_fill_blocks,top_fill_value
+hello.simg,20480,8272,0.4039,2,1,1,0,2,1,2,1,0,0xaaaaaaaa
+crc.simg,20480,8288,0.4047,2,1,1,1,2,1,2,1,0,0xaaaaaaaa
+total,40960,16560,0.4043,4,2,2,1,4,2,4,1,0,0xaaaaaaaa
+",
+        );
+}
+
+#[test]
+fn simg_bench() {
+    let output = Command::cargo_bin("simg_bench")
+        .unwrap()
+        .args(["--size", "1M", "--crc", "--entropy", "0.5", "-n", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("raw image:      1.0 MiB"));
+    for path in ["encode", "write", "read", "decode"] {
+        assert!(report.contains(&format!("\n{path} ")), "{path}");
+    }
+
+    Command::cargo_bin("simg_bench")
+        .unwrap()
+        .args(["--fill-ratio", "0.6", "--hole-ratio", "0.6"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_carve() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let blob = tmpdir.path().join("dump.bin");
+
+    let mut content = vec![0x5a; 1000];
+    content.extend(data("hello.simg"));
+    content.extend([0x5a; 10]);
+    content.extend(data("crc.simg"));
+    fs::write(&blob, content).unwrap();
+
+    Command::cargo_bin("simg_carve")
+        .unwrap()
+        .arg("-o")
+        .arg(tmpdir.path())
+        .arg(&blob)
+        .assert()
+        .success();
+
+    let hello = tmpdir.path().join("dump-3e8.simg");
+    let crc = tmpdir.path().join(format!("dump-{:x}.simg", 1010 + 8272));
+    assert_eq!(fs::read(hello).unwrap(), data("hello.simg"));
+    assert_eq!(fs::read(crc).unwrap(), data("crc.simg"));
+}
+
+#[test]
+fn simg_containers() {
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    for (format, magic) in [
+        ("qcow2", &b"QFI\xfb"[..]),
+        ("vhd", b"conectix"),
+        ("vhdx", b"vhdxfile"),
+        ("vmdk", b"KDMV"),
+    ] {
+        let image = tmpdir.path().join(format!("hello.{format}"));
+        let sparse = tmpdir.path().join(format!("{format}.simg"));
+
+        Command::cargo_bin("simg")
+            .unwrap()
+            .arg(format)
+            .arg(data_path("hello.simg"))
+            .arg(&image)
+            .assert()
+            .success();
+        assert!(fs::read(&image).unwrap().starts_with(magic));
+
+        Command::cargo_bin("simg")
+            .unwrap()
+            .arg(format)
+            .arg(&image)
+            .arg(&sparse)
+            .assert()
+            .success();
+
+        Command::cargo_bin("simg")
+            .unwrap()
+            .arg("diff")
+            .arg(data_path("hello.simg"))
+            .arg(&sparse)
+            .assert()
+            .success();
+    }
+}
+
+#[test]
+fn simg_bmap() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.bmap");
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("bmap")
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .assert()
+        .success();
+
+    let xml = fs::read_to_string(&dst).unwrap();
+    assert!(xml.contains("<MappedBlocksCount> 3 </MappedBlocksCount>"));
+    assert!(xml.contains("> 0-1 </Range>"));
+    assert!(xml.contains("> 4 </Range>"));
+}
+
+#[test]
+fn simg_care_map() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = |name| tmpdir.path().join(name);
+    fs::write(
+        path("build.prop"),
+        "ro.system.build.fingerprint=test/hello:14/release-keys\n",
+    )
+    .unwrap();
+    let partition = format!("system={}", data_path("hello.simg").display());
+
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["care-map", "--text", "--image-size", "system=16K", "--build-prop"])
+        .arg(format!("system={}", path("build.prop").display()))
+        .arg("-o")
+        .arg(path("care_map.txt"))
+        .arg(&partition)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"system: 2 blocks, sha1 "));
+    assert_eq!(
+        fs::read_to_string(path("care_map.txt")).unwrap(),
+        "system\n2,0,2\nro.system.build.fingerprint\ntest/hello:14/release-keys\n"
+    );
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["care-map", "-o"])
+        .arg(path("care_map.pb"))
+        .arg(&partition)
+        .assert()
+        .success();
+    let pb = fs::read(path("care_map.pb")).unwrap();
+    assert_eq!(&pb[2..10], b"\x0a\x06system");
+    assert_eq!(&pb[10..21], b"\x12\x094,0,2,4,5");
+}
+
+#[test]
+fn simg_encode_bmap() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let bmap = tmpdir.path().join("decoded.img.bmap");
+    let dst = tmpdir.path().join("decoded.simg");
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("bmap")
+        .arg(data_path("hello.simg"))
+        .arg(&bmap)
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("encode")
+        .arg("--bmap")
+        .arg(&bmap)
+        .arg(data_path("decoded.img"))
+        .arg(&dst)
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("diff")
+        .arg(data_path("hello.simg"))
+        .arg(&dst)
+        .assert()
+        .success();
+}
+
+#[test]
+fn simg_dump_lint() {
+    Command::cargo_bin("simg_dump")
+        .unwrap()
+        .arg("--lint")
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success();
+
+    // Make the don't care chunk cover no blocks.
+    let tmpdir = tempfile::tempdir().unwrap();
+    let image = tmpdir.path().join("broken.simg");
+    let mut content = data("hello.simg");
+    content[0x1038 + 4..0x1038 + 8].copy_from_slice(&0u32.to_le_bytes());
+    fs::write(&image, content).unwrap();
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("dump")
+        .arg("--lint")
+        .arg(&image)
+        .assert()
+        .code(1)
+        .stdout(
+            "chunk 2 at 0x1038: data chunk covers no blocks\n\
+             file header at 0x0: announces 5 blocks, but chunks cover 3\n",
+        );
+}
+
+#[test]
+fn simg_dump_space() {
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("dump")
+        .arg("--space")
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success()
+        .stdout(
+            "without holes:  20.0 KiB\n\
+             with holes:     12.0 KiB (60.0% of raw)\n\
+             data ends at:   0x5000\n\
+             partition size: 1.0 MiB (aligned to 1 MiB)\n",
+        );
+}
+
+#[test]
+fn simg_dump_formats() {
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--format", "csv"])
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success()
+        .stdout(
+            "chunk,offset,type,blocks,raw_offset,size\n\
+             0,28,Raw,1,0,4108\n\
+             1,4136,Fill,1,4096,16\n\
+             2,4152,DontCare,2,8192,12\n\
+             3,4164,Raw,1,16384,4108\n",
+        );
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--format", "tsv"])
+        .arg(data_path("hello.simg"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output
+        .stdout
+        .starts_with(b"chunk\toffset\ttype\tblocks\traw_offset\tsize\n0\t28\tRaw\t1\t0\t4108\n"));
+}
+
+#[test]
+fn simg_dump_hexdump() {
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--dump-block", "1"])
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success()
+        .stdout(
+            "00001000  aa aa aa aa aa aa aa aa  aa aa aa aa aa aa aa aa  |................|\n\
+             *\n\
+             00002000\n",
+        );
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--dump-chunk", "2"])
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success()
+        .stdout(
+            "00002000  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|\n\
+             *\n\
+             00004000\n",
+        );
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--dump-block", "5"])
+        .arg(data_path("hello.simg"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn simg_dump_entropy() {
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["dump", "--entropy"])
+        .arg(data_path("hello.simg"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(
+        lines[1],
+        "      0           0x0  Raw              1     8.00     4.0 KiB  compressed"
+    );
+    assert_eq!(lines[2].trim_end(), "      1        0x1000  Fill             1     0.00         0 B");
+    assert!(stdout.contains("incompressible: 4.0 KiB (50.0% of data)"));
+}
+
+#[test]
+fn simg_strings_grep() {
+    let output = Command::cargo_bin("simg_strings")
+        .unwrap()
+        .arg(data_path("hello.simg"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 16);
+    assert!(stdout.starts_with("0x20  !\"#$%&"));
+
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["grep", "-o", "-i", "xyz"])
+        .arg(data_path("hello.simg"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"0x58 XYZ\n0x78 xyz\n0x158 XYZ\n"));
+
+    Command::cargo_bin("simg_grep")
+        .unwrap()
+        .arg("no such string")
+        .arg(data_path("hello.simg"))
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn simg_anonymize() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = |name| tmpdir.path().join(name);
+    fs::write(
+        path("profiles.toml"),
+        "[xyz]\npattern = '(?i)xyz'\nblocks = \"1..2\" # fill\n",
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .args(["anonymize", "--list-profiles", "--profiles"])
+        .arg(path("profiles.toml"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "identifiers: 0 ranges, 3 patterns\nxyz: 1 range, 1 pattern\n"
+    );
+
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["anonymize", "-p", "identifiers", "-p", "xyz", "--profiles"])
+        .arg(path("profiles.toml"))
+        .arg("-o")
+        .arg(path("anonymized.simg"))
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success();
+    Command::cargo_bin("simg")
+        .unwrap()
+        .args(["grep", "-i", "xyz"])
+        .arg(path("anonymized.simg"))
+        .assert()
+        .code(1);
+
+    let output = Command::cargo_bin("simg")
+        .unwrap()
+        .arg("dump")
+        .arg(path("anonymized.simg"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains(" Fill "), "{stdout}");
+}
+
+#[test]
+fn simg_dump_dd_script() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = |name| tmpdir.path().join(name);
+    Command::cargo_bin("simg")
+        .unwrap()
+        .arg("dump")
+        .arg("--dd-script")
+        .arg(path("decode.sh"))
+        .arg(data_path("hello.simg"))
+        .assert()
+        .success();
+
+    Command::new("sh")
+        .arg(path("decode.sh"))
+        .arg(data_path("hello.simg"))
+        .arg(path("hello.img"))
+        .assert()
+        .success();
+    assert_eq!(fs::read(path("hello.img")).unwrap(), data("decoded.img"));
+}
+
+#[test]
+fn simg_dump_compare() {
+    // Change a byte of the first raw chunk's payload.
+    let tmpdir = tempfile::tempdir().unwrap();
+    let image = tmpdir.path().join("changed.simg");
+    let mut content = data("hello.simg");
+    content[0x28] ^= 1;
+    fs::write(&image, content).unwrap();
+
+    Command::cargo_bin("simg_dump")
+        .unwrap()
+        .arg("--compare")
+        .arg(&image)
+        .arg(data_path("hello.simg"))
+        .assert()
+        .code(1)
+        .stdout(
+            "                         old           new\n\
+             total blocks:              5             5\n\
+             total chunks:              4             4\n\
+             image checksum:   0x00000000    0x00000000\n\
+             chunk layouts are identical\n\
+             first differing chunk 0 (payload differs)\n  \
+             old: Raw chunk of 1 blocks at 0x1c, raw offset 0x0\n  \
+             new: Raw chunk of 1 blocks at 0x1c, raw offset 0x0\n",
+        );
+}
+
+#[test]
+fn simg2img_url() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hello.simg", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let content = data("hello.simg");
+        let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", content.len());
+        stream.write_all(header.as_bytes()).unwrap();
+        stream.write_all(&content).unwrap();
+    });
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dst = tmpdir.path().join("hello.img");
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg(&url)
+        .arg(&dst)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&dst).unwrap(), data("decoded.img"));
 }
@@ -0,0 +1,47 @@
+extern crate android_sparse as sparse;
+
+mod util;
+
+use self::util::data;
+use sparse::corpus;
+use std::path::PathBuf;
+
+fn corpus_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("corpus")
+}
+
+#[test]
+fn corpus_samples_are_accepted() {
+    let samples = corpus::load_dir(corpus_dir()).unwrap();
+    assert!(samples.len() >= 5);
+
+    for sample in &samples {
+        if let Err(err) = sample.check() {
+            panic!("{}: {err:#}", sample.name);
+        }
+    }
+}
+
+#[test]
+fn corpus_truncated_sample() {
+    let sample = corpus::Sample::load(corpus_dir().join("truncated-head.head")).unwrap();
+    assert!(!sample.is_complete());
+    assert_eq!(sample.chunks.len(), 4);
+    assert_eq!(sample.raw_size(), 4 << 30);
+}
+
+#[test]
+fn corpus_capture_matches_image() {
+    let image = data("hello.simg");
+    let mut captured = Vec::new();
+    corpus::capture(&image[..], &mut captured, None).unwrap();
+
+    let sample = corpus::Sample::read_from("hello", &captured[..]).unwrap();
+    assert_eq!(
+        sample,
+        corpus::Sample::load(corpus_dir().join("hello.head")).unwrap()
+    );
+    assert_eq!(sample.sparse_size(), image.len() as u64);
+}
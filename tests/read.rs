@@ -2,8 +2,15 @@ extern crate android_sparse as sparse;
 
 mod util;
 
-use self::util::{data_file, test_blocks};
-use sparse::{Block, Encoder, Reader};
+use self::util::{data, data_file, test_blocks};
+use sparse::{
+    read::{self, Prescan},
+    testutil, Block, BlockSource, CrcPolicy, Encoder, IterSource, Reader, Writer,
+};
+use std::{
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
 
 #[test]
 fn read_sparse() {
@@ -42,6 +49,192 @@ fn read_sparse_with_invalid_crc() {
     assert!(reader.nth(5).unwrap().is_err());
 }
 
+#[test]
+fn read_raw_batches() {
+    let blocks = testutil::BlockGen::new(3).blocks(200);
+    let sparse = testutil::write_sparse(&blocks, false).unwrap();
+    // Cut the image in the middle of a block.
+    let truncated = &sparse[..sparse.len() - 5000];
+
+    let read = |image: &[u8], batch_size| {
+        let mut reader = Reader::new(image, false)
+            .unwrap()
+            .raw_batch_size(batch_size);
+        let mut result = Vec::new();
+        while let Some(block) = reader.next() {
+            result.push((block.ok(), reader.offset()));
+        }
+        result
+    };
+
+    let expected = read(&sparse, 1);
+    let expected_truncated = read(truncated, 1);
+    let read_blocks: Vec<_> = expected.iter().map(|(b, _)| b.clone().unwrap()).collect();
+    assert_eq!(read_blocks, blocks);
+    assert!(expected_truncated.last().unwrap().0.is_none());
+
+    for batch_size in [0, 3, 64] {
+        assert_eq!(read(&sparse, batch_size), expected);
+        assert_eq!(read(truncated, batch_size), expected_truncated);
+    }
+}
+
+#[test]
+fn read_truncated_error() {
+    let image = data("hello.simg");
+    let reader = Reader::new(&image[..image.len() - 100], false).unwrap();
+    let err = reader.last().unwrap().unwrap_err();
+
+    assert_eq!(err.offset(), Some(0x1050));
+    assert_eq!(
+        format!("{err:#}"),
+        "Reading raw chunk 3 payload at offset 0x1050: unexpected end of file"
+    );
+}
+
+#[test]
+fn read_skip_to_next_chunk() {
+    let mut image = data("hello.simg");
+    // Corrupt the magic of the fill chunk following the first raw chunk.
+    image[28 + 12 + 4096] = 0;
+
+    let mut reader = Reader::new(&image[..], true).unwrap();
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_err());
+    assert_eq!(reader.skip_to_next_chunk().unwrap(), 14);
+
+    let blocks: Vec<_> = reader.map(|r| r.unwrap()).collect();
+    assert_eq!(blocks, test_blocks()[2..]);
+}
+
+#[test]
+fn read_prescan() {
+    let image = data("crc.simg");
+    let mut reader = Reader::new(Cursor::new(&image), true).unwrap();
+    let chunks: Vec<_> = sparse::dump::Chunks::new(&image[..])
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        reader.prescan().unwrap(),
+        Prescan {
+            chunks,
+            raw_blocks: 2,
+            fill_blocks: 1,
+            skip_blocks: 2,
+            checksum: true,
+            metadata: false,
+            sparse_size: image.len() as u64,
+        }
+    );
+    assert_eq!(reader.by_ref().count(), 6);
+    assert!(reader.prescan().is_err());
+
+    let truncated = &image[..image.len() - 4];
+    let mut reader = Reader::new(Cursor::new(truncated), true).unwrap();
+    let err = reader.prescan().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Chunk 4 at offset 0x2050 extends beyond the end of the image"
+    );
+
+    // Announce one block more than the chunks cover.
+    let mut corrupt = image.clone();
+    corrupt[16] += 1;
+    let mut reader = Reader::new(Cursor::new(corrupt), true).unwrap();
+    assert!(reader.prescan().is_err());
+}
+
+#[test]
+fn read_progress() {
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&progress);
+    let reader = Reader::new(data_file("crc.simg"), true)
+        .unwrap()
+        .on_progress(move |p| recorded.lock().unwrap().push(p.clone()));
+    let blocks = reader.count();
+
+    let chunks: Vec<_> = sparse::dump::Chunks::new(data_file("crc.simg"))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let progress = progress.lock().unwrap();
+    assert_eq!(progress.len(), blocks);
+
+    let mut expected = chunks.iter().enumerate().flat_map(|(index, chunk)| {
+        let blocks = chunk.header.chunk_size.max(1);
+        (0..blocks).map(move |block| (chunk, index as u32, block))
+    });
+    for p in progress.iter() {
+        let (chunk, index, block) = expected.next().unwrap();
+        assert_eq!(&p.chunk, chunk);
+        assert_eq!((p.chunk_index, p.block_in_chunk), (index, block));
+        assert_eq!(p.total_chunks, chunks.len() as u32);
+        assert_eq!(p.block_index(), chunk.start_block + u64::from(block));
+    }
+    assert!(expected.next().is_none());
+    assert_eq!(progress[2].to_string(), "DontCare chunk 3/5 (8.0 KiB)");
+}
+
+#[test]
+fn read_current_crc() {
+    let file = data_file("crc.simg");
+
+    let mut reader = Reader::new(file, true).unwrap();
+    assert_eq!(reader.current_crc(), Some(0));
+    reader.nth(4).unwrap().unwrap();
+    assert_eq!(reader.current_crc(), Some(0xffb880a5));
+    reader.next().unwrap().unwrap();
+    assert_eq!(reader.current_crc(), Some(0xffb880a5));
+
+    let mut writer = Writer::new(tempfile::tempfile().unwrap(), true).unwrap();
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    assert_eq!(writer.current_crc(), Some(0xffb880a5));
+}
+
+#[test]
+fn read_block_count_policy() {
+    use sparse::BlockCountPolicy;
+
+    let blocks = test_blocks();
+    let total = blocks.len() as u32;
+    let with_total_blocks = |total: u32| {
+        let mut image = data("hello.simg");
+        image[16..20].copy_from_slice(&total.to_le_bytes());
+        image
+    };
+    let read = |image: &[u8], policy| {
+        Reader::new(image, false)
+            .unwrap()
+            .block_count_policy(policy)
+            .collect::<sparse::Result<Vec<_>>>()
+    };
+
+    // The header announces more blocks than the chunks cover.
+    let missing = with_total_blocks(total + 2);
+    assert!(read(&missing, BlockCountPolicy::Error).is_err());
+    assert_eq!(read(&missing, BlockCountPolicy::Truncate).unwrap(), blocks);
+    let padded = read(&missing, BlockCountPolicy::PadWithZeros).unwrap();
+    assert_eq!(padded[..blocks.len()], blocks);
+    assert_eq!(
+        padded[blocks.len()..],
+        [Block::Fill([0; 4]), Block::Fill([0; 4])]
+    );
+
+    // The chunks cover more blocks than the header announces.
+    let excess = with_total_blocks(total - 1);
+    assert!(read(&excess, BlockCountPolicy::Error).is_err());
+    for policy in [BlockCountPolicy::Truncate, BlockCountPolicy::PadWithZeros] {
+        assert_eq!(read(&excess, policy).unwrap(), blocks[..blocks.len() - 1]);
+    }
+    assert_eq!(
+        read(&data("hello.simg"), BlockCountPolicy::Error).unwrap(),
+        blocks
+    );
+}
+
 #[test]
 fn encode_raw() {
     let file = data_file("hello.img");
@@ -55,3 +248,217 @@ fn encode_raw() {
         assert_eq!(blk, exp);
     }
 }
+
+#[test]
+fn encode_stats() {
+    let expected = test_blocks();
+    let count = |kind: fn(&Block) -> bool| expected.iter().filter(|b| kind(b)).count() as u64;
+
+    let mut encoder = Encoder::new(data_file("hello.img")).unwrap();
+    for block in encoder.by_ref() {
+        block.unwrap();
+    }
+    let stats = encoder.stats();
+    assert_eq!(stats.raw, count(|b| matches!(b, Block::Raw(_))));
+    assert_eq!(stats.fill, count(|b| matches!(b, Block::Fill(_))));
+    assert_eq!(stats.skip, count(|b| matches!(b, Block::Skip)));
+    assert_eq!(stats.blocks(), expected.len() as u64);
+    // Classification is only timed on request.
+    assert!(stats.classify_time.is_zero());
+}
+
+#[test]
+fn encode_from_iter() {
+    let raw = data("hello.img");
+    let expected = test_blocks();
+
+    let source = IterSource::new(raw.chunks(1000));
+    let blocks: Vec<_> = source.blocks().map(|r| r.unwrap()).collect();
+    assert_eq!(blocks.len(), expected.len());
+
+    for (blk, exp) in blocks.iter().zip(expected.iter()) {
+        assert_eq!(blk, exp);
+    }
+}
+
+#[test]
+fn encode_with_classifier() {
+    use sparse::classify::{BlockClassifier, BlockKind, DefaultClassifier};
+
+    let mut expected = test_blocks();
+    expected[2] = Block::Raw([0; Block::SIZE as usize].into());
+
+    let classify_raw_at_2 = |index: u64, data: &[u8; Block::SIZE as usize]| match index {
+        2 => BlockKind::Raw,
+        _ => DefaultClassifier.classify(index, data),
+    };
+    let encoder = Encoder::new(data_file("hello.img"))
+        .unwrap()
+        .classifier(classify_raw_at_2);
+    let blocks: Vec<_> = encoder.map(|r| r.unwrap()).collect();
+    assert_eq!(blocks, expected);
+}
+
+#[test]
+fn encode_dontcare_fill_values() {
+    use sparse::EncoderOptions;
+
+    let mut expected = test_blocks();
+    expected[1] = Block::Skip;
+
+    let options = EncoderOptions::new().dontcare_fill_values(&[[0xaa; 4]]);
+    let encoder = Encoder::with_options(data_file("hello.img"), options).unwrap();
+    let blocks: Vec<_> = encoder.map(|r| r.unwrap()).collect();
+    assert_eq!(blocks, expected);
+}
+
+#[test]
+fn encode_min_chunk_blocks() {
+    use sparse::EncoderOptions;
+
+    // The gap between the raw blocks is three blocks long.
+    let options = EncoderOptions::new().min_chunk_blocks(3);
+    let encoder = Encoder::with_options(data_file("hello.img"), options).unwrap();
+    let blocks: Vec<_> = encoder.map(|r| r.unwrap()).collect();
+    assert_eq!(blocks, test_blocks());
+
+    let options = EncoderOptions::new().min_chunk_blocks(4);
+    let encoder = Encoder::with_options(data_file("hello.img"), options).unwrap();
+    let blocks: Vec<_> = encoder.map(|r| r.unwrap()).collect();
+    assert_eq!(blocks.len(), 5);
+    assert!(blocks.iter().all(|b| matches!(b, Block::Raw(_))));
+    assert_eq!(blocks[1], Block::Raw([0xaa; Block::SIZE as usize].into()));
+    assert_eq!(blocks[2], Block::Raw([0; Block::SIZE as usize].into()));
+}
+
+#[test]
+fn read_empty_image() {
+    let encoder = Encoder::new(&[][..]).unwrap();
+    let blocks: Vec<_> = encoder.map(|r| r.unwrap()).collect();
+    assert!(blocks.is_empty());
+
+    for crc in [false, true] {
+        let mut sparse = Cursor::new(Vec::new());
+        let writer = Writer::new(&mut sparse, crc).unwrap();
+        assert!(writer.is_empty());
+        writer.close().unwrap();
+
+        let reader = Reader::new(&sparse.get_ref()[..], crc).unwrap();
+        assert!(reader.is_empty());
+        let blocks: Vec<_> = reader.map(|r| r.unwrap()).collect();
+        let expected = match crc {
+            true => vec![Block::Crc32(0)],
+            false => vec![],
+        };
+        assert_eq!(blocks, expected);
+    }
+}
+
+#[test]
+fn read_concatenated() {
+    let first = testutil::BlockGen::new(1).blocks(20);
+    let second = testutil::BlockGen::new(2).blocks(30);
+    let mut stream = testutil::write_sparse(&first, true).unwrap();
+    stream.extend(testutil::write_sparse(&[], false).unwrap());
+    stream.extend(testutil::write_sparse(&second, true).unwrap());
+    // Trailing padding that isn't an image is ignored.
+    stream.extend([0; 512]);
+
+    let blocks: Vec<_> = Reader::new(&stream[..], true)
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|b| !matches!(b, Block::Crc32(_)))
+        .collect();
+    assert_eq!(blocks, first);
+
+    let mut reader = Reader::new(&stream[..], true).unwrap().concatenated(true);
+    let blocks: Vec<_> = (&mut reader)
+        .map(Result::unwrap)
+        .filter(|b| !matches!(b, Block::Crc32(_)))
+        .collect();
+    assert_eq!(blocks, [first, second].concat());
+    assert_eq!(reader.size, 50 * u64::from(Block::SIZE));
+
+    // A truncated header of the next image is an error.
+    let stream = [&data("hello.simg")[..], &data("hello.simg")[..8]].concat();
+    let reader = Reader::new(&stream[..], false).unwrap().concatenated(true);
+    assert!(reader.last().unwrap().is_err());
+}
+
+#[test]
+fn read_crc_policy() {
+    let blocks = [Block::Fill([1; 4]), Block::Skip, Block::Fill([2; 4])];
+    let write = |policy, max_chunks| {
+        let mut sparse = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut sparse, true)
+            .unwrap()
+            .crc_policy(policy)
+            .max_chunks(max_chunks);
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+        sparse.into_inner()
+    };
+    let read = |sparse: &[u8], policy| -> sparse::Result<Vec<Block>> {
+        Reader::new(sparse, true)?.crc_policy(policy).collect()
+    };
+
+    let sparse = write(CrcPolicy::ExcludeSkipped, 4);
+    let read_blocks = read(&sparse, CrcPolicy::ExcludeSkipped).unwrap();
+    let data = [[1; 4]; 1024].concat().into_iter().chain([2; 4096]);
+    let crc = crc32fast::hash(&data.collect::<Vec<_>>());
+    assert_eq!(read_blocks.last(), Some(&Block::Crc32(crc)));
+    assert!(read(&sparse, CrcPolicy::IncludeSkipped).is_err());
+    let included = write(CrcPolicy::IncludeSkipped, 4);
+    assert!(read(&included, CrcPolicy::IncludeSkipped).is_ok());
+    assert!(read(&included, CrcPolicy::ExcludeSkipped).is_err());
+
+    // Skipped blocks stored as raw data once the chunk limit is reached
+    // always contribute.
+    let coalesced = write(CrcPolicy::ExcludeSkipped, 2);
+    let read_blocks = read(&coalesced, CrcPolicy::IncludeSkipped).unwrap();
+    assert_eq!(read_blocks.len(), 4);
+}
+
+#[test]
+fn read_memory_limited() {
+    let spec = sparse::gen::ImageSpec {
+        size: 4 << 20,
+        seed: 5,
+        ..Default::default()
+    };
+    let sparse = spec.sparse_image(true).unwrap();
+    let expected: Vec<_> = Reader::new(&sparse[..], true)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+
+    for limit in [read::MIN_MEMORY_LIMIT, 64 << 10] {
+        let blocks: Vec<_> = Reader::with_memory_limit(limit, &sparse[..], true)
+            .unwrap()
+            .raw_batch_size(1024)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(blocks, expected);
+    }
+    assert!(Reader::with_memory_limit(4096, &sparse[..], true).is_err());
+
+    // Metadata must leave room for a block of raw data.
+    let mut metadata = sparse::metadata::Metadata::new();
+    metadata.insert("build_id", "AP1A").unwrap();
+    let mut with_metadata = Cursor::new(Vec::new());
+    let mut writer = Writer::new(&mut with_metadata, false).unwrap();
+    writer.write_metadata(&metadata).unwrap();
+    writer.write_block(&Block::Skip).unwrap();
+    writer.close().unwrap();
+    let with_metadata = with_metadata.into_inner();
+
+    let read = |limit| {
+        Reader::with_memory_limit(limit, &with_metadata[..], false)
+            .unwrap()
+            .collect::<sparse::Result<Vec<_>>>()
+    };
+    assert!(read(read::MIN_MEMORY_LIMIT).is_err());
+    assert_eq!(read(read::MIN_MEMORY_LIMIT + 4096).unwrap(), [Block::Skip]);
+}
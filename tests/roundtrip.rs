@@ -0,0 +1,17 @@
+extern crate android_sparse as sparse;
+
+use sparse::testutil::{self, BlockGen};
+
+#[test]
+fn roundtrip_random_blocks() {
+    for seed in 0..32 {
+        let blocks = BlockGen::new(seed).blocks(1 + seed as usize * 3);
+        testutil::assert_roundtrip(&blocks);
+    }
+}
+
+#[test]
+fn block_gen_is_reproducible() {
+    assert_eq!(BlockGen::new(7).blocks(50), BlockGen::new(7).blocks(50));
+    assert_ne!(BlockGen::new(7).blocks(50), BlockGen::new(8).blocks(50));
+}
@@ -0,0 +1,76 @@
+extern crate android_sparse as sparse;
+
+mod util;
+
+use self::util::{data, data_file, data_path};
+use assert_cmd::prelude::*;
+use sparse::{
+    sign::{self, SigningKey},
+    Encoder, Reader,
+};
+use std::{fs, process::Command};
+
+const SECRET_KEY: [u8; 32] = [7; 32];
+
+#[test]
+fn sign_and_verify() {
+    let key = SigningKey::from_bytes(&SECRET_KEY);
+
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let signature = sign::sign(reader, &key).unwrap();
+
+    // The signature covers the decoded content, so it is independent of
+    // the encoding.
+    let reader = Reader::new(data_file("crc.simg"), false).unwrap();
+    sign::verify(reader, &key.verifying_key(), &signature).unwrap();
+
+    let encoder = Encoder::new(data_file("hello.img")).unwrap();
+    sign::verify(encoder, &key.verifying_key(), &signature).unwrap();
+
+    let mut tampered = data("decoded.img");
+    tampered[0] ^= 1;
+    let encoder = Encoder::new(&tampered[..]).unwrap();
+    assert!(sign::verify(encoder, &key.verifying_key(), &signature).is_err());
+}
+
+#[test]
+fn cli_sign_and_verify() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let secret = tmpdir.path().join("key");
+    let public = tmpdir.path().join("key.pub");
+    let key = SigningKey::from_bytes(&SECRET_KEY);
+    fs::write(&secret, key.to_bytes()).unwrap();
+    fs::write(&public, key.verifying_key().to_bytes()).unwrap();
+
+    let simg = tmpdir.path().join("hello.simg");
+    Command::cargo_bin("img2simg")
+        .unwrap()
+        .arg("--sign")
+        .arg(&secret)
+        .arg(data_path("hello.img"))
+        .arg(&simg)
+        .assert()
+        .success();
+    assert!(tmpdir.path().join("hello.simg.sig").exists());
+
+    let img = tmpdir.path().join("hello.img");
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg("--verify")
+        .arg(&public)
+        .arg(&simg)
+        .arg(&img)
+        .assert()
+        .success();
+
+    Command::cargo_bin("simg2img")
+        .unwrap()
+        .arg("--verify")
+        .arg(&public)
+        .arg("--signature")
+        .arg(tmpdir.path().join("hello.simg.sig"))
+        .arg(data_path("hello.simg"))
+        .arg(tmpdir.path().join("other.img"))
+        .assert()
+        .success();
+}
@@ -0,0 +1,55 @@
+extern crate android_sparse as sparse;
+
+mod util;
+
+use crate::util::{data_path, test_blocks};
+use sparse::{
+    dump::Chunks,
+    io::{ClonePerThread, PositionedFile},
+    Decoder, Encoder, Reader, Writer,
+};
+use std::{fs::File, sync::Arc, thread};
+
+fn assert_send<T: Send>() {}
+fn assert_sync<T: Sync>() {}
+
+#[test]
+fn send_and_sync() {
+    assert_send::<Reader<File>>();
+    assert_send::<Encoder<File>>();
+    assert_send::<Writer<File>>();
+    assert_send::<Decoder<File>>();
+    assert_send::<Chunks<File>>();
+    assert_send::<ClonePerThread>();
+
+    assert_sync::<Reader<PositionedFile>>();
+    assert_sync::<ClonePerThread>();
+}
+
+#[test]
+fn read_per_thread() {
+    let path = data_path("hello.simg");
+    let sources = [
+        ClonePerThread::Reopen(path.clone()),
+        ClonePerThread::Dup(Arc::new(File::open(&path).unwrap())),
+    ];
+
+    for source in sources {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let source = source.clone();
+                thread::spawn(move || {
+                    let file = source.open_positioned().unwrap();
+                    Reader::new(file, false)
+                        .unwrap()
+                        .collect::<sparse::Result<Vec<_>>>()
+                        .unwrap()
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), test_blocks());
+        }
+    }
+}
@@ -26,17 +26,17 @@ pub fn data(name: &str) -> Vec<u8> {
 
 pub fn test_blocks() -> Vec<Block> {
     let mut raw1 = [0; Block::SIZE as usize];
-    for i in 0..raw1.len() {
-        raw1[i] = i as u8;
+    for (i, b) in raw1.iter_mut().enumerate() {
+        *b = i as u8;
     }
     let mut raw2 = [0; Block::SIZE as usize];
     raw2[1] = 0x66;
 
     vec![
-        Block::Raw(Box::new(raw1)),
+        Block::Raw(raw1.into()),
         Block::Fill([0xaa; 4]),
         Block::Skip,
         Block::Skip,
-        Block::Raw(Box::new(raw2)),
+        Block::Raw(raw2.into()),
     ]
 }
@@ -0,0 +1,37 @@
+extern crate android_sparse as sparse;
+
+mod util;
+
+use self::util::data_file;
+use sparse::{verity, Block, IterSource, Reader};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn hash_tree_single_level() {
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let tree = verity::hash_tree(reader, b"salt").unwrap();
+
+    assert_eq!(tree.image_size, 5 * u64::from(Block::SIZE));
+    assert_eq!(tree.tree.len(), 4096);
+    assert_eq!(
+        hex(&tree.root_hash),
+        "cb2f1af43073b9a5b7a352533d1d6e5cbf5d0525e445c470ef3b649eb9b97330"
+    );
+}
+
+#[test]
+fn hash_tree_multi_level() {
+    let raw: Vec<_> = (0..300u32)
+        .map(|i| vec![(i % 251) as u8; Block::SIZE as usize])
+        .collect();
+    let tree = verity::hash_tree(IterSource::new(raw), b"").unwrap();
+
+    assert_eq!(tree.tree.len(), 16384);
+    assert_eq!(
+        hex(&tree.root_hash),
+        "edc24cd4615abcd726845d41c1e48a99aeb5837da57ffd6c74dce3919d1d6447"
+    );
+}
@@ -2,11 +2,17 @@ extern crate android_sparse as sparse;
 
 mod util;
 
-use crate::util::{data, test_blocks};
-use sparse::{Decoder, Writer};
+use crate::util::{data, data_file, test_blocks};
+use sparse::{
+    adapter::{CipherSink, CipherSource, Rechunk},
+    metadata::Metadata,
+    testutil, Block, BlockSink, BlockSource, Decoder, Encoder, Reader, Writer,
+};
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{prelude::*, SeekFrom},
+    ops::Bound,
+    sync::{Arc, Mutex},
 };
 
 fn read_from_start(file: &mut File) -> Vec<u8> {
@@ -46,6 +52,186 @@ fn write_sparse_crc() {
     assert_eq!(read_from_start(&mut tmpfile), data("crc.simg"));
 }
 
+#[test]
+fn write_crc_in_header() {
+    use sparse::CrcPlacement;
+
+    let blocks = test_blocks();
+    let write = |placement| {
+        let mut writer = Writer::new(tempfile::tempfile().unwrap(), true)
+            .unwrap()
+            .crc_placement(placement);
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        read_from_start(&mut writer.close().unwrap())
+    };
+    let crc = data("crc.simg");
+    let checksum = &crc[crc.len() - 4..];
+
+    // The checksum chunk is left out, and the header holds the checksum.
+    let header = write(CrcPlacement::Header);
+    assert_eq!(header[24..28], *checksum);
+    assert_eq!(header[..24], data("hello.simg")[..24]);
+    assert_eq!(header[28..], data("hello.simg")[28..]);
+    let both = write(CrcPlacement::Both);
+    assert_eq!(both[24..28], *checksum);
+    assert_eq!(both[..24], crc[..24]);
+    assert_eq!(both[28..], crc[28..]);
+
+    // Readers verify the header checksum.
+    let read = |image: &[u8]| {
+        Reader::new(image, true)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+    };
+    assert!(read(&header).is_ok());
+    let mut corrupted = header.clone();
+    corrupted[24] ^= 1;
+    assert!(read(&corrupted).is_err());
+}
+
+#[test]
+fn write_crc_interval() {
+    use sparse::{checksum, write::ChecksumMismatch, CrcPolicy};
+
+    let blocks = test_blocks();
+    let mut writer = Writer::new(tempfile::tempfile().unwrap(), false)
+        .unwrap()
+        .crc_interval(2);
+    for block in &blocks {
+        writer.write_block(block).unwrap();
+    }
+    let expected = writer.current_crc().unwrap();
+    let err = writer.write_block(&Block::Crc32(!expected)).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ChecksumMismatch>(),
+        Some(&ChecksumMismatch {
+            expected,
+            found: !expected
+        })
+    );
+    writer.write_block(&Block::Crc32(expected)).unwrap();
+    let mut file = writer.close().unwrap();
+
+    // Readers verify every checksum chunk.
+    let image = read_from_start(&mut file);
+    let read = |image: &[u8]| -> sparse::Result<Vec<Block>> { Reader::new(image, true)?.collect() };
+    let (checksums, data): (Vec<_>, Vec<_>) = read(&image)
+        .unwrap()
+        .into_iter()
+        .partition(|b| matches!(b, Block::Crc32(_)));
+    assert_eq!(data, blocks);
+    assert!(checksums.len() > 2);
+    assert_eq!(checksums.last(), Some(&Block::Crc32(expected)));
+    let verified = checksum::verify(file, 2, CrcPolicy::default()).unwrap();
+    assert_eq!(verified.checksum, Some(expected));
+
+    // Corrupting the first raw block fails the first checksum chunk.
+    let mut corrupted = image.clone();
+    corrupted[40] ^= 1;
+    let blocks = Reader::new(&corrupted[..], true).unwrap();
+    assert!(blocks.take(3).any(|b| b.is_err()));
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&corrupted).unwrap();
+    assert!(checksum::verify(file, 2, CrcPolicy::default()).is_err());
+}
+
+#[test]
+fn copy_checksummed_image() {
+    use sparse::{pipeline, write::ChecksumMismatch};
+
+    // Without checksum writing, the checksum chunk is copied as is.
+    for crc in [false, true] {
+        let mut reader = Reader::new(data_file("crc.simg"), true).unwrap();
+        let mut writer = Writer::new(tempfile::tempfile().unwrap(), crc).unwrap();
+        pipeline::copy(&mut reader, &mut writer).unwrap();
+        let mut file = writer.close().unwrap();
+        assert_eq!(read_from_start(&mut file), data("crc.simg"));
+
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let writer = Writer::new(tmpfile.try_clone().unwrap(), crc).unwrap();
+        let mut rechunk = Rechunk::new(writer, ..=16).unwrap();
+        let mut reader = Reader::new(data_file("crc.simg"), true).unwrap();
+        pipeline::copy(&mut reader, &mut rechunk).unwrap();
+        rechunk.close().unwrap();
+        let image = read_from_start(&mut tmpfile);
+        let copied = Reader::new(&image[..], true)
+            .unwrap()
+            .filter(|b| !matches!(b, Ok(Block::Crc32(_))))
+            .collect::<sparse::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(copied, test_blocks());
+    }
+
+    // Checksum blocks are verified if checksum writing is enabled.
+    let mut writer = Writer::new(tempfile::tempfile().unwrap(), true).unwrap();
+    writer.write_block(&test_blocks()[0]).unwrap();
+    let err = writer.write_block(&Block::Crc32(0)).unwrap_err();
+    assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+
+    // Otherwise, they are written through unchecked.
+    let mut writer = Writer::new(tempfile::tempfile().unwrap(), false).unwrap();
+    writer.write_block(&test_blocks()[0]).unwrap();
+    writer.write_block(&Block::Crc32(0)).unwrap();
+    let mut file = writer.close().unwrap();
+    let image = read_from_start(&mut file);
+    let blocks: Vec<_> = Reader::new(&image[..], false)
+        .unwrap()
+        .map(|b| b.unwrap())
+        .collect();
+    assert_eq!(blocks, [test_blocks()[0].clone(), Block::Crc32(0)]);
+}
+
+#[test]
+fn append_sparse() {
+    let blocks = test_blocks();
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let file = tmpfile.try_clone().unwrap();
+    let mut writer = Writer::new(file, true).unwrap();
+    for block in &blocks[..3] {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    let file = tmpfile.try_clone().unwrap();
+    let mut writer = Writer::append_to(file, true).unwrap();
+    for block in &blocks[3..] {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    assert_eq!(read_from_start(&mut tmpfile), data("crc.simg"));
+}
+
+#[test]
+fn write_positioned() {
+    let blocks = test_blocks();
+    let file = Arc::new(tempfile::tempfile().unwrap());
+
+    let mut writer = Writer::from_file_positioned(Arc::clone(&file), true).unwrap();
+    for block in &blocks {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let file = Arc::clone(&file);
+            std::thread::spawn(move || {
+                let reader = Reader::from_file_positioned(file, true).unwrap();
+                reader.map(|b| b.unwrap()).collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut expected = blocks;
+    expected.push(Block::Crc32(0xffb880a5));
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), expected);
+    }
+}
+
 #[test]
 fn decode_to_raw() {
     let blocks = test_blocks();
@@ -60,3 +246,609 @@ fn decode_to_raw() {
 
     assert_eq!(read_from_start(&mut tmpfile), data("decoded.img"));
 }
+
+#[test]
+fn decode_fill_values() {
+    // Cycle through more fill values than the cache holds.
+    let blocks: Vec<_> = (0..20).map(|i| Block::fill_u32(i % 7)).collect();
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let file = tmpfile.try_clone().unwrap();
+    let mut decoder = Decoder::new(file).unwrap().fill_cache_size(2);
+    for block in &blocks {
+        decoder.write_block(block).unwrap();
+    }
+    decoder.close().unwrap();
+
+    assert_eq!(read_from_start(&mut tmpfile), testutil::decode(&blocks));
+}
+
+#[test]
+fn decode_raw_batches() {
+    let blocks = testutil::BlockGen::new(7).blocks(200);
+    let raw = testutil::decode(&blocks);
+
+    for batch_size in [0, 3, 32] {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let file = tmpfile.try_clone().unwrap();
+        let mut decoder = Decoder::with_capacity(4096, file)
+            .unwrap()
+            .raw_batch_size(batch_size);
+        for block in &blocks {
+            decoder.write_block(block).unwrap();
+        }
+        decoder.close().unwrap();
+
+        assert_eq!(read_from_start(&mut tmpfile), raw);
+    }
+}
+
+#[test]
+fn decode_with_reflink() {
+    let src = data_file("hello.simg");
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let file = tmpfile.try_clone().unwrap();
+    let mut decoder = Decoder::new(file).unwrap();
+    decoder.reflink_from(&src).unwrap();
+
+    let mut reader = Reader::new(src.try_clone().unwrap(), false).unwrap();
+    while let Some(block) = reader.next() {
+        let offset = reader.offset() - u64::from(Block::SIZE);
+        decoder.write_block_from(&block.unwrap(), offset).unwrap();
+    }
+    decoder.close().unwrap();
+
+    assert_eq!(read_from_start(&mut tmpfile), data("decoded.img"));
+}
+
+#[test]
+fn decode_with_reflink_aligned() {
+    // The raw data of hello.simg is not block-aligned, so pad the first raw
+    // chunk to a block boundary with a metadata chunk. Its blocks are then
+    // cloned, or copied where the filesystem doesn't support cloning.
+    let raw = |byte| Block::Raw([byte; Block::SIZE as usize].into());
+    let blocks = [
+        raw(1),
+        raw(2),
+        raw(3),
+        Block::fill_u32(4),
+        raw(5),
+        Block::Skip,
+    ];
+    let mut metadata = Metadata::new();
+    metadata.insert("pad", "x".repeat(4039)).unwrap();
+
+    let mut src = tempfile::tempfile().unwrap();
+    let mut writer = Writer::new(src.try_clone().unwrap(), false).unwrap();
+    writer.write_metadata(&metadata).unwrap();
+    for block in &blocks {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+    src.rewind().unwrap();
+
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let file = tmpfile.try_clone().unwrap();
+    let mut decoder = Decoder::new(file).unwrap();
+    decoder.reflink_from(&src).unwrap();
+
+    let mut reader = Reader::new(src.try_clone().unwrap(), false).unwrap();
+    let mut offsets = Vec::new();
+    while let Some(block) = reader.next() {
+        let offset = reader.offset() - u64::from(Block::SIZE);
+        decoder.write_block_from(&block.unwrap(), offset).unwrap();
+        offsets.push(offset);
+    }
+    decoder.close().unwrap();
+
+    assert_eq!(offsets[..3], [4096, 8192, 12288]);
+    assert_eq!(read_from_start(&mut tmpfile), testutil::decode(&blocks));
+}
+
+#[test]
+fn decode_block_count_policy() {
+    use sparse::BlockCountPolicy;
+
+    let blocks = testutil::BlockGen::new(3).blocks(10);
+    let decode = |total, policy| {
+        let mut decoded = Vec::new();
+        let w = sparse::io::ZeroSeek::new(&mut decoded);
+        let mut decoder = Decoder::new(w).unwrap().block_count_policy(total, policy);
+        for block in &blocks {
+            decoder.write_block(block)?;
+        }
+        decoder.write_block(&Block::Crc32(0))?;
+        decoder.close()?;
+        Ok::<_, sparse::Error>(decoded)
+    };
+
+    let raw = testutil::decode(&blocks);
+    let block_size = Block::SIZE as usize;
+    for policy in [BlockCountPolicy::Error, BlockCountPolicy::Truncate] {
+        assert_eq!(decode(10, policy).unwrap(), raw);
+    }
+
+    // Excess blocks
+    assert!(decode(8, BlockCountPolicy::Error).is_err());
+    for policy in [BlockCountPolicy::Truncate, BlockCountPolicy::PadWithZeros] {
+        assert_eq!(decode(8, policy).unwrap(), raw[..8 * block_size]);
+    }
+
+    // Missing blocks
+    assert!(decode(12, BlockCountPolicy::Error).is_err());
+    assert_eq!(decode(12, BlockCountPolicy::Truncate).unwrap(), raw);
+    let padded = decode(12, BlockCountPolicy::PadWithZeros).unwrap();
+    assert_eq!(padded[..raw.len()], raw);
+    assert_eq!(padded[raw.len()..], vec![0; 2 * block_size]);
+}
+
+#[test]
+fn copy_encoded_to_writer() {
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let mut encoder = Encoder::new(data_file("hello.img")).unwrap();
+    let mut writer = Writer::new(tmpfile.try_clone().unwrap(), false).unwrap();
+    let count = sparse::pipeline::copy(&mut encoder, &mut writer).unwrap();
+    BlockSink::close(writer).unwrap();
+
+    assert_eq!(count, test_blocks().len() as u64);
+    assert_eq!(read_from_start(&mut tmpfile), data("hello.simg"));
+}
+
+#[test]
+fn write_encrypted() {
+    let blocks = test_blocks();
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let xor = |index: u64, data: &mut [u8]| data.iter_mut().for_each(|b| *b ^= index as u8 + 1);
+
+    let writer = Writer::new(tmpfile.try_clone().unwrap(), false).unwrap();
+    let mut sink = CipherSink::new(writer, xor);
+    for block in &blocks {
+        sink.write_block(block).unwrap();
+    }
+    sink.close().unwrap();
+
+    let encrypted = read_from_start(&mut tmpfile);
+    assert_eq!(encrypted.len(), data("hello.simg").len());
+    assert_ne!(encrypted, data("hello.simg"));
+
+    let reader = Reader::new(&encrypted[..], false).unwrap();
+    let decrypted: Vec<_> = CipherSource::new(reader, xor)
+        .blocks()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(decrypted, blocks);
+}
+
+#[test]
+fn auto_convert() {
+    use sparse::convert::Format;
+
+    let mut decoded = Vec::new();
+    let format = sparse::auto_convert(&data("hello.simg")[..], &mut decoded).unwrap();
+    assert_eq!(format, Format::Sparse);
+    assert_eq!(decoded, data("decoded.img"));
+
+    let mut encoded = Vec::new();
+    let format = sparse::auto_convert(&data("hello.img")[..], &mut encoded).unwrap();
+    assert_eq!(format, Format::Raw);
+    assert_eq!(encoded, data("hello.simg"));
+}
+
+#[test]
+fn split_and_merge() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let prefix = tmpdir.path().join("hello.simg");
+
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let parts = sparse::split::split(reader, 6000, &prefix).unwrap();
+    assert_eq!(parts.len(), 2);
+    for part in &parts {
+        assert!(part.metadata().unwrap().len() <= 6000);
+    }
+
+    let mut readers: Vec<_> = parts
+        .iter()
+        .map(|p| Reader::new(File::open(p).unwrap(), false).unwrap())
+        .collect();
+    let mut decoded = Vec::new();
+    let mut decoder = Decoder::new(sparse::io::ZeroSeek::new(&mut decoded)).unwrap();
+    sparse::merge::merge(&mut readers, &mut decoder).unwrap();
+    decoder.close().unwrap();
+    assert_eq!(decoded, data("decoded.img"));
+
+    let old = Reader::new(data_file("hello.simg"), false).unwrap();
+    let new = Reader::new(File::open(&parts[0]).unwrap(), false).unwrap();
+    assert_eq!(sparse::diff::diff_ranges(old, new).unwrap(), vec![4..5]);
+}
+
+#[test]
+fn split_at_boundaries() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let prefix = tmpdir.path().join("hello.simg");
+
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let parts = sparse::split::split_at(reader, &[4096, 4 * 4096, 4 * 4096], &prefix).unwrap();
+    assert_eq!(parts.len(), 4);
+
+    let mut readers: Vec<_> = parts
+        .iter()
+        .map(|p| Reader::new(File::open(p).unwrap(), false).unwrap())
+        .collect();
+    for reader in &readers {
+        assert_eq!(reader.size, 5 * 4096);
+    }
+    let region: Vec<_> = Reader::new(File::open(&parts[1]).unwrap(), false)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(region[0], Block::Skip);
+    assert_eq!(region[1..4], test_blocks()[1..4]);
+    assert_eq!(region[4], Block::Skip);
+
+    let mut decoded = Vec::new();
+    let mut decoder = Decoder::new(sparse::io::ZeroSeek::new(&mut decoded)).unwrap();
+    sparse::merge::merge(&mut readers, &mut decoder).unwrap();
+    decoder.close().unwrap();
+    assert_eq!(decoded, data("decoded.img"));
+
+    for boundaries in [&[100][..], &[8192, 4096], &[6 * 4096]] {
+        let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+        assert!(sparse::split::split_at(reader, boundaries, &prefix).is_err());
+    }
+}
+
+#[test]
+fn split_with_callback() {
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let count = sparse::split::split_with(reader, 6000, |index| {
+        File::create(tmpdir.path().join(format!("part-{index}.simg")))
+    })
+    .unwrap();
+    assert_eq!(count, 2);
+
+    let prefix = tmpdir.path().join("hello.simg");
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let parts = sparse::split::split(reader, 6000, &prefix).unwrap();
+    for (index, part) in parts.iter().enumerate() {
+        let custom = tmpdir.path().join(format!("part-{index}.simg"));
+        assert_eq!(fs::read(custom).unwrap(), fs::read(part).unwrap());
+    }
+}
+
+#[test]
+fn split_for_profile() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let profile = sparse::profile::Profile {
+        max_chunks: Some(4),
+        require_crc: true,
+        ..sparse::profile::Profile::new("board")
+    };
+
+    let prefix = tmpdir.path().join("hello.simg");
+    let reader = Reader::new(data_file("hello.simg"), false).unwrap();
+    let parts = sparse::split::split_for(reader, &profile, &prefix).unwrap();
+    assert_eq!(parts.len(), 2);
+
+    let mut readers = Vec::new();
+    for part in &parts {
+        let report = sparse::lint::lint(File::open(part).unwrap(), &profile).unwrap();
+        assert_eq!(report.findings, []);
+        readers.push(Reader::new(File::open(part).unwrap(), true).unwrap());
+    }
+    let mut decoded = Vec::new();
+    let mut decoder = Decoder::new(sparse::io::ZeroSeek::new(&mut decoded)).unwrap();
+    sparse::merge::merge(&mut readers, &mut decoder).unwrap();
+    decoder.close().unwrap();
+    assert_eq!(decoded, data("decoded.img"));
+}
+
+#[test]
+fn write_max_chunks() {
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let file = tmpfile.try_clone().unwrap();
+    let mut writer = Writer::new(file, true).unwrap().max_chunks(3);
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let reader = Reader::new(&mut tmpfile, true).unwrap();
+    let mut decoded = Vec::new();
+    let mut decoder = Decoder::new(sparse::io::ZeroSeek::new(&mut decoded)).unwrap();
+    for block in reader {
+        decoder.write_block(&block.unwrap()).unwrap();
+    }
+    decoder.close().unwrap();
+    assert_eq!(decoded, data("decoded.img"));
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let chunks = sparse::dump::Chunks::new(&mut tmpfile).unwrap();
+    assert_eq!(chunks.header().total_chunks, 3);
+
+    let file = tempfile::tempfile().unwrap();
+    let mut writer = Writer::new(file, true).unwrap().max_chunks(1);
+    assert!(writer.write_block(&Block::Skip).is_err());
+}
+
+#[test]
+fn write_fragmented() {
+    // Every block starts a new chunk.
+    let blocks: Vec<_> = (0..200)
+        .map(|i| match i % 2 {
+            0 => Block::fill_u32(i),
+            _ => Block::Skip,
+        })
+        .collect();
+    let decode = |blocks: &[Block]| {
+        let mut raw = Vec::new();
+        for block in blocks {
+            block.write_decoded(&mut raw).unwrap();
+        }
+        raw
+    };
+
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let recorded = reported.clone();
+    let mut writer = Writer::new(tempfile::tempfile().unwrap(), false)
+        .unwrap()
+        .on_fragmentation(8, move |blocks, chunks| {
+            recorded.lock().unwrap().push((blocks, chunks))
+        });
+    for block in &blocks {
+        writer.write_block(block).unwrap();
+    }
+    assert_eq!(writer.chunks_written(), 200);
+    writer.close().unwrap();
+    assert_eq!(*reported.lock().unwrap(), [(64, 64)]);
+
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let mut writer = Writer::new(tmpfile.try_clone().unwrap(), true)
+        .unwrap()
+        .min_blocks_per_chunk(8);
+    for block in &blocks {
+        writer.write_block(block).unwrap();
+    }
+    assert!(writer.chunks_written() <= 200 / 8 + 1);
+    writer.close().unwrap();
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let read: Vec<_> = Reader::new(&mut tmpfile, true)
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|b| !matches!(b, Block::Crc32(_)))
+        .collect();
+    assert_eq!(decode(&read), decode(&blocks));
+}
+
+#[test]
+fn write_rechunked() {
+    let raw = |n: u8| Block::Raw(Box::new([n; Block::SIZE as usize]).into());
+    let mut blocks: Vec<_> = (0..10).map(raw).collect();
+    blocks.extend([Block::fill_u32(7), Block::Skip, raw(10), raw(11)]);
+    blocks.extend([Block::Skip, Block::Skip, Block::Skip]);
+
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let writer = Writer::new(tmpfile.try_clone().unwrap(), false).unwrap();
+    let mut rechunk = Rechunk::new(writer, 3..=4).unwrap();
+    for block in &blocks {
+        rechunk.write_block(block).unwrap();
+    }
+    rechunk.close().unwrap();
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let chunks: Vec<_> = sparse::dump::Chunks::new(&mut tmpfile)
+        .unwrap()
+        .map(|c| c.unwrap().header.chunk_size)
+        .collect();
+    assert_eq!(chunks, [4, 4, 3, 1, 3, 2]);
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let read: Vec<_> = Reader::new(&mut tmpfile, false)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let decode = |blocks: &[Block]| {
+        let mut raw = Vec::new();
+        for block in blocks {
+            block.write_decoded(&mut raw).unwrap();
+        }
+        raw
+    };
+    assert_eq!(decode(&read), decode(&blocks));
+
+    let writer = || Writer::new(tempfile::tempfile().unwrap(), false).unwrap();
+    assert!(Rechunk::new(writer(), ..=0).is_err());
+    let reversed = (Bound::Included(5), Bound::Included(4));
+    assert!(Rechunk::new(writer(), reversed).is_err());
+}
+
+#[test]
+fn write_counters() {
+    let blocks = test_blocks();
+    let mut tmpfile = tempfile::tempfile().unwrap();
+
+    let mut writer = Writer::new(tmpfile.try_clone().unwrap(), false).unwrap();
+    assert_eq!(writer.bytes_written_sparse(), 28);
+    for block in &blocks[..3] {
+        writer.write_block(block).unwrap();
+    }
+    let (chunks, bytes) = (writer.chunks_written(), writer.bytes_written_sparse());
+    writer.close().unwrap();
+    assert_eq!(bytes, tmpfile.metadata().unwrap().len());
+
+    // Appending picks up the counters of the existing image.
+    let mut writer = Writer::append_to(tmpfile.try_clone().unwrap(), false).unwrap();
+    assert_eq!(writer.chunks_written(), chunks);
+    assert_eq!(writer.bytes_written_sparse(), bytes);
+    for block in &blocks[3..] {
+        writer.write_block(block).unwrap();
+    }
+    assert_eq!(writer.blocks_written(), blocks.len() as u64);
+    let bytes = writer.bytes_written_sparse();
+    writer.close().unwrap();
+    assert_eq!(read_from_start(&mut tmpfile), data("hello.simg"));
+    assert_eq!(bytes, data("hello.simg").len() as u64);
+}
+
+#[test]
+fn write_on_chunk() {
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let entries = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = entries.clone();
+    let mut writer = Writer::new(tmpfile.try_clone().unwrap(), false)
+        .unwrap()
+        .on_chunk(move |entry| recorded.lock().unwrap().push(entry.clone()));
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let chunks: Vec<_> = sparse::dump::Chunks::new(tmpfile)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(*entries.lock().unwrap(), chunks);
+}
+
+#[test]
+fn write_metadata() {
+    let mut metadata = sparse::metadata::Metadata::new();
+    metadata.insert("build_id", "AP1A.240305.019").unwrap();
+
+    let mut tmpfile = tempfile::tempfile().unwrap();
+    let mut writer = Writer::new(tmpfile.try_clone().unwrap(), true).unwrap();
+    writer.write_metadata(&metadata).unwrap();
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let read = sparse::metadata::read(&mut tmpfile).unwrap();
+    assert_eq!(read.as_ref(), Some(&metadata));
+
+    tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = Reader::new(&mut tmpfile, true).unwrap();
+    let blocks: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+    assert_eq!(blocks[..blocks.len() - 1], test_blocks()[..]);
+    assert_eq!(reader.metadata(), Some(&metadata));
+}
+
+#[test]
+fn write_deterministic() {
+    let write = |keys: &[&str]| {
+        let mut metadata = sparse::metadata::Metadata::new();
+        for key in keys {
+            metadata.insert(*key, "value").unwrap();
+        }
+        let mut sparse = std::io::Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut sparse, true).unwrap().deterministic(true);
+        writer.write_metadata(&metadata).unwrap();
+        for block in &test_blocks() {
+            writer.write_block(block).unwrap();
+        }
+        writer.close().unwrap();
+        sparse.into_inner()
+    };
+
+    assert_eq!(write(&["a", "b", "c"]), write(&["c", "a", "b"]));
+}
+
+#[test]
+fn write_locked() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("hello.simg");
+    let writer = Writer::new(File::create(&path).unwrap(), true)
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    let other = File::options().write(true).open(&path).unwrap();
+    let err = Decoder::new(other).unwrap().lock().err().unwrap();
+    assert!(err.downcast_ref::<sparse::io::AlreadyLocked>().is_some());
+
+    writer.close().unwrap();
+    let other = File::options().write(true).open(&path).unwrap();
+    Decoder::new(other).unwrap().lock().unwrap();
+}
+
+#[test]
+fn close_returns_destination() {
+    let mut writer = Writer::new(std::io::Cursor::new(Vec::new()), false).unwrap();
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    let sparse = writer.close().unwrap().into_inner();
+    assert_eq!(sparse, data("hello.simg"));
+
+    let mut decoder = Decoder::new(tempfile::tempfile().unwrap()).unwrap();
+    for block in &test_blocks() {
+        decoder.write_block(block).unwrap();
+    }
+    let mut raw = decoder.close().unwrap();
+    assert_eq!(raw.stream_position().unwrap(), 5 * 4096);
+    assert_eq!(read_from_start(&mut raw), data("decoded.img"));
+}
+
+#[test]
+fn sync_on_close() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("hello.simg");
+    let mut writer = Writer::new(File::create(&path).unwrap(), false)
+        .unwrap()
+        .sync_on_close(true);
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    writer.close().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), data("hello.simg"));
+
+    let mut raw = tempfile::tempfile().unwrap();
+    let mut decoder = Decoder::new(&mut raw).unwrap().sync_on_close(true);
+    for block in &test_blocks() {
+        decoder.write_block(block).unwrap();
+    }
+    decoder.close().unwrap();
+    assert_eq!(read_from_start(&mut raw), data("decoded.img"));
+}
+
+#[test]
+fn abort_on_drop() {
+    let mut sparse = std::io::Cursor::new(Vec::new());
+    let mut writer = Writer::new(&mut sparse, true).unwrap();
+    writer.set_abort_on_drop(true);
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    assert!(!writer.finished());
+    drop(writer);
+    assert!(Reader::new(&sparse.get_ref()[..], false).is_err());
+
+    let mut writer = Writer::new(std::io::Cursor::new(Vec::new()), false).unwrap();
+    for block in &test_blocks() {
+        writer.write_block(block).unwrap();
+    }
+    writer.finish().unwrap();
+    assert!(writer.finished());
+    assert!(writer.write_block(&Block::Skip).is_err());
+    assert!(writer.finish().is_err());
+    let sparse = writer.close().unwrap().into_inner();
+    assert_eq!(sparse, data("hello.simg"));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Decoder dropped without being closed")]
+fn must_close() {
+    let mut decoder = Decoder::new(std::io::Cursor::new(Vec::new())).unwrap();
+    decoder.set_must_close(true);
+    decoder.write_block(&Block::Skip).unwrap();
+}